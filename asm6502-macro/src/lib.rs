@@ -0,0 +1,169 @@
+// asm6502! -- 6502 assembly at compile time
+//
+//   const CODE: &[u8] = asm6502!{ org $8000; LDA #$10; STA $0200,X; loop: BNE loop };
+//   let (origin, bytes, symbols) = asm6502_program!{ ... };
+//
+// asm6502! gives the image (see Program::image()) as a &'static [u8], asm6502_program!
+// a (u16, &'static [u8], &'static [(&'static str, u16)]) with the origin of the image and
+// the labels and equates (without the anonymous labels).
+//
+// The source is the one of the assembler (ca65 syntax, see src/asm/mod.rs) written as Rust
+// tokens: the statements are separated by ';' or by new lines, the comments are // and /* */,
+// and the directives can be written without the '.' (org $8000). Rust doesn't accept numbers
+// like $1E or $0E5 (the E is an exponent), they have to be written as 0x1E (0b0101 works too).
+//
+// The assembly errors are reported as compile errors on the offending token.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+#[allow(dead_code, unused_imports)] // the language server parts are not used here
+#[path = "../../src/asm/mod.rs"]
+mod asm;
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/cpu.rs"]
+mod cpu;
+#[allow(dead_code)]
+#[path = "../../src/disasm.rs"]
+mod disasm;
+#[cfg(test)]
+#[allow(dead_code)] // only for the tests of asm
+#[path = "../../src/tempdir.rs"]
+mod tempdir;
+
+use asm::{Assembler, Dialect, Program};
+
+#[proc_macro]
+pub fn asm6502(input: TokenStream) -> TokenStream {
+    expand(input, |program| {
+        let (_, bytes) = program.image().unwrap_or_default();
+        format!("({} as &'static [u8])", Literal::byte_string(&bytes))
+    })
+}
+
+#[proc_macro]
+pub fn asm6502_program(input: TokenStream) -> TokenStream {
+    expand(input, |program| {
+        let (origin, bytes) = program.image().unwrap_or_default();
+        let symbols: Vec<String> = program.symbols.iter()
+            .filter(|(name, _)| !name.starts_with(':'))
+            .map(|(name, value)| format!("({}, {:#06x}u16)", Literal::string(name), value))
+            .collect();
+        format!("({:#06x}u16, {} as &'static [u8], &[{}] as &'static [(&'static str, u16)])",
+            origin, Literal::byte_string(&bytes), symbols.join(", "))
+    })
+}
+
+fn expand(input: TokenStream, output: impl Fn(&Program) -> String) -> TokenStream {
+    let mut source = Source::default();
+    source.tokens(input);
+    match Assembler::new().assemble(&source.text) {
+        Ok(program) => output(&program).parse().unwrap(),
+        Err(errors) => {
+            let errors = errors.iter().flat_map(|e| {
+                let message = match &e.note {
+                    Some(note) => format!("{}\n{}", e.message, note),
+                    None => e.message.clone(),
+                };
+                compile_error(&message, source.span(e.span.line, e.span.column))
+            });
+            TokenTree::from(Group::new(Delimiter::Brace, errors.collect())).into()
+        }
+    }
+}
+
+// the source text and where its tokens come from
+#[derive(Default)]
+struct Source {
+    text: String,
+    lines: Vec<Vec<(usize, Span)>>, // column (0-based) and span of the tokens
+    last: Option<(usize, usize)>,   // Rust line and end column of the previous token
+}
+
+impl Source {
+    fn tokens(&mut self, input: TokenStream) {
+        for token in input {
+            match &token {
+                TokenTree::Punct(p) if p.as_char() == ';' => self.last = None,
+                TokenTree::Group(g) => {
+                    let (open, close) = match g.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, g.span_open());
+                    self.tokens(g.stream());
+                    self.push(close, g.span_close());
+                }
+                TokenTree::Literal(l) => {
+                    let text = l.to_string();
+                    let text = match (text.get(..2), text.get(2..)) {
+                        (Some("0x"), Some(digits)) => format!("${}", digits.replace('_', "")),
+                        (Some("0b"), Some(digits)) => format!("%{}", digits.replace('_', "")),
+                        _ => text,
+                    };
+                    self.push(&text, l.span());
+                }
+                TokenTree::Ident(i) => {
+                    // a directive without the '.' at the start of a statement
+                    let text = i.to_string();
+                    let first = self.last.is_none_or(|(line, _)| line != i.span().line());
+                    if first && Dialect::Ca65.directive(&format!(".{}", text)).is_some()
+                        && !disasm::OPCODES.iter().any(|o| o.mnemonic.eq_ignore_ascii_case(&text)) {
+                        self.push(&format!(".{}", text), i.span());
+                    } else {
+                        self.push(&text, i.span());
+                    }
+                }
+                TokenTree::Punct(p) => self.push(&p.as_char().to_string(), p.span()),
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str, span: Span) {
+        if text.is_empty() {
+            return;
+        }
+        let (line, column) = (span.line(), span.column());
+        match self.last {
+            Some((last_line, _)) if last_line != line => self.new_line(),
+            Some((_, end)) if end != column => self.text.push(' '),
+            Some(_) => {}
+            None => self.new_line(),
+        }
+        let start = self.text.len() - self.text.rfind('\n').map_or(0, |i| i + 1);
+        self.text.push_str(text);
+        self.lines.last_mut().unwrap().push((start, span));
+        self.last = Some((line, span.end().column()));
+    }
+
+    fn new_line(&mut self) {
+        if !self.lines.is_empty() {
+            self.text.push('\n');
+        }
+        self.lines.push(Vec::new());
+    }
+
+    // the span of the token at a line and column (1-based) of the source text
+    fn span(&self, line: usize, column: usize) -> Span {
+        let tokens = match line.checked_sub(1).and_then(|l| self.lines.get(l)) {
+            Some(tokens) if !tokens.is_empty() => tokens,
+            _ => return Span::call_site(),
+        };
+        let column = column.saturating_sub(1);
+        tokens.iter().rev().find(|(start, _)| *start <= column).unwrap_or(&tokens[0]).1
+    }
+}
+
+// compile_error!{"message"} with the span of the error
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut group = Group::new(Delimiter::Brace, TokenTree::from(literal).into());
+    group.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    [TokenTree::from(Ident::new("compile_error", span)), bang.into(), group.into()].into_iter().collect()
+}
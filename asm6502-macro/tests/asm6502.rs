@@ -0,0 +1,45 @@
+use asm6502_macro::{asm6502, asm6502_program};
+
+#[test]
+fn test_bytes() {
+    let code = asm6502!{ org $8000; LDA #$10; STA $0200,X; loop: BNE loop };
+    assert!(code == [0xA9, 0x10, 0x9D, 0x00, 0x02, 0xD0, 0xFE]);
+
+    const TABLE: &[u8] = asm6502!{
+        .byte 1, 2, "ab"
+        word $C000, 0x1E0E
+        LDA ($20),Y   // comment
+        JMP ($FFFC)
+    };
+    assert!(TABLE == [1, 2, b'a', b'b', 0x00, 0xC0, 0x0E, 0x1E, 0xB1, 0x20, 0x6C, 0xFC, 0xFF]);
+}
+
+#[test]
+fn test_program() {
+    let (origin, bytes, symbols) = asm6502_program!{
+        org $C000
+        count = 0b0000_0011
+        start:
+            LDX #count
+        :   DEX
+            BNE :-
+        done: RTS
+    };
+    assert!(origin == 0xC000);
+    assert!(bytes == [0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x60]);
+    assert!(symbols == [("count", 3), ("done", 0xC005), ("start", 0xC000)]);
+}
+
+#[test]
+fn test_macros() {
+    let code = asm6502!{
+        macro inc16 addr
+            INC addr
+            BNE @skip
+            INC addr+1
+        @skip:
+        endmacro
+        inc16 $10; inc16 $20
+    };
+    assert!(code == [0xE6, 0x10, 0xD0, 0x02, 0xE6, 0x11, 0xE6, 0x20, 0xD0, 0x02, 0xE6, 0x21]);
+}
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mos6502_fuzz::fuzz;
+
+fuzz_target!(|data: &[u8]| {
+    if let Err(e) = fuzz::step(data) {
+        panic!("{}", e);
+    }
+});
@@ -0,0 +1,16 @@
+// The checks of src/fuzz.rs for the targets of fuzz_targets/, with the modules they use
+//
+//   cargo fuzz run step
+//   cargo fuzz run differential
+
+#[allow(dead_code, clippy::all)]
+#[path = "../../src/cpu.rs"]
+mod cpu;
+#[allow(dead_code)]
+#[path = "../../src/disasm.rs"]
+mod disasm;
+#[allow(dead_code)]
+#[path = "../../src/step.rs"]
+mod step;
+#[path = "../../src/fuzz.rs"]
+pub mod fuzz;
@@ -0,0 +1,360 @@
+// Source dialects
+//
+// The native syntax is the one of ca65. The other dialects differ in:
+//
+//                      ca65            ACME                    64tass
+//   directives         .byte           !byte                   .byte
+//   set the address    .org $C000      *= $C000                * = $C000
+//   local labels       @loop           .loop (per !zone)       _loop
+//   anonymous labels   : / :- :+       - / + (also --, ++)     - / + (-- is the 2nd previous)
+//   labels             name:           name                    name
+//   macro call         name args       +name args              #name args
+//   blocks             .if / .endif    !if x { ... } else { }  .if / .elsif / .fi
+//   comments           ;               ;                       ; and .comment / .endc
+//   operators          .and .mod ...   AND OR XOR DIV MOD NOT  && || ^ ~
+//   scopes             .proc / .scope  (!zone: local labels)   .proc / .pend, .block / .bend
+//   assembled          .org            !pseudopc x { ... }     .logical / .here
+//     elsewhere
+//   variables                          !set name = value
+//
+// Everything is mapped to the native directives and operators here and in the lexer,
+// so the rest of the assembler doesn't know about the dialects. !align of ACME takes the
+// and-value and the equal-value of ACME, .align of the others the boundary.
+//
+// Not supported:
+//   ca65    .define with parameters, the effect of .feature (the directive is accepted),
+//           .align and .global in a relocatable object (.align needs the address of the segment,
+//           .global works as .export / .import there), the warnings of .assert are only listed
+//           in Program::output, .org inside .proc and .scope
+//   ACME    !for, !do, !while, !addr, !convtab, !scr, !pet, !initmem, !warn / !error / !serious
+//   64tass  the labels of a .proc or .block referred to from outside as name.label (only the
+//           code inside sees them), an unused .proc is assembled anyway, .section, .struct,
+//           .union, .for, .rept, .bfor, .var
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Ca65,
+    Acme,
+    Tass64,
+}
+
+// dialect name -> native name ("" for the directives which are accepted and ignored)
+const CA65: &[(&str, &str)] = &[
+    ("org", "org"), ("byte", "byte"), ("byt", "byte"), ("word", "word"), ("addr", "word"),
+    ("res", "res"), ("incbin", "incbin"), ("include", "include"),
+    ("segment", "segment"), ("code", "code"), ("data", "data"), ("rodata", "rodata"), ("bss", "bss"),
+    ("zeropage", "zeropage"),
+    ("macro", "macro"), ("mac", "macro"), ("endmacro", "endmacro"), ("endmac", "endmacro"),
+    ("if", "if"), ("ifdef", "ifdef"), ("ifndef", "ifndef"), ("elseif", "elseif"), ("else", "else"),
+    ("endif", "endif"),
+    ("import", "import"), ("importzp", "importzp"), ("export", "export"), ("exportzp", "exportzp"),
+    ("asciiz", "asciiz"), ("dbyt", "dbyt"), ("lobytes", "lobytes"), ("hibytes", "hibytes"),
+    ("proc", "proc"), ("endproc", "endproc"), ("repeat", "repeat"), ("endrep", "endrep"), ("endrepeat", "endrep"),
+    ("ascii", "byte"), ("align", "align"), ("global", "global"), ("globalzp", "globalzp"), ("define", "define"),
+    ("scope", "scope"), ("endscope", "endscope"), ("local", "local"), ("enum", "enum"), ("endenum", "endenum"),
+    ("assert", "assert"), ("out", "out"),
+    ("setcpu", ""), ("p02", ""), ("debuginfo", ""), ("feature", ""),
+];
+
+const ACME: &[(&str, &str)] = &[
+    ("byte", "byte"), ("by", "byte"), ("8", "byte"), ("08", "byte"), ("text", "byte"), ("tx", "byte"),
+    ("raw", "byte"), ("word", "word"), ("wo", "word"), ("16", "word"),
+    ("fill", "res"), ("fi", "res"), ("skip", "res"),
+    ("binary", "incbin"), ("bin", "incbin"), ("bi", "incbin"), ("source", "include"), ("src", "include"),
+    ("macro", "macro"), ("if", "if"), ("ifdef", "ifdef"), ("ifndef", "ifndef"), ("zone", "zone"), ("zn", "zone"),
+    ("pseudopc", "logical"), ("align", "align"), ("set", "set"),
+    // the closing braces of the blocks are translated into these
+    ("endmacro", "endmacro"), ("else", "else"), ("endif", "endif"), ("endzone", "endzone"), ("realpc", "here"),
+    ("to", ""), ("cpu", ""), ("sl", ""),
+];
+
+const TASS64: &[(&str, &str)] = &[
+    ("byte", "byte"), ("char", "byte"), ("text", "byte"), ("word", "word"), ("addr", "word"),
+    ("fill", "res"), ("binary", "incbin"), ("include", "include"),
+    ("macro", "macro"), ("endm", "endmacro"), ("endmacro", "endmacro"),
+    ("if", "if"), ("elsif", "elseif"), ("else", "else"), ("fi", "endif"), ("endif", "endif"),
+    ("null", "asciiz"), ("proc", "proc"), ("pend", "endproc"), ("endproc", "endproc"), ("block", "scope"),
+    ("bend", "endscope"), ("endblock", "endscope"), ("logical", "logical"), ("here", "here"), ("endlogical", "here"),
+    ("align", "align"),
+    ("cpu", ""),
+];
+
+// ACME: the operators written as words
+const ACME_OPERATORS: &[(&str, &str)] = &[
+    ("and", "&"), ("or", "|"), ("xor", "^"), ("eor", "^"), ("div", "/"), ("mod", "mod"), ("not", "~"),
+];
+
+// ca65: the operators written as directives
+const CA65_OPERATORS: &[(&str, &str)] = &[
+    (".and", "&&"), (".or", "||"), (".not", "!"), (".mod", "mod"), (".bitand", "&"), (".bitor", "|"),
+    (".bitxor", "^"), (".bitnot", "~"), (".shl", "<<"), (".shr", ">>"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    If,
+    Macro,
+    Zone,
+    PseudoPc,
+    Other,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name.to_ascii_lowercase().as_str() {
+            "ca65" => Some(Dialect::Ca65),
+            "acme" => Some(Dialect::Acme),
+            "64tass" => Some(Dialect::Tass64),
+            _ => None,
+        }
+    }
+
+    pub fn directive_prefix(self) -> char {
+        match self {
+            Dialect::Acme => '!',
+            _ => '.',
+        }
+    }
+
+    // the native name of a directive (given with its prefix)
+    pub fn directive(self, name: &str) -> Option<&'static str> {
+        let name = name.strip_prefix(self.directive_prefix())?;
+        self.directive_table().iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, native)| *native)
+    }
+
+    // all the directives, with their prefix
+    pub fn directives(self) -> Vec<String> {
+        // the closing braces of ACME are not written as directives
+        let hidden = |name: &str| self == Dialect::Acme && (name.starts_with("end") || name == "else");
+        self.directive_table().iter()
+            .filter(|(name, _)| !hidden(name))
+            .map(|(name, _)| format!("{}{}", self.directive_prefix(), name))
+            .collect()
+    }
+
+    fn directive_table(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Dialect::Ca65 => CA65,
+            Dialect::Acme => ACME,
+            Dialect::Tass64 => TASS64,
+        }
+    }
+
+    // the native operator for a word (the result is a one or two character operator, or "mod")
+    pub fn operator(self, word: &str) -> Option<&'static str> {
+        let table = match self {
+            Dialect::Ca65 => CA65_OPERATORS,
+            Dialect::Acme => ACME_OPERATORS,
+            Dialect::Tass64 => return None,
+        };
+        table.iter().find(|(w, _)| w.eq_ignore_ascii_case(word)).map(|(_, op)| *op)
+    }
+
+    pub fn local_prefix(self) -> char {
+        match self {
+            Dialect::Ca65 => '@',
+            Dialect::Acme => '.',
+            Dialect::Tass64 => '_',
+        }
+    }
+
+    // do the normal labels start a new scope for the local ones? (in ACME only !zone does)
+    pub fn labels_open_scope(self) -> bool {
+        self != Dialect::Acme
+    }
+
+    // labels without ':', anonymous labels - and +, macro calls with a prefix
+    pub fn plain_labels(self) -> bool {
+        self != Dialect::Ca65
+    }
+
+    // the character in front of the macro name in a macro call
+    pub fn macro_call_prefix(self) -> Option<char> {
+        match self {
+            Dialect::Ca65 => None,
+            Dialect::Acme => Some('+'),
+            Dialect::Tass64 => Some('#'),
+        }
+    }
+
+    // the namespace and the distance of an anonymous label written as `count` times `sign`
+    // (in ACME -- is a different label than -, in 64tass it's the second previous -)
+    pub fn anonymous(self, sign: char, count: usize) -> (String, i32) {
+        let dir = if sign == '+' { 1 } else { -1 };
+        match self {
+            Dialect::Acme => (sign.to_string().repeat(count), dir),
+            _ => (sign.to_string(), dir * count as i32),
+        }
+    }
+
+    // the lines of a source file with their numbers, the block syntax is turned into
+    // the usual directives (one line may become several)
+    pub fn lines(self, source: &str) -> Vec<(usize, String)> {
+        let lines = source.lines().enumerate().map(|(idx, text)| (idx + 1, text.to_string()));
+        match self {
+            Dialect::Ca65 => lines.collect(),
+            Dialect::Acme => {
+                let mut blocks = Vec::new();
+                lines.flat_map(|(line, text)| acme_blocks(&text, &mut blocks).into_iter().map(move |t| (line, t))).collect()
+            }
+            Dialect::Tass64 => {
+                let mut comment = false;
+                lines.map(|(line, text)| {
+                    let first = text.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+                    if first == ".comment" {
+                        comment = true;
+                    }
+                    if comment {
+                        comment = first != ".endc";
+                        return (line, format!(";{}", text));
+                    }
+                    (line, text)
+                }).collect()
+            }
+        }
+    }
+}
+
+// ACME: `!if x {`, `} else {`, `}` -> !if x, !else, !endif (the same for !macro, !zone and
+// !pseudopc, which ends with !realpc);
+// the parts after a brace are indented to keep their columns for the errors
+fn acme_blocks(text: &str, blocks: &mut Vec<Block>) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut i = 0;
+
+    let flush = |out: &mut Vec<String>, from: usize, to: usize| {
+        let s: String = chars[from..to].iter().collect();
+        if !s.trim().is_empty() && !s.trim().starts_with(';') {
+            out.push(" ".repeat(from) + &s);
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == ';' => break,
+            None if c == '{' => {
+                let header: String = chars[start..i].iter().collect();
+                let keyword = header.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+                blocks.push(match keyword.as_str() {
+                    "!if" | "!ifdef" | "!ifndef" => Block::If,
+                    "!macro" => Block::Macro,
+                    "!zone" | "!zn" => Block::Zone,
+                    "!pseudopc" => Block::PseudoPc,
+                    _ => Block::Other,
+                });
+                flush(&mut out, start, i);
+                start = i + 1;
+            }
+            None if c == '}' => {
+                flush(&mut out, start, i);
+                let rest: String = chars[i + 1..].iter().collect();
+                let after_else = rest.trim_start().strip_prefix("else").map(str::trim_start);
+                match (blocks.pop(), after_else) {
+                    (Some(Block::If), Some(after)) if after.starts_with('{') => {
+                        out.push("!else".to_string());
+                        blocks.push(Block::If);
+                        i = chars.len() - after.chars().count();
+                    }
+                    (Some(Block::If), _) => out.push("!endif".to_string()),
+                    (Some(Block::Macro), _) => out.push("!endmacro".to_string()),
+                    (Some(Block::Zone), _) => out.push("!endzone".to_string()),
+                    (Some(Block::PseudoPc), _) => out.push("!realpc".to_string()),
+                    (Some(Block::Other), _) => {}
+                    (None, _) => out.push("}".to_string()), // reported by the lexer
+                }
+                start = i + 1;
+            }
+            None => {}
+        }
+        i += 1;
+    }
+    flush(&mut out, start, chars.len());
+    if out.is_empty() {
+        out.push(text.to_string()); // keep the empty and comment lines for the listing
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(dialect: Dialect, source: &str) -> Vec<(usize, String)> {
+        dialect.lines(source).into_iter().map(|(line, text)| (line, text.trim().to_string())).collect()
+    }
+
+    #[test]
+    fn test_directives() {
+        assert!(Dialect::Ca65.directive(".BYT") == Some("byte"));
+        assert!(Dialect::Ca65.directive("!byte").is_none());
+        assert!(Dialect::Acme.directive("!by") == Some("byte"));
+        assert!(Dialect::Acme.directive(".byte").is_none());
+        assert!(Dialect::Acme.directive("!to") == Some(""));
+        assert!(Dialect::Tass64.directive(".fi") == Some("endif"));
+        assert!(Dialect::Tass64.directive(".org").is_none());
+        assert!(Dialect::Tass64.directive(".null") == Some("asciiz"));
+        assert!(Dialect::Acme.directive("!pseudopc") == Some("logical"));
+        assert!(Dialect::Ca65.directive(".feature") == Some(""));
+        assert!(Dialect::from_name("64TASS") == Some(Dialect::Tass64));
+        assert!(Dialect::Acme.directives().contains(&"!zone".to_string()));
+        assert!(!Dialect::Acme.directives().contains(&"!endif".to_string()));
+        assert!(Dialect::Ca65.directives()[0] == ".org");
+    }
+
+    #[test]
+    fn test_anonymous() {
+        assert!(Dialect::Acme.anonymous('-', 2) == ("--".to_string(), -1));
+        assert!(Dialect::Tass64.anonymous('-', 2) == ("-".to_string(), -2));
+        assert!(Dialect::Tass64.anonymous('+', 1) == ("+".to_string(), 1));
+    }
+
+    #[test]
+    fn test_acme_blocks() {
+        let src = "!if X = 1 {\n  lda #1\n} else {\n  !macro m .a { lda .a }\n}\n!zone { ; comment\n  } ; x\n";
+        assert!(lines(Dialect::Acme, src) == vec![
+            (1, "!if X = 1".to_string()),
+            (2, "lda #1".to_string()),
+            (3, "!else".to_string()),
+            (4, "!macro m .a".to_string()),
+            (4, "lda .a".to_string()),
+            (4, "!endmacro".to_string()),
+            (5, "!endif".to_string()),
+            (6, "!zone".to_string()),
+            (7, "!endzone".to_string()),
+        ]);
+        assert!(lines(Dialect::Acme, "!pseudopc $1000 { nop }") == vec![
+            (1, "!pseudopc $1000".to_string()),
+            (1, "nop".to_string()),
+            (1, "!realpc".to_string()),
+        ]);
+        assert!(lines(Dialect::Acme, "!byte '{', \"}\"\n}") == vec![
+            (1, "!byte '{', \"}\"".to_string()),
+            (2, "}".to_string()),
+        ]);
+        // the columns of the parts after a brace are kept
+        assert!(Dialect::Acme.lines("!if 1 {   lda  #$zz\n}") == vec![
+            (1, "!if 1 ".to_string()),
+            (1, "          lda  #$zz".to_string()),
+            (2, "!endif".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_tass_comments() {
+        let src = "  nop\n.comment\n  lda\n  .endc\n  rts";
+        assert!(lines(Dialect::Tass64, src) == vec![
+            (1, "nop".to_string()),
+            (2, ";.comment".to_string()),
+            (3, ";  lda".to_string()),
+            (4, ";  .endc".to_string()),
+            (5, "rts".to_string()),
+        ]);
+    }
+}
@@ -0,0 +1,508 @@
+// Constant expressions
+//
+// Binary operators, from the lowest priority to the highest:
+//   ||
+//   &&
+//   = == != <> < > <= >=
+//   |
+//   ^
+//   &
+//   << >>
+//   + -
+//   * / mod
+// Unary: - + ~ ! and the byte selectors <expr (low byte), >expr (high byte), ^expr (bank byte).
+//
+// '*' in the place of a value is the address of the current instruction.
+// In ACME and 64tass a lone run of '-' or '+' is a reference to an anonymous label.
+//
+// The symbols referred to inside a ca65 scope are searched in the scope, then in the enclosing
+// ones (see lookup()). The address size prefixes of ca65 (a:expr, z:expr) are kept as the unary
+// operators 'a' and 'z', which don't change the value.
+
+use std::collections::HashMap;
+
+use super::dialect::Dialect;
+use super::lexer::{tokenize, Tok, Token};
+use super::object::Target;
+use super::{AsmError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Symbol(String, Span),
+    Anon(String, i32, Span), // namespace and distance (:- is ":", -1), replaced by a Symbol in pass 1
+    Pc,
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>, Span),
+}
+
+pub type Symbols = HashMap<String, i64>;
+
+// relocatable objects: the symbols which are relative to a segment or an import
+pub type Bases = HashMap<String, Target>;
+
+// the number of the anonymous labels defined so far, by namespace
+pub type AnonCounts = HashMap<String, usize>;
+
+// a symbol referred to inside the scope `path` ("outer::inner::"), resolved by lookup()
+pub fn scoped(path: &str, name: &str) -> String {
+    format!("{} {}", path, name)
+}
+
+// the value of a symbol, the innermost of the scopes of a scoped() name which has it
+pub fn lookup<'m, V>(map: &'m HashMap<String, V>, name: &str) -> Option<&'m V> {
+    let (mut path, name) = match name.split_once(' ') {
+        Some(split) => split,
+        None => return map.get(name),
+    };
+    loop {
+        if let Some(v) = map.get(&format!("{}{}", path, name)) {
+            return Some(v);
+        }
+        if path.is_empty() {
+            return None;
+        }
+        path = &path[..path[..path.len() - 2].rfind("::").map_or(0, |i| i + 2)];
+    }
+}
+
+// the name as it is written in the source
+pub fn written_name(name: &str) -> &str {
+    name.rsplit(' ').next().unwrap_or(name)
+}
+
+// the symbol of an anonymous label (they are numbered in the order of definition)
+pub fn anon_name(namespace: &str, idx: usize) -> String {
+    if namespace == ":" {
+        format!(":{}", idx)
+    } else {
+        format!(":{}{}", namespace, idx)
+    }
+}
+
+const BINARY_OPS: [&[&str]; 9] = [
+    &["||"],
+    &["&&"],
+    &["=", "==", "!=", "<>", "<", ">", "<=", ">="],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "mod"],
+];
+
+fn binary_op(t: &Token) -> Option<&'static str> {
+    match t.tok {
+        Tok::Op(op) => Some(op),
+        Tok::Punct(c) => BINARY_OPS.iter().flat_map(|ops| ops.iter()).find(|op| op.starts_with(c) && op.len() == 1).copied(),
+        _ => None,
+    }
+}
+
+impl Expr {
+    // Ok(None) if some of the symbols are not defined (yet)
+    pub fn eval(&self, symbols: &Symbols, pc: i64) -> Result<Option<i64>, AsmError> {
+        Ok(match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Symbol(name, _) => lookup(symbols, name).copied(),
+            Expr::Anon(..) => None,
+            Expr::Pc => Some(pc),
+            Expr::Unary(op, e) => e.eval(symbols, pc)?.map(|v| match op {
+                '-' => v.wrapping_neg(),
+                '~' => !v,
+                '!' => (v == 0) as i64,
+                '<' => v & 0xff,
+                '>' => (v >> 8) & 0xff,
+                '^' => (v >> 16) & 0xff,
+                _ => v,
+            }),
+            Expr::Binary(op, l, r, span) => {
+                let (l, r) = match (l.eval(symbols, pc)?, r.eval(symbols, pc)?) {
+                    (Some(l), Some(r)) => (l, r),
+                    _ => return Ok(None),
+                };
+                Some(match *op {
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "*" => l.wrapping_mul(r),
+                    "/" if r == 0 => return Err(AsmError::new(*span, "division by zero")),
+                    "/" => l / r,
+                    "mod" if r == 0 => return Err(AsmError::new(*span, "division by zero")),
+                    "mod" => l % r,
+                    "&" => l & r,
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "<<" => l.wrapping_shl(r as u32),
+                    ">>" => l.wrapping_shr(r as u32),
+                    "=" | "==" => (l == r) as i64,
+                    "!=" | "<>" => (l != r) as i64,
+                    "<" => (l < r) as i64,
+                    ">" => (l > r) as i64,
+                    "<=" => (l <= r) as i64,
+                    ">=" => (l >= r) as i64,
+                    "&&" => (l != 0 && r != 0) as i64,
+                    "||" => (l != 0 || r != 0) as i64,
+                    _ => unreachable!(),
+                })
+            }
+        })
+    }
+
+    // relocatable objects: the segment or import the value is relative to (None for the constants),
+    // only <base> + <constant> and <base> - <base> (both in the same segment) are allowed
+    pub fn base(&self, bases: &Bases, pc: &Target, span: Span) -> Result<Option<Target>, AsmError> {
+        let not_relocatable = |span| Err(AsmError::new(span, "expression is not relocatable"));
+        match self {
+            Expr::Number(_) | Expr::Anon(..) => Ok(None),
+            Expr::Symbol(name, _) => Ok(lookup(bases, name).cloned()),
+            Expr::Pc => Ok(Some(pc.clone())),
+            Expr::Unary('+' | 'a' | 'z', e) => e.base(bases, pc, span),
+            Expr::Unary(_, e) => match e.base(bases, pc, span)? {
+                Some(_) => not_relocatable(span),
+                None => Ok(None),
+            },
+            Expr::Binary(op, l, r, op_span) => match (*op, l.base(bases, pc, span)?, r.base(bases, pc, span)?) {
+                (_, None, None) => Ok(None),
+                ("+", Some(base), None) | ("+", None, Some(base)) | ("-", Some(base), None) => Ok(Some(base)),
+                ("-", Some(l), Some(r)) if l == r && matches!(l, Target::Segment(_)) => Ok(None),
+                _ => not_relocatable(*op_span),
+            },
+        }
+    }
+
+    pub fn first_undefined(&self, symbols: &Symbols) -> Option<(&str, Span)> {
+        match self {
+            Expr::Symbol(name, span) if lookup(symbols, name).is_none() => Some((name, *span)),
+            Expr::Anon(_, _, span) => Some((":", *span)),
+            Expr::Unary(_, e) => e.first_undefined(symbols),
+            Expr::Binary(_, l, r, _) => l.first_undefined(symbols).or_else(|| r.first_undefined(symbols)),
+            _ => None,
+        }
+    }
+
+    // @local -> scope@local, :+/:- -> the name of the anonymous label, name -> scoped(path, name)
+    // (::name is the one outside of all the scopes)
+    pub fn localize(&mut self, scope: &str, path: &str, anon: &AnonCounts) {
+        match self {
+            Expr::Symbol(name, _) if name.starts_with('@') => *name = format!("{}{}", scope, name),
+            Expr::Symbol(name, _) if name.starts_with("::") => *name = name[2..].to_string(),
+            Expr::Symbol(name, _) if !path.is_empty() && !name.starts_with(':') && !name.contains(' ') => *name = scoped(path, name),
+            Expr::Anon(namespace, n, span) => {
+                let count = anon.get(namespace.as_str()).copied().unwrap_or(0) as i64;
+                let idx = if *n > 0 { count + *n as i64 - 1 } else { count + *n as i64 };
+                if idx >= 0 {
+                    *self = Expr::Symbol(anon_name(namespace, idx as usize), *span);
+                }
+            }
+            Expr::Unary(_, e) => e.localize(scope, path, anon),
+            Expr::Binary(_, l, r, _) => {
+                l.localize(scope, path, anon);
+                r.localize(scope, path, anon);
+            }
+            _ => {}
+        }
+    }
+
+    // the variables (ACME !set) replaced by their value at this point of the source
+    pub fn freeze(&mut self, variables: &Symbols) {
+        match self {
+            Expr::Symbol(name, _) => {
+                if let Some(v) = lookup(variables, name) {
+                    *self = Expr::Number(*v);
+                }
+            }
+            Expr::Unary(_, e) => e.freeze(variables),
+            Expr::Binary(_, l, r, _) => {
+                l.freeze(variables);
+                r.freeze(variables);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    end: Span, // reported when the expression ends too early
+    dialect: Dialect,
+}
+
+// the length of the run of '-' or '+' (without spaces) at tokens[pos]
+pub fn sign_run(tokens: &[Token], pos: usize) -> Option<(char, usize)> {
+    let sign = match tokens.get(pos)?.tok {
+        Tok::Punct(c) if c == '-' || c == '+' => c,
+        _ => return None,
+    };
+    let mut len = 1;
+    while let Some(t) = tokens.get(pos + len) {
+        let prev = tokens[pos + len - 1].span;
+        if !t.is_punct(sign) || t.span.column != prev.column + prev.len {
+            break;
+        }
+        len += 1;
+    }
+    Some((sign, len))
+}
+
+impl<'a> ExprParser<'a> {
+    pub fn new(tokens: &'a [Token], end: Span) -> ExprParser<'a> {
+        ExprParser { tokens, pos: 0, end, dialect: Dialect::Ca65 }
+    }
+
+    pub fn dialect(mut self, dialect: Dialect) -> ExprParser<'a> {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    pub fn next(&mut self) -> Option<&'a Token> {
+        let t = self.tokens.get(self.pos);
+        self.pos += 1;
+        t
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    pub fn error_here(&self, msg: &str) -> AsmError {
+        AsmError::new(self.peek().map_or(self.end, |t| t.span), msg)
+    }
+
+    pub fn expect_punct(&mut self, c: char) -> Result<(), AsmError> {
+        match self.peek() {
+            Some(t) if t.is_punct(c) => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(self.error_here(&format!("expected '{}'", c))),
+        }
+    }
+
+    pub fn expr(&mut self) -> Result<Expr, AsmError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, AsmError> {
+        if level == BINARY_OPS.len() {
+            return self.unary();
+        }
+
+        let mut l = self.binary(level + 1)?;
+        while let Some(t) = self.peek() {
+            let op = match binary_op(t) {
+                Some(op) if BINARY_OPS[level].contains(&op) => op,
+                _ => break,
+            };
+            self.pos += 1;
+            let r = self.binary(level + 1)?;
+            l = Expr::Binary(op, Box::new(l), Box::new(r), t.span);
+        }
+        Ok(l)
+    }
+
+    fn unary(&mut self) -> Result<Expr, AsmError> {
+        if let Some(e) = self.anonymous() {
+            return Ok(e);
+        }
+        match self.peek().map(|t| &t.tok) {
+            Some(Tok::Punct(c)) if "-+~!<>^".contains(*c) => {
+                self.pos += 1;
+                Ok(Expr::Unary(*c, Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    // ACME / 64tass: - -- + ++ ... followed by the end of the operand
+    fn anonymous(&mut self) -> Option<Expr> {
+        if !self.dialect.plain_labels() {
+            return None;
+        }
+        let (sign, len) = sign_run(self.tokens, self.pos)?;
+        match self.tokens.get(self.pos + len) {
+            Some(t) if !t.is_punct(',') && !t.is_punct(')') => return None,
+            _ => {}
+        }
+        let span = self.tokens[self.pos].span.to(self.tokens[self.pos + len - 1].span);
+        self.pos += len;
+        let (namespace, n) = self.dialect.anonymous(sign, len);
+        Some(Expr::Anon(namespace, n, span))
+    }
+
+    fn primary(&mut self) -> Result<Expr, AsmError> {
+        let t = match self.peek() {
+            Some(t) => t,
+            None => return Err(self.error_here("expected expression")),
+        };
+        let e = match &t.tok {
+            Tok::Number(n) => Expr::Number(*n),
+            Tok::Ident(name) if !name.starts_with('.') => Expr::Symbol(name.clone(), t.span),
+            Tok::Anon(n) => Expr::Anon(":".to_string(), *n, t.span),
+            Tok::Punct('*') => Expr::Pc,
+            Tok::Punct('(') => {
+                self.pos += 1;
+                let e = self.expr()?;
+                self.expect_punct(')')?;
+                return Ok(e);
+            }
+            _ => return Err(self.error_here("expected expression")),
+        };
+        self.pos += 1;
+        Ok(e)
+    }
+}
+
+// An expression on its own, with the values of the symbols given at the evaluation (the
+// specifications of the superoptimizer: A = X + 1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula(Expr);
+
+impl Formula {
+    pub fn parse(text: &str) -> Result<Formula, AsmError> {
+        let tokens = tokenize(text, 1, Dialect::Ca65)?;
+        let mut p = ExprParser::new(&tokens, Span::new(1, text.len() + 1, 0));
+        let e = p.expr()?;
+        if !p.at_end() {
+            return Err(p.error_here("unexpected token after expression"));
+        }
+        Ok(Formula(e))
+    }
+
+    // None if a symbol is not defined
+    pub fn eval(&self, symbols: &Symbols) -> Option<i64> {
+        self.0.eval(symbols, 0).ok().flatten()
+    }
+
+    // the symbols and the numbers of the expression
+    pub fn leaves(&self) -> (Vec<String>, Vec<i64>) {
+        fn walk(e: &Expr, symbols: &mut Vec<String>, numbers: &mut Vec<i64>) {
+            match e {
+                Expr::Number(n) => numbers.push(*n),
+                Expr::Symbol(name, _) if !symbols.contains(name) => symbols.push(name.clone()),
+                Expr::Unary(_, e) => walk(e, symbols, numbers),
+                Expr::Binary(_, l, r, _) => {
+                    walk(l, symbols, numbers);
+                    walk(r, symbols, numbers);
+                }
+                _ => {}
+            }
+        }
+        let (mut symbols, mut numbers) = (Vec::new(), Vec::new());
+        walk(&self.0, &mut symbols, &mut numbers);
+        (symbols, numbers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Expr, AsmError> {
+        parse_in(Dialect::Ca65, text)
+    }
+
+    fn parse_in(dialect: Dialect, text: &str) -> Result<Expr, AsmError> {
+        let tokens = tokenize(text, 1, dialect).unwrap();
+        let mut p = ExprParser::new(&tokens, Span::new(1, text.len() + 1, 0)).dialect(dialect);
+        let e = p.expr()?;
+        if !p.at_end() {
+            return Err(p.error_here("junk"));
+        }
+        Ok(e)
+    }
+
+    fn eval(text: &str, symbols: &Symbols) -> Result<Option<i64>, AsmError> {
+        parse(text)?.eval(symbols, 0x8000)
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut symbols = Symbols::new();
+        symbols.insert("base".to_string(), 0x0200);
+
+        assert!(eval("1 + 2 * 3", &symbols).unwrap() == Some(7));
+        assert!(eval("(1 + 2) * 3", &symbols).unwrap() == Some(9));
+        assert!(eval("-$10 + %11", &symbols).unwrap() == Some(-13));
+        assert!(eval("base + 'A' - 1", &symbols).unwrap() == Some(0x0240));
+        assert!(eval("* + 3", &symbols).unwrap() == Some(0x8003));
+        assert!(eval("10 / 3", &symbols).unwrap() == Some(3));
+        assert!(eval("undefined + 1", &symbols).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_operators() {
+        let symbols = Symbols::new();
+
+        assert!(eval("<$1234", &symbols).unwrap() == Some(0x34));
+        assert!(eval(">$1234", &symbols).unwrap() == Some(0x12));
+        assert!(eval(">$1234 + 1", &symbols).unwrap() == Some(0x13)); // (>$1234) + 1
+        assert!(eval("1 << 4 | 1", &symbols).unwrap() == Some(0x11));
+        assert!(eval("$F0 & ~$30 ^ 1", &symbols).unwrap() == Some(0xc1));
+        assert!(eval("2 + 2 = 4 && 1 <> 2", &symbols).unwrap() == Some(1));
+        assert!(eval("3 < 2 || !1", &symbols).unwrap() == Some(0));
+        assert!(eval("-1 >= 0", &symbols).unwrap() == Some(0));
+        assert!(eval("17 .mod 5 .shl 1", &symbols).unwrap() == Some(4));
+        assert!(eval("^$123456 + >$1234", &symbols).unwrap() == Some(0x24));
+    }
+
+    #[test]
+    fn test_localize() {
+        let mut e = parse("@loop + :- - :++").unwrap();
+        e.localize("main", "", &AnonCounts::from([(":".to_string(), 3)]));
+        let mut symbols = Symbols::new();
+        symbols.insert("main@loop".to_string(), 100);
+        symbols.insert(":2".to_string(), 10); // the previous anonymous label
+        symbols.insert(":4".to_string(), 1);  // the second next one
+        assert!(e.eval(&symbols, 0).unwrap() == Some(109));
+
+        // ACME: - and -- are different labels, 64tass: -- is the second previous -
+        let anon = AnonCounts::from([("-".to_string(), 2), ("--".to_string(), 1)]);
+        let mut e = parse_in(Dialect::Acme, "--").unwrap();
+        e.localize("", "", &anon);
+        assert!(e == Expr::Symbol(":--0".to_string(), Span::new(1, 1, 2)));
+        assert!(parse_in(Dialect::Acme, "+") == Ok(Expr::Anon("+".to_string(), 1, Span::new(1, 1, 1))));
+        assert!(parse_in(Dialect::Acme, "-1") == Ok(Expr::Unary('-', Box::new(Expr::Number(1)))));
+        let mut e = parse_in(Dialect::Tass64, "--").unwrap();
+        e.localize("", "", &anon);
+        assert!(e == Expr::Symbol(":-0".to_string(), Span::new(1, 1, 2)));
+    }
+
+    #[test]
+    fn test_scopes() {
+        let symbols = Symbols::from([("a::x".to_string(), 1), ("x".to_string(), 2), ("a::b::y".to_string(), 3)]);
+        let value = |text: &str, path: &str| {
+            let mut e = parse(text).unwrap();
+            e.localize("", path, &AnonCounts::new());
+            e.eval(&symbols, 0).unwrap()
+        };
+        assert!(value("x", "a::b::") == Some(1));
+        assert!(value("::x", "a::b::") == Some(2));
+        assert!(value("x", "") == Some(2));
+        assert!(value("b::y", "a::") == Some(3));
+        assert!(value("a::b::y", "c::") == Some(3));
+        assert!(value("y", "a::").is_none());
+        assert!(written_name(&scoped("a::", "y")) == "y");
+
+        let mut e = parse("i * 2 + j").unwrap();
+        e.freeze(&Symbols::from([("i".to_string(), 3)]));
+        assert!(e.eval(&Symbols::from([("i".to_string(), 5), ("j".to_string(), 1)]), 0).unwrap() == Some(7));
+    }
+
+    #[test]
+    fn test_errors() {
+        let symbols = Symbols::new();
+        assert!(eval("1 / 0", &symbols).unwrap_err().span == Span::new(1, 3, 1));
+        assert!(eval("(1 + 2", &symbols).unwrap_err().message == "expected ')'");
+        assert!(eval("1 +", &symbols).unwrap_err().message == "expected expression");
+    }
+}
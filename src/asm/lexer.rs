@@ -0,0 +1,251 @@
+// Lexer: splits one line of the source into tokens
+//
+// Numbers are accepted in the formats from docs.md:
+//   %00001111 -- binary
+//   $FA       -- hex
+//   123       -- decimal
+// plus 'c' for a character code. Everything after ';' is a comment.
+//
+// Local labels start with '@', anonymous label references are ':+', ':-', ':++', ...
+// In ca65 the names in a scope are written scope::name (::name outside of all the scopes).
+// The local labels and the operators of the other dialects are turned into these here.
+
+use super::dialect::Dialect;
+use super::{AsmError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok {
+    Ident(String), // labels, mnemonics, registers, directives (with the leading '.')
+    Number(i64),
+    Str(String),
+    Punct(char),
+    Op(&'static str), // two character operators: << >> <= >= == != <> && ||
+    Anon(i32),        // :+ is 1, :- is -1, :++ is 2, ...
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub tok: Tok,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn is_punct(&self, c: char) -> bool {
+        self.tok == Tok::Punct(c)
+    }
+
+    pub fn ident(&self) -> Option<&str> {
+        match &self.tok {
+            Tok::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+const OPS: [&str; 9] = ["<<", ">>", "<=", ">=", "==", "!=", "<>", "&&", "||"];
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@'
+}
+
+// ACME: !byte etc. (but !x is "not x" in the middle of an expression)
+fn is_pseudo_op_start(dialect: Dialect, chars: &[char], i: usize, tokens: &[Token]) -> bool {
+    dialect.directive_prefix() == '!'
+        && chars[i] == '!'
+        && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphanumeric())
+        && tokens.iter().all(|t| matches!(t.tok, Tok::Ident(_) | Tok::Punct(':') | Tok::Punct('-') | Tok::Punct('+')))
+}
+
+// an identifier in the native form: a local label, an operator or just a name
+fn ident(dialect: Dialect, name: String) -> Tok {
+    match dialect.operator(&name) {
+        Some(op) if op.len() == 1 => Tok::Punct(op.chars().next().unwrap()),
+        Some(op) => Tok::Op(op),
+        None => match name.strip_prefix(dialect.local_prefix()) {
+            Some(local) if !local.is_empty() => Tok::Ident(format!("@{}", local)),
+            _ => Tok::Ident(name),
+        },
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// ca65: the '::' of a scoped name at chars[i]
+fn is_scope_separator(dialect: Dialect, chars: &[char], i: usize) -> bool {
+    dialect == Dialect::Ca65
+        && chars[i..].starts_with(&[':', ':'])
+        && chars.get(i + 2).is_some_and(|c| c.is_ascii_alphabetic() || *c == '_')
+}
+
+pub fn tokenize(text: &str, line: usize, dialect: Dialect) -> Result<Vec<Token>, AsmError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let span = |end: usize| Span::new(line, start + 1, end - start);
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let tok = if c == '$' || c == '%' || c.is_ascii_digit() {
+            let (radix, first) = match c {
+                '$' => (16, i + 1),
+                '%' => (2, i + 1),
+                _ => (10, i),
+            };
+            i = first;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let digits: String = chars[first..i].iter().collect();
+            match i64::from_str_radix(&digits, radix) {
+                Ok(n) if !digits.is_empty() && n <= 0xffff_ffff => Tok::Number(n),
+                _ => return Err(AsmError::new(span(i), "bad number")),
+            }
+        } else if is_ident_start(c) || is_pseudo_op_start(dialect, &chars, i, &tokens)
+            || (c == '\\' && dialect == Dialect::Tass64) || is_scope_separator(dialect, &chars, i) {
+            // (64tass: \name is a macro parameter)
+            i += if c == ':' { 2 } else { 1 };
+            loop {
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                if !is_scope_separator(dialect, &chars, i) {
+                    break;
+                }
+                i += 2;
+            }
+            ident(dialect, chars[start..i].iter().collect())
+        } else if c == '\'' {
+            // 'c'
+            if i + 2 >= chars.len() || chars[i + 2] != '\'' {
+                return Err(AsmError::new(span((i + 3).min(chars.len())), "bad character literal"));
+            }
+            i += 3;
+            Tok::Number(chars[i - 2] as i64)
+        } else if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(AsmError::new(span(i), "unterminated string"));
+            }
+            i += 1;
+            Tok::Str(chars[start + 1..i - 1].iter().collect())
+        } else if c == ':' && dialect == Dialect::Ca65 && i + 1 < chars.len() && (chars[i + 1] == '+' || chars[i + 1] == '-') {
+            let sign = chars[i + 1];
+            i += 1;
+            while i < chars.len() && chars[i] == sign {
+                i += 1;
+            }
+            let n = (i - start - 1) as i32;
+            Tok::Anon(if sign == '+' { n } else { -n })
+        } else if let Some(op) = OPS.iter().find(|op| chars[i..].iter().take(2).copied().eq(op.chars())) {
+            i += 2;
+            Tok::Op(op)
+        } else if "#,():=+-*/<>&|^~!".contains(c) {
+            i += 1;
+            Tok::Punct(c)
+        } else {
+            return Err(AsmError::new(span(i + 1), &format!("unexpected character '{}'", c)));
+        };
+
+        tokens.push(Token { tok, span: span(i) });
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toks(text: &str) -> Vec<Tok> {
+        toks_in(Dialect::Ca65, text)
+    }
+
+    fn toks_in(dialect: Dialect, text: &str) -> Vec<Tok> {
+        super::tokenize(text, 1, dialect).unwrap().into_iter().map(|t| t.tok).collect()
+    }
+
+    fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+        super::tokenize(text, line, Dialect::Ca65)
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert!(toks("%00001111 $FA 123 'A'") == vec![
+            Tok::Number(0x0f), Tok::Number(0xfa), Tok::Number(123), Tok::Number(0x41),
+        ]);
+        assert!(tokenize("$", 1).is_err());
+        assert!(tokenize("%102", 1).is_err());
+        assert!(tokenize("12ab", 1).is_err());
+    }
+
+    #[test]
+    fn test_line() {
+        assert!(toks("loop: LDA ($44),Y ; comment") == vec![
+            Tok::Ident("loop".to_string()), Tok::Punct(':'),
+            Tok::Ident("LDA".to_string()),
+            Tok::Punct('('), Tok::Number(0x44), Tok::Punct(')'), Tok::Punct(','), Tok::Ident("Y".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert!(toks("<x >> 2 != ~@y") == vec![
+            Tok::Punct('<'), Tok::Ident("x".to_string()), Tok::Op(">>"), Tok::Number(2),
+            Tok::Op("!="), Tok::Punct('~'), Tok::Ident("@y".to_string()),
+        ]);
+        assert!(toks("BNE :- BEQ :++ : ") == vec![
+            Tok::Ident("BNE".to_string()), Tok::Anon(-1),
+            Tok::Ident("BEQ".to_string()), Tok::Anon(2),
+            Tok::Punct(':'),
+        ]);
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("  STA $0200,X", 7).unwrap();
+        assert!(tokens[0].span == Span::new(7, 3, 3));
+        assert!(tokens[1].span == Span::new(7, 7, 5));
+        assert!(tokens[3].span == Span::new(7, 13, 1));
+
+        let err = tokenize("  LDA ?", 3).unwrap_err();
+        assert!(err.span == Span::new(3, 7, 1));
+    }
+
+    #[test]
+    fn test_dialects() {
+        let ident = |s: &str| Tok::Ident(s.to_string());
+
+        assert!(toks("@x .and .bitnot y .MOD 2") == vec![
+            ident("@x"), Tok::Op("&&"), Tok::Punct('~'), ident("y"), Tok::Op("mod"), Tok::Number(2),
+        ]);
+        assert!(toks_in(Dialect::Acme, "!by .x AND NOT y, !z") == vec![
+            ident("!by"), ident("@x"), Tok::Punct('&'), Tok::Punct('~'), ident("y"), Tok::Punct(','),
+            Tok::Punct('!'), ident("z"),
+        ]);
+        assert!(toks_in(Dialect::Acme, "- bne -") == vec![
+            Tok::Punct('-'), ident("bne"), Tok::Punct('-'),
+        ]);
+        assert!(toks_in(Dialect::Tass64, "_loop lda \\1,x") == vec![
+            ident("@loop"), ident("lda"), ident("\\1"), Tok::Punct(','), ident("x"),
+        ]);
+        assert!(toks_in(Dialect::Tass64, ":+") == vec![Tok::Punct(':'), Tok::Punct('+')]);
+        assert!(toks("jmp ::a::b c::d: : :+") == vec![
+            ident("jmp"), ident("::a::b"), ident("c::d"), Tok::Punct(':'), Tok::Punct(':'), Tok::Anon(1),
+        ]);
+    }
+}
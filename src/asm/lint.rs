@@ -0,0 +1,215 @@
+// Warnings about the instructions of an assembled program
+//
+//   - branches to another page: one more cycle when taken
+//   - indexed reads whose address can cross a page: one more cycle when it does; (zp),Y only with
+//     Options::pointers, as every read through a pointer which isn't known would be reported
+//   - JMP ($xxFF): the 6502 reads the high byte of the target from $xx00
+//   - undocumented opcodes
+//   - writes into the vectors ($FFFA-$FFFF), direct or indexed (not the ones through a pointer)
+//   - zero page addresses with absolute addressing (usually a forward reference)
+//   - SED on the 2A03 (NES), which has no decimal mode
+// The instructions of a macro expansion are reported on the line of the invocation.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::Program;
+use crate::cpu::AddressingMode::*;
+use crate::disasm::{decode, encode, format, Instruction, OPCODES, WRITES};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub file: String,
+    pub line: usize,
+    pub address: u16,
+    pub instruction: String,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        write!(f, "{}: warning: {} at ${:04X}: {}", self.line, self.instruction, self.address, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub nes: bool,      // the program runs on the 2A03
+    pub pointers: bool, // the page crossings of (zp),Y too
+}
+
+pub fn lint(program: &Program, options: Options) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for info in &program.lines {
+        // the names of the addresses for the messages: the symbols written on the line (no local
+        // or anonymous labels), the other addresses stay numbers
+        let code = info.text.split(';').next().unwrap_or_default();
+        let words: Vec<&str> = code.split(|c: char| !(c.is_alphanumeric() || "_@.:".contains(c)))
+            .map(|w| w.trim_matches(':'))
+            .collect();
+        let mut names = HashMap::new();
+        for (name, value) in &program.symbols {
+            if !name.contains('@') && words.contains(&name.as_str()) {
+                names.entry(*value).or_insert_with(|| name.clone());
+            }
+        }
+        for &offset in &info.instructions {
+            let ins = match decode(&info.bytes[offset..]) {
+                Some(ins) => ins,
+                None => continue,
+            };
+            let address = info.address.wrapping_add(offset as u16);
+            for message in check(&ins, address, options) {
+                warnings.push(Warning {
+                    file: program.files[info.file].clone(),
+                    line: info.line,
+                    address,
+                    instruction: format(&ins, address, Some(&names)),
+                    message,
+                });
+            }
+        }
+    }
+    warnings
+}
+
+fn check(ins: &Instruction, address: u16, options: Options) -> Vec<String> {
+    let mut messages = Vec::new();
+    let opcode = &OPCODES[ins.opcode as usize];
+
+    if let Some(target) = ins.branch_target(address) {
+        if target >> 8 != address.wrapping_add(ins.len as u16) >> 8 {
+            messages.push(format!("the branch crosses a page: {} cycles instead of {} when taken", opcode.cycles + 2, opcode.cycles + 1));
+        }
+    }
+    if matches!(ins.mode, AbsoluteX | AbsoluteY) && opcode.page_penalty() && ins.operand & 0xff != 0 {
+        let index = if ins.mode == AbsoluteX { 'X' } else { 'Y' };
+        messages.push(format!(
+            "the indexed read crosses a page when {} > ${:02X}: one more cycle ({} instead of {})",
+            index, 0xff - (ins.operand & 0xff), opcode.cycles + 1, opcode.cycles
+        ));
+    }
+    if ins.mode == ZeroPageIndirectY && opcode.page_penalty() && options.pointers {
+        messages.push(format!(
+            "the indexed read crosses a page when (${:02X}) + Y does: one more cycle ({} instead of {})",
+            ins.operand, opcode.cycles + 1, opcode.cycles
+        ));
+    }
+    if ins.mnemonic == "JMP" && ins.mode == AbsoluteIndirect && ins.operand & 0xff == 0xff {
+        messages.push(format!(
+            "the 6502 reads the high byte of the target from ${:04X}, not from ${:04X}",
+            ins.operand & 0xff00, ins.operand.wrapping_add(1)
+        ));
+    }
+    if opcode.illegal {
+        let why = if ins.mnemonic == "JAM" { "it halts the CPU" } else { "its behavior differs between the 6502 variants" };
+        messages.push(format!("undocumented opcode ${:02X}: {}", ins.opcode, why));
+    }
+    if WRITES.contains(&ins.mnemonic) {
+        let vectors = "the vectors at $FFFA-$FFFF (NMI, RESET, IRQ)";
+        match ins.mode {
+            Absolute if ins.operand >= 0xfffa => messages.push(format!("writes into {}, which are ROM on most systems", vectors)),
+            AbsoluteX | AbsoluteY if ins.operand >= 0xfffa => {
+                messages.push(format!("the indexed write is into {}, which are ROM on most systems", vectors))
+            }
+            AbsoluteX | AbsoluteY if ins.operand >= 0xfffa - 0xff => {
+                let index = if ins.mode == AbsoluteX { 'X' } else { 'Y' };
+                messages.push(format!(
+                    "the indexed write reaches {} when {} >= ${:02X} (ROM on most systems)",
+                    vectors, index, 0xfffa - ins.operand
+                ));
+            }
+            _ => {}
+        }
+    }
+    let zero_page = match ins.mode {
+        Absolute => Some(ZeroPage),
+        AbsoluteX => Some(ZeroPageX),
+        AbsoluteY => Some(ZeroPageY),
+        _ => None,
+    };
+    if let Some(mode) = zero_page.filter(|_| ins.operand <= 0xff) {
+        if let Some(zp) = encode(ins.mnemonic, mode) {
+            messages.push(format!(
+                "zero page address with absolute addressing: ${:02X} ({} cycles) is one byte and one cycle shorter (a forward reference?)",
+                zp, OPCODES[zp as usize].cycles
+            ));
+        }
+    }
+    if options.nes && ins.mnemonic == "SED" {
+        messages.push("the 2A03 has no decimal mode: ADC and SBC stay binary".to_string());
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn warnings(source: &str, options: Options) -> Vec<String> {
+        lint(&Assembler::new().assemble(source).unwrap(), options).iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        let src = "
+        .org $80F8
+start:  LDA table,X
+        LDA $1200,Y
+        STA table,X
+        JMP ($12FF)
+        JMP ($1234)
+        STA $FFFE
+        LAX $44
+        LDX var
+        LDA var,Y
+@loop:  DEX
+        BNE @loop
+        BEQ start
+        SED
+table = $1234
+var = $10
+";
+        assert!(warnings(src, Options::default()) == vec![
+            "3: warning: LDA table,X at $80F8: the indexed read crosses a page when X > $CB: one more cycle (5 instead of 4)",
+            "6: warning: JMP ($12FF) at $8101: the 6502 reads the high byte of the target from $1200, not from $1300",
+            "8: warning: STA $FFFE at $8107: writes into the vectors at $FFFA-$FFFF (NMI, RESET, IRQ), which are ROM on most systems",
+            "9: warning: LAX $44 at $810A: undocumented opcode $A7: its behavior differs between the 6502 variants",
+            "10: warning: LDX var at $810C: zero page address with absolute addressing: $A6 (3 cycles) is one byte and one cycle shorter (a forward reference?)",
+            "11: warning: LDA var,Y at $810F: the indexed read crosses a page when Y > $EF: one more cycle (5 instead of 4)",
+            "14: warning: BEQ start at $8115: the branch crosses a page: 4 cycles instead of 3 when taken",
+        ]);
+        // only the names written on the line
+        assert!(warnings("later = $10\n  LAX $10\n  LAX later", Options::default()) == vec![
+            "2: warning: LAX $10 at $0000: undocumented opcode $A7: its behavior differs between the 6502 variants",
+            "3: warning: LAX later at $0002: undocumented opcode $A7: its behavior differs between the 6502 variants",
+        ]);
+        assert!(warnings(src, Options { nes: true, ..Options::default() }).last().unwrap() == "15: warning: SED at $8117: the 2A03 has no decimal mode: ADC and SBC stay binary");
+
+        // the pointer isn't known, only the stores have no penalty
+        let src = "  LDA ($20),Y\n  STA ($20),Y\n  LDA ($20,X)";
+        assert!(warnings(src, Options { pointers: true, ..Options::default() }) == vec![
+            "1: warning: LDA ($20),Y at $0000: the indexed read crosses a page when ($20) + Y does: one more cycle (6 instead of 5)",
+        ]);
+        assert!(warnings(src, Options::default()).is_empty());
+
+        assert!(warnings("  STA $FF00,X\n  STA $FFFA,Y\n  STA $FEFB,X\n  STA $FEFA,Y", Options::default()) == vec![
+            "1: warning: STA $FF00,X at $0000: the indexed write reaches the vectors at $FFFA-$FFFF (NMI, RESET, IRQ) when X >= $FA (ROM on most systems)",
+            "2: warning: STA $FFFA,Y at $0003: the indexed write is into the vectors at $FFFA-$FFFF (NMI, RESET, IRQ), which are ROM on most systems",
+            "3: warning: STA $FEFB,X at $0006: the indexed write reaches the vectors at $FFFA-$FFFF (NMI, RESET, IRQ) when X >= $FF (ROM on most systems)",
+        ]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let src = ".macro clear addr\n  LDA #0\n  STA addr\n.endmacro\n  clear $FFFC\n";
+        let program = Assembler::new().assemble(src).unwrap();
+        let warnings = lint(&program, Options::default());
+        assert!(warnings.len() == 1 && warnings[0].line == 5 && warnings[0].address == 2);
+        assert!(warnings[0].instruction == "STA $FFFC");
+    }
+}
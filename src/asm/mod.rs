@@ -0,0 +1,2248 @@
+// Two-pass 6502 assembler
+//
+// Pass 1 reads the source line by line (following .include and macro expansions),
+// assigns addresses to the labels and decides the size of every instruction
+// (zero page vs absolute addressing: zero page is used only when the operand is known
+// to fit into it at this moment, so forward references get absolute addressing).
+// Everything that produces bytes is recorded with its address.
+// Pass 2 evaluates the recorded operands with the complete symbol table and emits the bytes.
+//
+// Directives:
+//   .org addr                 -- set the address of the current segment
+//   .byte value|"text", ...
+//   .word value, ...
+//   .res count [, fill]       -- reserve `count` bytes (filled with zeroes by default)
+//   .align boundary [, fill]  -- fill up to the next multiple of `boundary`
+//   .incbin "file" [, offset [, size]]
+//   .include "file"
+//   .segment "NAME"           -- switch to another segment, each one has its own address
+//   .code .data .rodata .bss .zeropage -- the same as .segment "CODE", ...
+//   .macro name [param, ...] / .endmacro
+//   .if expr / .ifdef name / .ifndef name / .elseif expr / .else / .endif
+//   .zone / .endzone          -- a new scope for the local labels (ACME)
+//   .proc name / .endproc     -- the label `name` and a scope of the same name
+//   .scope [name] / .endscope -- a scope, the labels defined in it are name::label outside
+//   .enum [name] / .endenum   -- the constants on the lines in between, numbered from 0
+//   .define name text         -- `name` is replaced with `text` on the following lines
+//   .local name, ...          -- the names are different in each expansion of the macro
+//   .logical addr / .here     -- the code in between is assembled for `addr` (ACME !pseudopc)
+//   .set name = value         -- a variable, which can be set again (ACME)
+//   .assert expr, error|warning [, "message"]
+//   .out "text"               -- the text is added to Program::output
+//   .import name, ... / .importzp name, ...  -- symbols defined by another object
+//   .export name, ... / .exportzp name, ...  -- symbols for the other objects
+//   .global name, ... / .globalzp name, ...  -- exported if defined here, imported otherwise
+// The values of .org, .res count, .align and .if have to be known in pass 1.
+//
+// A relocatable object (Assembler::relocatable(), see object.rs) has every segment starting
+// at 0 and no .org, the linker decides the addresses. The labels are relative to their segment
+// and the values which depend on them get a relocation. The zero page addressing is used for
+// the labels of the ZEROPAGE segment and the .importzp symbols.
+//
+// @name is a local label: it belongs to the last normal label (every macro expansion
+// has its own scope). A single ':' is an anonymous label, :- / :+ refer to the previous /
+// next one (:-- / :++ to the one before / after that, ...). A name used inside a .proc or a
+// .scope is the one of the innermost scope which defines it (see expr::lookup()).
+//
+// The syntax is the one of ca65, see dialect.rs for ACME and 64tass.
+
+mod dialect;
+mod expr;
+mod lexer;
+mod lint;
+mod object;
+mod parser;
+mod refs;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::{addressing_mode_pc_advance, AddressingMode};
+use crate::disasm::{encode, OPCODES};
+pub use dialect::Dialect;
+pub use expr::Formula;
+pub use lint::{lint, Options as LintOptions};
+pub use object::{Object, RelocKind, Target};
+pub use refs::{references, Reference};
+use expr::{anon_name, lookup, written_name, AnonCounts, Bases, Expr, Symbols};
+use object::{Export, Import, ObjSegment, Relocation};
+use lexer::tokenize;
+use parser::{directive_name, parse_line, Arg, Operand, Statement, Stmt};
+
+const MAX_NESTING: usize = 64; // .include and macro expansions
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,   // 1-based
+    pub column: usize, // 1-based
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Span {
+        Span { line, column, len }
+    }
+
+    // from the beginning of self to the end of other (on the same line)
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.line, self.column, other.column + other.len - self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub file: String, // empty for the source passed to Assembler::assemble()
+    pub span: Span,   // line 0 if the error is about the whole file
+    pub message: String,
+    pub note: Option<String>, // the macro invocation for the errors inside macros
+}
+
+impl AsmError {
+    pub fn new(span: Span, message: &str) -> AsmError {
+        AsmError { file: String::new(), span, message: message.to_string(), note: None }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        if self.span.line > 0 {
+            write!(f, "{}:{}:", self.span.line, self.span.column)?;
+        }
+        write!(f, " error: {}", self.message)?;
+        if let Some(note) = &self.note {
+            write!(f, "\n{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+// continuous run of bytes starting at `origin`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub segment: String,
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+}
+
+// one line of the source files, for the listing
+// (the bytes of a macro expansion belong to the line with the invocation)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineInfo {
+    pub file: usize, // index into Program::files
+    pub line: usize,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub instructions: Vec<usize>, // offsets of the instructions among the bytes
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub chunks: Vec<Chunk>,
+    pub symbols: BTreeMap<String, u16>,
+    pub files: Vec<String>,
+    pub lines: Vec<LineInfo>,
+    pub object: Option<Object>, // for Assembler::relocatable()
+    pub output: Vec<String>,    // the text of .out and the warnings of .assert
+}
+
+impl Program {
+    // all the chunks as one image, the gaps are filled with zeroes
+    pub fn image(&self) -> Option<(u16, Vec<u8>)> {
+        let start = self.chunks.iter().map(|c| c.origin as usize).min()?;
+        let end = self.chunks.iter().map(|c| c.origin as usize + c.bytes.len()).max()?;
+        let mut image = vec![0u8; end - start];
+        for chunk in &self.chunks {
+            let offset = chunk.origin as usize - start;
+            image[offset..offset + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+        }
+        Some((start as u16, image))
+    }
+
+    // address, bytes, cycles (see Opcode::cycles_text()) and the source text of every line
+    pub fn listing(&self) -> String {
+        let mut out = String::new();
+        let mut file = None;
+
+        for info in &self.lines {
+            if file != Some(info.file) && self.files.len() > 1 {
+                out.push_str(&format!("; {}\n", self.files[info.file]));
+            }
+            file = Some(info.file);
+
+            let cycles = match info.instructions[..] {
+                [offset] => OPCODES[info.bytes[offset] as usize].cycles_text(),
+                _ => String::new(),
+            };
+            let mut rows = info.bytes.chunks(3);
+            let first = rows.next().map(hex_bytes).unwrap_or_default();
+            let address = if info.bytes.is_empty() { String::new() } else { format!("{:04X}", info.address) };
+            let row = format!("{:<4}  {:<8}  {:<3}  {}", address, first, cycles, info.text);
+            out.push_str(row.trim_end());
+            out.push('\n');
+
+            let mut address = info.address as usize;
+            for bytes in rows {
+                address += 3;
+                out.push_str(&format!("{:04X}  {}\n", address & 0xffff, hex_bytes(bytes)));
+            }
+        }
+        out
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+pub struct Assembler {
+    origin: u16,
+    include_dirs: Vec<PathBuf>,
+    dialect: Dialect,
+    relocatable: bool,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler { origin: 0, include_dirs: Vec::new(), dialect: Dialect::Ca65, relocatable: false }
+    }
+
+    pub fn dialect(mut self, dialect: Dialect) -> Assembler {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn origin(mut self, origin: u16) -> Assembler {
+        self.origin = origin;
+        self
+    }
+
+    // produce a relocatable object (Program::object) for the linker
+    pub fn relocatable(mut self) -> Assembler {
+        self.relocatable = true;
+        self
+    }
+
+    // searched by .include and .incbin after the directory of the including file
+    pub fn include_dir<P: AsRef<Path>>(mut self, dir: P) -> Assembler {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    // .include and .incbin are relative to the current directory
+    pub fn assemble(&self, source: &str) -> Result<Program, Vec<AsmError>> {
+        let mut pass = Pass::new(self);
+        pass.push_file(String::new(), source);
+        pass.run()
+    }
+
+    pub fn assemble_file<P: AsRef<Path>>(&self, path: P) -> Result<Program, Vec<AsmError>> {
+        let name = path.as_ref().display().to_string();
+        let source = fs::read_to_string(&path).map_err(|e| {
+            let mut err = AsmError::new(Span::new(0, 0, 0), &e.to_string());
+            err.file = name.clone();
+            vec![err]
+        })?;
+        let mut pass = Pass::new(self);
+        pass.push_file(name, &source);
+        pass.run()
+    }
+}
+
+// where the current line comes from
+#[derive(Debug, Clone)]
+struct Loc {
+    file: usize,
+    line_info: usize,
+    note: Option<String>,
+}
+
+struct Expansion {
+    line_info: usize,
+    note: String,
+    outer_scope: String,
+}
+
+// a source file or a macro expansion
+struct Frame {
+    file: usize,
+    lines: Vec<(usize, String)>,
+    next: usize,
+    expansion: Option<Expansion>,
+    locals: Vec<(String, String)>, // .local: the names in the expansion and their replacement
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<(usize, String)>,
+    file: usize,
+}
+
+// the body of a .repeat up to its .endrep (`depth` counts the nested ones)
+struct Repeat {
+    count: usize,
+    var: Option<String>,
+    body: Vec<(usize, String)>,
+    depth: usize,
+    loc: Loc,
+    span: Span,
+}
+
+struct Cond {
+    active: bool,
+    parent_active: bool,
+    taken: bool, // one of the branches was active already
+    else_seen: bool,
+    loc: Loc,
+    span: Span,
+}
+
+struct Segment {
+    name: String,
+    pc: i64,
+    delta: i64, // .logical: the address the code is assembled for - the one of its bytes
+}
+
+enum Emit {
+    Instruction(String, Operand, AddressingMode),
+    Bytes(Vec<Arg>),
+    Words(Vec<Expr>),
+    Fill(usize, Option<Expr>),
+    Raw(Vec<u8>),
+    Assert(Expr, bool, Option<String>), // (the error or the warning, the message)
+}
+
+// something that produces bytes, recorded in pass 1
+struct Record {
+    emit: Emit,
+    pc: i64,
+    at: i64, // the address of the bytes (not pc in a .logical block)
+    segment: usize,
+    span: Span,
+    loc: Loc,
+}
+
+struct Pass<'a> {
+    assembler: &'a Assembler,
+    files: Vec<String>,
+    frames: Vec<Frame>,
+    lines: Vec<LineInfo>,
+    symbols: Symbols,
+    macros: HashMap<String, Macro>,
+    collecting: Option<(String, Macro, Loc, Span)>, // the macro being defined
+    repeating: Option<Repeat>,
+    conds: Vec<Cond>,
+    segments: Vec<Segment>,
+    segment: usize,
+    scope: String, // the last normal label, for the local ones
+    path: String,  // the .proc and .scope the line is in: "outer::inner::"
+    zones: Vec<(String, String)>, // the scope and the path outside of the .zone, .proc and .scope
+    logical: Vec<(usize, i64, Loc, Span)>, // the segment and its delta before .logical
+    enumerating: Option<(String, i64, Loc, Span)>, // in .enum: the prefix of the names and the next value
+    defines: Vec<(String, String)>,
+    variables: Symbols,
+    globals: Vec<(String, bool, Span, Loc)>, // (the name, .globalzp)
+    unique: usize, // for the names of the anonymous scopes and of the .local symbols
+    output: Vec<String>,
+    anon: AnonCounts,
+    expansions: usize,
+    records: Vec<Record>,
+    deferred: Vec<(String, Expr, i64, usize, Span, Loc)>, // equates with forward references (and their segment)
+    bases: Bases,
+    imports: Vec<Import>,
+    exports: Vec<(String, Span, Loc)>,
+    errors: Vec<(usize, AsmError)>,
+}
+
+impl<'a> Pass<'a> {
+    fn new(assembler: &'a Assembler) -> Pass<'a> {
+        Pass {
+            assembler,
+            files: Vec::new(),
+            frames: Vec::new(),
+            lines: Vec::new(),
+            symbols: Symbols::new(),
+            macros: HashMap::new(),
+            collecting: None,
+            repeating: None,
+            conds: Vec::new(),
+            segments: vec![Segment { name: "CODE".to_string(), pc: if assembler.relocatable { 0 } else { assembler.origin as i64 }, delta: 0 }],
+            segment: 0,
+            scope: String::new(),
+            path: String::new(),
+            zones: Vec::new(),
+            logical: Vec::new(),
+            enumerating: None,
+            defines: Vec::new(),
+            variables: Symbols::new(),
+            globals: Vec::new(),
+            unique: 0,
+            output: Vec::new(),
+            anon: AnonCounts::new(),
+            expansions: 0,
+            records: Vec::new(),
+            deferred: Vec::new(),
+            bases: Bases::new(),
+            imports: Vec::new(),
+            exports: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn push_file(&mut self, name: String, source: &str) {
+        self.files.push(name);
+        let lines = self.assembler.dialect.lines(source);
+        self.frames.push(Frame { file: self.files.len() - 1, lines, next: 0, expansion: None, locals: Vec::new() });
+    }
+
+    fn run(mut self) -> Result<Program, Vec<AsmError>> {
+        self.first();
+        self.resolve_globals();
+        self.resolve_equates();
+        let program = self.second();
+
+        if self.errors.is_empty() {
+            Ok(program)
+        } else {
+            self.errors.sort_by_key(|(file, e)| (*file, e.span));
+            Err(self.errors.into_iter().map(|(_, e)| e).collect())
+        }
+    }
+
+    fn error(&mut self, loc: &Loc, mut e: AsmError) {
+        e.file = self.files[loc.file].clone();
+        e.note = loc.note.clone();
+        self.errors.push((loc.file, e));
+    }
+
+    fn pc(&self) -> i64 {
+        self.segments[self.segment].pc
+    }
+
+    fn define(&mut self, name: &str, value: i64, span: Span, loc: &Loc) {
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            self.error(loc, AsmError::new(span, &format!("symbol '{}' is already defined", name)));
+        }
+    }
+
+    // relocatable objects: the labels are relative to their segment
+    fn set_base(&mut self, name: &str, base: Option<Target>) {
+        if let (true, Some(base)) = (self.assembler.relocatable, base) {
+            self.bases.insert(name.to_string(), base);
+        }
+    }
+
+    fn segment_base(&self, segment: usize) -> Target {
+        Target::Segment(self.segments[segment].name.clone())
+    }
+
+    // the name of a symbol defined here
+    fn local_name(&self, name: &str) -> String {
+        if name.starts_with('@') {
+            format!("{}{}", self.scope, name)
+        } else {
+            format!("{}{}", self.path, name)
+        }
+    }
+
+    // the names of the symbols referred to in the expression, as in the symbol table
+    fn localize(&self, e: &mut Expr) {
+        e.localize(&self.scope, &self.path, &self.anon);
+        e.freeze(&self.variables);
+    }
+
+    // the name of a symbol referred to here, as in the symbol table
+    fn reference(&self, name: &str, span: Span) -> String {
+        let mut e = Expr::Symbol(name.to_string(), span);
+        self.localize(&mut e);
+        match e {
+            Expr::Symbol(name, _) => name,
+            _ => name.to_string(),
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.conds.last().is_none_or(|c| c.active)
+    }
+
+    fn first(&mut self) {
+        while let Some(frame) = self.frames.last_mut() {
+            let (line, text) = match frame.lines.get(frame.next) {
+                Some(l) => l.clone(),
+                None => {
+                    if let Some(expansion) = self.frames.pop().unwrap().expansion {
+                        self.scope = expansion.outer_scope;
+                    }
+                    continue;
+                }
+            };
+            frame.next += 1;
+            let text = match frame.locals.is_empty() {
+                true => text,
+                false => {
+                    let (names, replacements): (Vec<String>, Vec<String>) = frame.locals.iter().cloned().unzip();
+                    substitute(&text, line, self.assembler.dialect, &names, &replacements)
+                }
+            };
+
+            let loc = match &frame.expansion {
+                Some(expansion) => Loc { file: frame.file, line_info: expansion.line_info, note: Some(expansion.note.clone()) },
+                None => {
+                    let file = frame.file;
+                    self.lines.push(LineInfo {
+                        file,
+                        line,
+                        address: self.pc() as u16,
+                        bytes: Vec::new(),
+                        instructions: Vec::new(),
+                        text: text.clone(),
+                    });
+                    Loc { file, line_info: self.lines.len() - 1, note: None }
+                }
+            };
+            self.line(line, &text, &loc);
+        }
+
+        if let Some((name, _, loc, span)) = self.collecting.take() {
+            self.error(&loc, AsmError::new(span, &format!("missing .endmacro for '{}'", name)));
+        }
+        if let Some(r) = self.repeating.take() {
+            self.error(&r.loc, AsmError::new(r.span, "missing .endrep"));
+        }
+        if let Some((_, _, loc, span)) = self.enumerating.take() {
+            self.error(&loc, AsmError::new(span, "missing .endenum"));
+        }
+        for (_, _, loc, span) in std::mem::take(&mut self.logical) {
+            self.error(&loc, AsmError::new(span, "missing .here"));
+        }
+        for cond in std::mem::take(&mut self.conds) {
+            self.error(&cond.loc, AsmError::new(cond.span, "missing .endif"));
+        }
+    }
+
+    fn line(&mut self, line: usize, text: &str, loc: &Loc) {
+        let dialect = self.assembler.dialect;
+        if let Some((_, m, _, _)) = &mut self.collecting {
+            if directive_name(text, line, dialect).as_deref() == Some("endmacro") {
+                let (name, m, _, _) = self.collecting.take().unwrap();
+                self.macros.insert(name, m);
+            } else {
+                m.body.push((line, text.to_string()));
+            }
+            return;
+        }
+        if let Some(r) = &mut self.repeating {
+            match directive_name(text, line, dialect).as_deref() {
+                Some("endrep") if r.depth == 0 => {
+                    let r = self.repeating.take().unwrap();
+                    self.repeat(r);
+                }
+                name => {
+                    match name {
+                        Some("repeat") => r.depth += 1,
+                        Some("endrep") => r.depth -= 1,
+                        _ => {}
+                    }
+                    r.body.push((line, text.to_string()));
+                }
+            }
+            return;
+        }
+
+        if !self.active() {
+            // only the nesting of the conditionals matters here
+            let span = Span::new(line, 1, 0);
+            match directive_name(text, line, dialect).as_deref() {
+                Some("if") | Some("ifdef") | Some("ifndef") => {
+                    return self.conds.push(Cond {
+                        active: false,
+                        parent_active: false,
+                        taken: true,
+                        else_seen: false,
+                        loc: loc.clone(),
+                        span,
+                    })
+                }
+                // parsed and evaluated below
+                Some("elseif") if self.conds.last().is_some_and(|c| c.parent_active && !c.taken) => {}
+                Some("else") => return self.cond_else(span, loc),
+                Some("endif") => return self.cond_endif(span, loc),
+                _ => return,
+            }
+        }
+
+        let text = match self.defines.is_empty() {
+            true => text.to_string(),
+            false => {
+                let (names, replacements): (Vec<String>, Vec<String>) = self.defines.iter().cloned().unzip();
+                substitute(text, line, dialect, &names, &replacements)
+            }
+        };
+        match parse_line(&text, line, dialect) {
+            Ok(statements) => {
+                for st in statements {
+                    self.statement(st, loc);
+                }
+            }
+            Err(e) => self.error(loc, e),
+        }
+    }
+
+    fn statement(&mut self, st: Statement, loc: &Loc) {
+        if self.enumerating.is_some() {
+            return self.enum_member(st, loc);
+        }
+        let pc = self.pc();
+        match st.stmt {
+            Stmt::Label(name) => {
+                let local = name.starts_with('@');
+                let name = self.local_name(&name);
+                if !local && self.assembler.dialect.labels_open_scope() {
+                    self.scope = name.clone();
+                }
+                self.define(&name, pc, st.span, loc);
+                self.set_base(&name, Some(self.segment_base(self.segment)));
+            }
+            Stmt::AnonLabel(namespace) => {
+                let count = self.anon.entry(namespace.clone()).or_insert(0);
+                let name = anon_name(&namespace, *count);
+                *count += 1;
+                self.define(&name, pc, st.span, loc);
+                self.set_base(&name, Some(self.segment_base(self.segment)));
+            }
+            Stmt::Equate(name, mut e) => {
+                let name = self.local_name(&name);
+                self.localize(&mut e);
+                match e.eval(&self.symbols, pc) {
+                    Ok(Some(v)) => self.define_equate(&name, v, &e, self.segment, st.span, loc),
+                    Ok(None) => self.deferred.push((name, e, pc, self.segment, st.span, loc.clone())),
+                    Err(e) => self.error(loc, e),
+                }
+            }
+            Stmt::Instruction(mnemonic, mut operand) => {
+                if let Some(e) = operand.expr_mut() {
+                    self.localize(e);
+                }
+                match self.select_mode(&mnemonic, &operand, pc, st.span) {
+                    Ok(mode) => {
+                        let size = 1 + addressing_mode_pc_advance(mode) as usize;
+                        self.emit(Emit::Instruction(mnemonic, operand, mode), size, st.span, loc);
+                    }
+                    Err(e) => self.error(loc, e),
+                }
+            }
+            Stmt::Macro(name, params) => {
+                if parser::is_mnemonic(&name) {
+                    self.error(loc, AsmError::new(st.span, &format!("'{}' is an instruction", name)));
+                } else if self.macros.contains_key(&name) {
+                    self.error(loc, AsmError::new(st.span, &format!("macro '{}' is already defined", name)));
+                }
+                let m = Macro { params, body: Vec::new(), file: loc.file };
+                self.collecting = Some((name, m, loc.clone(), st.span));
+            }
+            Stmt::MacroCall(name, args) => self.expand(&name, args, st.span, loc),
+            Stmt::Directive(name, args) => self.directive(&name, args, st.span, loc),
+            Stmt::Define(name, text) => {
+                if self.defines.iter().any(|(n, _)| *n == name) {
+                    self.error(loc, AsmError::new(st.span, &format!("'{}' is already defined", name)));
+                }
+                self.defines.push((name, text));
+            }
+        }
+    }
+
+    // a line between .enum and .endenum: `name` or `name = value`
+    fn enum_member(&mut self, st: Statement, loc: &Loc) {
+        let (prefix, next, _, _) = self.enumerating.as_ref().unwrap();
+        let (prefix, next) = (prefix.clone(), *next);
+        let (name, value) = match st.stmt {
+            Stmt::Directive(name, args) if name == "endenum" && args.is_empty() => {
+                self.enumerating = None;
+                return;
+            }
+            Stmt::MacroCall(name, args) if args.is_empty() => (name, Some(next)),
+            Stmt::Equate(name, e) => {
+                let value = self.known(Some(&Arg::Expr(e)), st.span, loc);
+                (name, value)
+            }
+            _ => return self.error(loc, AsmError::new(st.span, "expected an enum member (name or name = value)")),
+        };
+        if let Some(value) = value {
+            self.define(&format!("{}{}", prefix, name), value, st.span, loc);
+            self.enumerating.as_mut().unwrap().1 = value + 1;
+        }
+    }
+
+    fn define_equate(&mut self, name: &str, value: i64, e: &Expr, segment: usize, span: Span, loc: &Loc) {
+        self.define(name, value, span, loc);
+        if self.assembler.relocatable {
+            match e.base(&self.bases, &self.segment_base(segment), span) {
+                Ok(base) => self.set_base(name, base),
+                Err(e) => self.error(loc, e),
+            }
+        }
+    }
+
+    fn emit(&mut self, emit: Emit, size: usize, span: Span, loc: &Loc) {
+        let pc = self.pc();
+        let at = pc - self.segments[self.segment].delta;
+        if [pc, at].iter().any(|a| a + size as i64 > 0x10000 && *a <= 0x10000) || at < 0 {
+            self.error(loc, AsmError::new(span, "program does not fit into the memory"));
+        }
+        self.records.push(Record { emit, pc, at, segment: self.segment, span, loc: loc.clone() });
+        self.segments[self.segment].pc += size as i64;
+    }
+
+    fn expand(&mut self, name: &str, args: Vec<String>, span: Span, loc: &Loc) {
+        // (64tass: .name)
+        let (name, directive) = match name.strip_prefix('.') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let m = match self.macros.get(name) {
+            Some(m) => m,
+            None if directive => return self.error(loc, AsmError::new(span, &format!("unknown directive '.{}'", name))),
+            None => return self.error(loc, AsmError::new(span, &format!("unknown instruction '{}'", name))),
+        };
+        if args.len() > m.params.len() {
+            let msg = format!("macro '{}' takes {} argument(s)", name, m.params.len());
+            return self.error(loc, AsmError::new(span, &msg));
+        }
+        if self.frames.len() >= MAX_NESTING {
+            return self.error(loc, AsmError::new(span, "too many nested macro expansions and includes"));
+        }
+
+        let dialect = self.assembler.dialect;
+        let lines = m.body.iter().map(|(line, text)| (*line, substitute(text, *line, dialect, &m.params, &args))).collect();
+        let file = m.file;
+        let call = match self.files[loc.file].as_str() {
+            "" => format!("{}", span.line),
+            f => format!("{}:{}", f, span.line),
+        };
+        let expansion = Expansion {
+            line_info: loc.line_info,
+            note: format!("{}: note: in expansion of macro '{}'", call, name),
+            outer_scope: std::mem::replace(&mut self.scope, format!("__macro{}", self.expansions)),
+        };
+        self.expansions += 1;
+        self.frames.push(Frame { file, lines, next: 0, expansion: Some(expansion), locals: Vec::new() });
+    }
+
+    // the body of a .repeat, `count` times with its variable replaced by 0, 1, ...
+    fn repeat(&mut self, r: Repeat) {
+        if self.frames.len() >= MAX_NESTING {
+            return self.error(&r.loc, AsmError::new(r.span, "too many nested macro expansions and includes"));
+        }
+        let dialect = self.assembler.dialect;
+        let params: Vec<String> = r.var.into_iter().collect();
+        let mut lines = Vec::new();
+        for i in 0..r.count {
+            let args = [i.to_string()];
+            lines.extend(r.body.iter().map(|(line, text)| (*line, substitute(text, *line, dialect, &params, &args))));
+        }
+        let call = match self.files[r.loc.file].as_str() {
+            "" => format!("{}", r.span.line),
+            f => format!("{}:{}", f, r.span.line),
+        };
+        let expansion = Expansion {
+            line_info: r.loc.line_info,
+            note: format!("{}: note: in expansion of .repeat", call),
+            outer_scope: self.scope.clone(),
+        };
+        self.frames.push(Frame { file: r.loc.file, lines, next: 0, expansion: Some(expansion), locals: Vec::new() });
+    }
+
+    // the value of an argument which has to be known in pass 1
+    fn known(&mut self, arg: Option<&Arg>, span: Span, loc: &Loc) -> Option<i64> {
+        let e = match arg {
+            Some(Arg::Expr(e)) => e,
+            Some(Arg::Str(_, span)) => {
+                self.error(loc, AsmError::new(*span, "expected expression"));
+                return None;
+            }
+            None => {
+                self.error(loc, AsmError::new(span, "expected expression"));
+                return None;
+            }
+        };
+        let mut e = e.clone();
+        self.localize(&mut e);
+        match e.eval(&self.symbols, self.pc()) {
+            Ok(Some(v)) => Some(v),
+            Ok(None) => {
+                self.error(loc, AsmError::new(span, "value must be known in the first pass"));
+                None
+            }
+            Err(e) => {
+                self.error(loc, e);
+                None
+            }
+        }
+    }
+
+    fn string_arg(&mut self, args: &[Arg], span: Span, loc: &Loc) -> Option<String> {
+        match args {
+            [Arg::Str(s, _)] => Some(s.clone()),
+            _ => {
+                self.error(loc, AsmError::new(span, "expected a string"));
+                None
+            }
+        }
+    }
+
+    fn directive(&mut self, name: &str, mut args: Vec<Arg>, span: Span, loc: &Loc) {
+        let count = args.len();
+        let wrong_count = AsmError::new(span, &format!("wrong number of arguments for .{}", name));
+
+        match name {
+            "org" if self.assembler.relocatable => {
+                self.error(loc, AsmError::new(span, ".org is not allowed in a relocatable object"))
+            }
+            "org" if count == 1 => {
+                if let Some(v) = self.known(args.first(), span, loc) {
+                    if (0..=0xffff).contains(&v) {
+                        self.segments[self.segment].pc = v;
+                    } else {
+                        self.error(loc, AsmError::new(span, &format!("address {} is out of range", v)));
+                    }
+                }
+            }
+            "byte" | "word" if count > 0 => {
+                for arg in args.iter_mut() {
+                    if let Arg::Expr(e) = arg {
+                        self.localize(e);
+                    }
+                }
+                if name == "byte" {
+                    let size = args.iter().map(|a| match a {
+                        Arg::Str(s, _) => s.chars().count(),
+                        Arg::Expr(_) => 1,
+                    }).sum();
+                    self.emit(Emit::Bytes(args), size, span, loc);
+                } else {
+                    let mut words = Vec::new();
+                    for arg in args {
+                        match arg {
+                            Arg::Expr(e) => words.push(e),
+                            Arg::Str(_, span) => self.error(loc, AsmError::new(span, "expected expression")),
+                        }
+                    }
+                    let size = words.len() * 2;
+                    self.emit(Emit::Words(words), size, span, loc);
+                }
+            }
+            "asciiz" | "dbyt" | "lobytes" | "hibytes" if count > 0 => {
+                // written as a .byte: the strings with a 0, or the bytes of the expressions
+                let mut bytes = Vec::new();
+                for arg in args {
+                    let byte_of = |op: char, e: &Expr| Arg::Expr(Expr::Unary(op, Box::new(e.clone())));
+                    match (name, arg) {
+                        ("asciiz", arg) => bytes.push(arg),
+                        ("dbyt", Arg::Expr(e)) => bytes.extend([byte_of('>', &e), byte_of('<', &e)]),
+                        ("lobytes", Arg::Expr(e)) => bytes.push(byte_of('<', &e)),
+                        (_, Arg::Expr(e)) => bytes.push(byte_of('>', &e)),
+                        (_, Arg::Str(_, span)) => return self.error(loc, AsmError::new(span, "expected expression")),
+                    }
+                }
+                if name == "asciiz" {
+                    bytes.push(Arg::Expr(Expr::Number(0)));
+                }
+                self.directive("byte", bytes, span, loc);
+            }
+            "res" if count <= 2 => {
+                let n = match self.known(args.first(), span, loc) {
+                    Some(n) if (0..=0x10000).contains(&n) => n as usize,
+                    Some(n) => return self.error(loc, AsmError::new(span, &format!("bad size {}", n))),
+                    None => return,
+                };
+                let fill = match args.get(1) {
+                    Some(Arg::Expr(e)) => {
+                        let mut e = e.clone();
+                        self.localize(&mut e);
+                        Some(e)
+                    }
+                    Some(Arg::Str(_, span)) => return self.error(loc, AsmError::new(*span, "expected expression")),
+                    None => None,
+                };
+                self.emit(Emit::Fill(n, fill), n, span, loc);
+            }
+            // ACME: !align and, equal [, fill] -- up to the address where (pc & and) == equal
+            "align" if self.assembler.dialect == Dialect::Acme && (2..=3).contains(&count) => {
+                let (mask, equal) = match (self.known(args.first(), span, loc), self.known(args.get(1), span, loc)) {
+                    (Some(mask), Some(equal)) => (mask, equal),
+                    _ => return,
+                };
+                let pc = self.pc();
+                match (0..=0x10000).find(|n| (pc + n) & mask == equal) {
+                    Some(n) => self.directive("res", [Arg::Expr(Expr::Number(n))].into_iter().chain(args.drain(2..)).collect(), span, loc),
+                    None => self.error(loc, AsmError::new(span, &format!("no address with (address & ${:X}) = ${:X}", mask, equal))),
+                }
+            }
+            "align" if self.assembler.relocatable => {
+                self.error(loc, AsmError::new(span, ".align is not allowed in a relocatable object"))
+            }
+            "align" if self.assembler.dialect != Dialect::Acme && (1..=2).contains(&count) => {
+                let n = match self.known(args.first(), span, loc) {
+                    Some(boundary) if (1..=0x10000).contains(&boundary) => (boundary - self.pc() % boundary) % boundary,
+                    Some(boundary) => return self.error(loc, AsmError::new(span, &format!("bad alignment {}", boundary))),
+                    None => return,
+                };
+                self.directive("res", [Arg::Expr(Expr::Number(n))].into_iter().chain(args.drain(1..)).collect(), span, loc);
+            }
+            "incbin" if (1..=3).contains(&count) => {
+                let file = match self.string_arg(&args[..1], span, loc) {
+                    Some(file) => file,
+                    None => return,
+                };
+                let mut bounds = Vec::new();
+                for arg in &args[1..] {
+                    match self.known(Some(arg), span, loc) {
+                        Some(v) => bounds.push(v),
+                        None => return,
+                    }
+                }
+                let path = self.resolve(loc.file, &file);
+                let bytes = match fs::read(&path) {
+                    Ok(bytes) => bytes,
+                    Err(e) => return self.error(loc, AsmError::new(span, &format!("{}: {}", path.display(), e))),
+                };
+                let start = bounds.first().copied().unwrap_or(0);
+                let size = bounds.get(1).copied().unwrap_or(bytes.len() as i64 - start);
+                if start < 0 || size < 0 || start + size > bytes.len() as i64 {
+                    let msg = format!("{}: {} bytes at offset {} are not in the file ({} bytes)", path.display(), size, start, bytes.len());
+                    return self.error(loc, AsmError::new(span, &msg));
+                }
+                let bytes = bytes[start as usize..(start + size) as usize].to_vec();
+                let size = bytes.len();
+                self.emit(Emit::Raw(bytes), size, span, loc);
+            }
+            "include" => {
+                let file = match self.string_arg(&args, span, loc) {
+                    Some(file) => file,
+                    None => return,
+                };
+                let path = self.resolve(loc.file, &file);
+                if self.frames.len() >= MAX_NESTING {
+                    self.error(loc, AsmError::new(span, "too many nested macro expansions and includes"));
+                } else {
+                    match fs::read_to_string(&path) {
+                        Ok(source) => self.push_file(path.display().to_string(), &source),
+                        Err(e) => self.error(loc, AsmError::new(span, &format!("{}: {}", path.display(), e))),
+                    }
+                }
+            }
+            "segment" => {
+                if let Some(name) = self.string_arg(&args, span, loc) {
+                    self.switch_segment(name);
+                }
+            }
+            "if" | "ifdef" | "ifndef" if count == 1 => {
+                let value = if name == "if" {
+                    self.known(args.first(), span, loc).map(|v| v != 0)
+                } else if let Some(Arg::Expr(Expr::Symbol(symbol, symbol_span))) = args.first() {
+                    Some(lookup(&self.symbols, &self.reference(symbol, *symbol_span)).is_some() == (name == "ifdef"))
+                } else {
+                    self.error(loc, AsmError::new(span, "expected symbol name"));
+                    None
+                };
+                // after an error neither of the branches is assembled
+                let cond = Cond {
+                    active: value.unwrap_or(false),
+                    parent_active: value.is_some(),
+                    taken: value.unwrap_or(false),
+                    else_seen: false,
+                    loc: loc.clone(),
+                    span,
+                };
+                self.conds.push(cond);
+            }
+            "elseif" if count == 1 => match self.conds.last() {
+                Some(cond) if cond.else_seen => self.error(loc, AsmError::new(span, ".elseif after .else")),
+                Some(cond) if cond.parent_active && !cond.taken => {
+                    let value = self.known(args.first(), span, loc).is_some_and(|v| v != 0);
+                    let cond = self.conds.last_mut().unwrap();
+                    cond.active = value;
+                    cond.taken = value;
+                }
+                Some(_) => self.conds.last_mut().unwrap().active = false,
+                None => self.error(loc, AsmError::new(span, ".elseif without .if")),
+            },
+            "else" if count == 0 => self.cond_else(span, loc),
+            "endif" if count == 0 => self.cond_endif(span, loc),
+            "endmacro" => self.error(loc, AsmError::new(span, ".endmacro without .macro")),
+            "code" | "data" | "rodata" | "bss" | "zeropage" if count == 0 => {
+                let name = name.to_ascii_uppercase();
+                self.switch_segment(name);
+            }
+            "zone" => {
+                let scope = std::mem::replace(&mut self.scope, format!("__zone{}", self.zones.len()));
+                self.zones.push((scope, self.path.clone()));
+            }
+            "proc" if count == 1 => match args.pop() {
+                Some(Arg::Expr(Expr::Symbol(symbol, symbol_span))) if !symbol.starts_with('@') => {
+                    self.zones.push((self.scope.clone(), self.path.clone()));
+                    self.statement(Statement { stmt: Stmt::Label(symbol.clone()), span: symbol_span }, loc);
+                    self.path = format!("{}{}::", self.path, symbol);
+                }
+                _ => self.error(loc, AsmError::new(span, "expected symbol name")),
+            },
+            "scope" if count <= 1 => {
+                let symbol = match args.pop() {
+                    Some(Arg::Expr(Expr::Symbol(symbol, _))) if !symbol.starts_with('@') => symbol,
+                    None => {
+                        self.unique += 1;
+                        format!("__scope{}", self.unique)
+                    }
+                    _ => return self.error(loc, AsmError::new(span, "expected symbol name")),
+                };
+                self.zones.push((self.scope.clone(), self.path.clone()));
+                self.path = format!("{}{}::", self.path, symbol);
+            }
+            "endzone" | "endproc" | "endscope" if count == 0 => match self.zones.pop() {
+                Some((scope, path)) => {
+                    self.scope = scope;
+                    self.path = path;
+                }
+                None if name == "endzone" => self.error(loc, AsmError::new(span, "} without a block")),
+                None => self.error(loc, AsmError::new(span, &format!(".{} without .{}", name, &name[3..]))),
+            },
+            "enum" if count <= 1 => {
+                let prefix = match args.pop() {
+                    Some(Arg::Expr(Expr::Symbol(symbol, _))) if !symbol.starts_with('@') => format!("{}{}::", self.path, symbol),
+                    None => self.path.clone(),
+                    _ => return self.error(loc, AsmError::new(span, "expected symbol name")),
+                };
+                self.enumerating = Some((prefix, 0, loc.clone(), span));
+            }
+            "endenum" => self.error(loc, AsmError::new(span, ".endenum without .enum")),
+            "local" if count > 0 => {
+                if self.frames.last().is_none_or(|f| f.expansion.is_none()) {
+                    return self.error(loc, AsmError::new(span, ".local outside of a macro"));
+                }
+                self.unique += 1;
+                let unique = self.unique;
+                for arg in args {
+                    match arg {
+                        Arg::Expr(Expr::Symbol(symbol, _)) if !symbol.starts_with('@') => {
+                            let replacement = format!("__local{}_{}", unique, symbol);
+                            self.frames.last_mut().unwrap().locals.push((symbol, replacement));
+                        }
+                        _ => self.error(loc, AsmError::new(span, "expected symbol name")),
+                    }
+                }
+            }
+            "set" if count == 2 => {
+                let (symbol, symbol_span) = match &args[0] {
+                    Arg::Expr(Expr::Symbol(symbol, symbol_span)) => (self.local_name(symbol), *symbol_span),
+                    _ => return self.error(loc, AsmError::new(span, "expected symbol name")),
+                };
+                if self.symbols.contains_key(&symbol) && !self.variables.contains_key(&symbol) {
+                    return self.error(loc, AsmError::new(symbol_span, &format!("symbol '{}' is already defined", symbol)));
+                }
+                if let Some(value) = self.known(args.get(1), span, loc) {
+                    self.symbols.insert(symbol.clone(), value);
+                    self.variables.insert(symbol, value);
+                }
+            }
+            "logical" if self.assembler.relocatable => {
+                self.error(loc, AsmError::new(span, ".logical is not allowed in a relocatable object"))
+            }
+            "logical" if count == 1 => {
+                if let Some(v) = self.known(args.first(), span, loc) {
+                    if !(0..=0xffff).contains(&v) {
+                        return self.error(loc, AsmError::new(span, &format!("address {} is out of range", v)));
+                    }
+                    let segment = &mut self.segments[self.segment];
+                    self.logical.push((self.segment, segment.delta, loc.clone(), span));
+                    segment.delta += v - segment.pc;
+                    segment.pc = v;
+                }
+            }
+            "here" if count == 0 => match self.logical.pop() {
+                Some((index, delta, _, _)) => {
+                    let segment = &mut self.segments[index];
+                    segment.pc += delta - segment.delta;
+                    segment.delta = delta;
+                }
+                None => self.error(loc, AsmError::new(span, ".here without .logical")),
+            },
+            "assert" if (2..=3).contains(&count) => {
+                let error = match &args[1] {
+                    Arg::Expr(Expr::Symbol(action, _)) if ["error", "lderror"].contains(&action.to_ascii_lowercase().as_str()) => true,
+                    Arg::Expr(Expr::Symbol(action, _)) if ["warning", "ldwarning"].contains(&action.to_ascii_lowercase().as_str()) => false,
+                    _ => return self.error(loc, AsmError::new(span, "expected error or warning")),
+                };
+                let message = match args.get(2) {
+                    Some(Arg::Str(message, _)) => Some(message.clone()),
+                    Some(Arg::Expr(_)) => return self.error(loc, AsmError::new(span, "expected a string")),
+                    None => None,
+                };
+                if let Some(Arg::Expr(e)) = args.first_mut() {
+                    self.localize(e);
+                    let e = e.clone();
+                    self.emit(Emit::Assert(e, error, message), 0, span, loc);
+                } else {
+                    self.error(loc, AsmError::new(span, "expected expression"));
+                }
+            }
+            "out" => {
+                if let Some(text) = self.string_arg(&args, span, loc) {
+                    self.output.push(text);
+                }
+            }
+            "repeat" if count <= 2 => {
+                let var = match args.get(1) {
+                    Some(Arg::Expr(Expr::Symbol(var, _))) => Some(var.clone()),
+                    Some(_) => return self.error(loc, AsmError::new(span, "expected symbol name")),
+                    None => None,
+                };
+                // the body is skipped after an error
+                let count = match self.known(args.first(), span, loc) {
+                    Some(n) if (0..=0x10000).contains(&n) => n as usize,
+                    Some(n) => {
+                        self.error(loc, AsmError::new(span, &format!("bad count {}", n)));
+                        0
+                    }
+                    None => 0,
+                };
+                self.repeating = Some(Repeat { count, var, body: Vec::new(), depth: 0, loc: loc.clone(), span });
+            }
+            "endrep" => self.error(loc, AsmError::new(span, ".endrep without .repeat")),
+            "import" | "importzp" if count > 0 => {
+                if !self.assembler.relocatable {
+                    return self.error(loc, AsmError::new(span, &format!(".{} needs a relocatable object", name)));
+                }
+                for arg in args {
+                    match arg {
+                        Arg::Expr(Expr::Symbol(symbol, symbol_span)) => {
+                            self.define(&symbol, 0, symbol_span, loc);
+                            self.set_base(&symbol, Some(Target::Import(symbol.clone())));
+                            self.imports.push(Import { name: symbol, zero_page: name == "importzp" });
+                        }
+                        _ => self.error(loc, AsmError::new(span, "expected symbol name")),
+                    }
+                }
+            }
+            "export" | "exportzp" if count > 0 => {
+                for arg in args {
+                    match arg {
+                        Arg::Expr(Expr::Symbol(symbol, symbol_span)) => {
+                            let symbol = self.reference(&symbol, symbol_span);
+                            self.exports.push((symbol, symbol_span, loc.clone()));
+                        }
+                        _ => self.error(loc, AsmError::new(span, "expected symbol name")),
+                    }
+                }
+            }
+            // outside of a relocatable object every symbol is global
+            "global" | "globalzp" if count > 0 => {
+                for arg in args {
+                    match arg {
+                        Arg::Expr(Expr::Symbol(symbol, symbol_span)) if !symbol.starts_with('@') => {
+                            if self.assembler.relocatable {
+                                self.globals.push((symbol, name == "globalzp", symbol_span, loc.clone()));
+                            }
+                        }
+                        _ => self.error(loc, AsmError::new(span, "expected symbol name")),
+                    }
+                }
+            }
+            "" => {} // the directives of the other assemblers which don't matter here
+            "org" | "byte" | "word" | "res" | "if" | "ifdef" | "ifndef" | "elseif" | "else" | "endif" | "code" | "data"
+            | "rodata" | "bss" | "zeropage" | "import" | "importzp" | "export" | "exportzp" | "asciiz" | "dbyt" | "lobytes"
+            | "hibytes" | "proc" | "endproc" | "repeat" | "align" | "incbin" | "scope" | "endscope" | "endzone" | "enum"
+            | "local" | "set" | "logical" | "here" | "assert" | "global" | "globalzp" => self.error(loc, wrong_count),
+            _ => self.error(loc, AsmError::new(span, &format!("unknown directive '.{}'", name))),
+        }
+    }
+
+    fn switch_segment(&mut self, name: String) {
+        self.segment = match self.segments.iter().position(|s| s.name == name) {
+            Some(idx) => idx,
+            None => {
+                self.segments.push(Segment { name, pc: 0, delta: 0 });
+                self.segments.len() - 1
+            }
+        };
+    }
+
+    fn cond_else(&mut self, span: Span, loc: &Loc) {
+        match self.conds.last_mut() {
+            Some(cond) if !cond.else_seen => {
+                cond.else_seen = true;
+                cond.active = cond.parent_active && !cond.taken;
+                cond.taken = true;
+            }
+            Some(_) => self.error(loc, AsmError::new(span, "duplicate .else")),
+            None => self.error(loc, AsmError::new(span, ".else without .if")),
+        }
+    }
+
+    fn cond_endif(&mut self, span: Span, loc: &Loc) {
+        if self.conds.pop().is_none() {
+            self.error(loc, AsmError::new(span, ".endif without .if"));
+        }
+    }
+
+    // relative to the including file first, then the include directories
+    fn resolve(&self, from: usize, name: &str) -> PathBuf {
+        let dir = Path::new(&self.files[from]).parent().unwrap_or(Path::new("")).to_path_buf();
+        std::iter::once(&dir)
+            .chain(self.assembler.include_dirs.iter())
+            .map(|d| d.join(name))
+            .find(|p| p.exists())
+            .unwrap_or_else(|| dir.join(name))
+    }
+
+    // .global: the symbols defined here are exported, the others imported
+    fn resolve_globals(&mut self) {
+        for (name, zero_page, span, loc) in std::mem::take(&mut self.globals) {
+            if self.symbols.contains_key(&name) || self.deferred.iter().any(|d| d.0 == name) {
+                self.exports.push((name, span, loc));
+            } else if !self.imports.iter().any(|i| i.name == name) {
+                self.define(&name, 0, span, &loc);
+                self.set_base(&name, Some(Target::Import(name.clone())));
+                self.imports.push(Import { name, zero_page });
+            }
+        }
+    }
+
+    fn resolve_equates(&mut self) {
+        let mut progress = true;
+        while progress {
+            progress = false;
+            let mut i = 0;
+            while i < self.deferred.len() {
+                let (name, e, pc, segment, span, loc) = self.deferred[i].clone();
+                match e.eval(&self.symbols, pc) {
+                    Ok(Some(v)) => {
+                        self.define_equate(&name, v, &e, segment, span, &loc);
+                        self.deferred.remove(i);
+                        progress = true;
+                    }
+                    Ok(None) => i += 1,
+                    Err(e) => {
+                        self.error(&loc, e);
+                        self.deferred.remove(i);
+                    }
+                }
+            }
+        }
+
+        for (_, e, _, _, _, loc) in std::mem::take(&mut self.deferred) {
+            if let Some((name, span)) = e.first_undefined(&self.symbols) {
+                self.error(&loc, undefined(name, span));
+            }
+        }
+    }
+
+    fn select_mode(&self, mnemonic: &str, operand: &Operand, pc: i64, span: Span) -> Result<AddressingMode, AsmError> {
+        use AddressingMode::*;
+
+        let has = |mode| encode(mnemonic, mode).is_some();
+        let unsupported = || AsmError::new(span, &format!("addressing mode is not supported by {}", mnemonic));
+        let single = |mode| if has(mode) { Ok(mode) } else { Err(unsupported()) };
+
+        let (zp, abs, e) = match operand {
+            Operand::None if has(Implied) => return Ok(Implied),
+            Operand::None => return single(Accumulator),
+            Operand::Accumulator => return single(Accumulator),
+            Operand::Immediate(_) => return single(Immediate),
+            Operand::Indirect(_) => return single(AbsoluteIndirect),
+            Operand::IndirectX(_) => return single(ZeroPageXIndirect),
+            Operand::IndirectY(_) => return single(ZeroPageIndirectY),
+            Operand::Direct(_) if has(Relative) => return Ok(Relative),
+            Operand::Direct(e) => (ZeroPage, Absolute, e),
+            Operand::IndexedX(e) => (ZeroPageX, AbsoluteX, e),
+            Operand::IndexedY(e) => (ZeroPageY, AbsoluteY, e),
+        };
+
+        let fits_zero_page = match self.relocation(e, self.segment, span)? {
+            _ if matches!(e, Expr::Unary('a', _)) => false, // a:expr, z:expr
+            _ if matches!(e, Expr::Unary('z', _)) => true,
+            Some((RelocKind::Word, target, _)) => self.zero_page(&target),
+            Some(_) => true, // <label, >label
+            None => matches!(e.eval(&self.symbols, pc)?, Some(v) if (0..=0xff).contains(&v)),
+        };
+        if fits_zero_page && has(zp) {
+            Ok(zp)
+        } else if has(abs) {
+            Ok(abs)
+        } else if has(zp) {
+            Ok(zp)
+        } else {
+            Err(unsupported())
+        }
+    }
+
+    fn second(&mut self) -> Program {
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut lines = std::mem::take(&mut self.lines);
+        let mut relocations = Vec::new();
+
+        for record in std::mem::take(&mut self.records) {
+            if let Emit::Assert(e, error, message) = &record.emit {
+                self.assert(e, *error, message, &record);
+                continue;
+            }
+            let (bytes, errors) = self.bytes(&record, &mut relocations);
+            for e in errors {
+                self.error(&record.loc, e);
+            }
+
+            let info = &mut lines[record.loc.line_info];
+            if info.bytes.is_empty() {
+                info.address = record.pc as u16;
+            }
+            if let Emit::Instruction(..) = record.emit {
+                info.instructions.push(info.bytes.len());
+            }
+            info.bytes.extend(&bytes);
+
+            let segment = &self.segments[record.segment].name;
+            match chunks.last_mut() {
+                Some(c) if c.segment == *segment && c.origin as i64 + c.bytes.len() as i64 == record.at => {
+                    c.bytes.extend(&bytes);
+                }
+                _ if bytes.is_empty() => {}
+                _ => chunks.push(Chunk { segment: segment.clone(), origin: record.at as u16, bytes }),
+            }
+        }
+
+        let symbols = self.symbols.iter()
+            .filter(|(name, _)| !name.starts_with(':'))
+            .map(|(name, v)| (name.clone(), *v as u16))
+            .collect();
+
+        let object = match self.assembler.relocatable {
+            true => Some(self.object(&chunks, relocations)),
+            false => None,
+        };
+        let output = std::mem::take(&mut self.output);
+        Program { chunks, symbols, files: self.files.clone(), lines, object, output }
+    }
+
+    // .assert: a false condition is an error, or a warning in Program::output
+    fn assert(&mut self, e: &Expr, error: bool, message: &Option<String>, record: &Record) {
+        let value = match self.known_constant(e, record) {
+            Ok(value) => value,
+            Err(e) => return self.error(&record.loc, e),
+        };
+        if value != 0 {
+            return;
+        }
+        let e = AsmError::new(record.span, message.as_deref().unwrap_or("assertion failed"));
+        if error {
+            self.error(&record.loc, e);
+        } else {
+            let mut warning = e;
+            warning.file = self.files[record.loc.file].clone();
+            warning.note = record.loc.note.clone();
+            self.output.push(warning.to_string().replacen(" error: ", " warning: ", 1));
+        }
+    }
+
+    fn object(&mut self, chunks: &[Chunk], relocations: Vec<Relocation>) -> Object {
+        let mut exports = Vec::new();
+        for (name, span, loc) in std::mem::take(&mut self.exports) {
+            let value = match lookup(&self.symbols, &name) {
+                Some(v) => *v as u16,
+                None => {
+                    let msg = format!("exported symbol '{}' is not defined", written_name(&name));
+                    self.error(&loc, AsmError::new(span, &msg));
+                    continue;
+                }
+            };
+            let segment = match lookup(&self.bases, &name) {
+                Some(Target::Segment(segment)) => Some(segment.clone()),
+                Some(Target::Import(_)) => {
+                    self.error(&loc, AsmError::new(span, &format!("'{}' is imported", name)));
+                    continue;
+                }
+                None => None,
+            };
+            exports.push(Export { name: written_name(&name).to_string(), segment, value });
+        }
+
+        // the empty segments only if something refers to them
+        let used = |name: &String| {
+            exports.iter().any(|e| e.segment.as_ref() == Some(name))
+                || relocations.iter().any(|r| r.target == Target::Segment(name.clone()))
+        };
+        let segments = self.segments.iter()
+            .filter(|s| s.pc > 0 || used(&s.name))
+            .map(|s| {
+                let mut bytes = vec![0; s.pc as usize];
+                for chunk in chunks.iter().filter(|c| c.segment == s.name) {
+                    let start = chunk.origin as usize;
+                    bytes[start..start + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+                }
+                ObjSegment { name: s.name.clone(), bytes }
+            })
+            .collect();
+
+        Object { segments, imports: self.imports.clone(), exports, relocations }
+    }
+
+    // relocatable objects: the kind and the target of the relocation of a value and the expression
+    // giving the addend (None for the constants)
+    fn relocation<'e>(&self, e: &'e Expr, segment: usize, span: Span) -> Result<Option<(RelocKind, Target, &'e Expr)>, AsmError> {
+        if !self.assembler.relocatable {
+            return Ok(None);
+        }
+        let (kind, e) = match e {
+            Expr::Unary('<', e) => (RelocKind::Low, &**e),
+            Expr::Unary('>', e) => (RelocKind::High, &**e),
+            _ => (RelocKind::Word, e),
+        };
+        Ok(e.base(&self.bases, &self.segment_base(segment), span)?.map(|target| (kind, target, e)))
+    }
+
+    fn zero_page(&self, target: &Target) -> bool {
+        match target {
+            Target::Segment(name) => name == "ZEROPAGE",
+            Target::Import(name) => self.imports.iter().any(|i| i.name == *name && i.zero_page),
+        }
+    }
+
+    // records the relocation of a value of `size` bytes at `at`
+    fn relocate(&self, record: &Record, e: &Expr, at: i64, size: usize, relocations: &mut Vec<Relocation>) -> Result<(), AsmError> {
+        let (kind, target, e) = match self.relocation(e, record.segment, record.span)? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        relocations.push(Relocation {
+            segment: self.segments[record.segment].name.clone(),
+            offset: at as u16,
+            kind: if size == 1 && kind == RelocKind::Word { RelocKind::Byte } else { kind },
+            target,
+            addend: self.value(e, record.pc)?,
+        });
+        Ok(())
+    }
+
+    // the bytes of a record (with zeroes in the place of the values with errors)
+    fn bytes(&self, record: &Record, relocations: &mut Vec<Relocation>) -> (Vec<u8>, Vec<AsmError>) {
+        let mut bytes = Vec::new();
+        let mut errors = Vec::new();
+        let pc = record.pc;
+
+        match &record.emit {
+            Emit::Instruction(mnemonic, operand, mode) => {
+                bytes.push(encode(mnemonic, *mode).unwrap());
+                let size = addressing_mode_pc_advance(*mode) as usize;
+                match self.operand(operand, *mode, pc, record.span) {
+                    Ok(value) => {
+                        bytes.extend(&value.to_le_bytes()[..size]);
+                        if let Err(e) = operand.expr().map_or(Ok(()), |e| self.relocate_operand(record, e, *mode, relocations)) {
+                            errors.push(e);
+                        }
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        bytes.extend(&[0, 0][..size]);
+                    }
+                }
+            }
+            Emit::Bytes(args) => {
+                for arg in args {
+                    match arg {
+                        Arg::Str(s, _) => bytes.extend(s.chars().map(|c| c as u8)),
+                        Arg::Expr(e) => match self.value(e, pc).and_then(|v| byte(v, record.span)) {
+                            Ok(b) => {
+                                if let Err(e) = self.relocate(record, e, pc + bytes.len() as i64, 1, relocations) {
+                                    errors.push(e);
+                                }
+                                bytes.push(b);
+                            }
+                            Err(e) => {
+                                errors.push(e);
+                                bytes.push(0);
+                            }
+                        },
+                    }
+                }
+            }
+            Emit::Words(words) => {
+                for e in words {
+                    match self.value(e, pc) {
+                        Ok(v) if (-0x8000..=0xffff).contains(&v) => {
+                            if let Err(e) = self.relocate(record, e, pc + bytes.len() as i64, 2, relocations) {
+                                errors.push(e);
+                            }
+                            bytes.extend((v as u16).to_le_bytes());
+                        }
+                        Ok(v) => {
+                            errors.push(AsmError::new(record.span, &format!("value {} does not fit into a word", v)));
+                            bytes.extend([0, 0]);
+                        }
+                        Err(e) => {
+                            errors.push(e);
+                            bytes.extend([0, 0]);
+                        }
+                    }
+                }
+            }
+            Emit::Fill(n, fill) => {
+                let value = match fill.as_ref().map(|e| self.known_constant(e, record).and_then(|v| byte(v, record.span))) {
+                    Some(Ok(b)) => b,
+                    Some(Err(e)) => {
+                        errors.push(e);
+                        0
+                    }
+                    None => 0,
+                };
+                bytes.resize(*n, value);
+            }
+            Emit::Raw(raw) => bytes.extend(raw),
+            Emit::Assert(..) => {} // checked by second()
+        }
+
+        (bytes, errors)
+    }
+
+    // the relocation of an instruction operand, the branches have to stay in their segment
+    fn relocate_operand(&self, record: &Record, e: &Expr, mode: AddressingMode, relocations: &mut Vec<Relocation>) -> Result<(), AsmError> {
+        if mode != AddressingMode::Relative {
+            return self.relocate(record, e, record.pc + 1, addressing_mode_pc_advance(mode) as usize, relocations);
+        }
+        match self.relocation(e, record.segment, record.span)? {
+            Some((_, target, _)) if target != self.segment_base(record.segment) => {
+                Err(AsmError::new(record.span, "branch target is not in the same segment"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // a value which must not need a relocation
+    fn known_constant(&self, e: &Expr, record: &Record) -> Result<i64, AsmError> {
+        match self.relocation(e, record.segment, record.span)? {
+            Some(_) => Err(AsmError::new(record.span, "expression is not relocatable")),
+            None => self.value(e, record.pc),
+        }
+    }
+
+    fn value(&self, e: &Expr, pc: i64) -> Result<i64, AsmError> {
+        match e.eval(&self.symbols, pc)? {
+            Some(v) => Ok(v),
+            None => {
+                let (name, span) = e.first_undefined(&self.symbols).unwrap();
+                Err(undefined(name, span))
+            }
+        }
+    }
+
+    // value of the operand, ready to be emitted as 1 or 2 little endian bytes
+    fn operand(&self, operand: &Operand, mode: AddressingMode, pc: i64, span: Span) -> Result<u16, AsmError> {
+        use AddressingMode::*;
+
+        let e = match operand.expr() {
+            Some(e) => e,
+            None => return Ok(0),
+        };
+        let v = self.value(e, pc)?;
+
+        match mode {
+            Relative => {
+                let offset = v - (pc + 2);
+                if !(-128..=127).contains(&offset) {
+                    return Err(AsmError::new(span, &format!("branch out of range ({} bytes)", offset)));
+                }
+                Ok(offset as u8 as u16)
+            }
+            Immediate => byte(v, span).map(|b| b as u16),
+            ZeroPage | ZeroPageX | ZeroPageY | ZeroPageXIndirect | ZeroPageIndirectY if !(0..=0xff).contains(&v) => {
+                Err(AsmError::new(span, &format!("address ${:X} is not in the zero page", v)))
+            }
+            _ if !(0..=0xffff).contains(&v) => {
+                Err(AsmError::new(span, &format!("address {} is out of range", v)))
+            }
+            _ => Ok(v as u16),
+        }
+    }
+}
+
+fn undefined(name: &str, span: Span) -> AsmError {
+    if name.starts_with(':') {
+        AsmError::new(span, "there is no such anonymous label")
+    } else {
+        AsmError::new(span, &format!("undefined symbol '{}'", written_name(name)))
+    }
+}
+
+fn byte(v: i64, span: Span) -> Result<u8, AsmError> {
+    if (-128..=0xff).contains(&v) {
+        Ok(v as u8)
+    } else {
+        Err(AsmError::new(span, &format!("value {} does not fit into a byte", v)))
+    }
+}
+
+// replaces the macro parameters with the arguments (whole identifiers only,
+// in 64tass \name or \1 for the first one)
+fn substitute(text: &str, line: usize, dialect: Dialect, params: &[String], args: &[String]) -> String {
+    let tokens = match tokenize(text, line, dialect) {
+        Ok(tokens) => tokens,
+        Err(_) => return text.to_string(), // reported when the line is parsed
+    };
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::new();
+    let mut last = 0;
+    for t in &tokens {
+        let param = |name: &str| match name.strip_prefix('\\') {
+            Some(n) => n.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).or_else(|| params.iter().position(|p| p == n)),
+            None => params.iter().position(|p| p == name),
+        };
+        if let Some(idx) = t.ident().and_then(param) {
+            out.extend(&chars[last..t.span.column - 1]);
+            out.push_str(args.get(idx).map_or("", String::as_str));
+            last = t.span.column - 1 + t.span.len;
+        }
+    }
+    out.extend(&chars[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::opcodes::*;
+    use crate::disasm::disassemble_source;
+    use crate::tempdir::TempDir;
+
+    fn bytes(source: &str, origin: u16) -> Vec<u8> {
+        let program = Assembler::new().origin(origin).assemble(source).unwrap();
+        program.image().map(|(_, image)| image).unwrap_or_default()
+    }
+
+    fn errors_of(source: &str) -> Vec<(usize, usize, String)> {
+        Assembler::new().assemble(source).unwrap_err().into_iter().map(|e| (e.span.line, e.span.column, e.message)).collect()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let src = "
+            CLC
+            ASL A
+            ASL
+            LDA #$10
+            LDA $44
+            LDA $44,X
+            LDX $44,Y
+            LDA $0200
+            STA $0200,X
+            LDA $0200,Y
+            JMP ($FFFC)
+            LDA ($44,X)
+            ORA ($44),Y
+            LDA $0044     ; the value decides, not the way it is written
+            LDX $0200,Y
+            STX $44,Y     ; STX has no absolute,Y
+            JMP $0044     ; JMP has no zero page
+        ";
+        assert!(bytes(src, 0) == vec![
+            CLC_18,
+            ASL_0A,
+            ASL_0A,
+            LDA_A9, 0x10,
+            LDA_A5, 0x44,
+            LDA_B5, 0x44,
+            LDX_B6, 0x44,
+            LDA_AD, 0x00, 0x02,
+            STA_9D, 0x00, 0x02,
+            LDA_B9, 0x00, 0x02,
+            JMP_6C, 0xfc, 0xff,
+            LDA_A1, 0x44,
+            ORA_11, 0x44,
+            LDA_A5, 0x44,
+            LDX_BE, 0x00, 0x02,
+            STX_96, 0x44,
+            JMP_4C, 0x44, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn test_labels() {
+        let src = "
+            COUNT = 5
+            ptr = $10
+                    LDX #COUNT
+            loop:   LDA (ptr),Y
+                    STA table,X     ; forward reference: absolute addressing
+                    DEX
+                    BNE loop
+                    BEQ done
+                    JSR done
+            done:   RTS
+            table = $0300 + COUNT * 2
+        ";
+        let program = Assembler::new().origin(0x8000).assemble(src).unwrap();
+        assert!(program.chunks[0].origin == 0x8000);
+        assert!(program.chunks[0].bytes == vec![
+            LDX_A2, 0x05,
+            LDA_B1, 0x10,
+            STA_9D, 0x0a, 0x03,
+            DEX_CA,
+            BNE_D0, 0xf8,
+            BEQ_F0, 0x03,
+            JSR_20, 0x0f, 0x80,
+            RTS_60,
+        ]);
+        assert!(program.symbols["loop"] == 0x8002);
+        assert!(program.symbols["done"] == 0x800f);
+        assert!(program.symbols["table"] == 0x030a);
+    }
+
+    #[test]
+    fn test_forward_zero_page() {
+        // the size can't change between the passes: zp is defined later, so it's absolute
+        let src = "
+            LDA zp
+            zp = $44
+            LDA zp
+        ";
+        assert!(bytes(src, 0) == vec![LDA_AD, 0x44, 0x00, LDA_A5, 0x44]);
+    }
+
+    #[test]
+    fn test_pc() {
+        assert!(bytes("JMP *", 0x1234) == vec![JMP_4C, 0x34, 0x12]);
+        assert!(bytes("BNE * + 2", 0x1234) == vec![BNE_D0, 0x00]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(errors_of("  LDA #1\n  BAD") == vec![(2, 3, "unknown instruction 'BAD'".to_string())]);
+        assert!(errors_of("  LDA missing") == vec![(1, 7, "undefined symbol 'missing'".to_string())]);
+        assert!(errors_of("x = y\n") == vec![(1, 5, "undefined symbol 'y'".to_string())]);
+        assert!(errors_of("a: NOP\na: NOP") == vec![(2, 1, "symbol 'a' is already defined".to_string())]);
+        assert!(errors_of("  LDA #$100") == vec![(1, 3, "value 256 does not fit into a byte".to_string())]);
+        assert!(errors_of("  LDA ($100),Y") == vec![(1, 3, "address $100 is not in the zero page".to_string())]);
+        assert!(errors_of("  JMP #1") == vec![(1, 3, "addressing mode is not supported by JMP".to_string())]);
+        assert!(errors_of("  LDA $10000") == vec![(1, 3, "address 65536 is out of range".to_string())]);
+
+        let far = format!("  BNE far\n{}far: RTS", "  NOP\n".repeat(128));
+        assert!(errors_of(&far) == vec![(1, 3, "branch out of range (128 bytes)".to_string())]);
+
+        // all the errors are reported, not only the first one
+        assert!(errors_of("  LDA x\n  LDA y").len() == 2);
+    }
+
+    #[test]
+    fn test_directives() {
+        let src = r#"
+                    .org $8000
+            start:  .byte 1, -1, "AB", <start, >start
+                    .word start, $1234
+                    .res 2
+                    .res 3, $EA
+                    .org $9000
+                    .byte * >> 8
+        "#;
+        let program = Assembler::new().assemble(src).unwrap();
+        assert!(program.chunks.len() == 2);
+        assert!(program.chunks[0].origin == 0x8000);
+        assert!(program.chunks[0].bytes == vec![
+            0x01, 0xff, b'A', b'B', 0x00, 0x80,
+            0x00, 0x80, 0x34, 0x12,
+            0x00, 0x00,
+            0xea, 0xea, 0xea,
+        ]);
+        assert!(program.chunks[1] == Chunk { segment: "CODE".to_string(), origin: 0x9000, bytes: vec![0x90] });
+    }
+
+    #[test]
+    fn test_segments() {
+        let src = r#"
+                    .segment "VECTORS"
+                    .org $FFFA
+                    .word nmi, reset, nmi
+                    .segment "CODE"
+            reset:  SEI
+            nmi:    RTI
+        "#;
+        let program = Assembler::new().origin(0xc000).assemble(src).unwrap();
+        assert!(program.chunks == vec![
+            Chunk { segment: "VECTORS".to_string(), origin: 0xfffa, bytes: vec![0x01, 0xc0, 0x00, 0xc0, 0x01, 0xc0] },
+            Chunk { segment: "CODE".to_string(), origin: 0xc000, bytes: vec![SEI_78, RTI_40] },
+        ]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let src = "
+            .macro add16 dst, value
+                    CLC
+                    LDA dst
+                    ADC #<value
+                    STA dst
+                    BCC @done
+                    INC dst+1
+            @done:
+            .endmacro
+
+                    add16 $10, $0102
+                    add16 $20, 3
+        ";
+        let expansion = |zp: u8, v: u8| vec![CLC_18, LDA_A5, zp, ADC_69, v, STA_85, zp, BCC_90, 0x02, INC_E6, zp + 1];
+        assert!(bytes(src, 0) == [expansion(0x10, 0x02), expansion(0x20, 0x03)].concat());
+
+        let errors = Assembler::new().assemble(".macro m\n  LDA #$100\n.endmacro\n  m\n  m 1").unwrap_err();
+        assert!(errors.len() == 2);
+        assert!(errors[0].to_string() == "2:3: error: value 256 does not fit into a byte\n4: note: in expansion of macro 'm'");
+        assert!(errors[1].to_string() == "5:3: error: macro 'm' takes 0 argument(s)");
+
+        assert!(errors_of(".macro m\n  m\n.endmacro\n  m").len() == 1); // no endless recursion
+        assert!(errors_of(".macro m\n  NOP") == vec![(1, 1, "missing .endmacro for 'm'".to_string())]);
+        assert!(errors_of(".macro lda\n.endmacro") == vec![(1, 1, "'lda' is an instruction".to_string())]);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let src = "
+            DEBUG = 1
+            .if DEBUG
+                    LDA #1
+                .if DEBUG > 1
+                    LDA #2
+                .else
+                    LDA #3
+                .endif
+            .else
+                    LDA #4
+                .if 1
+                    LDA #5
+                .endif
+            .endif
+            .ifdef DEBUG
+                    NOP
+            .endif
+            .ifndef DEBUG
+                    BAD INSTRUCTION, not assembled
+            .endif
+        ";
+        assert!(bytes(src, 0) == vec![LDA_A9, 1, LDA_A9, 3, NOP_EA]);
+
+        assert!(errors_of(".if later\n.endif\nlater = 1") == vec![(1, 1, "value must be known in the first pass".to_string())]);
+        assert!(errors_of(".if 1") == vec![(1, 1, "missing .endif".to_string())]);
+        assert!(errors_of(".else") == vec![(1, 1, ".else without .if".to_string())]);
+        assert!(errors_of(".endif") == vec![(1, 1, ".endif without .if".to_string())]);
+    }
+
+    #[test]
+    fn test_ca65_directives() {
+        let src = r#"
+            .proc   clear
+                    LDX #2
+            @loop:  STA $10,X
+                    DEX
+                    BPL @loop
+                    RTS
+            .endproc
+            .proc   table
+                    .repeat 3, I
+                    .repeat 2
+                    .byte I
+                    .endrep
+                    .endrepeat
+            .endproc
+                    .asciiz "ok", "!"
+                    .dbyt $1234, table
+                    .lobytes $1234, table
+                    .hibytes $1234, table
+        "#;
+        let program = Assembler::new().origin(0x8000).assemble(src).unwrap();
+        assert!(program.symbols["clear"] == 0x8000 && program.symbols["table"] == 0x8008);
+        assert!(program.image().unwrap().1 == vec![
+            LDX_A2, 2, STA_95, 0x10, DEX_CA, BPL_10, 0xfb, RTS_60,
+            0, 0, 1, 1, 2, 2,
+            b'o', b'k', b'!', 0,
+            0x12, 0x34, 0x80, 0x08,
+            0x34, 0x08,
+            0x12, 0x80,
+        ]);
+
+        assert!(errors_of(".repeat 2
+  NOP") == vec![(1, 1, "missing .endrep".to_string())]);
+        assert!(errors_of(".endrep") == vec![(1, 1, ".endrep without .repeat".to_string())]);
+        assert!(errors_of(".endproc") == vec![(1, 1, ".endproc without .proc".to_string())]);
+        assert!(errors_of(".dbyt \"ab\"") == vec![(1, 7, "expected expression".to_string())]);
+        let errors = Assembler::new().assemble(".repeat 2
+  LDA #$100
+.endrep").unwrap_err();
+        assert!(errors[0].to_string() == "2:3: error: value 256 does not fit into a byte\n1: note: in expansion of .repeat");
+    }
+
+    #[test]
+    fn test_dialects() {
+        let ca65 = r#"
+                    .setcpu "6502"
+            COUNT = 3
+            .macro fill addr, value
+                    LDA #value
+                    STA addr
+            .endmacro
+                    .org $C000
+            start:  LDX #COUNT .mod 2
+            @loop:  DEX
+                    BNE @loop
+            :       fill $10, <start
+                    BEQ :-
+            .if COUNT .and 0
+                    BRK
+            .elseif COUNT = 3
+                    NOP
+            .else
+                    BRK
+            .endif
+                    .byt "ok"
+        "#;
+        let acme = r#"
+                    !cpu 6502
+            COUNT = 3
+            !macro fill .addr, .value {
+                    LDA #.value
+                    STA .addr
+            }
+                    *= $C000
+            start   LDX #COUNT MOD 2
+            .loop   DEX
+                    BNE .loop
+            -       +fill $10, <start
+                    BEQ -
+            !if COUNT AND 0 { BRK } else { NOP }
+                    !text "ok"
+        "#;
+        let tass = r#"
+            COUNT = 3
+            fill    .macro addr, value
+                    LDA #\value
+                    STA \1
+                    .endm
+                    * = $C000
+            start   LDX #COUNT & 1
+            _loop   DEX
+                    BNE _loop
+            -       #fill $10, <start
+                    BEQ -
+            .if COUNT && 0
+                    BRK
+            .elsif COUNT == 3
+                    NOP
+            .else
+                    BRK
+            .fi
+            .comment
+                    BRK
+            .endc
+                    .text "ok"
+        "#;
+        let expected = vec![LDX_A2, 1, DEX_CA, BNE_D0, 0xfd, LDA_A9, 0x00, STA_85, 0x10, BEQ_F0, 0xfa, NOP_EA, b'o', b'k'];
+        for (dialect, src) in [(Dialect::Ca65, ca65), (Dialect::Acme, acme), (Dialect::Tass64, tass)] {
+            let program = Assembler::new().dialect(dialect).assemble(src).unwrap_or_else(|e| panic!("{:?}: {:?}", dialect, e));
+            assert!(program.chunks[0].bytes == expected, "{:?}", dialect);
+        }
+
+        // ACME: the normal labels don't start a new scope for .local, !zone does
+        let src = "a\n.x nop\nb jmp .x\n!zone {\n.x nop\n}\n jmp .x";
+        assert!(Assembler::new().dialect(Dialect::Acme).assemble(src).unwrap().chunks[0].bytes == vec![
+            NOP_EA, JMP_4C, 0x00, 0x00, NOP_EA, JMP_4C, 0x00, 0x00,
+        ]);
+        let errors = Assembler::new().dialect(Dialect::Acme).assemble("!if 1 {   lda  #$zz\n}").unwrap_err();
+        assert!(errors.len() == 1 && errors[0].span.column == 17, "{:?}", errors);
+
+        let program = Assembler::new().assemble(".zeropage\n.res 2\n.code\nNOP").unwrap();
+        assert!(program.chunks.iter().map(|c| c.segment.as_str()).collect::<Vec<_>>() == ["ZEROPAGE", "CODE"]);
+    }
+
+    #[test]
+    fn test_ca65_scopes() {
+        let src = r#"
+            .feature labels_without_colons
+            .define ptr $10
+            .enum color
+                    black
+                    white = 4
+                    red
+            .endenum
+            .macro clear addr
+                    .local again
+                    LDX #2
+            again:  STA addr,X
+                    DEX
+                    BPL again
+            .endmacro
+            x       := 1
+            .proc   main
+            x = 2
+                    LDA #x
+                    LDA #::x
+                    LDA #inner::y
+            .scope  inner
+            y = 3
+                    LDA #x
+            .endscope
+                    clear ptr
+                    clear ptr
+            .endproc
+                    LDA #main::x + color::red + ^$012345
+                    LDA a:ptr
+                    STA z:main::inner::y
+                    .ascii "ok"
+                    .align 4, $ff
+                    .assert * = $8020, error, "bad size"
+                    .assert 0, warning, "just checking"
+                    .out "done"
+        "#;
+        let program = Assembler::new().origin(0x8000).assemble(src).unwrap();
+        assert!(program.symbols["main::x"] == 2 && program.symbols["x"] == 1 && program.symbols["color::red"] == 5);
+        assert!(program.image().unwrap().1 == vec![
+            LDA_A9, 2, LDA_A9, 1, LDA_A9, 3, LDA_A9, 2,
+            LDX_A2, 2, STA_95, 0x10, DEX_CA, BPL_10, 0xfb,
+            LDX_A2, 2, STA_95, 0x10, DEX_CA, BPL_10, 0xfb,
+            LDA_A9, 8, LDA_AD, 0x10, 0x00, STA_85, 3,
+            b'o', b'k', 0xff,
+        ]);
+        assert!(program.output == vec!["done".to_string(), "35:21: warning: just checking".to_string()]);
+
+        let dir = TempDir::new("asm_scopes");
+        dir.write("data.bin", b"abcdef");
+        let path = dir.write("main.s", ".incbin \"data.bin\", 1, 3\n.incbin \"data.bin\", 4");
+        assert!(Assembler::new().assemble_file(path.to_str().unwrap()).unwrap().image().unwrap().1 == b"bcdef");
+        dir.write("main.s", ".incbin \"data.bin\", 4, 3");
+        let errors = Assembler::new().assemble_file(path.to_str().unwrap()).unwrap_err();
+        assert!(errors[0].message.ends_with("3 bytes at offset 4 are not in the file (6 bytes)"), "{:?}", errors);
+
+        assert!(errors_of(".assert 1 = 2, error") == vec![(1, 1, "assertion failed".to_string())]);
+        assert!(errors_of(".scope s\nx = 1\n.endscope\nLDA x") == vec![(4, 5, "undefined symbol 'x'".to_string())]);
+        assert!(errors_of(".enum\na\nLDA #1") == vec![
+            (1, 1, "missing .endenum".to_string()),
+            (3, 1, "expected an enum member (name or name = value)".to_string()),
+        ]);
+        assert!(errors_of("LDA #1\n.local x") == vec![(2, 1, ".local outside of a macro".to_string())]);
+        assert!(errors_of(".endscope") == vec![(1, 1, ".endscope without .scope".to_string())]);
+
+        let program = Assembler::new().relocatable().assemble(".global start, far\n.globalzp ptr\nstart: JSR far\nLDA ptr").unwrap();
+        let object = program.object.unwrap();
+        assert!(object.exports == vec![Export { name: "start".to_string(), segment: Some("CODE".to_string()), value: 0 }]);
+        assert!(object.imports == vec![
+            Import { name: "far".to_string(), zero_page: false },
+            Import { name: "ptr".to_string(), zero_page: true },
+        ]);
+    }
+
+    #[test]
+    fn test_acme_directives() {
+        let src = r#"
+            !set j = 1
+                    *= $c000
+                    !pseudopc $0200 {
+            loop        LDA #j
+                        JMP loop
+            }
+            after   !set j = j + 1
+                    LDA #j
+                    !align 3, 0, $ea
+                    JMP after
+        "#;
+        let program = Assembler::new().dialect(Dialect::Acme).assemble(src).unwrap_or_else(|e| panic!("{:?}", e));
+        assert!(program.symbols["loop"] == 0x0200 && program.symbols["after"] == 0xc005 && program.symbols["j"] == 2);
+        assert!(program.chunks[0].origin == 0xc000);
+        assert!(program.chunks[0].bytes == vec![LDA_A9, 1, JMP_4C, 0x00, 0x02, LDA_A9, 2, NOP_EA, JMP_4C, 0x05, 0xc0]);
+
+        let errors = Assembler::new().dialect(Dialect::Acme).assemble("i = 1\n!set i = 2").unwrap_err();
+        assert!(errors[0].message == "symbol 'i' is already defined");
+    }
+
+    #[test]
+    fn test_tass64_directives() {
+        let src = r#"
+                    * = $1000
+            clear   .proc
+                    LDX #0
+            loop    DEX
+                    BNE loop
+                    RTS
+                    .pend
+            data    .block
+            loop    .byte 1
+                    .bend
+                    .logical $2000
+            far     JMP far
+                    .here
+                    .align 4
+                    .null "ok"
+        "#;
+        let program = Assembler::new().dialect(Dialect::Tass64).assemble(src).unwrap_or_else(|e| panic!("{:?}", e));
+        assert!(program.symbols["clear"] == 0x1000 && program.symbols["clear::loop"] == 0x1002);
+        assert!(program.symbols["data"] == 0x1006 && program.symbols["data::loop"] == 0x1006);
+        assert!(program.symbols["far"] == 0x2000);
+        assert!(program.image().unwrap().1 == vec![
+            LDX_A2, 0, DEX_CA, BNE_D0, 0xfd, RTS_60, 1, JMP_4C, 0x00, 0x20, 0, 0, b'o', b'k', 0,
+        ]);
+
+        let errors = Assembler::new().dialect(Dialect::Tass64).assemble("  .foo 1").unwrap_err();
+        assert!(errors[0].message == "unknown directive '.foo'");
+        let errors = Assembler::new().dialect(Dialect::Tass64).assemble("  .logical $2000\n  nop").unwrap_err();
+        assert!(errors[0].message == "missing .here");
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let src = "
+            first:  LDX #2
+            @loop:  DEX
+                    BNE @loop
+            second: LDY #2
+            @loop:  DEY
+                    BNE @loop
+            :       JMP :+
+                    JMP :-
+            :       JMP :--
+        ";
+        let program = Assembler::new().origin(0x1000).assemble(src).unwrap();
+        assert!(program.chunks[0].bytes == vec![
+            LDX_A2, 2, DEX_CA, BNE_D0, 0xfd,
+            LDY_A0, 2, DEY_88, BNE_D0, 0xfd,
+            JMP_4C, 0x10, 0x10,
+            JMP_4C, 0x0a, 0x10,
+            JMP_4C, 0x0a, 0x10,
+        ]);
+        assert!(program.symbols["first@loop"] == 0x1002);
+        assert!(program.symbols["second@loop"] == 0x1007);
+        assert!(program.symbols.keys().all(|name| !name.starts_with(':')));
+
+        assert!(errors_of("  JMP :-") == vec![(1, 7, "there is no such anonymous label".to_string())]);
+        assert!(errors_of(": JMP :++") == vec![(1, 7, "there is no such anonymous label".to_string())]);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = TempDir::new("asm_include");
+        let main = dir.write("main.s", ".include \"defs.s\"\n  LDA #VALUE\n  .incbin \"data.bin\"\n  .include \"bad.s\"\n");
+        dir.write("inc/defs.s", "VALUE = 7\n");
+        dir.write("data.bin", [1, 2, 3]);
+        let bad = dir.write("bad.s", "\n  LDA undefined\n");
+
+        let errors = Assembler::new().include_dir(dir.path().join("inc")).assemble_file(&main).unwrap_err();
+        assert!(errors.len() == 1);
+        assert!(errors[0].file == bad.display().to_string());
+        assert!(errors[0].span.line == 2);
+
+        dir.write("bad.s", "");
+        let program = Assembler::new().include_dir(dir.path().join("inc")).assemble_file(&main).unwrap();
+        assert!(program.chunks[0].bytes == vec![LDA_A9, 7, 1, 2, 3]);
+        assert!(program.files.len() == 3);
+
+        let errors = Assembler::new().assemble_file(&main).unwrap_err();
+        assert!(errors[0].message.starts_with(&dir.path().join("defs.s").display().to_string()));
+    }
+
+    #[test]
+    fn test_listing() {
+        let src = ".macro twice\n  INX\n  INX\n.endmacro\n  .org $C000\nstart: LDA $0200,X\n  twice\n  .byte 1, 2, 3, 4\n  BNE start";
+        let program = Assembler::new().assemble(src).unwrap();
+        let listing = program.listing();
+        let listing: Vec<&str> = listing.lines().collect();
+        assert!(listing[4..] == [
+            "                       .org $C000",
+            "C000  BD 00 02  4*   start: LDA $0200,X",
+            "C003  E8 E8            twice",
+            "C005  01 02 03         .byte 1, 2, 3, 4",
+            "C008  04",
+            "C009  D0 F5     2**    BNE start",
+        ], "{:#?}", listing);
+    }
+
+    #[test]
+    fn test_disassembly_roundtrip() {
+        let mut image = vec![0u8; 0x100];
+        let prog = [
+            LDX_A2, 0x05, LDA_BD, 0x00, 0x03, STA_95, 0x10, DEX_CA, BNE_D0, 0xf8,
+            JSR_20, 0x12, 0xff, JMP_4C, 0x0a, 0xff, 0x12, 0x34, RTS_60,
+            LDA_AD, 0x44, 0x00, LDA_AD, 0x0b, 0xff,
+        ];
+        image[..prog.len()].copy_from_slice(&prog);
+        image[0xfa..].copy_from_slice(&[0x12, 0xff, 0x00, 0xff, 0x12, 0xff]);
+
+        let src = disassemble_source(&image, 0xff00, &[0xff13, 0xff16]);
+        let program = Assembler::new().assemble(&src).unwrap();
+        assert!(program.image() == Some((0xff00, image)), "{}", src);
+    }
+
+    #[test]
+    fn test_image() {
+        let program = Assembler::new().origin(0xc000).assemble("start: LDA #$10\n STA $0200").unwrap();
+        assert!(program.image() == Some((0xc000, vec![LDA_A9, 0x10, STA_8D, 0x00, 0x02])));
+        assert!(program.symbols["start"] == 0xc000);
+    }
+
+    #[test]
+    fn test_relocatable() {
+        let src = "
+            .import far
+            .importzp ptr
+            .export start, SIZE, table
+            SIZE = end - start
+            start:  LDA ptr
+                    STA $10
+                    JMP loop
+            loop:   LDA #>table
+                    BNE loop
+                    JSR far+2
+            end:
+            .zeropage
+            temp:   .res 1
+            .rodata
+            table:  .word start, far
+                    .byte temp, <far
+        ";
+        let program = Assembler::new().relocatable().assemble(src).unwrap();
+        let object = program.object.unwrap();
+        let segment = |name: &str| object.segments.iter().find(|s| s.name == name).map(|s| s.bytes.clone());
+        assert!(segment("CODE") == Some(vec![LDA_A5, 0x00, STA_85, 0x10, JMP_4C, 0x07, 0x00, LDA_A9, 0x00, BNE_D0, 0xFC, JSR_20, 0x02, 0x00]));
+        assert!(segment("ZEROPAGE") == Some(vec![0]));
+        assert!(segment("RODATA") == Some(vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00]));
+        assert!(object.imports == vec![
+            Import { name: "far".to_string(), zero_page: false },
+            Import { name: "ptr".to_string(), zero_page: true },
+        ]);
+        assert!(object.exports == vec![
+            Export { name: "start".to_string(), segment: Some("CODE".to_string()), value: 0 },
+            Export { name: "SIZE".to_string(), segment: None, value: 14 },
+            Export { name: "table".to_string(), segment: Some("RODATA".to_string()), value: 0 },
+        ]);
+        let reloc = |segment: &str, offset, kind, target: Target, addend| Relocation { segment: segment.to_string(), offset, kind, target, addend };
+        let code = || Target::Segment("CODE".to_string());
+        let far = || Target::Import("far".to_string());
+        assert!(object.relocations == vec![
+            reloc("CODE", 1, RelocKind::Byte, Target::Import("ptr".to_string()), 0),
+            reloc("CODE", 5, RelocKind::Word, code(), 7),
+            reloc("CODE", 8, RelocKind::High, Target::Segment("RODATA".to_string()), 0),
+            reloc("CODE", 12, RelocKind::Word, far(), 2),
+            reloc("RODATA", 0, RelocKind::Word, code(), 0),
+            reloc("RODATA", 2, RelocKind::Word, far(), 0),
+            reloc("RODATA", 4, RelocKind::Byte, Target::Segment("ZEROPAGE".to_string()), 0),
+            reloc("RODATA", 5, RelocKind::Low, far(), 0),
+        ]);
+        assert!(Object::parse(&object.to_string()) == Ok(object));
+
+        let errors = |source: &str| -> Vec<(usize, String)> {
+            let errors = Assembler::new().relocatable().assemble(source).unwrap_err();
+            errors.into_iter().map(|e| (e.span.line, e.message)).collect()
+        };
+        assert!(errors(".org $8000") == vec![(1, ".org is not allowed in a relocatable object".to_string())]);
+        assert!(errors(".import f\nBNE f\nx = f * 2\n.byte f & 1") == vec![
+            (2, "branch target is not in the same segment".to_string()),
+            (3, "expression is not relocatable".to_string()),
+            (4, "expression is not relocatable".to_string()),
+        ]);
+        assert!(errors(".import f\n.export f, g") == vec![(2, "'f' is imported".to_string()), (2, "exported symbol 'g' is not defined".to_string())]);
+        assert!(errors_of(".import f") == vec![(1, 1, ".import needs a relocatable object".to_string())]);
+    }
+}
@@ -0,0 +1,244 @@
+// Relocatable objects
+//
+// An object is written by `asm -c` and read by the linker (see src/link). It is a text file,
+// one item per line, the numbers are hex with a '$' (the addends may be negative):
+//
+//   mos6502 object 1                   -- the first line
+//   segment CODE $0005                 -- a segment and its size, the following `bytes`
+//   bytes A9 00 4C 00 00                  lines are its contents (at most 16 bytes per line)
+//   import foo                         -- a symbol defined by another object
+//   importzp ptr                       -- the same, in the zero page
+//   export main CODE $0000             -- a symbol relative to a segment of this object
+//   export SIZE $0010                  -- a constant
+//   reloc CODE $0003 word CODE +$0002  -- the word at CODE+3 is the address of CODE+2
+//   reloc CODE $0001 low foo* +$0000   -- the byte at CODE+1 is the low byte of foo (imports end with '*')
+//
+// The kinds of the relocations are `word`, `byte` (a zero page address), `low` and `high`
+// (<expr and >expr). The relocated bytes contain the value relative to the segment start
+// (zeroes for the imports), the linker overwrites them.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Segment(String),
+    Import(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    Word,
+    Byte,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub segment: String,
+    pub offset: u16,
+    pub kind: RelocKind,
+    pub target: Target,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjSegment {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub name: String,
+    pub zero_page: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub segment: Option<String>, // None for the constants
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Object {
+    pub segments: Vec<ObjSegment>,
+    pub imports: Vec<Import>,
+    pub exports: Vec<Export>,
+    pub relocations: Vec<Relocation>,
+}
+
+const MAGIC: &str = "mos6502 object 1";
+
+impl RelocKind {
+    fn name(self) -> &'static str {
+        match self {
+            RelocKind::Word => "word",
+            RelocKind::Byte => "byte",
+            RelocKind::Low => "low",
+            RelocKind::High => "high",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        if self == RelocKind::Word { 2 } else { 1 }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", MAGIC)?;
+        for segment in &self.segments {
+            writeln!(f, "segment {} ${:04X}", segment.name, segment.bytes.len())?;
+            for row in segment.bytes.chunks(16) {
+                let bytes: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+                writeln!(f, "bytes {}", bytes.join(" "))?;
+            }
+        }
+        for import in &self.imports {
+            writeln!(f, "{} {}", if import.zero_page { "importzp" } else { "import" }, import.name)?;
+        }
+        for export in &self.exports {
+            match &export.segment {
+                Some(segment) => writeln!(f, "export {} {} ${:04X}", export.name, segment, export.value)?,
+                None => writeln!(f, "export {} ${:04X}", export.name, export.value)?,
+            }
+        }
+        for r in &self.relocations {
+            let target = match &r.target {
+                Target::Segment(name) => name.clone(),
+                Target::Import(name) => format!("{}*", name),
+            };
+            let sign = if r.addend < 0 { '-' } else { '+' };
+            writeln!(f, "reloc {} ${:04X} {} {} {}${:04X}", r.segment, r.offset, r.kind.name(), target, sign, r.addend.abs())?;
+        }
+        Ok(())
+    }
+}
+
+fn hex(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let v = i64::from_str_radix(s.strip_prefix('$')?, 16).ok()?;
+    Some(if negative { -v } else { v })
+}
+
+fn word(s: &str) -> Option<u16> {
+    hex(s).and_then(|v| u16::try_from(v).ok())
+}
+
+impl Object {
+    // the errors come with their line (0 for the whole file)
+    pub fn parse(text: &str) -> Result<Object, (usize, String)> {
+        let mut lines = text.lines().enumerate().map(|(idx, l)| (idx + 1, l));
+        if lines.next().map(|(_, l)| l.trim()) != Some(MAGIC) {
+            return Err((1, "not an object file".to_string()));
+        }
+
+        let mut object = Object::default();
+        let mut sizes = Vec::new();
+        for (line, text) in lines {
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            let bad = || (line, format!("bad line '{}'", text.trim()));
+            match fields.as_slice() {
+                [] => {}
+                ["segment", name, size] => {
+                    object.segments.push(ObjSegment { name: name.to_string(), bytes: Vec::new() });
+                    sizes.push((line, word(size).ok_or_else(bad)? as usize));
+                }
+                ["bytes", bytes @ ..] => {
+                    let segment = object.segments.last_mut().ok_or_else(bad)?;
+                    for b in bytes {
+                        segment.bytes.push(u8::from_str_radix(b, 16).map_err(|_| bad())?);
+                    }
+                }
+                ["import", name] | ["importzp", name] => {
+                    object.imports.push(Import { name: name.to_string(), zero_page: fields[0] == "importzp" });
+                }
+                ["export", name, value] => {
+                    object.exports.push(Export { name: name.to_string(), segment: None, value: word(value).ok_or_else(bad)? });
+                }
+                ["export", name, segment, value] => {
+                    let value = word(value).ok_or_else(bad)?;
+                    object.exports.push(Export { name: name.to_string(), segment: Some(segment.to_string()), value });
+                }
+                ["reloc", segment, offset, kind, target, addend] => {
+                    let kind = [RelocKind::Word, RelocKind::Byte, RelocKind::Low, RelocKind::High]
+                        .into_iter()
+                        .find(|k| k.name() == *kind)
+                        .ok_or_else(bad)?;
+                    let target = match target.strip_suffix('*') {
+                        Some(name) => Target::Import(name.to_string()),
+                        None => Target::Segment(target.to_string()),
+                    };
+                    object.relocations.push(Relocation {
+                        segment: segment.to_string(),
+                        offset: word(offset).ok_or_else(bad)?,
+                        kind,
+                        target,
+                        addend: hex(addend).ok_or_else(bad)?,
+                    });
+                }
+                _ => return Err(bad()),
+            }
+        }
+
+        for (segment, (line, size)) in object.segments.iter().zip(sizes) {
+            if segment.bytes.len() != size {
+                return Err((line, format!("segment {} has {} bytes instead of {}", segment.name, segment.bytes.len(), size)));
+            }
+        }
+        for r in &object.relocations {
+            let size = match object.segments.iter().find(|s| s.name == r.segment) {
+                Some(s) => s.bytes.len(),
+                None => return Err((0, format!("relocation in the unknown segment {}", r.segment))),
+            };
+            if r.offset as usize + r.kind.size() > size {
+                return Err((0, format!("relocation at {}+${:04X} is outside of the segment", r.segment, r.offset)));
+            }
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let object = Object {
+            segments: vec![
+                ObjSegment { name: "CODE".to_string(), bytes: (0..20).collect() },
+                ObjSegment { name: "BSS".to_string(), bytes: vec![] },
+            ],
+            imports: vec![Import { name: "foo".to_string(), zero_page: false }, Import { name: "ptr".to_string(), zero_page: true }],
+            exports: vec![
+                Export { name: "main".to_string(), segment: Some("CODE".to_string()), value: 2 },
+                Export { name: "SIZE".to_string(), segment: None, value: 0x10 },
+            ],
+            relocations: vec![
+                Relocation { segment: "CODE".to_string(), offset: 3, kind: RelocKind::Word, target: Target::Segment("CODE".to_string()), addend: 2 },
+                Relocation { segment: "CODE".to_string(), offset: 1, kind: RelocKind::High, target: Target::Import("foo".to_string()), addend: -1 },
+            ],
+        };
+        let text = object.to_string();
+        assert!(text.lines().nth(2) == Some("bytes 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F"));
+        assert!(text.contains("\nreloc CODE $0001 high foo* -$0001\n"));
+        assert!(Object::parse(&text) == Ok(object));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| Object::parse(text).unwrap_err();
+        assert!(error("segment CODE $0001") == (1, "not an object file".to_string()));
+        assert!(error("mos6502 object 1\nsegment CODE $0002\nbytes 01") == (2, "segment CODE has 1 bytes instead of 2".to_string()));
+        assert!(error("mos6502 object 1\nbytes 01") == (2, "bad line 'bytes 01'".to_string()));
+        assert!(error("mos6502 object 1\nexport x $10000") == (2, "bad line 'export x $10000'".to_string()));
+        let text = "mos6502 object 1\nsegment CODE $0001\nbytes 01\nreloc CODE $0000 word CODE +$0000";
+        assert!(error(text) == (0, "relocation at CODE+$0000 is outside of the segment".to_string()));
+    }
+}
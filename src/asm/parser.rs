@@ -0,0 +1,575 @@
+// Parser: turns the tokens of one line into statements
+//
+//   [label:] [mnemonic [operand]]
+//   [label:] .directive [arg, ...]
+//   [label:] macro_name [arg, ...]
+//   name = expr  (or name := expr)
+//
+// A single ':' in the place of the label defines an anonymous label.
+//
+// Operand syntax (see docs.md):
+//   A  #expr  expr  expr,X  expr,Y  (expr)  (expr,X)  (expr),Y
+// In ca65 a:expr and z:expr force the absolute and the zero page addressing.
+
+use super::dialect::Dialect;
+use super::expr::{sign_run, Expr, ExprParser};
+use super::lexer::{tokenize, Tok, Token};
+use super::{AsmError, Span};
+use crate::disasm::OPCODES;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Direct(Expr), // zero page, absolute or relative
+    IndexedX(Expr),
+    IndexedY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+impl Operand {
+    pub fn expr(&self) -> Option<&Expr> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(e) | Operand::Direct(e) | Operand::IndexedX(e) | Operand::IndexedY(e) |
+            Operand::Indirect(e) | Operand::IndirectX(e) | Operand::IndirectY(e) => Some(e),
+        }
+    }
+
+    pub fn expr_mut(&mut self) -> Option<&mut Expr> {
+        match self {
+            Operand::None | Operand::Accumulator => None,
+            Operand::Immediate(e) | Operand::Direct(e) | Operand::IndexedX(e) | Operand::IndexedY(e) |
+            Operand::Indirect(e) | Operand::IndirectX(e) | Operand::IndirectY(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Expr(Expr),
+    Str(String, Span),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Label(String),
+    AnonLabel(String),                // the namespace: ":" or -, +, --, ... (ACME, 64tass)
+    Equate(String, Expr),
+    Instruction(String, Operand),     // the mnemonic is upper case
+    Directive(String, Vec<Arg>),      // the name is lower case, without the '.'
+    Macro(String, Vec<String>),       // .macro name param, ...
+    MacroCall(String, Vec<String>),   // the arguments are kept as text (64tass: .name keeps its '.')
+    Define(String, String),           // ca65: .define name replacement
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub stmt: Stmt,
+    pub span: Span,
+}
+
+pub fn is_mnemonic(name: &str) -> bool {
+    OPCODES.iter().any(|o| o.mnemonic.eq_ignore_ascii_case(name))
+}
+
+fn is_register(t: &Token, reg: &str) -> bool {
+    t.ident().is_some_and(|name| name.eq_ignore_ascii_case(reg))
+}
+
+// the number of the tokens of the label at the beginning of the line (0 if there isn't one)
+fn label_len(tokens: &[Token], dialect: Dialect) -> usize {
+    let prefix = dialect.directive_prefix();
+    match tokens.first().map(|t| &t.tok) {
+        Some(Tok::Punct(':')) if dialect == Dialect::Ca65 => 1,
+        Some(Tok::Punct(_)) if dialect.plain_labels() && !is_macro_call(tokens, dialect) => {
+            sign_run(tokens, 0).map_or(0, |(_, len)| len)
+        }
+        Some(Tok::Ident(name)) if !name.starts_with(prefix) => match tokens.get(1) {
+            Some(_) if is_assignment(tokens) => 0,
+            Some(t) if t.is_punct(':') => 2,
+            Some(t) if t.is_punct('=') => 0,
+            _ if dialect.plain_labels() && !is_mnemonic(name) => 1,
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+// name := expr
+fn is_assignment(tokens: &[Token]) -> bool {
+    match tokens {
+        [_, colon, equals, ..] => colon.is_punct(':') && equals.is_punct('=') && equals.span.column == colon.span.column + 1,
+        _ => false,
+    }
+}
+
+// ACME: +name, 64tass: #name
+fn is_macro_call(tokens: &[Token], dialect: Dialect) -> bool {
+    match (dialect.macro_call_prefix(), tokens) {
+        (Some(prefix), [first, name, ..]) => {
+            first.is_punct(prefix) && name.ident().is_some() && name.span.column == first.span.column + 1
+        }
+        _ => false,
+    }
+}
+
+// The native name of the directive on the line, if there is one.
+// Used for the lines which are skipped (.if) or collected (.macro) without parsing.
+pub fn directive_name(text: &str, line: usize, dialect: Dialect) -> Option<String> {
+    let tokens = tokenize(text, line, dialect).ok()?;
+    let name = tokens.get(label_len(&tokens, dialect))?.ident()?;
+    dialect.directive(name).map(str::to_string)
+}
+
+pub fn parse_line(text: &str, line: usize, dialect: Dialect) -> Result<Vec<Statement>, AsmError> {
+    let tokens = tokenize(text, line, dialect)?;
+    let end = Span::new(line, text.chars().count() + 1, 0);
+    let mut statements = Vec::new();
+
+    // label: / name = expr / : / name, - and + (ACME, 64tass)
+    let pos = label_len(&tokens, dialect);
+    if pos > 0 {
+        let (stmt, span) = match &tokens[0].tok {
+            Tok::Ident(name) => (Stmt::Label(name.clone()), tokens[0].span),
+            Tok::Punct(':') => (Stmt::AnonLabel(":".to_string()), tokens[0].span),
+            Tok::Punct(sign) => (Stmt::AnonLabel(dialect.anonymous(*sign, pos).0), tokens[0].span.to(tokens[pos - 1].span)),
+            _ => unreachable!(),
+        };
+        statements.push(Statement { stmt, span });
+    } else if let (Some(name), Some(next)) = (tokens.first().and_then(Token::ident), tokens.get(1)) {
+        if (next.is_punct('=') || is_assignment(&tokens)) && !name.starts_with(dialect.directive_prefix()) {
+            let first = if next.is_punct('=') { 2 } else { 3 };
+            let mut p = ExprParser::new(&tokens[first..], end).dialect(dialect);
+            let e = p.expr()?;
+            if !p.at_end() {
+                return Err(p.error_here("unexpected token after expression"));
+            }
+            statements.push(Statement { stmt: Stmt::Equate(name.to_string(), e), span: tokens[0].span });
+            return Ok(statements);
+        }
+    }
+
+    if let Some(t) = tokens.get(pos) {
+        let span = tokens[pos..].last().map_or(t.span, |last| t.span.to(last.span));
+        let args = &tokens[pos + 1..];
+        let stmt = match t.ident() {
+            _ if is_macro_call(&tokens[pos..], dialect) => {
+                Stmt::MacroCall(args[0].ident().unwrap().to_string(), macro_args(text, &args[1..]))
+            }
+            // *= addr
+            None if t.is_punct('*') && args.first().is_some_and(|t| t.is_punct('=')) => {
+                let mut p = ExprParser::new(&args[1..], end).dialect(dialect);
+                let e = p.expr()?;
+                if !p.at_end() {
+                    return Err(p.error_here("unexpected token after expression"));
+                }
+                Stmt::Directive("org".to_string(), vec![Arg::Expr(e)])
+            }
+            Some(name) if name.starts_with(dialect.directive_prefix()) => parse_directive(text, t, args, end, dialect)?,
+            Some(name) if is_mnemonic(name) => {
+                Stmt::Instruction(name.to_ascii_uppercase(), parse_operand(args, end, dialect)?)
+            }
+            Some(name) if dialect.macro_call_prefix().is_none() => Stmt::MacroCall(name.to_string(), macro_args(text, args)),
+            Some(name) => return Err(AsmError::new(t.span, &format!("unknown instruction '{}'", name))),
+            None => return Err(AsmError::new(t.span, "expected instruction")),
+        };
+
+        // 64tass: name .proc, the name of the scope of a .block is the label in front of it
+        if let (Dialect::Tass64, Stmt::Directive(name, args)) = (dialect, &stmt) {
+            let label = match statements.last() {
+                Some(Statement { stmt: Stmt::Label(label), span }) if args.is_empty() => Some(Expr::Symbol(label.clone(), *span)),
+                _ => None,
+            };
+            if let (Some(label), "proc" | "scope") = (label, name.as_str()) {
+                if name == "proc" {
+                    statements.pop();
+                }
+                statements.push(Statement { stmt: Stmt::Directive(name.clone(), vec![Arg::Expr(label)]), span });
+                return Ok(statements);
+            }
+        }
+
+        // 64tass: name .macro params
+        if let Stmt::Macro(name, _) = &stmt {
+            if name.is_empty() {
+                match statements.pop() {
+                    Some(Statement { stmt: Stmt::Label(label), span }) => {
+                        let stmt = match stmt {
+                            Stmt::Macro(_, params) => Stmt::Macro(label, params),
+                            _ => unreachable!(),
+                        };
+                        statements.push(Statement { stmt, span });
+                        return Ok(statements);
+                    }
+                    _ => return Err(AsmError::new(t.span, "expected macro name in front of .macro")),
+                }
+            }
+        }
+        statements.push(Statement { stmt, span });
+    }
+
+    Ok(statements)
+}
+
+fn parse_directive(text: &str, t: &Token, tokens: &[Token], end: Span, dialect: Dialect) -> Result<Stmt, AsmError> {
+    let raw = t.ident().unwrap();
+    let name = match dialect.directive(raw) {
+        Some(native) => native.to_string(),
+        // 64tass: .name is a macro call too
+        None if dialect == Dialect::Tass64 => return Ok(Stmt::MacroCall(raw.to_string(), macro_args(text, tokens))),
+        None => return Err(AsmError::new(t.span, &format!("unknown directive '{}'", raw))),
+    };
+
+    if name == "define" {
+        let define = match tokens.first().and_then(Token::ident) {
+            Some(define) => define.to_string(),
+            None => return Err(AsmError::new(tokens.first().map_or(end, |t| t.span), "expected symbol name")),
+        };
+        if let Some(t) = tokens.get(1).filter(|t| t.is_punct('(') && t.span.column == tokens[0].span.column + tokens[0].span.len) {
+            return Err(AsmError::new(t.span, "the parameters of .define are not supported"));
+        }
+        let chars: Vec<char> = text.chars().collect();
+        let replacement = match (tokens.get(1), tokens.last()) {
+            (Some(first), Some(last)) => chars[first.span.column - 1..last.span.column - 1 + last.span.len].iter().collect(),
+            _ => String::new(),
+        };
+        return Ok(Stmt::Define(define, replacement));
+    }
+
+    // ACME: !set name = value
+    if name == "set" {
+        return match tokens {
+            [t, equals, rest @ ..] if t.ident().is_some() && equals.is_punct('=') => {
+                let mut p = ExprParser::new(rest, end).dialect(dialect);
+                let value = p.expr()?;
+                if !p.at_end() {
+                    return Err(p.error_here("unexpected token after expression"));
+                }
+                Ok(Stmt::Directive(name, vec![Arg::Expr(Expr::Symbol(t.ident().unwrap().to_string(), t.span)), Arg::Expr(value)]))
+            }
+            _ => Err(AsmError::new(tokens.first().map_or(end, |t| t.span), "expected name = value")),
+        };
+    }
+
+    if name == "macro" {
+        let mut idents = tokens.iter().filter(|t| !t.is_punct(','));
+        // (in 64tass the name is the label in front of .macro)
+        let macro_name = if dialect == Dialect::Tass64 {
+            String::new()
+        } else {
+            match idents.next().and_then(Token::ident) {
+                Some(n) => n.to_string(),
+                None => return Err(AsmError::new(tokens.first().map_or(end, |t| t.span), "expected macro name")),
+            }
+        };
+        let mut params = Vec::new();
+        for t in idents {
+            match t.ident() {
+                Some(p) => params.push(p.to_string()),
+                None => return Err(AsmError::new(t.span, "expected parameter name")),
+            }
+        }
+        return Ok(Stmt::Macro(macro_name, params));
+    }
+
+    let mut args = Vec::new();
+    let mut p = ExprParser::new(tokens, end).dialect(dialect);
+    while !p.at_end() {
+        match p.peek() {
+            Some(Token { tok: Tok::Str(s), span }) => {
+                p.next();
+                args.push(Arg::Str(s.clone(), *span));
+            }
+            _ => args.push(Arg::Expr(p.expr()?)),
+        }
+        if !p.at_end() {
+            p.expect_punct(',')?;
+            if p.at_end() {
+                return Err(p.error_here("expected expression"));
+            }
+        }
+    }
+
+    Ok(Stmt::Directive(name, args))
+}
+
+// the text of the comma separated arguments (commas inside parentheses don't count)
+fn macro_args(text: &str, tokens: &[Token]) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let slice = |from: &Token, to: &Token| -> String {
+        chars[from.span.column - 1..to.span.column - 1 + to.span.len].iter().collect()
+    };
+
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut first = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        if t.is_punct('(') {
+            depth += 1;
+        } else if t.is_punct(')') {
+            depth -= 1;
+        } else if t.is_punct(',') && depth == 0 {
+            args.push(if i > first { slice(&tokens[first], &tokens[i - 1]) } else { String::new() });
+            first = i + 1;
+        }
+    }
+    if first < tokens.len() {
+        args.push(slice(&tokens[first], &tokens[tokens.len() - 1]));
+    } else if !tokens.is_empty() {
+        args.push(String::new());
+    }
+    args
+}
+
+pub fn parse_operand(tokens: &[Token], end: Span, dialect: Dialect) -> Result<Operand, AsmError> {
+    // ca65: a:expr, z:expr (abs: and zp: too)
+    if let [size, colon, rest @ ..] = tokens {
+        let prefix = size.ident().filter(|_| dialect == Dialect::Ca65 && colon.is_punct(':') && !rest.is_empty());
+        let op = match prefix.map(str::to_ascii_lowercase).as_deref() {
+            Some("a" | "abs") => Some('a'),
+            Some("z" | "zp") => Some('z'),
+            Some("f" | "far") => return Err(AsmError::new(size.span, "far addressing is not supported by the 6502")),
+            _ => None,
+        };
+        if let Some(op) = op {
+            let mut operand = parse_operand(rest, end, dialect)?;
+            match operand.expr_mut() {
+                Some(e) if !matches!(rest[0].tok, Tok::Punct('#')) => *e = Expr::Unary(op, Box::new(e.clone())),
+                _ => return Err(AsmError::new(size.span, "address size prefix without an address")),
+            }
+            return Ok(operand);
+        }
+    }
+
+    let mut p = ExprParser::new(tokens, end).dialect(dialect);
+
+    let operand = match p.peek() {
+        None => return Ok(Operand::None),
+        Some(t) if tokens.len() == 1 && is_register(t, "A") => return Ok(Operand::Accumulator),
+        Some(t) if t.is_punct('#') => {
+            p.next();
+            Operand::Immediate(p.expr()?)
+        }
+        Some(t) if t.is_punct('(') => match parse_indirect(tokens, end, dialect)? {
+            Some(operand) => return Ok(operand),
+            // just an expression in parentheses, like (1 + 2) * 3
+            None => parse_direct(&mut p)?,
+        },
+        Some(_) => parse_direct(&mut p)?,
+    };
+
+    if !p.at_end() {
+        return Err(p.error_here("unexpected token after operand"));
+    }
+    Ok(operand)
+}
+
+fn parse_direct(p: &mut ExprParser) -> Result<Operand, AsmError> {
+    let e = p.expr()?;
+    if p.peek().is_some_and(|t| t.is_punct(',')) {
+        p.next();
+        return match p.next() {
+            Some(t) if is_register(t, "X") => Ok(Operand::IndexedX(e)),
+            Some(t) if is_register(t, "Y") => Ok(Operand::IndexedY(e)),
+            Some(t) => Err(AsmError::new(t.span, "expected X or Y")),
+            None => Err(p.error_here("expected X or Y")),
+        };
+    }
+    Ok(Operand::Direct(e))
+}
+
+// (expr)  (expr,X)  (expr),Y
+// None if the parentheses turned out to be a part of an expression
+fn parse_indirect(tokens: &[Token], end: Span, dialect: Dialect) -> Result<Option<Operand>, AsmError> {
+    let mut p = ExprParser::new(tokens, end).dialect(dialect);
+    p.expect_punct('(')?;
+    let e = p.expr()?;
+
+    if p.peek().is_some_and(|t| t.is_punct(',')) {
+        p.next();
+        match p.next() {
+            Some(t) if is_register(t, "X") => {}
+            _ => return Err(AsmError::new(tokens[p.pos() - 1].span, "expected X")),
+        }
+        p.expect_punct(')')?;
+        if !p.at_end() {
+            return Err(p.error_here("unexpected token after operand"));
+        }
+        return Ok(Some(Operand::IndirectX(e)));
+    }
+
+    p.expect_punct(')')?;
+    if p.at_end() {
+        return Ok(Some(Operand::Indirect(e)));
+    }
+    if p.peek().is_some_and(|t| t.is_punct(',')) {
+        p.next();
+        match p.next() {
+            Some(t) if is_register(t, "Y") => {}
+            _ => return Err(AsmError::new(tokens[p.pos() - 1].span, "expected Y")),
+        }
+        if !p.at_end() {
+            return Err(p.error_here("unexpected token after operand"));
+        }
+        return Ok(Some(Operand::IndirectY(e)));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(text: &str, line: usize) -> Result<Vec<Statement>, AsmError> {
+        super::parse_line(text, line, Dialect::Ca65)
+    }
+
+    fn directive_name(text: &str, line: usize) -> Option<String> {
+        super::directive_name(text, line, Dialect::Ca65)
+    }
+
+    fn stmts_in(dialect: Dialect, text: &str) -> Vec<Stmt> {
+        super::parse_line(text, 1, dialect).unwrap().into_iter().map(|s| s.stmt).collect()
+    }
+
+    fn operand(text: &str) -> Operand {
+        match &parse_line(text, 1).unwrap()[0].stmt {
+            Stmt::Instruction(_, operand) => operand.clone(),
+            _ => panic!("not an instruction: {}", text),
+        }
+    }
+
+    #[test]
+    fn test_operands() {
+        let n = |v| Expr::Number(v);
+
+        assert!(operand("CLC") == Operand::None);
+        assert!(operand("ASL A") == Operand::Accumulator);
+        assert!(operand("LDA #$10") == Operand::Immediate(n(0x10)));
+        assert!(operand("LDA $44") == Operand::Direct(n(0x44)));
+        assert!(operand("STA $0200,X") == Operand::IndexedX(n(0x200)));
+        assert!(operand("LDX $44,y") == Operand::IndexedY(n(0x44)));
+        assert!(operand("JMP ($FFFC)") == Operand::Indirect(n(0xfffc)));
+        assert!(operand("LDA ($44,X)") == Operand::IndirectX(n(0x44)));
+        assert!(operand("ORA ($44),Y") == Operand::IndirectY(n(0x44)));
+        assert!(matches!(operand("LDA (1+2)*3"), Operand::Direct(Expr::Binary("*", _, _, _))));
+        assert!(matches!(operand("LDA (1+2)*3,X"), Operand::IndexedX(_)));
+    }
+
+    #[test]
+    fn test_statements() {
+        let stmts = parse_line("loop: dex ; comment", 4).unwrap();
+        assert!(stmts.len() == 2);
+        assert!(stmts[0].stmt == Stmt::Label("loop".to_string()));
+        assert!(stmts[1].stmt == Stmt::Instruction("DEX".to_string(), Operand::None));
+        assert!(stmts[1].span == Span::new(4, 7, 3));
+
+        let stmts = parse_line("COUNT = 5", 1).unwrap();
+        assert!(stmts[0].stmt == Stmt::Equate("COUNT".to_string(), Expr::Number(5)));
+
+        assert!(parse_line("  ; only comment", 1).unwrap().is_empty());
+
+        let stmts = parse_line(": BNE :-", 1).unwrap();
+        assert!(stmts[0].stmt == Stmt::AnonLabel(":".to_string()));
+        assert!(stmts[1].stmt == Stmt::Instruction("BNE".to_string(), Operand::Direct(Expr::Anon(":".to_string(), -1, Span::new(1, 7, 2)))));
+    }
+
+    #[test]
+    fn test_directives() {
+        let stmts = parse_line("table: .BYTE 1, \"ab\", <table", 1).unwrap();
+        assert!(stmts[0].stmt == Stmt::Label("table".to_string()));
+        match &stmts[1].stmt {
+            Stmt::Directive(name, args) => {
+                assert!(name == "byte");
+                assert!(args.len() == 3);
+                assert!(args[0] == Arg::Expr(Expr::Number(1)));
+                assert!(args[1] == Arg::Str("ab".to_string(), Span::new(1, 17, 4)));
+            }
+            _ => panic!(),
+        }
+
+        let stmts = parse_line(".macro add16 dst, src", 1).unwrap();
+        assert!(stmts[0].stmt == Stmt::Macro("add16".to_string(), vec!["dst".to_string(), "src".to_string()]));
+
+        let stmts = parse_line("  add16 ptr, (1, 2)+1,", 1).unwrap();
+        assert!(stmts[0].stmt == Stmt::MacroCall("add16".to_string(),
+            vec!["ptr".to_string(), "(1, 2)+1".to_string(), String::new()]));
+
+        assert!(directive_name("  .endmacro", 1) == Some("endmacro".to_string()));
+        assert!(directive_name("x: .IF 1", 1) == Some("if".to_string()));
+        assert!(directive_name(": .else", 1) == Some("else".to_string()));
+        assert!(directive_name("  LDA #1", 1).is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        fn _t(text: &str, column: usize, msg: &str) {
+            let err = parse_line(text, 1).unwrap_err();
+            assert!(err.span.column == column, "{}: {:?}", text, err);
+            assert!(err.message == msg, "{}: {:?}", text, err);
+        }
+
+        _t(".byte 1,", 9, "expected expression");
+        _t("  .foo 1", 3, "unknown directive '.foo'");
+        _t(".byte 1 2", 9, "expected ','");
+        _t("LDA ($44,Y)", 10, "expected X");
+        _t("LDA ($44),X", 11, "expected Y");
+        _t("LDA $44,Z", 9, "expected X or Y");
+        _t("LDA #1 2", 8, "unexpected token after operand");
+        _t("LDA #", 6, "expected expression");
+    }
+
+    #[test]
+    fn test_dialects() {
+        let label = |s: &str| Stmt::Label(s.to_string());
+        let ins = |m: &str, o| Stmt::Instruction(m.to_string(), o);
+        let sym = |s: &str, col, len| Expr::Symbol(s.to_string(), Span::new(1, col, len));
+
+        // ACME
+        assert!(stmts_in(Dialect::Acme, "loop dex") == vec![label("loop"), ins("DEX", Operand::None)]);
+        assert!(stmts_in(Dialect::Acme, ".loop") == vec![label("@loop")]);
+        assert!(stmts_in(Dialect::Acme, "-- bne --") == vec![
+            Stmt::AnonLabel("--".to_string()),
+            ins("BNE", Operand::Direct(Expr::Anon("--".to_string(), -1, Span::new(1, 8, 2)))),
+        ]);
+        assert!(stmts_in(Dialect::Acme, "*= $c000") == vec![Stmt::Directive("org".to_string(), vec![Arg::Expr(Expr::Number(0xc000))])]);
+        assert!(stmts_in(Dialect::Acme, "  !BY 1") == vec![Stmt::Directive("byte".to_string(), vec![Arg::Expr(Expr::Number(1))])]);
+        assert!(stmts_in(Dialect::Acme, "  +add .a, 2") == vec![Stmt::MacroCall("add".to_string(), vec![".a".to_string(), "2".to_string()])]);
+        assert!(stmts_in(Dialect::Acme, "!macro add .a, .b") == vec![Stmt::Macro("add".to_string(), vec!["@a".to_string(), "@b".to_string()])]);
+        assert!(stmts_in(Dialect::Acme, "  lda #x AND 3") == vec![
+            ins("LDA", Operand::Immediate(Expr::Binary("&", Box::new(sym("x", 8, 1)), Box::new(Expr::Number(3)), Span::new(1, 10, 3)))),
+        ]);
+
+        // 64tass
+        assert!(stmts_in(Dialect::Tass64, "_x = 1") == vec![Stmt::Equate("@x".to_string(), Expr::Number(1))]);
+        assert!(stmts_in(Dialect::Tass64, "+ jmp -") == vec![
+            Stmt::AnonLabel("+".to_string()),
+            ins("JMP", Operand::Direct(Expr::Anon("-".to_string(), -1, Span::new(1, 7, 1)))),
+        ]);
+        assert!(stmts_in(Dialect::Tass64, "add .macro a, b") == vec![Stmt::Macro("add".to_string(), vec!["a".to_string(), "b".to_string()])]);
+        assert!(stmts_in(Dialect::Tass64, "  #add 1") == vec![Stmt::MacroCall("add".to_string(), vec!["1".to_string()])]);
+        assert!(stmts_in(Dialect::Tass64, "  .add 1") == vec![Stmt::MacroCall(".add".to_string(), vec!["1".to_string()])]);
+        assert!(stmts_in(Dialect::Tass64, "f .proc") == vec![Stmt::Directive("proc".to_string(), vec![Arg::Expr(sym("f", 1, 1))])]);
+        assert!(stmts_in(Dialect::Tass64, "b .block") == vec![label("b"), Stmt::Directive("scope".to_string(), vec![Arg::Expr(sym("b", 1, 1))])]);
+        assert!(stmts_in(Dialect::Acme, "!set i = i + 1")[0] == Stmt::Directive("set".to_string(), vec![
+            Arg::Expr(sym("i", 6, 1)), Arg::Expr(Expr::Binary("+", Box::new(sym("i", 10, 1)), Box::new(Expr::Number(1)), Span::new(1, 12, 1))),
+        ]));
+        assert!(super::directive_name("name .macro", 1, Dialect::Tass64) == Some("macro".to_string()));
+        assert!(super::directive_name("  .elsif x", 1, Dialect::Tass64) == Some("elseif".to_string()));
+        assert!(super::directive_name("- !fill 3", 1, Dialect::Acme) == Some("res".to_string()));
+
+        // ca65 has the mnemonics and macro calls in the same place: no labels without ':'
+        assert!(stmts_in(Dialect::Ca65, "  name 1") == vec![Stmt::MacroCall("name".to_string(), vec!["1".to_string()])]);
+        assert!(super::parse_line("  !byte 1", 1, Dialect::Ca65).is_err());
+        assert!(stmts_in(Dialect::Ca65, "x := 2") == vec![Stmt::Equate("x".to_string(), Expr::Number(2))]);
+        assert!(stmts_in(Dialect::Ca65, ".define ptr ($10),y ; x") == vec![Stmt::Define("ptr".to_string(), "($10),y".to_string())]);
+        assert!(operand("LDA a:$10,X") == Operand::IndexedX(Expr::Unary('a', Box::new(Expr::Number(0x10)))));
+        assert!(operand("STA z:s::ptr") == Operand::Direct(Expr::Unary('z', Box::new(sym("s::ptr", 7, 6)))));
+        assert!(parse_line("LDA f:$10", 1).unwrap_err().message == "far addressing is not supported by the 6502");
+    }
+}
@@ -0,0 +1,144 @@
+// Where the symbols are defined and used in a source file (for the language server)
+//
+// The local labels get the name of their scope as in the assembler (scope@name), the macro
+// names are symbols too. The anonymous labels, the symbols inside the included files and
+// the macro expansions are not followed.
+
+use super::dialect::Dialect;
+use super::lexer::tokenize;
+use super::expr::Expr;
+use super::parser::{is_mnemonic, parse_line, Arg, Stmt};
+use super::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    pub definition: bool,
+}
+
+pub fn references(source: &str, dialect: Dialect) -> Vec<Reference> {
+    let mut refs = Vec::new();
+    let mut scope = String::new();
+    let mut zones = Vec::new();
+
+    for (line, text) in dialect.lines(source) {
+        let mut defined = Vec::new();
+        for st in parse_line(&text, line, dialect).unwrap_or_default() {
+            match st.stmt {
+                Stmt::Label(name) => {
+                    if !name.starts_with('@') && dialect.labels_open_scope() {
+                        scope = name.clone();
+                    }
+                    defined.push(name);
+                }
+                Stmt::Equate(name, _) | Stmt::Macro(name, _) => defined.push(name),
+                Stmt::Directive(name, _) if name == "zone" => {
+                    zones.push(std::mem::replace(&mut scope, format!("__zone{}", zones.len())));
+                }
+                Stmt::Directive(name, args) if name == "proc" => {
+                    if let Some(Arg::Expr(Expr::Symbol(symbol, _))) = args.first() {
+                        zones.push(std::mem::replace(&mut scope, symbol.clone()));
+                        defined.push(symbol.clone());
+                    }
+                }
+                Stmt::Directive(name, _) if name == "scope" => zones.push(scope.clone()),
+                Stmt::Directive(name, _) if ["endzone", "endproc", "endscope"].contains(&name.as_str()) => {
+                    scope = zones.pop().unwrap_or_default()
+                }
+                _ => {}
+            }
+        }
+
+        for t in tokenize(&text, line, dialect).unwrap_or_default() {
+            let name = match t.ident() {
+                Some(name) if !name.starts_with(['.', '!', '\\']) && !is_mnemonic(name) => name,
+                _ => continue,
+            };
+            if ["A", "X", "Y"].iter().any(|r| r.eq_ignore_ascii_case(name)) {
+                continue;
+            }
+            // the first occurrence on the line is the definition
+            let definition = match defined.iter().position(|d| d == name) {
+                Some(idx) => {
+                    defined.remove(idx);
+                    true
+                }
+                None => false,
+            };
+            let name = if name.starts_with('@') { format!("{}{}", scope, name) } else { name.to_string() };
+            refs.push(Reference { name, span: t.span, definition });
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(source: &str, dialect: Dialect) -> Vec<(String, usize, usize, bool)> {
+        references(source, dialect).into_iter().map(|r| (r.name, r.span.line, r.span.column, r.definition)).collect()
+    }
+
+    #[test]
+    fn test_references() {
+        let src = "
+start:  LDX #COUNT
+@loop:  DEX
+        BNE @loop
+        JMP start
+COUNT = 3
+other:  BNE @loop
+.macro inc16 addr
+        INC addr
+.endmacro
+        inc16 COUNT, X
+";
+        assert!(refs(src, Dialect::Ca65) == vec![
+            ("start".to_string(), 2, 1, true),
+            ("COUNT".to_string(), 2, 14, false),
+            ("start@loop".to_string(), 3, 1, true),
+            ("start@loop".to_string(), 4, 13, false),
+            ("start".to_string(), 5, 13, false),
+            ("COUNT".to_string(), 6, 1, true),
+            ("other".to_string(), 7, 1, true),
+            ("other@loop".to_string(), 7, 13, false),
+            ("inc16".to_string(), 8, 8, true),
+            ("addr".to_string(), 8, 14, false),
+            ("addr".to_string(), 9, 13, false),
+            ("inc16".to_string(), 11, 9, false),
+            ("COUNT".to_string(), 11, 15, false),
+        ]);
+    }
+
+    #[test]
+    fn test_dialects() {
+        let src = "!zone {\n.x lda #0\n beq .x\n}\n.x rts";
+        assert!(refs(src, Dialect::Acme) == vec![
+            ("__zone0@x".to_string(), 2, 1, true),
+            ("__zone0@x".to_string(), 3, 6, false),
+            ("@x".to_string(), 5, 1, true),
+        ]);
+        assert!(refs("f nop\n_l nop\n jmp _l", Dialect::Tass64) == vec![
+            ("f".to_string(), 1, 1, true),
+            ("f@l".to_string(), 2, 1, true),
+            ("f@l".to_string(), 3, 6, false),
+        ]);
+        assert!(refs(".proc f
+@l: jmp @l
+.endproc
+@l: rts", Dialect::Ca65) == vec![
+            ("f".to_string(), 1, 7, true),
+            ("f@l".to_string(), 2, 1, true),
+            ("f@l".to_string(), 2, 9, false),
+            ("@l".to_string(), 4, 1, true),
+        ]);
+        assert!(refs("f: .scope s\ng: rts\n.endscope\n@l: rts", Dialect::Ca65) == vec![
+            ("f".to_string(), 1, 1, true),
+            ("s".to_string(), 1, 11, false),
+            ("g".to_string(), 2, 1, true),
+            ("f@l".to_string(), 4, 1, true),
+        ]);
+    }
+}
@@ -0,0 +1,439 @@
+// Control flow graph
+//
+// Basic blocks and functions recovered from a binary image. The code is followed from the
+// hardware vectors and the given entry points as by the recursive disassembler; a block ends
+// at a branch, a jump, RTS/RTI/BRK or before the target of another jump. The entry points and
+// the JSR targets are the functions, the blocks of a function are the ones reachable from its
+// entry without following the calls.
+//
+// Two kinds of jump tables are recognized:
+//   LDA lo,X / STA ptr / LDA hi,X / STA ptr+1 / JMP (ptr)  -- two tables of bytes, or one of
+//                                                             words when hi is lo+1
+//   LDA hi,X / PHA / LDA lo,X / PHA / RTS                   -- the addresses minus one
+// The entries are read until one of them does not point to code. JMP (ptr) with the pointer
+// in the image (and no table) jumps to the address found there.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::cpu::AddressingMode::*;
+use crate::disasm::{decode, format, Instruction, OPCODES, VECTORS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Taken,    // branch taken
+    NotTaken, // branch not taken
+    Next,     // falls into the next block, which starts at a target (jump, branch, call or entry)
+    Jump,
+    Table,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    pub successors: Vec<Edge>,
+    pub calls: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub entry: u16,
+    pub blocks: Vec<u16>, // the starts of the blocks, the entry first
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpTable {
+    pub dispatch: u16, // the JMP (ptr) or the RTS
+    pub table: u16,    // the first byte of the (low byte) table
+    pub targets: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    pub functions: Vec<Function>,
+    pub tables: Vec<JumpTable>,
+}
+
+struct Tracer<'a> {
+    image: &'a [u8],
+    origin: usize,
+    instructions: BTreeMap<u16, Instruction>,
+    leaders: BTreeSet<u16>,
+    entries: Vec<(String, u16)>,
+    tables: Vec<JumpTable>,
+}
+
+impl<'a> Tracer<'a> {
+    fn contains(&self, addr: u16) -> bool {
+        (addr as usize) >= self.origin && (addr as usize) < self.origin + self.image.len()
+    }
+
+    fn byte(&self, addr: u16) -> Option<u8> {
+        self.contains(addr).then(|| self.image[addr as usize - self.origin])
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        Some(self.byte(addr)? as u16 | (self.byte(addr.wrapping_add(1))? as u16) << 8)
+    }
+
+    // a documented instruction at `addr`
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        if !self.contains(addr) {
+            return None;
+        }
+        decode(&self.image[addr as usize - self.origin..]).filter(|ins| !OPCODES[ins.opcode as usize].illegal)
+    }
+
+    fn entry(&mut self, name: String, addr: u16) {
+        if !self.entries.iter().any(|(_, a)| *a == addr) {
+            self.entries.push((name, addr));
+        }
+    }
+
+    fn trace(&mut self, entry: u16) {
+        let mut work = vec![entry];
+        while let Some(start) = work.pop() {
+            self.leaders.insert(start);
+            let mut pc = start;
+            let mut recent = Vec::new(); // the straight line up to pc, for the jump tables
+            while !self.instructions.contains_key(&pc) {
+                let ins = match self.decode(pc) {
+                    Some(ins) => ins,
+                    None => break,
+                };
+                self.instructions.insert(pc, ins);
+                recent.push((pc, ins));
+                let next = pc.wrapping_add(ins.len as u16);
+                match (ins.mnemonic, ins.mode) {
+                    (_, Relative) => {
+                        let target = ins.branch_target(pc).unwrap();
+                        self.leaders.extend([target, next]);
+                        work.push(target);
+                    }
+                    ("JSR", _) => {
+                        self.entry(format!("L{:04X}", ins.operand), ins.operand);
+                        work.push(ins.operand);
+                    }
+                    ("JMP", Absolute) => {
+                        work.push(ins.operand);
+                        break;
+                    }
+                    ("JMP", _) | ("RTS", _) => {
+                        work.extend(self.table(&recent));
+                        break;
+                    }
+                    ("RTI", _) | ("BRK", _) => break,
+                    _ => {}
+                }
+                pc = next;
+            }
+        }
+    }
+
+    // the targets of the JMP (ptr) or RTS ending `recent`
+    fn table(&mut self, recent: &[(u16, Instruction)]) -> Vec<u16> {
+        let indexed_load = |idx: usize| match recent.get(idx) {
+            Some((_, ins)) if ins.mnemonic == "LDA" && matches!(ins.mode, AbsoluteX | AbsoluteY) => Some(ins.operand),
+            _ => None,
+        };
+        let (dispatch, last) = recent[recent.len() - 1];
+        let n = recent.len();
+
+        let found = if last.mnemonic == "JMP" {
+            let ptr = last.operand;
+            // the table feeding STA ptr / STA ptr+1
+            let store = |target: u16| {
+                (1..n).rev().find(|&i| {
+                    let ins = &recent[i].1;
+                    ins.mnemonic == "STA" && matches!(ins.mode, Absolute | ZeroPage) && ins.operand == target
+                })
+                .and_then(|i| indexed_load(i - 1))
+            };
+            match (store(ptr), store(ptr.wrapping_add(1))) {
+                (Some(lo), Some(hi)) => Some((lo, hi, 0)),
+                _ => {
+                    let target = self.word(ptr).filter(|t| self.decode(*t).is_some());
+                    return target.into_iter().collect();
+                }
+            }
+        } else if n >= 5 && recent[n - 2].1.mnemonic == "PHA" && recent[n - 4].1.mnemonic == "PHA" {
+            indexed_load(n - 3).zip(indexed_load(n - 5)).map(|(lo, hi)| (lo, hi, 1))
+        } else {
+            None
+        };
+
+        let (lo, hi, add) = match found {
+            Some(t) => t,
+            None => return Vec::new(),
+        };
+        let step = if hi == lo.wrapping_add(1) { 2 } else { 1 };
+        let mut targets = Vec::new();
+        for i in 0..0x100u16 {
+            let (l, h) = (lo.wrapping_add(i * step), hi.wrapping_add(i * step));
+            // a table of low bytes ends where the one of the high bytes starts
+            if step == 1 && lo < hi && l >= hi {
+                break;
+            }
+            if self.instructions.contains_key(&l) || self.instructions.contains_key(&h) {
+                break;
+            }
+            let target = match (self.byte(l), self.byte(h)) {
+                (Some(l), Some(h)) => (l as u16 | (h as u16) << 8).wrapping_add(add),
+                _ => break,
+            };
+            if self.decode(target).is_none() {
+                break;
+            }
+            targets.push(target);
+        }
+        self.tables.push(JumpTable { dispatch, table: lo, targets: targets.clone() });
+        self.leaders.extend(targets.iter().copied());
+        targets
+    }
+}
+
+impl Cfg {
+    // `origin`: the address of the first byte of `image`
+    pub fn build(image: &[u8], origin: u16, entry_points: &[u16]) -> Cfg {
+        let mut tracer = Tracer {
+            image,
+            origin: origin as usize,
+            instructions: BTreeMap::new(),
+            leaders: BTreeSet::new(),
+            entries: Vec::new(),
+            tables: Vec::new(),
+        };
+        for (vector, name) in VECTORS {
+            if let Some(target) = tracer.word(vector) {
+                tracer.entry(name.to_string(), target);
+            }
+        }
+        for &entry in entry_points {
+            tracer.entry(format!("L{:04X}", entry), entry);
+        }
+        let mut idx = 0;
+        while idx < tracer.entries.len() {
+            tracer.trace(tracer.entries[idx].1);
+            idx += 1;
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in &tracer.leaders {
+            if !tracer.instructions.contains_key(&start) {
+                continue;
+            }
+            let mut block = Block { start, instructions: Vec::new(), successors: Vec::new(), calls: Vec::new() };
+            let mut pc = start;
+            loop {
+                let ins = tracer.instructions[&pc];
+                block.instructions.push((pc, ins));
+                let next = pc.wrapping_add(ins.len as u16);
+                let edge = |kind, target| Edge { kind, target };
+                match (ins.mnemonic, ins.mode) {
+                    (_, Relative) => {
+                        block.successors = vec![edge(EdgeKind::Taken, ins.branch_target(pc).unwrap()), edge(EdgeKind::NotTaken, next)];
+                        break;
+                    }
+                    ("JMP", Absolute) => {
+                        block.successors = vec![edge(EdgeKind::Jump, ins.operand)];
+                        break;
+                    }
+                    ("JMP", _) | ("RTS", _) => {
+                        if let Some(table) = tracer.tables.iter().find(|t| t.dispatch == pc) {
+                            block.successors = table.targets.iter().map(|t| edge(EdgeKind::Table, *t)).collect();
+                        } else if let Some(target) = tracer.word(ins.operand).filter(|_| ins.mnemonic == "JMP") {
+                            if tracer.instructions.contains_key(&target) {
+                                block.successors = vec![edge(EdgeKind::Jump, target)];
+                            }
+                        }
+                        break;
+                    }
+                    ("RTI", _) | ("BRK", _) => break,
+                    ("JSR", _) => block.calls.push(ins.operand),
+                    _ => {}
+                }
+                if !tracer.instructions.contains_key(&next) {
+                    break;
+                }
+                if tracer.leaders.contains(&next) {
+                    block.successors = vec![edge(EdgeKind::Next, next)];
+                    break;
+                }
+                pc = next;
+            }
+            blocks.insert(start, block);
+        }
+
+        let mut functions = Vec::new();
+        for (name, entry) in tracer.entries {
+            if !blocks.contains_key(&entry) {
+                continue;
+            }
+            let mut reached = vec![entry];
+            let mut idx = 0;
+            while idx < reached.len() {
+                for edge in &blocks[&reached[idx]].successors {
+                    if blocks.contains_key(&edge.target) && !reached.contains(&edge.target) {
+                        reached.push(edge.target);
+                    }
+                }
+                idx += 1;
+            }
+            reached[1..].sort();
+            functions.push(Function { name, entry, blocks: reached });
+        }
+
+        Cfg { blocks, functions, tables: tracer.tables }
+    }
+
+    fn names(&self) -> HashMap<u16, String> {
+        self.functions.iter().map(|f| (f.entry, f.name.clone())).collect()
+    }
+
+    // Graphviz source of the blocks of `function`
+    pub fn dot(&self, function: &Function) -> String {
+        let names = self.names();
+        let mut out = format!("digraph \"{}\" {{\n    node [shape=box, fontname=\"monospace\"];\n", function.name);
+        for start in &function.blocks {
+            let block = &self.blocks[start];
+            let mut label = format!("{}:\\l", names.get(start).cloned().unwrap_or(format!("L{:04X}", start)));
+            for (pc, ins) in &block.instructions {
+                label += &format!("{:04X}  {}\\l", pc, format(ins, *pc, Some(&names)));
+            }
+            out += &format!("    L{:04X} [label=\"{}\"];\n", start, label);
+        }
+        for start in &function.blocks {
+            for edge in &self.blocks[start].successors {
+                let style = match edge.kind {
+                    EdgeKind::Taken => " [color=\"darkgreen\"]",
+                    EdgeKind::NotTaken => " [color=\"red\"]",
+                    EdgeKind::Table => " [style=\"dashed\"]",
+                    EdgeKind::Next | EdgeKind::Jump => "",
+                };
+                out += &format!("    L{:04X} -> L{:04X}{};\n", start, edge.target, style);
+            }
+        }
+        out += "}\n";
+        out
+    }
+}
+
+// a summary: the functions with their blocks and calls, the jump tables
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = self.names();
+        let name = |addr: &u16| names.get(addr).cloned().unwrap_or(format!("${:04X}", addr));
+        for function in &self.functions {
+            let plural = if function.blocks.len() > 1 { "s" } else { "" };
+            write!(f, "{} ${:04X}: {} block{}", function.name, function.entry, function.blocks.len(), plural)?;
+            let calls: BTreeSet<u16> = function.blocks.iter().flat_map(|b| self.blocks[b].calls.iter().copied()).collect();
+            if !calls.is_empty() {
+                write!(f, ", calls {}", calls.iter().map(name).collect::<Vec<_>>().join(" "))?;
+            }
+            writeln!(f)?;
+        }
+        for table in &self.tables {
+            let targets: Vec<String> = table.targets.iter().map(|t| format!("${:04X}", t)).collect();
+            writeln!(f, "jump table ${:04X} (dispatch at ${:04X}): {}", table.table, table.dispatch, targets.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn build(source: &str, entries: &[u16]) -> Cfg {
+        let (origin, image) = Assembler::new().assemble(source).unwrap().image().unwrap();
+        Cfg::build(&image, origin, entries)
+    }
+
+    #[test]
+    fn test_blocks() {
+        let src = "
+        .org $8000
+reset:  LDX #0
+loop:   JSR sub
+        DEX
+        BNE loop
+        JMP reset
+sub:    LDA #1
+        RTS
+        .org $FFFC
+        .word reset
+";
+        let cfg = build(src, &[]);
+        // start, instructions, successors
+        type Summary = (u16, usize, Vec<(EdgeKind, u16)>);
+        let blocks: Vec<Summary> = cfg.blocks.values()
+            .map(|b| (b.start, b.instructions.len(), b.successors.iter().map(|e| (e.kind, e.target)).collect()))
+            .collect();
+        assert!(blocks == vec![
+            (0x8000, 1, vec![(EdgeKind::Next, 0x8002)]),
+            (0x8002, 3, vec![(EdgeKind::Taken, 0x8002), (EdgeKind::NotTaken, 0x8008)]),
+            (0x8008, 1, vec![(EdgeKind::Jump, 0x8000)]),
+            (0x800B, 2, vec![]),
+        ]);
+        assert!(cfg.blocks[&0x8002].calls == vec![0x800B]);
+        assert!(cfg.functions.iter().map(|f| (f.name.as_str(), f.blocks.clone())).collect::<Vec<_>>() == vec![
+            ("reset", vec![0x8000, 0x8002, 0x8008]),
+            ("L800B", vec![0x800B]),
+        ]);
+        assert!(cfg.to_string() == "reset $8000: 3 blocks, calls L800B\nL800B $800B: 1 block\n");
+        assert!(cfg.dot(&cfg.functions[1]) == "\
+digraph \"L800B\" {
+    node [shape=box, fontname=\"monospace\"];
+    L800B [label=\"L800B:\\l800B  LDA #$01\\l800D  RTS\\l\"];
+}
+");
+        assert!(cfg.dot(&cfg.functions[0]).contains("    L8002 -> L8002 [color=\"darkgreen\"];\n    L8002 -> L8008 [color=\"red\"];\n"));
+    }
+
+    #[test]
+    fn test_tables() {
+        let src = "
+        .org $8000
+        LDA lo,X
+        STA $10
+        LDA hi,X
+        STA $11
+        JMP ($0010)
+        LDA words+1,Y
+        PHA
+        LDA words,Y
+        PHA
+        RTS
+lo:     .byte <one, <two
+hi:     .byte >one, >two
+words:  .word three-1, one-1
+        .byte $FF, $FF
+one:    RTS
+two:    RTS
+three:  JMP ($FFFC)
+        .org $FFFC
+        .word $8000
+";
+        let cfg = build(src, &[0x800d]);
+        assert!(cfg.tables == vec![
+            JumpTable { dispatch: 0x800a, table: 0x8016, targets: vec![0x8020, 0x8021] },
+            JumpTable { dispatch: 0x8015, table: 0x801a, targets: vec![0x8022, 0x8020] },
+        ]);
+        assert!(cfg.blocks[&0x800d].successors.iter().map(|e| (e.kind, e.target)).collect::<Vec<_>>() == vec![
+            (EdgeKind::Table, 0x8022),
+            (EdgeKind::Table, 0x8020),
+        ]);
+        assert!(cfg.blocks[&0x8022].successors == vec![Edge { kind: EdgeKind::Jump, target: 0x8000 }]);
+        assert!(cfg.functions[0].blocks == vec![0x8000, 0x8020, 0x8021]);
+    }
+}
@@ -0,0 +1,264 @@
+// Coverage
+//
+// The runs of the harness count how many times each byte of an instruction was executed and how
+// many times each branch was taken or not. The Lines of the source come from the line info of the
+// assembler or from a ca65 debug file (dbg.rs), and an Lcov adds up the counts of the lines of
+// several programs into an lcov .info file:
+//
+//   SF:math.s
+//   BRDA:12,0,0,3         (line, block, branch: 0 taken, 1 not taken, count or - if never run)
+//   BRDA:12,0,1,1
+//   BRF:2                 (branches found, and hit)
+//   BRH:2
+//   DA:12,4               (line, count)
+//   LF:20                 (lines found, and hit)
+//   LH:18
+//   end_of_record
+//
+// Only the lines with instructions count. The source passed to Assembler::assemble() has no file
+// name and is left out.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::asm::Program;
+use crate::cpu::AddressingMode::Relative;
+use crate::dbg::DebugInfo;
+use crate::disasm::decode;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    pub executed: BTreeMap<u16, u64>,        // the bytes of the instructions
+    pub branches: BTreeMap<u16, (u64, u64)>, // taken, not taken
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // `taken`: for the branches
+    pub fn record(&mut self, pc: u16, len: u8, taken: Option<bool>) {
+        for i in 0..len as u16 {
+            *self.executed.entry(pc.wrapping_add(i)).or_default() += 1;
+        }
+        if let Some(taken) = taken {
+            let counts = self.branches.entry(pc).or_default();
+            if taken {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (address, count) in &other.executed {
+            *self.executed.entry(*address).or_default() += count;
+        }
+        for (address, (taken, not_taken)) in &other.branches {
+            let counts = self.branches.entry(*address).or_default();
+            counts.0 += taken;
+            counts.1 += not_taken;
+        }
+    }
+}
+
+// a line of source with its instructions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub file: String,
+    pub line: usize,
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub instructions: Vec<usize>, // offsets among the bytes
+}
+
+impl Line {
+    pub fn from_program(program: &Program) -> Vec<Line> {
+        let lines = program.lines.iter().filter(|info| !info.instructions.is_empty() && !program.files[info.file].is_empty());
+        lines
+            .map(|info| Line {
+                file: program.files[info.file].clone(),
+                line: info.line,
+                address: info.address,
+                bytes: info.bytes.clone(),
+                instructions: info.instructions.clone(),
+            })
+            .collect()
+    }
+
+    // the bytes of the code lines from the image, decoded from the start of the lines
+    pub fn from_dbg(info: &DebugInfo, image: &[u8], origin: u16) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for line in info.lines.iter().filter(|line| !line.data && line.size > 0) {
+            let start = match (line.address as usize).checked_sub(origin as usize) {
+                Some(start) if start + line.size <= image.len() => start,
+                _ => continue,
+            };
+            let bytes = image[start..start + line.size].to_vec();
+            let mut instructions = Vec::new();
+            let mut offset = 0;
+            while let Some(ins) = decode(&bytes[offset..]).filter(|_| offset < bytes.len()) {
+                instructions.push(offset);
+                offset += ins.len as usize;
+            }
+            lines.push(Line { file: line.file.clone(), line: line.line, address: line.address, bytes, instructions });
+        }
+        lines
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Counts {
+    hits: u64,
+    branches: Vec<(u64, u64)>,
+}
+
+// the counts by file and line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lcov {
+    files: BTreeMap<String, BTreeMap<usize, Counts>>,
+}
+
+impl Lcov {
+    pub fn new() -> Lcov {
+        Lcov::default()
+    }
+
+    // the coverage of a program with its lines
+    pub fn add(&mut self, lines: &[Line], coverage: &Coverage) {
+        for line in lines {
+            let counts = self.files.entry(line.file.clone()).or_default().entry(line.line).or_default();
+            let mut branches = Vec::new();
+            let mut hits = 0;
+            for &offset in &line.instructions {
+                let address = line.address.wrapping_add(offset as u16);
+                hits = hits.max(coverage.executed.get(&address).copied().unwrap_or(0));
+                if decode(&line.bytes[offset..]).is_some_and(|ins| ins.mode == Relative) {
+                    branches.push(coverage.branches.get(&address).copied().unwrap_or_default());
+                }
+            }
+            counts.hits += hits;
+            // the same line in several programs, or a line with several spans
+            if counts.branches.len() < branches.len() {
+                counts.branches.resize(branches.len(), (0, 0));
+            }
+            for (total, (taken, not_taken)) in counts.branches.iter_mut().zip(branches) {
+                total.0 += taken;
+                total.1 += not_taken;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Lcov {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (file, lines) in &self.files {
+            writeln!(f, "SF:{}", file)?;
+            let (mut found, mut hit) = (0, 0);
+            for (line, counts) in lines {
+                for (block, &(taken, not_taken)) in counts.branches.iter().enumerate() {
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        match counts.hits {
+                            0 => writeln!(f, "BRDA:{},{},{},-", line, block, branch)?,
+                            _ => writeln!(f, "BRDA:{},{},{},{}", line, block, branch, count)?,
+                        }
+                        found += 1;
+                        hit += (count > 0) as usize;
+                    }
+                }
+            }
+            writeln!(f, "BRF:{}\nBRH:{}", found, hit)?;
+            for (line, counts) in lines {
+                writeln!(f, "DA:{},{}", line, counts.hits)?;
+            }
+            let hit = lines.values().filter(|counts| counts.hits > 0).count();
+            writeln!(f, "LF:{}\nLH:{}\nend_of_record", lines.len(), hit)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::harness::Harness;
+    use crate::tempdir::TempDir;
+
+    const SOURCE: &str = "\
+        .org $8000
+abs:    CMP #0
+        BPL done
+neg:    EOR #$FF
+        CLC
+        ADC #1
+done:   RTS
+table:  .byte 1, 2, 3
+";
+
+    #[test]
+    fn test_lcov() {
+        let dir = TempDir::new("coverage");
+        let path = dir.write("abs.s", SOURCE);
+        let program = Assembler::new().assemble_file(&path).unwrap();
+        let (origin, image) = program.image().unwrap();
+        let lines = Line::from_program(&program);
+        assert!(lines.len() == 6 && lines[0].line == 2 && lines[1].instructions == [0]);
+
+        // a taken BPL for 5, and a negative number through neg
+        let mut coverage = Coverage::new();
+        for a in [5, 0xf9] {
+            let outcome = Harness::new(&image, origin).call(program.symbols["abs"]).with_a(a).run().unwrap();
+            assert!(outcome.a == if a == 5 { 5 } else { 7 });
+            coverage.merge(&outcome.coverage);
+        }
+        assert!(coverage.executed.get(&0x8004) == Some(&1) && coverage.executed.get(&0x8000) == Some(&2));
+        assert!(coverage.branches == BTreeMap::from([(0x8002, (1, 1))]));
+        let mut lcov = Lcov::new();
+        lcov.add(&lines, &coverage);
+        let file = path.display();
+        assert!(lcov.to_string() == format!("\
+SF:{file}
+BRDA:3,0,0,1
+BRDA:3,0,1,1
+BRF:2
+BRH:2
+DA:2,2
+DA:3,2
+DA:4,1
+DA:5,1
+DA:6,1
+DA:7,2
+LF:6
+LH:6
+end_of_record
+"));
+    }
+
+    #[test]
+    fn test_dbg() {
+        let image = [0xc9, 0x00, 0x10, 0x01, 0x60, 0x01, 0x02];
+        let info = DebugInfo::parse("\
+file\tid=0,name=\"abs.s\"
+line\tid=0,file=0,line=2,span=0
+line\tid=1,file=0,line=3,span=1
+line\tid=2,file=0,line=4,span=2
+line\tid=3,file=0,line=5,span=3
+seg\tid=0,name=\"CODE\",start=0x8000,size=7
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=2
+span\tid=2,seg=0,start=4,size=1
+span\tid=3,seg=0,start=5,size=2,type=0
+").unwrap();
+        let lines = Line::from_dbg(&info, &image, 0x8000);
+        assert!(lines.len() == 3 && lines[1] == Line { file: "abs.s".to_string(), line: 3, address: 0x8002, bytes: vec![0x10, 0x01], instructions: vec![0] });
+
+        let mut coverage = Coverage::new();
+        coverage.record(0x8000, 2, None);
+        let mut lcov = Lcov::new();
+        lcov.add(&lines, &coverage);
+        assert!(lcov.to_string() == "SF:abs.s\nBRDA:3,0,0,-\nBRDA:3,0,1,-\nBRF:2\nBRH:0\nDA:2,1\nDA:3,0\nDA:4,0\nLF:3\nLH:1\nend_of_record\n");
+    }
+}
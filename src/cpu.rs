@@ -18,7 +18,6 @@
     - tests: make the cpu to start execution from some `org`, not from 0x00.
       it will allow to test the zero_page overlap (addresses 0xff and 0x00).
     - make all memory addressing aux functions return not the reference but address (usize)
-    - add aux function to work with stack (push, push_2b, pop, pop_2b)
     - add tests for CONTROL instructions
     - run test roms for 6502
     - in all the instructions add table of correspondance of addressing mode and pc increment
@@ -29,9 +28,17 @@
 
 const MEM_SZ: usize = 65_536;
 
+// the 2A03 of the NES is an NMOS 6502 without the decimal mode: D is only a flag
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Variant {
+    Nmos,
+    Ricoh2A03,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Cpu {
     halted: bool,
+    variant: Variant,
 
     a: u8,   // accumulator
     x: u8,   // x index register
@@ -44,7 +51,7 @@ pub struct Cpu {
 }
 
 #[allow(non_snake_case)] // ?? FIXME ??
-mod Flags {
+pub(crate) mod Flags {
     #[allow(non_upper_case_globals)]
     pub(crate) const N_Negative: u8 = 0x80;
 
@@ -70,7 +77,7 @@ mod Flags {
     pub(crate) const C_Carry: u8 = 0x01;
 }
 
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum AddressingMode {
     Implied,
     Accumulator,
@@ -87,7 +94,7 @@ pub enum AddressingMode {
     Relative, // ???
 }
 
-mod opcodes {
+pub(crate) mod opcodes {
     // load
     pub const LDA_A9: u8 = 0xA9;
     pub const LDA_AD: u8 = 0xAD;
@@ -330,7 +337,7 @@ mod opcodes {
     pub const JAM_F2: u8 = 0xF2;
 }
 
-fn addressing_mode_pc_advance(mode: AddressingMode) -> u16 {
+pub(crate) fn addressing_mode_pc_advance(mode: AddressingMode) -> u16 {
     // FIXME: change it to indexable table
     match mode {
         AddressingMode::Implied => 0,
@@ -345,7 +352,7 @@ fn addressing_mode_pc_advance(mode: AddressingMode) -> u16 {
         AddressingMode::ZeroPageY => 1,
         AddressingMode::ZeroPageXIndirect => 1,
         AddressingMode::ZeroPageIndirectY => 1,
-        AddressingMode::Relative => todo!(),
+        AddressingMode::Relative => 1,
     }
 }
 
@@ -374,7 +381,7 @@ mod instructions {
         cpu.update_negative(cpu.a & 0x80 != 0);
         cpu.update_zero(cpu.a == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     pub fn ldx(cpu: &mut Cpu, mode: AddressingMode) {
@@ -390,7 +397,7 @@ mod instructions {
         cpu.update_negative(cpu.x & 0x80 != 0);
         cpu.update_zero(cpu.x == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     pub fn ldy(cpu: &mut Cpu, mode: AddressingMode) {
@@ -406,7 +413,7 @@ mod instructions {
         cpu.update_negative(cpu.y & 0x80 != 0);
         cpu.update_zero(cpu.y == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     pub fn sta(cpu: &mut Cpu, mode: AddressingMode) {
@@ -421,7 +428,7 @@ mod instructions {
             _ => unimplemented!("bad addressing mode for the STX instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     pub fn stx(cpu: &mut Cpu, mode: AddressingMode) {
@@ -432,7 +439,7 @@ mod instructions {
             _ => unimplemented!("bad addressing mode for the STX instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     pub fn sty(cpu: &mut Cpu, mode: AddressingMode) {
@@ -443,7 +450,7 @@ mod instructions {
             _ => unimplemented!("bad addressing mode for the STY instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     //
@@ -458,7 +465,7 @@ mod instructions {
         cpu.update_negative(cpu.x & 0x80 != 0);
         cpu.update_zero(cpu.x == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn tay(cpu: &mut Cpu, mode: AddressingMode) {
@@ -470,7 +477,7 @@ mod instructions {
         cpu.update_negative(cpu.y & 0x80 != 0);
         cpu.update_zero(cpu.y == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn tsx(cpu: &mut Cpu, mode: AddressingMode) {
@@ -482,7 +489,7 @@ mod instructions {
         cpu.update_negative(cpu.x & 0x80 != 0);
         cpu.update_zero(cpu.x == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn txa(cpu: &mut Cpu, mode: AddressingMode) {
@@ -494,7 +501,7 @@ mod instructions {
         cpu.update_negative(cpu.a & 0x80 != 0);
         cpu.update_zero(cpu.a == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn txs(cpu: &mut Cpu, mode: AddressingMode) {
@@ -503,7 +510,7 @@ mod instructions {
             _ => unimplemented!("bad addressing mode for the TXS instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn tya(cpu: &mut Cpu, mode: AddressingMode) {
@@ -515,7 +522,7 @@ mod instructions {
         cpu.update_negative(cpu.a & 0x80 != 0);
         cpu.update_zero(cpu.a == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     //
@@ -523,34 +530,28 @@ mod instructions {
     //
     pub fn pha(cpu: &mut Cpu, mode: AddressingMode) {
         match mode {
-            AddressingMode::Implied => {
-                cpu.memory[0x100 + cpu.s as usize] = cpu.a;
-                cpu.s -= 1;
-            }
+            AddressingMode::Implied => cpu.push(cpu.a),
             _ => unimplemented!("bad addressing mode for the PHA instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn php(cpu: &mut Cpu, mode: AddressingMode) {
         match mode {
             AddressingMode::Implied => {
-                cpu.memory[0x100 + cpu.s as usize] = cpu.p;
-                cpu.s -= 1;
+                // the pushed copy has B and the unused bit 5 set
+                cpu.push(cpu.p | 0x30);
             }
             _ => unimplemented!("bad addressing mode for the PHP instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn pla(cpu: &mut Cpu, mode: AddressingMode) {
         match mode {
-            AddressingMode::Implied => {
-                cpu.s += 1;
-                cpu.a = cpu.memory[0x100 + cpu.s as usize];
-            }
+            AddressingMode::Implied => cpu.a = cpu.pull(),
             _ => unimplemented!("bad addressing mode for the PLA instruction"),
         }
 
@@ -558,19 +559,16 @@ mod instructions {
         cpu.update_negative(cpu.a & 0x80 != 0);
         cpu.update_zero(cpu.a == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     pub fn plp(cpu: &mut Cpu, mode: AddressingMode) {
         match mode {
-            AddressingMode::Implied => {
-                cpu.s += 1;
-                cpu.p = cpu.memory[0x100 + cpu.s as usize];
-            }
+            AddressingMode::Implied => cpu.p = cpu.pull(),
             _ => unimplemented!("bad addressing mode for the PLP instruction"),
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode); // UNNECESSARY: SHOULD ALWAIS BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // UNNECESSARY: SHOULD ALWAIS BE 0
     }
 
     //
@@ -599,7 +597,7 @@ mod instructions {
             cpu.memory[addr] = _asl(cpu, cpu.memory[addr]);
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // SHIFT - LSR - Logic Shift Right
@@ -626,7 +624,7 @@ mod instructions {
             cpu.memory[addr] = _lsr(cpu, cpu.memory[addr]);
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // SHIFT - ROL - Rotate Left
@@ -657,7 +655,7 @@ mod instructions {
             cpu.memory[addr] = _rol(cpu, cpu.memory[addr]);
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // SHIFT - ROR - Rotate Right
@@ -692,7 +690,7 @@ mod instructions {
             cpu.memory[addr] = _ror(cpu, cpu.memory[addr]);
         }
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     //
@@ -719,16 +717,16 @@ mod instructions {
         };
 
         _and(cpu, cpu.memory[addr]);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // LOGIC - BIT - Test Bits in Memory with Accumulator
     pub fn bit(cpu: &mut Cpu, mode: AddressingMode) {
         fn _bit(cpu: &mut Cpu, val: u8) {
-            let r = cpu.a & val;
-            cpu.update_zero(r == 0);
-            cpu.update_overflow(r & 0x40 != 0);
-            cpu.update_negative(r & 0x80 != 0);
+            // N and V are the bits 7 and 6 of the operand, not of the AND
+            cpu.update_zero(cpu.a & val == 0);
+            cpu.update_overflow(val & 0x40 != 0);
+            cpu.update_negative(val & 0x80 != 0);
         }
 
         let addr = match mode {
@@ -738,7 +736,7 @@ mod instructions {
         };
 
         _bit(cpu, cpu.memory[addr]);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // LOGIC - EOR - Test Bits in Memory with Accumulator
@@ -762,7 +760,7 @@ mod instructions {
         };
 
         _eor(cpu, cpu.memory[addr]);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // LOGIC - ORA - "Exclusive OR" Memory with Accumulator
@@ -786,7 +784,7 @@ mod instructions {
         };
 
         _ora(cpu, cpu.memory[addr]);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     //
@@ -794,7 +792,7 @@ mod instructions {
     //
     // ARITH - ADC - Add Memory to Accumulator with Carry
     pub fn adc(cpu: &mut Cpu, mode: AddressingMode) {
-        if cpu.is_decimal() {
+        if cpu.is_decimal() && cpu.variant == Variant::Nmos {
             unimplemented!();
         }
 
@@ -818,13 +816,14 @@ mod instructions {
         };
         let v = cpu.memory[addr];
         let r: u16 = cpu.a as u16 + v as u16 + c;
+        // overflow: both operands have the same sign and the result the other one
+        cpu.update_overflow((cpu.a ^ r as u8) & (v ^ r as u8) & 0x80 != 0);
         cpu.a = r as u8;
-        // TODO: Add overflow flag update
         cpu.update_negative(r & 0x80 != 0);
         cpu.update_zero(r & 0xff == 0);
         cpu.update_carry(r & 0x0100 != 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // ARITH - CMP - Subtract Memory from Accumulator with Borrow
@@ -847,7 +846,7 @@ mod instructions {
         cpu.update_zero(r & 0xff == 0);
         cpu.update_carry(r & 0x0100 != 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // ARITH - CPX - Compare Index Register X To Memory
@@ -865,7 +864,7 @@ mod instructions {
         cpu.update_zero(r & 0xff == 0);
         cpu.update_carry(r & 0x0100 != 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
         // FIXME: 0x30 - 0x40 ? -0x10 ? should the N_Negative flags be set or not?
     }
 
@@ -884,13 +883,13 @@ mod instructions {
         cpu.update_zero(r & 0xff == 0);
         cpu.update_carry(r & 0x0100 != 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
         // FIXME: 0x30 - 0x40 ? -0x10 ? should the N_Negative flags be set or not?
     }
 
     // ARITH - SBC - Add Memory to Accumulator with Carry
     pub fn sbc(cpu: &mut Cpu, mode: AddressingMode) {
-        if cpu.is_decimal() {
+        if cpu.is_decimal() && cpu.variant == Variant::Nmos {
             unimplemented!();
         }
 
@@ -911,16 +910,16 @@ mod instructions {
         } else {
             0
         };
-        let v = cpu.memory[addr];
-        let r: u16 = 0x0100 + cpu.a as u16 - v as u16 - c;
+        // A + !M + C: the carry is the opposite of the borrow
+        let v = !cpu.memory[addr];
+        let r: u16 = cpu.a as u16 + v as u16 + c;
+        cpu.update_overflow((cpu.a ^ r as u8) & (v ^ r as u8) & 0x80 != 0);
         cpu.a = r as u8;
-
-        // TODO: Add overflow flag update
         cpu.update_negative(r & 0x80 != 0);
         cpu.update_zero(r & 0xff == 0);
-        cpu.update_carry(r & 0x0100 == 0);
+        cpu.update_carry(r & 0x0100 != 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     //
@@ -943,7 +942,7 @@ mod instructions {
         cpu.update_negative(v & 0x80 != 0);
         cpu.update_zero(v == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // INCREMENT - DEX - Decrement Index Register X By One
@@ -952,7 +951,7 @@ mod instructions {
         cpu.x = cpu.x.wrapping_sub(1);
         cpu.update_negative(cpu.x & 0x80 != 0);
         cpu.update_zero(cpu.x & 0xff == 0);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // INCREMENT - DEY - Decrement Index Register Y By One
@@ -961,7 +960,7 @@ mod instructions {
         cpu.y = cpu.y.wrapping_sub(1);
         cpu.update_negative(cpu.y & 0x80 != 0);
         cpu.update_zero(cpu.y & 0xff == 0);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // INCREMENT - INC - Increment Memory By One
@@ -981,7 +980,7 @@ mod instructions {
         cpu.update_negative(v & 0x80 != 0);
         cpu.update_zero(v == 0);
 
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // INCREMENT - INX - Increment Index Register X By One
@@ -990,7 +989,7 @@ mod instructions {
         cpu.x = cpu.x.wrapping_add(1);
         cpu.update_negative(cpu.x & 0x80 != 0);
         cpu.update_zero(cpu.x & 0xff == 0);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     // INCREMENT - INY - Increment Index Register Y By One
@@ -999,7 +998,7 @@ mod instructions {
         cpu.y = cpu.y.wrapping_add(1);
         cpu.update_negative(cpu.y & 0x80 != 0);
         cpu.update_zero(cpu.y & 0xff == 0);
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
     }
 
     //
@@ -1007,7 +1006,7 @@ mod instructions {
     //
     pub fn brk(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied, "bad addressing mode for the BRK instruction");
-        cpu.pc += addressing_mode_pc_advance(mode);
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode));
         unimplemented!();
     }
 
@@ -1023,52 +1022,38 @@ mod instructions {
     }
 
     pub fn jsr(cpu: &mut Cpu, mode: AddressingMode) {
-        // TODO: functions for working with stack
         assert!(mode == AddressingMode::Absolute, "bad addressing mode for the JSR instruction");
 
         // store program counter on stack
-        let pc = cpu.pc + 1; // +1 -> the last byte of this 3byte instruction. TODO: pay attention
-        cpu.memory[0x0100 + cpu.s as usize] = (pc >> 8) as u8;
-        cpu.s -= 1;
-        cpu.memory[0x0100 + cpu.s as usize] = pc as u8;
-        cpu.s -= 1;
+        let pc = cpu.pc.wrapping_add(1); // +1 -> the last byte of this 3byte instruction
+        cpu.push((pc >> 8) as u8);
+        cpu.push(pc as u8);
 
         // load new program counter
         cpu.pc = cpu._absolute() as u16;
     }
 
     pub fn rti(cpu: &mut Cpu, mode: AddressingMode) {
-        // TODO: add function to work with the stack
         assert!(mode == AddressingMode::Implied, "bad addressing mode for the RTI instruction");
 
         // restore status flags
-        cpu.p = cpu.memory[0x0100 + cpu.s as usize];
-        cpu.s += 1;
-
-        // restore pc: low byte
-        cpu.pc = cpu.memory[0x0100 + cpu.s as usize] as u16;
-        cpu.s += 1;
-        // restore pc: high byte
-        cpu.pc |= (cpu.memory[0x0100 + cpu.s as usize] as u16) << 8;
-        cpu.s += 1;
+        cpu.p = cpu.pull();
 
-        // here += 1 should be used instead of addressing_mode_pc_advance
-        cpu.pc += 1;
+        // restore pc: low byte, high byte. Unlike RTS, the pushed pc is the return address
+        cpu.pc = cpu.pull() as u16;
+        cpu.pc |= (cpu.pull() as u16) << 8;
     }
 
     pub fn rts(cpu: &mut Cpu, mode: AddressingMode) {
-        // TODO: add function to work with the stack
         assert!(mode == AddressingMode::Implied, "bad addressing mode for the RTS instruction");
 
         // low byte
-        cpu.s += 1;
-        cpu.pc = cpu.memory[0x0100 + cpu.s as usize] as u16;
+        cpu.pc = cpu.pull() as u16;
         // high byte
-        cpu.s += 1;
-        cpu.pc |= (cpu.memory[0x0100 + cpu.s as usize] as u16) << 8;
+        cpu.pc |= (cpu.pull() as u16) << 8;
 
         // here += 1 should be used instead of addressing_mode_pc_advance
-        cpu.pc += 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
     }
 
     //
@@ -1077,81 +1062,49 @@ mod instructions {
     // BRANCH - BCC - Branch Carry Clear
     pub fn bcc(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BCC instruction");
-        if !cpu.is_carry() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(!cpu.is_carry());
     }
 
     // BRANCH - BCS - Branch Carry Set
     pub fn bcs(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BCS instruction");
-        if cpu.is_carry() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(cpu.is_carry());
     }
 
     // BRANCH - BEQ - Branch Equal
     pub fn beq(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BEQ instruction");
-        if cpu.is_zero() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(cpu.is_zero());
     }
 
     // BRANCH - BMI - Branch MInus
     pub fn bmi(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BMI instruction");
-        if cpu.is_negative() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(cpu.is_negative());
     }
 
     // BRANCH - BNE - Branch Not Equal
     pub fn bne(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BNE instruction");
-        if !cpu.is_zero() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(!cpu.is_zero());
     }
 
     // BRANCH - BPL - Branch PLus
     pub fn bpl(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BPL instruction");
-        if !cpu.is_negative() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(!cpu.is_negative());
     }
 
     // BRANCH - BVC - Branch oVerflow Clear
     pub fn bvc(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BVC instruction");
-        if !cpu.is_overflow() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(!cpu.is_overflow());
     }
 
     // BRANCH - BVS - Branch oVerflow Set
     pub fn bvs(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Relative, "bad addressing mode for the BVS instruction");
-        if cpu.is_overflow() {
-            cpu.set_pc_to_current_addr_in_memory();
-        } else {
-            cpu.pc += 2;
-        }
+        cpu.branch(cpu.is_overflow());
     }
 
     //
@@ -1161,49 +1114,49 @@ mod instructions {
     pub fn clc(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_carry(false);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - CLD - Clear Decimal Mode
     pub fn cld(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_decimal(false);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - CLI - Clear Interrupt Disable
     pub fn cli(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_interrupt_disable(false);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - CLV - Clear Overflow Flag
     pub fn clv(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_overflow(false);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - SEC - Set Carry Flag
     pub fn sec(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_carry(true);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - SED - Set Decimal Mode
     pub fn sed(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_decimal(true);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     // FLAGS - SEI - Set Interrupt Disable
     pub fn sei(cpu: &mut Cpu, mode: AddressingMode) {
         assert!(mode == AddressingMode::Implied);
         cpu.update_interrupt_disable(true);
-        cpu.pc += addressing_mode_pc_advance(mode); // SHOULD BE 0
+        cpu.pc = cpu.pc.wrapping_add(addressing_mode_pc_advance(mode)); // SHOULD BE 0
     }
 
     //
@@ -1214,7 +1167,10 @@ mod instructions {
     }
 
     pub fn rra(cpu: &mut Cpu, mode: AddressingMode, _cycles: usize) {
+        // ROR then ADC on the same operand: the pc moves past it once
+        let pc = cpu.pc;
         ror(cpu, mode);
+        cpu.pc = pc;
         adc(cpu, mode);
     }
 
@@ -1233,6 +1189,7 @@ impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
             halted: false,
+            variant: Variant::Nmos,
 
             a: 0,
             x: 0,
@@ -1369,8 +1326,24 @@ impl Cpu {
         }
     }
 
-    fn set_pc_to_current_addr_in_memory(&mut self) {
-        self.pc = self._absolute() as u16;
+    // the offset is signed, from the address of the next instruction
+    fn branch(&mut self, taken: bool) {
+        let offset = self.memory[self._immediate()] as i8;
+        self.pc = self.pc.wrapping_add(1);
+        if taken {
+            self.pc = self.pc.wrapping_add(offset as u16);
+        }
+    }
+
+    // the stack is the page $01, S wraps around it
+    fn push(&mut self, value: u8) {
+        self.memory[0x100 + self.s as usize] = value;
+        self.s = self.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        self.memory[0x100 + self.s as usize]
     }
 
 
@@ -1384,23 +1357,24 @@ impl Cpu {
 
     fn _absolute(&mut self) -> usize {
         let mut addr = self.memory[self.pc as usize] as usize;
-        addr |= (self.memory[self.pc as usize + 1] as usize) << 8;
+        addr |= (self.memory[self.pc.wrapping_add(1) as usize] as usize) << 8;
         addr
     }
 
     fn _absolute_indirect(&mut self) -> usize {
+        // the high byte is read from the same page: JMP ($10FF) reads $10FF and $1000
         let addr = self._absolute();
         let mut addr2 = self.memory[addr] as usize;
-        addr2 |= (self.memory[addr+1] as usize) << 8;
+        addr2 |= (self.memory[addr & 0xff00 | (addr + 1) & 0xff] as usize) << 8;
         addr2
     }
 
     fn _absolute_x(&mut self) -> usize {
-        self._absolute() + self.x as usize
+        (self._absolute() + self.x as usize) & 0xffff
     }
 
     fn _absolute_y(&mut self) -> usize {
-        self._absolute() + self.y as usize
+        (self._absolute() + self.y as usize) & 0xffff
     }
 
     fn _zero_page(&mut self) -> usize {
@@ -1427,7 +1401,7 @@ impl Cpu {
         let mut addr2 = self.memory[addr] as usize; // lo
         addr2 += (self.memory[(addr+1) & 0xff] as usize) << 8; // hi
         addr2 += self.y as usize;
-        addr2
+        addr2 & 0xffff
     }
 
     fn reset(&mut self) {
@@ -1447,9 +1421,9 @@ impl Cpu {
         hi | lo
     }
 
-    fn step(&mut self) {
+    pub(crate) fn step(&mut self) {
         let opcode = self.memory[self.pc as usize];
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
         match opcode {
             //
             // LOAD - LDA
@@ -1814,6 +1788,31 @@ impl Cpu {
     pub fn update_pc(&mut self, new_pc: u16) {
         self.pc = new_pc;
     }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // a, x, y, s, p
+    pub(crate) fn registers(&self) -> (u8, u8, u8, u8, u8) {
+        (self.a, self.x, self.y, self.s, self.p)
+    }
+
+    pub(crate) fn set_registers(&mut self, a: u8, x: u8, y: u8, s: u8, p: u8) {
+        (self.a, self.x, self.y, self.s, self.p) = (a, x, y, s, p);
+    }
+
+    pub(crate) fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub(crate) fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub(crate) fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
 }
 
 #[cfg(test)]
@@ -2611,6 +2610,20 @@ mod tests {
         assert_eq!(cpu.pc, fresh.pc + 1);
     }
 
+    #[test]
+    fn test_rra() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0x10, 0, 0, 0xfd, 0);
+        cpu.patch_memory(0, &[RRA_67, 0x20]);
+        cpu.patch_memory(0x20, &[0x03]);
+        cpu.step();
+
+        // ROR $20 gives $01 and C, then ADC $20 gives $10 + $01 + 1
+        assert!(cpu.memory[0x20] == 0x01);
+        assert!(cpu.a == 0x12 && cpu.p == 0);
+        assert!(cpu.pc == 2);
+    }
+
     //
     // INC
     //
@@ -3189,6 +3202,34 @@ mod tests {
         // FIXME: test for overflows
     }
 
+    #[test]
+    fn test_adc_sbc_overflow() {
+        fn _t(opcode: u8, a: u8, v: u8, carry: bool, exp_a: u8, exp_flags: u8) {
+            let mut cpu = Cpu::new();
+            cpu.set_registers(a, 0, 0, 0xfd, if carry { C_Carry } else { 0 });
+            cpu.patch_memory(0, &[opcode, v]);
+            cpu.step();
+
+            assert!(cpu.a == exp_a, "{:02x} {:02x} {:02x}", opcode, a, v);
+            assert!(cpu.p == exp_flags, "{:02x} {:02x} {:02x}: {:02x}", opcode, a, v, cpu.p);
+        }
+
+        //      opcode   a    v  carry  exp_a  flags
+        _t(ADC_69, 0x50, 0x10, false, 0x60, 0);
+        _t(ADC_69, 0x50, 0x50, false, 0xa0, N_Negative|V_Overflow);
+        _t(ADC_69, 0x50, 0x90, false, 0xe0, N_Negative);
+        _t(ADC_69, 0xd0, 0x90, false, 0x60, V_Overflow|C_Carry);
+        _t(ADC_69, 0x7f, 0x00,  true, 0x80, N_Negative|V_Overflow);
+        // the carry is the opposite of the borrow
+        _t(SBC_E9, 0x50, 0xf0,  true, 0x60, 0);
+        _t(SBC_E9, 0x50, 0xb0,  true, 0xa0, N_Negative|V_Overflow);
+        _t(SBC_E9, 0x50, 0x70,  true, 0xe0, N_Negative);
+        _t(SBC_E9, 0xd0, 0x70,  true, 0x60, V_Overflow|C_Carry);
+        _t(SBC_E9, 0x04, 0x03, false, 0x00, Z_Zero|C_Carry);
+        _t(SBC_E9, 0x00, 0x00, false, 0xff, N_Negative);
+        _t(SBC_E9, 0x80, 0x00, false, 0x7f, V_Overflow|C_Carry);
+    }
+
     #[test]
     fn test_sbc_f9() { // SBC $nnnn,Y
         fn _t(addr: usize, y: u8, addr2: usize, a: u8, v: u8, carry: bool, exp_a: u8, exp_flags: u8) {
@@ -4283,6 +4324,18 @@ mod tests {
         assert!(pushed_value & Flags::I_InterruptDisable != 0);
     }
 
+    #[test]
+    fn test_php_break() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0, 0, 0, 0xfd, C_Carry);
+        cpu.patch_memory(0, &[PHP_08]);
+        cpu.step();
+
+        // B and the bit 5 are set in the pushed copy only
+        assert!(cpu.memory[0x1fd] == 0x30 | C_Carry);
+        assert!(cpu.p == C_Carry);
+    }
+
     #[test]
     fn test_pla() {
         let mut cpu = Cpu::new();
@@ -4353,6 +4406,18 @@ mod tests {
         assert!(cpu.pc == 0x7099);
     }
 
+    #[test]
+    fn test_jmp_6c_page() { // JMP ($nnFF)
+        let mut cpu = Cpu::new();
+        cpu.patch_memory(0, &[JMP_6C, 0xff, 0x40]);
+        cpu.patch_memory(0x4000, &[0x12]);
+        cpu.patch_memory(0x40ff, &[0x99, 0x70]);
+        cpu.step();
+
+        // the high byte is read from $4000, not $4100
+        assert!(cpu.pc == 0x1299);
+    }
+
     #[test]
     fn test_jsr_20() { // JSR $nnnn
         let mut cpu = Cpu::new();
@@ -4450,135 +4515,226 @@ mod tests {
         assert!(cpu.y == 120);
     }
 
+    #[test]
+    fn test_rti_return() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0, 0, 0, 0xfc, 0);
+        cpu.patch_memory(0, &[RTI_40]);
+        cpu.patch_memory(0x1fd, &[C_Carry, 0x34, 0x12]);
+        cpu.step();
+
+        // P, then the pc as pushed (no +1 as for RTS)
+        assert!(cpu.p == C_Carry && cpu.s == 0xff);
+        assert!(cpu.pc == 0x1234);
+    }
+
+    //
+    // WRAP
+    //
+    #[test]
+    fn test_stack_wrap() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0x42, 0, 0, 0x00, 0);
+        cpu.patch_memory(0, &[PHA_48, PLA_68, PLA_68]);
+        cpu.patch_memory(0x100, &[0x99]);
+        cpu.step();
+        assert!(cpu.memory[0x100] == 0x42 && cpu.s == 0xff);
+
+        // back to $0100, then $0101
+        cpu.step();
+        assert!(cpu.a == 0x42 && cpu.s == 0x00);
+        cpu.step();
+        assert!(cpu.a == 0x00 && cpu.s == 0x01);
+    }
+
+    #[test]
+    fn test_address_wrap() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0, 0x20, 0x30, 0xfd, 0);
+        cpu.patch_memory(0, &[LDA_BD, 0xf0, 0xff, LDA_B1, 0x80]);
+        cpu.patch_memory(0x80, &[0xf0, 0xff]);
+        cpu.patch_memory(0x10, &[0x42]);
+        cpu.patch_memory(0x20, &[0x43]);
+
+        // $FFF0 + X and ($80) + Y wrap around to the zero page
+        cpu.step();
+        assert!(cpu.a == 0x42);
+        cpu.step();
+        assert!(cpu.a == 0x43);
+    }
+
+    #[test]
+    fn test_pc_wrap() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0, 0, 0, 0xfd, 0);
+        cpu.patch_memory(0xffff, &[LDA_A9]);
+        cpu.patch_memory(0, &[0x42, NOP_EA]);
+        cpu.update_pc(0xffff);
+
+        // the operand is at $0000
+        cpu.step();
+        assert!(cpu.a == 0x42 && cpu.pc == 0x0001);
+    }
+
     //
     // BRANCH
     //
     #[test]
     fn test_bcc() {
-        let memory: [u8; 3] = [BCC_90, 0x28, 0xA0]; // BCC 0xA028
+        let memory: [u8; 2] = [BCC_90, 0x28]; // BCC +$28
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0xA028);
+        assert!(cpu.pc == 0x022A);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.p |= Flags::C_Carry;
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0202);
     }
 
     #[test]
     fn test_bcs() {
-        let memory: [u8; 3] = [BCS_B0, 0xF0, 0x80]; // BCS 0x80F0
+        let memory: [u8; 2] = [BCS_B0, 0xF0]; // BCS -$10
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0202);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.p |= Flags::C_Carry;
         cpu.step();
-        assert!(cpu.pc == 0x80F0);
+        assert!(cpu.pc == 0x01F2);
     }
 
     #[test]
     fn test_beq() {
-        let memory: [u8; 3] = [BEQ_F0, 0xAA, 0x80];
+        let memory: [u8; 2] = [BEQ_F0, 0x7F]; // BEQ +$7F
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0202);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.p |= Flags::Z_Zero;
         cpu.step();
-        assert!(cpu.pc == 0x80AA);
+        assert!(cpu.pc == 0x0281);
     }
 
     #[test]
     fn test_bmi() {
-        let memory: [u8; 3] = [BMI_30, 0x00, 0x80];
+        let memory: [u8; 2] = [BMI_30, 0x80]; // BMI -$80
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0202);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.p |= Flags::N_Negative;
         cpu.step();
-        assert!(cpu.pc == 0x8000);
+        assert!(cpu.pc == 0x0182);
     }
 
     #[test]
     fn test_bne() {
-        let memory: [u8; 3] = [BNE_D0, 0x00, 0x80];
+        let memory: [u8; 2] = [BNE_D0, 0xFC]; // BNE -$04
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
-        cpu.p |= Flags::Z_Zero;
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x01FE);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
+        cpu.p |= Flags::Z_Zero;
         cpu.step();
-        assert!(cpu.pc == 0x8000);
+        assert!(cpu.pc == 0x0202);
     }
 
     #[test]
     fn test_bpl() {
-        let memory: [u8; 3] = [BPL_10, 0x00, 0x40];
+        let memory: [u8; 2] = [BPL_10, 0x10]; // BPL +$10
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
-        cpu.p |= Flags::N_Negative;
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0212);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
+        cpu.p |= Flags::N_Negative;
         cpu.step();
-        assert!(cpu.pc == 0x4000);
+        assert!(cpu.pc == 0x0202);
     }
 
     #[test]
     fn test_bvc() {
-        let memory: [u8; 3] = [BVC_50, 0x00, 0x40];
+        let memory: [u8; 2] = [BVC_50, 0xFE]; // BVC -$02
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
-        cpu.p |= Flags::V_Overflow;
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0200);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
+        cpu.p |= Flags::V_Overflow;
         cpu.step();
-        assert!(cpu.pc == 0x4000);
+        assert!(cpu.pc == 0x0202);
     }
 
     #[test]
     fn test_bvs() {
-        let memory: [u8; 3] = [BVS_70, 0x00, 0x40];
+        let memory: [u8; 2] = [BVS_70, 0x01]; // BVS +$01
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.step();
-        assert!(cpu.pc == 0x0003);
+        assert!(cpu.pc == 0x0202);
 
         let mut cpu = Cpu::new();
-        cpu.patch_memory(0, &memory);
+        cpu.patch_memory(0x0200, &memory);
+        cpu.update_pc(0x0200);
         cpu.p |= Flags::V_Overflow;
         cpu.step();
-        assert!(cpu.pc == 0x4000);
+        assert!(cpu.pc == 0x0203);
+    }
+
+    #[test]
+    fn test_branch_wraps() {
+        let mut cpu = Cpu::new();
+        cpu.patch_memory(0, &[BNE_D0, 0xF0]); // BNE -$10
+        cpu.step();
+        assert!(cpu.pc == 0xFFF2);
+
+        let mut cpu = Cpu::new();
+        cpu.patch_memory(0xFFF0, &[BNE_D0, 0x7F]); // BNE +$7F
+        cpu.update_pc(0xFFF0);
+        cpu.step();
+        assert!(cpu.pc == 0x0071);
     }
 
     //
@@ -5566,6 +5722,18 @@ mod tests {
         _t(&mem, 0x3a, 0);
     }
 
+    #[test]
+    fn test_bit_operand() {
+        let mut cpu = Cpu::new();
+        cpu.set_registers(0x01, 0, 0, 0xfd, 0);
+        cpu.patch_memory(0, &[BIT_24, 0x10]);
+        cpu.patch_memory(0x10, &[0xc0]);
+        cpu.step();
+
+        // N and V come from the operand even when the AND is 0
+        assert!(cpu.p == N_Negative|V_Overflow|Z_Zero);
+    }
+
     #[test]
     fn test_bit_24() { // BIT $nn
         let mut mem = [0u8; MEM_SZ];
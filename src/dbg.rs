@@ -0,0 +1,166 @@
+// ca65 debug files
+//
+// The debug information written by ld65 (--dbgfile, with ca65 -g), one record per line:
+//
+//   file    id=0,name="main.s",size=1234,mtime=0x5F5E0F00,mod=0
+//   line    id=3,file=0,line=12,span=4
+//   seg     id=0,name="CODE",start=0x008000,size=0x0120,addrsize=absolute,type=ro
+//   span    id=4,seg=0,start=16,size=3
+//   sym     id=2,name="reset",addrsize=absolute,scope=0,def=5,ref=9,val=0x8000,seg=0,type=lab
+//
+// The address of a line is the start of its segment plus the start of its span. The spans of the
+// data directives (.byte, .word, ...) have a type, the ones of the instructions don't. The lines
+// of the macro bodies (type=2) are left out: their bytes belong to the line of the invocation.
+// The symbols are the labels (type=lab), the cheap locals (@loop, with a parent) are left out.
+
+use std::collections::HashMap;
+
+// the fields of a record, by name
+struct Record {
+    kind: String,
+    fields: HashMap<String, String>,
+}
+
+impl Record {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+
+    // a decimal or 0x number
+    fn number(&self, name: &str) -> Option<usize> {
+        let value = self.get(name)?;
+        match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+}
+
+fn records(text: &str) -> Result<Vec<Record>, String> {
+    let mut records = Vec::new();
+    for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let (kind, rest) = line.split_once(char::is_whitespace).ok_or_else(|| format!("line {}: expected fields", n + 1))?;
+        let mut fields = HashMap::new();
+        let mut rest = rest.trim();
+        while !rest.is_empty() {
+            let (name, value) = rest.split_once('=').ok_or_else(|| format!("line {}: expected name=value", n + 1))?;
+            let (value, next) = match value.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"').ok_or_else(|| format!("line {}: unterminated string", n + 1))?;
+                    (&quoted[..end], quoted[end + 1..].strip_prefix(',').unwrap_or(&quoted[end + 1..]))
+                }
+                None => value.split_once(',').unwrap_or((value, "")),
+            };
+            fields.insert(name.to_string(), value.to_string());
+            rest = next;
+        }
+        records.push(Record { kind: kind.to_string(), fields });
+    }
+    Ok(records)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub file: String,
+    pub line: usize,
+    pub address: u16,
+    pub size: usize,
+    pub data: bool, // the bytes of .byte, .word, ...
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub lines: Vec<Line>,
+    pub symbols: Vec<(String, u16)>, // by address
+}
+
+impl DebugInfo {
+    pub fn parse(text: &str) -> Result<DebugInfo, String> {
+        let records = records(text)?;
+        let by_id = |kind: &str| -> HashMap<usize, &Record> {
+            records.iter().filter(|r| r.kind == kind).filter_map(|r| Some((r.number("id")?, r))).collect()
+        };
+        let (files, segs, spans) = (by_id("file"), by_id("seg"), by_id("span"));
+
+        let mut lines = Vec::new();
+        for record in records.iter().filter(|r| r.kind == "line" && r.number("type").unwrap_or(0) != 2) {
+            let what = || format!("line record {}", record.get("id").unwrap_or("?"));
+            let file = record.number("file").and_then(|id| files.get(&id)).and_then(|f| f.get("name"));
+            let file = file.ok_or_else(|| format!("{}: unknown file", what()))?;
+            let number = record.number("line").ok_or_else(|| format!("{}: the line number is missing", what()))?;
+            for id in record.get("span").into_iter().flat_map(|spans| spans.split('+')) {
+                let span = id.parse().ok().and_then(|id: usize| spans.get(&id)).ok_or_else(|| format!("{}: unknown span {}", what(), id))?;
+                let seg = span.number("seg").and_then(|id| segs.get(&id)).ok_or_else(|| format!("{}: unknown segment", what()))?;
+                let address = seg.number("start").unwrap_or(0) + span.number("start").unwrap_or(0);
+                lines.push(Line {
+                    file: file.to_string(),
+                    line: number,
+                    address: address as u16,
+                    size: span.number("size").unwrap_or(0),
+                    data: span.get("type").is_some(),
+                });
+            }
+        }
+        lines.sort_by(|a, b| (&a.file, a.line, a.address).cmp(&(&b.file, b.line, b.address)));
+
+        let mut symbols = Vec::new();
+        for record in records.iter().filter(|r| r.kind == "sym" && r.get("type") == Some("lab") && r.get("parent").is_none()) {
+            let what = || format!("sym record {}", record.get("id").unwrap_or("?"));
+            let name = record.get("name").ok_or_else(|| format!("{}: the name is missing", what()))?;
+            let value = record.number("val").filter(|value| *value <= 0xffff).ok_or_else(|| format!("{}: bad value", what()))?;
+            symbols.push((name.to_string(), value as u16));
+        }
+        symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        Ok(DebugInfo { lines, symbols })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DBG: &str = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=2,lib=0,line=5,mod=1,scope=1,seg=2,span=4,sym=2,type=1
+file\tid=0,name=\"main.s\",size=100,mtime=0x5F5E0F00,mod=0
+file\tid=1,name=\"macros.inc\",size=40,mtime=0x5F5E0F00,mod=0
+line\tid=0,file=0,line=3,span=0
+line\tid=1,file=0,line=4,span=1
+line\tid=2,file=1,line=2,type=2,span=1
+line\tid=3,file=0,line=9,span=2+3
+line\tid=4,file=0,line=1
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x0007,addrsize=absolute,type=ro,oname=\"main.bin\",ooffs=0
+seg\tid=1,name=\"RODATA\",start=0x009000,size=0x0002,addrsize=absolute,type=ro
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=0,start=5,size=2
+span\tid=3,seg=1,start=0,size=2,type=0
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=0,val=0x8000,seg=0,type=lab
+sym\tid=1,name=\"@loop\",addrsize=absolute,scope=0,parent=0,def=1,val=0x8002,seg=0,type=lab
+sym\tid=2,name=\"PPUCTRL\",addrsize=absolute,scope=0,def=2,val=0x2000,type=equ
+sym\tid=3,name=\"table\",addrsize=absolute,scope=0,def=3,val=0x9000,seg=1,type=lab
+";
+
+    #[test]
+    fn test_parse() {
+        let info = DebugInfo::parse(DBG).unwrap();
+        let lines: Vec<(&str, usize, u16, usize, bool)> =
+            info.lines.iter().map(|l| (l.file.as_str(), l.line, l.address, l.size, l.data)).collect();
+        assert!(lines == [
+            ("main.s", 3, 0x8000, 2, false),
+            ("main.s", 4, 0x8002, 3, false),
+            ("main.s", 9, 0x8005, 2, false),
+            ("main.s", 9, 0x9000, 2, true),
+        ], "{:?}", lines);
+        assert!(info.symbols == [("reset".to_string(), 0x8000), ("table".to_string(), 0x9000)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(DebugInfo::parse("line\tid=0,file=5,line=1").unwrap_err() == "line record 0: unknown file");
+        assert!(DebugInfo::parse("file\tid=0,name=\"a.s\"\nline\tid=0,file=0,line=1,span=7").unwrap_err() == "line record 0: unknown span 7");
+        assert!(DebugInfo::parse("file\tid=0,name=\"a.s").unwrap_err() == "line 1: unterminated string");
+        assert!(DebugInfo::parse("version").unwrap_err() == "line 1: expected fields");
+        assert!(DebugInfo::parse("sym\tid=4,name=\"x\",val=0x12345,type=lab").unwrap_err() == "sym record 4: bad value");
+    }
+}
@@ -0,0 +1,969 @@
+// Disassembler
+//
+// The opcode table below is the same information that is spelled out in the
+// comments of Cpu::step(), but for all 256 opcodes (including the undocumented
+// ones, named as on https://www.masswerk.at/6502/6502_instruction_set.html).
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::cpu::{addressing_mode_pc_advance, AddressingMode, Cpu};
+use crate::cpu::AddressingMode::*;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub cycles: u8, // without the page crossing / taken branch penalties; 0 for JAM
+    pub illegal: bool,
+}
+
+impl Opcode {
+    // +1 cycle when the indexed address crosses a page (for the branches: +1 when taken,
+    // one more when the target is on another page). Only the read instructions have it,
+    // they are the ones that are 1 cycle faster than the stores with the same mode.
+    pub fn page_penalty(&self) -> bool {
+        match self.mode {
+            Relative => true,
+            AbsoluteX | AbsoluteY => self.cycles == 4,
+            ZeroPageIndirectY => self.cycles == 5,
+            _ => false,
+        }
+    }
+
+    // 4, 4* or 2** as in the usual opcode tables
+    pub fn cycles_text(&self) -> String {
+        match (self.mode, self.page_penalty()) {
+            (Relative, _) => format!("{}**", self.cycles),
+            (_, true) => format!("{}*", self.cycles),
+            _ => format!("{}", self.cycles),
+        }
+    }
+}
+
+const fn op(mnemonic: &'static str, mode: AddressingMode, cycles: u8) -> Opcode {
+    Opcode { mnemonic, mode, cycles, illegal: false }
+}
+
+const fn ill(mnemonic: &'static str, mode: AddressingMode, cycles: u8) -> Opcode {
+    Opcode { mnemonic, mode, cycles, illegal: true }
+}
+
+pub const OPCODES: [Opcode; 256] = [
+    /* 00 */ op("BRK", Implied, 7),
+    /* 01 */ op("ORA", ZeroPageXIndirect, 6),
+    /* 02 */ ill("JAM", Implied, 0),
+    /* 03 */ ill("SLO", ZeroPageXIndirect, 8),
+    /* 04 */ ill("NOP", ZeroPage, 3),
+    /* 05 */ op("ORA", ZeroPage, 3),
+    /* 06 */ op("ASL", ZeroPage, 5),
+    /* 07 */ ill("SLO", ZeroPage, 5),
+    /* 08 */ op("PHP", Implied, 3),
+    /* 09 */ op("ORA", Immediate, 2),
+    /* 0A */ op("ASL", Accumulator, 2),
+    /* 0B */ ill("ANC", Immediate, 2),
+    /* 0C */ ill("NOP", Absolute, 4),
+    /* 0D */ op("ORA", Absolute, 4),
+    /* 0E */ op("ASL", Absolute, 6),
+    /* 0F */ ill("SLO", Absolute, 6),
+    /* 10 */ op("BPL", Relative, 2),
+    /* 11 */ op("ORA", ZeroPageIndirectY, 5),
+    /* 12 */ ill("JAM", Implied, 0),
+    /* 13 */ ill("SLO", ZeroPageIndirectY, 8),
+    /* 14 */ ill("NOP", ZeroPageX, 4),
+    /* 15 */ op("ORA", ZeroPageX, 4),
+    /* 16 */ op("ASL", ZeroPageX, 6),
+    /* 17 */ ill("SLO", ZeroPageX, 6),
+    /* 18 */ op("CLC", Implied, 2),
+    /* 19 */ op("ORA", AbsoluteY, 4),
+    /* 1A */ ill("NOP", Implied, 2),
+    /* 1B */ ill("SLO", AbsoluteY, 7),
+    /* 1C */ ill("NOP", AbsoluteX, 4),
+    /* 1D */ op("ORA", AbsoluteX, 4),
+    /* 1E */ op("ASL", AbsoluteX, 7),
+    /* 1F */ ill("SLO", AbsoluteX, 7),
+    /* 20 */ op("JSR", Absolute, 6),
+    /* 21 */ op("AND", ZeroPageXIndirect, 6),
+    /* 22 */ ill("JAM", Implied, 0),
+    /* 23 */ ill("RLA", ZeroPageXIndirect, 8),
+    /* 24 */ op("BIT", ZeroPage, 3),
+    /* 25 */ op("AND", ZeroPage, 3),
+    /* 26 */ op("ROL", ZeroPage, 5),
+    /* 27 */ ill("RLA", ZeroPage, 5),
+    /* 28 */ op("PLP", Implied, 4),
+    /* 29 */ op("AND", Immediate, 2),
+    /* 2A */ op("ROL", Accumulator, 2),
+    /* 2B */ ill("ANC", Immediate, 2),
+    /* 2C */ op("BIT", Absolute, 4),
+    /* 2D */ op("AND", Absolute, 4),
+    /* 2E */ op("ROL", Absolute, 6),
+    /* 2F */ ill("RLA", Absolute, 6),
+    /* 30 */ op("BMI", Relative, 2),
+    /* 31 */ op("AND", ZeroPageIndirectY, 5),
+    /* 32 */ ill("JAM", Implied, 0),
+    /* 33 */ ill("RLA", ZeroPageIndirectY, 8),
+    /* 34 */ ill("NOP", ZeroPageX, 4),
+    /* 35 */ op("AND", ZeroPageX, 4),
+    /* 36 */ op("ROL", ZeroPageX, 6),
+    /* 37 */ ill("RLA", ZeroPageX, 6),
+    /* 38 */ op("SEC", Implied, 2),
+    /* 39 */ op("AND", AbsoluteY, 4),
+    /* 3A */ ill("NOP", Implied, 2),
+    /* 3B */ ill("RLA", AbsoluteY, 7),
+    /* 3C */ ill("NOP", AbsoluteX, 4),
+    /* 3D */ op("AND", AbsoluteX, 4),
+    /* 3E */ op("ROL", AbsoluteX, 7),
+    /* 3F */ ill("RLA", AbsoluteX, 7),
+    /* 40 */ op("RTI", Implied, 6),
+    /* 41 */ op("EOR", ZeroPageXIndirect, 6),
+    /* 42 */ ill("JAM", Implied, 0),
+    /* 43 */ ill("SRE", ZeroPageXIndirect, 8),
+    /* 44 */ ill("NOP", ZeroPage, 3),
+    /* 45 */ op("EOR", ZeroPage, 3),
+    /* 46 */ op("LSR", ZeroPage, 5),
+    /* 47 */ ill("SRE", ZeroPage, 5),
+    /* 48 */ op("PHA", Implied, 3),
+    /* 49 */ op("EOR", Immediate, 2),
+    /* 4A */ op("LSR", Accumulator, 2),
+    /* 4B */ ill("ALR", Immediate, 2),
+    /* 4C */ op("JMP", Absolute, 3),
+    /* 4D */ op("EOR", Absolute, 4),
+    /* 4E */ op("LSR", Absolute, 6),
+    /* 4F */ ill("SRE", Absolute, 6),
+    /* 50 */ op("BVC", Relative, 2),
+    /* 51 */ op("EOR", ZeroPageIndirectY, 5),
+    /* 52 */ ill("JAM", Implied, 0),
+    /* 53 */ ill("SRE", ZeroPageIndirectY, 8),
+    /* 54 */ ill("NOP", ZeroPageX, 4),
+    /* 55 */ op("EOR", ZeroPageX, 4),
+    /* 56 */ op("LSR", ZeroPageX, 6),
+    /* 57 */ ill("SRE", ZeroPageX, 6),
+    /* 58 */ op("CLI", Implied, 2),
+    /* 59 */ op("EOR", AbsoluteY, 4),
+    /* 5A */ ill("NOP", Implied, 2),
+    /* 5B */ ill("SRE", AbsoluteY, 7),
+    /* 5C */ ill("NOP", AbsoluteX, 4),
+    /* 5D */ op("EOR", AbsoluteX, 4),
+    /* 5E */ op("LSR", AbsoluteX, 7),
+    /* 5F */ ill("SRE", AbsoluteX, 7),
+    /* 60 */ op("RTS", Implied, 6),
+    /* 61 */ op("ADC", ZeroPageXIndirect, 6),
+    /* 62 */ ill("JAM", Implied, 0),
+    /* 63 */ ill("RRA", ZeroPageXIndirect, 8),
+    /* 64 */ ill("NOP", ZeroPage, 3),
+    /* 65 */ op("ADC", ZeroPage, 3),
+    /* 66 */ op("ROR", ZeroPage, 5),
+    /* 67 */ ill("RRA", ZeroPage, 5),
+    /* 68 */ op("PLA", Implied, 4),
+    /* 69 */ op("ADC", Immediate, 2),
+    /* 6A */ op("ROR", Accumulator, 2),
+    /* 6B */ ill("ARR", Immediate, 2),
+    /* 6C */ op("JMP", AbsoluteIndirect, 5),
+    /* 6D */ op("ADC", Absolute, 4),
+    /* 6E */ op("ROR", Absolute, 6),
+    /* 6F */ ill("RRA", Absolute, 6),
+    /* 70 */ op("BVS", Relative, 2),
+    /* 71 */ op("ADC", ZeroPageIndirectY, 5),
+    /* 72 */ ill("JAM", Implied, 0),
+    /* 73 */ ill("RRA", ZeroPageIndirectY, 8),
+    /* 74 */ ill("NOP", ZeroPageX, 4),
+    /* 75 */ op("ADC", ZeroPageX, 4),
+    /* 76 */ op("ROR", ZeroPageX, 6),
+    /* 77 */ ill("RRA", ZeroPageX, 6),
+    /* 78 */ op("SEI", Implied, 2),
+    /* 79 */ op("ADC", AbsoluteY, 4),
+    /* 7A */ ill("NOP", Implied, 2),
+    /* 7B */ ill("RRA", AbsoluteY, 7),
+    /* 7C */ ill("NOP", AbsoluteX, 4),
+    /* 7D */ op("ADC", AbsoluteX, 4),
+    /* 7E */ op("ROR", AbsoluteX, 7),
+    /* 7F */ ill("RRA", AbsoluteX, 7),
+    /* 80 */ ill("NOP", Immediate, 2),
+    /* 81 */ op("STA", ZeroPageXIndirect, 6),
+    /* 82 */ ill("NOP", Immediate, 2),
+    /* 83 */ ill("SAX", ZeroPageXIndirect, 6),
+    /* 84 */ op("STY", ZeroPage, 3),
+    /* 85 */ op("STA", ZeroPage, 3),
+    /* 86 */ op("STX", ZeroPage, 3),
+    /* 87 */ ill("SAX", ZeroPage, 3),
+    /* 88 */ op("DEY", Implied, 2),
+    /* 89 */ ill("NOP", Immediate, 2),
+    /* 8A */ op("TXA", Implied, 2),
+    /* 8B */ ill("ANE", Immediate, 2),
+    /* 8C */ op("STY", Absolute, 4),
+    /* 8D */ op("STA", Absolute, 4),
+    /* 8E */ op("STX", Absolute, 4),
+    /* 8F */ ill("SAX", Absolute, 4),
+    /* 90 */ op("BCC", Relative, 2),
+    /* 91 */ op("STA", ZeroPageIndirectY, 6),
+    /* 92 */ ill("JAM", Implied, 0),
+    /* 93 */ ill("SHA", ZeroPageIndirectY, 6),
+    /* 94 */ op("STY", ZeroPageX, 4),
+    /* 95 */ op("STA", ZeroPageX, 4),
+    /* 96 */ op("STX", ZeroPageY, 4),
+    /* 97 */ ill("SAX", ZeroPageY, 4),
+    /* 98 */ op("TYA", Implied, 2),
+    /* 99 */ op("STA", AbsoluteY, 5),
+    /* 9A */ op("TXS", Implied, 2),
+    /* 9B */ ill("TAS", AbsoluteY, 5),
+    /* 9C */ ill("SHY", AbsoluteX, 5),
+    /* 9D */ op("STA", AbsoluteX, 5),
+    /* 9E */ ill("SHX", AbsoluteY, 5),
+    /* 9F */ ill("SHA", AbsoluteY, 5),
+    /* A0 */ op("LDY", Immediate, 2),
+    /* A1 */ op("LDA", ZeroPageXIndirect, 6),
+    /* A2 */ op("LDX", Immediate, 2),
+    /* A3 */ ill("LAX", ZeroPageXIndirect, 6),
+    /* A4 */ op("LDY", ZeroPage, 3),
+    /* A5 */ op("LDA", ZeroPage, 3),
+    /* A6 */ op("LDX", ZeroPage, 3),
+    /* A7 */ ill("LAX", ZeroPage, 3),
+    /* A8 */ op("TAY", Implied, 2),
+    /* A9 */ op("LDA", Immediate, 2),
+    /* AA */ op("TAX", Implied, 2),
+    /* AB */ ill("LXA", Immediate, 2),
+    /* AC */ op("LDY", Absolute, 4),
+    /* AD */ op("LDA", Absolute, 4),
+    /* AE */ op("LDX", Absolute, 4),
+    /* AF */ ill("LAX", Absolute, 4),
+    /* B0 */ op("BCS", Relative, 2),
+    /* B1 */ op("LDA", ZeroPageIndirectY, 5),
+    /* B2 */ ill("JAM", Implied, 0),
+    /* B3 */ ill("LAX", ZeroPageIndirectY, 5),
+    /* B4 */ op("LDY", ZeroPageX, 4),
+    /* B5 */ op("LDA", ZeroPageX, 4),
+    /* B6 */ op("LDX", ZeroPageY, 4),
+    /* B7 */ ill("LAX", ZeroPageY, 4),
+    /* B8 */ op("CLV", Implied, 2),
+    /* B9 */ op("LDA", AbsoluteY, 4),
+    /* BA */ op("TSX", Implied, 2),
+    /* BB */ ill("LAS", AbsoluteY, 4),
+    /* BC */ op("LDY", AbsoluteX, 4),
+    /* BD */ op("LDA", AbsoluteX, 4),
+    /* BE */ op("LDX", AbsoluteY, 4),
+    /* BF */ ill("LAX", AbsoluteY, 4),
+    /* C0 */ op("CPY", Immediate, 2),
+    /* C1 */ op("CMP", ZeroPageXIndirect, 6),
+    /* C2 */ ill("NOP", Immediate, 2),
+    /* C3 */ ill("DCP", ZeroPageXIndirect, 8),
+    /* C4 */ op("CPY", ZeroPage, 3),
+    /* C5 */ op("CMP", ZeroPage, 3),
+    /* C6 */ op("DEC", ZeroPage, 5),
+    /* C7 */ ill("DCP", ZeroPage, 5),
+    /* C8 */ op("INY", Implied, 2),
+    /* C9 */ op("CMP", Immediate, 2),
+    /* CA */ op("DEX", Implied, 2),
+    /* CB */ ill("SBX", Immediate, 2),
+    /* CC */ op("CPY", Absolute, 4),
+    /* CD */ op("CMP", Absolute, 4),
+    /* CE */ op("DEC", Absolute, 6),
+    /* CF */ ill("DCP", Absolute, 6),
+    /* D0 */ op("BNE", Relative, 2),
+    /* D1 */ op("CMP", ZeroPageIndirectY, 5),
+    /* D2 */ ill("JAM", Implied, 0),
+    /* D3 */ ill("DCP", ZeroPageIndirectY, 8),
+    /* D4 */ ill("NOP", ZeroPageX, 4),
+    /* D5 */ op("CMP", ZeroPageX, 4),
+    /* D6 */ op("DEC", ZeroPageX, 6),
+    /* D7 */ ill("DCP", ZeroPageX, 6),
+    /* D8 */ op("CLD", Implied, 2),
+    /* D9 */ op("CMP", AbsoluteY, 4),
+    /* DA */ ill("NOP", Implied, 2),
+    /* DB */ ill("DCP", AbsoluteY, 7),
+    /* DC */ ill("NOP", AbsoluteX, 4),
+    /* DD */ op("CMP", AbsoluteX, 4),
+    /* DE */ op("DEC", AbsoluteX, 7),
+    /* DF */ ill("DCP", AbsoluteX, 7),
+    /* E0 */ op("CPX", Immediate, 2),
+    /* E1 */ op("SBC", ZeroPageXIndirect, 6),
+    /* E2 */ ill("NOP", Immediate, 2),
+    /* E3 */ ill("ISC", ZeroPageXIndirect, 8),
+    /* E4 */ op("CPX", ZeroPage, 3),
+    /* E5 */ op("SBC", ZeroPage, 3),
+    /* E6 */ op("INC", ZeroPage, 5),
+    /* E7 */ ill("ISC", ZeroPage, 5),
+    /* E8 */ op("INX", Implied, 2),
+    /* E9 */ op("SBC", Immediate, 2),
+    /* EA */ op("NOP", Implied, 2),
+    /* EB */ ill("USBC", Immediate, 2),
+    /* EC */ op("CPX", Absolute, 4),
+    /* ED */ op("SBC", Absolute, 4),
+    /* EE */ op("INC", Absolute, 6),
+    /* EF */ ill("ISC", Absolute, 6),
+    /* F0 */ op("BEQ", Relative, 2),
+    /* F1 */ op("SBC", ZeroPageIndirectY, 5),
+    /* F2 */ ill("JAM", Implied, 0),
+    /* F3 */ ill("ISC", ZeroPageIndirectY, 8),
+    /* F4 */ ill("NOP", ZeroPageX, 4),
+    /* F5 */ op("SBC", ZeroPageX, 4),
+    /* F6 */ op("INC", ZeroPageX, 6),
+    /* F7 */ ill("ISC", ZeroPageX, 6),
+    /* F8 */ op("SED", Implied, 2),
+    /* F9 */ op("SBC", AbsoluteY, 4),
+    /* FA */ ill("NOP", Implied, 2),
+    /* FB */ ill("ISC", AbsoluteY, 7),
+    /* FC */ ill("NOP", AbsoluteX, 4),
+    /* FD */ op("SBC", AbsoluteX, 4),
+    /* FE */ op("INC", AbsoluteX, 7),
+    /* FF */ ill("ISC", AbsoluteX, 7),
+];
+
+// the instructions which write into their operand
+pub const WRITES: [&str; 20] = [
+    "STA", "STX", "STY", "INC", "DEC", "ASL", "LSR", "ROL", "ROR",
+    "SAX", "SHA", "SHX", "SHY", "TAS", "SLO", "RLA", "SRE", "RRA", "DCP", "ISC",
+];
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub operand: u16, // 0 for the modes without operand, u8 operands are zero-extended
+    pub len: u8,      // opcode + operand bytes
+}
+
+impl Instruction {
+    // Target of a relative branch located at `pc`
+    pub fn branch_target(&self, pc: u16) -> Option<u16> {
+        if self.mode != Relative {
+            return None;
+        }
+        let offset = self.operand as u8 as i8 as i16;
+        Some(pc.wrapping_add(self.len as u16).wrapping_add(offset as u16))
+    }
+}
+
+// What the instructions do, the flags are some of "NVDIZC"
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Mnemonic {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub reads: &'static str,
+    pub writes: &'static str,
+}
+
+const fn mn(name: &'static str, description: &'static str, reads: &'static str, writes: &'static str) -> Mnemonic {
+    Mnemonic { name, description, reads, writes }
+}
+
+pub const MNEMONICS: [Mnemonic; 77] = [
+    mn("ADC", "Add memory to A with carry", "CD", "NVZC"),
+    mn("AND", "AND memory with A", "", "NZ"),
+    mn("ASL", "Shift left one bit", "", "NZC"),
+    mn("BCC", "Branch if carry clear", "C", ""),
+    mn("BCS", "Branch if carry set", "C", ""),
+    mn("BEQ", "Branch if equal (Z set)", "Z", ""),
+    mn("BIT", "Test bits of memory with A (bits 7 and 6 to N and V)", "", "NVZ"),
+    mn("BMI", "Branch if minus (N set)", "N", ""),
+    mn("BNE", "Branch if not equal (Z clear)", "Z", ""),
+    mn("BPL", "Branch if plus (N clear)", "N", ""),
+    mn("BRK", "Force interrupt (pushes PC+2 and P with B set)", "NVDIZC", "I"),
+    mn("BVC", "Branch if overflow clear", "V", ""),
+    mn("BVS", "Branch if overflow set", "V", ""),
+    mn("CLC", "Clear carry", "", "C"),
+    mn("CLD", "Clear decimal mode", "", "D"),
+    mn("CLI", "Clear interrupt disable", "", "I"),
+    mn("CLV", "Clear overflow", "", "V"),
+    mn("CMP", "Compare memory with A", "", "NZC"),
+    mn("CPX", "Compare memory with X", "", "NZC"),
+    mn("CPY", "Compare memory with Y", "", "NZC"),
+    mn("DEC", "Decrement memory by one", "", "NZ"),
+    mn("DEX", "Decrement X by one", "", "NZ"),
+    mn("DEY", "Decrement Y by one", "", "NZ"),
+    mn("EOR", "Exclusive-OR memory with A", "", "NZ"),
+    mn("INC", "Increment memory by one", "", "NZ"),
+    mn("INX", "Increment X by one", "", "NZ"),
+    mn("INY", "Increment Y by one", "", "NZ"),
+    mn("JMP", "Jump to new location", "", ""),
+    mn("JSR", "Jump to subroutine (pushes the return address - 1)", "", ""),
+    mn("LDA", "Load A with memory", "", "NZ"),
+    mn("LDX", "Load X with memory", "", "NZ"),
+    mn("LDY", "Load Y with memory", "", "NZ"),
+    mn("LSR", "Shift right one bit", "", "NZC"),
+    mn("NOP", "No operation", "", ""),
+    mn("ORA", "OR memory with A", "", "NZ"),
+    mn("PHA", "Push A on the stack", "", ""),
+    mn("PHP", "Push the processor status on the stack (with B set)", "NVDIZC", ""),
+    mn("PLA", "Pull A from the stack", "", "NZ"),
+    mn("PLP", "Pull the processor status from the stack", "", "NVDIZC"),
+    mn("ROL", "Rotate one bit left through the carry", "C", "NZC"),
+    mn("ROR", "Rotate one bit right through the carry", "C", "NZC"),
+    mn("RTI", "Return from interrupt (pulls P and PC)", "", "NVDIZC"),
+    mn("RTS", "Return from subroutine", "", ""),
+    mn("SBC", "Subtract memory from A with borrow", "CD", "NVZC"),
+    mn("SEC", "Set carry", "", "C"),
+    mn("SED", "Set decimal mode", "", "D"),
+    mn("SEI", "Set interrupt disable", "", "I"),
+    mn("STA", "Store A in memory", "", ""),
+    mn("STX", "Store X in memory", "", ""),
+    mn("STY", "Store Y in memory", "", ""),
+    mn("TAX", "Transfer A to X", "", "NZ"),
+    mn("TAY", "Transfer A to Y", "", "NZ"),
+    mn("TSX", "Transfer the stack pointer to X", "", "NZ"),
+    mn("TXA", "Transfer X to A", "", "NZ"),
+    mn("TXS", "Transfer X to the stack pointer", "", ""),
+    mn("TYA", "Transfer Y to A", "", "NZ"),
+    // the undocumented ones
+    mn("ALR", "AND immediate with A, then LSR A", "", "NZC"),
+    mn("ANC", "AND immediate with A, bit 7 to carry", "", "NZC"),
+    mn("ANE", "(A OR magic) AND X AND immediate to A (unstable)", "", "NZ"),
+    mn("ARR", "AND immediate with A, then ROR A (C and V from bits 6 and 5)", "CD", "NVZC"),
+    mn("DCP", "DEC memory, then CMP", "", "NZC"),
+    mn("ISC", "INC memory, then SBC", "CD", "NVZC"),
+    mn("JAM", "Halt the processor", "", ""),
+    mn("LAS", "Memory AND S to A, X and S", "", "NZ"),
+    mn("LAX", "Load A and X with memory", "", "NZ"),
+    mn("LXA", "(A OR magic) AND immediate to A and X (unstable)", "", "NZ"),
+    mn("RLA", "ROL memory, then AND", "C", "NZC"),
+    mn("RRA", "ROR memory, then ADC", "CD", "NVZC"),
+    mn("SAX", "Store A AND X in memory", "", ""),
+    mn("SBX", "(A AND X) minus immediate to X (without borrow)", "", "NZC"),
+    mn("SHA", "Store A AND X AND (high byte of the address + 1) (unstable)", "", ""),
+    mn("SHX", "Store X AND (high byte of the address + 1) (unstable)", "", ""),
+    mn("SHY", "Store Y AND (high byte of the address + 1) (unstable)", "", ""),
+    mn("SLO", "ASL memory, then ORA", "", "NZC"),
+    mn("SRE", "LSR memory, then EOR", "", "NZC"),
+    mn("TAS", "A AND X to S, then store S AND (high byte of the address + 1) (unstable)", "", ""),
+    mn("USBC", "SBC immediate (the same as $E9)", "CD", "NVZC"),
+];
+
+pub fn mnemonic(name: &str) -> Option<&'static Mnemonic> {
+    MNEMONICS.iter().find(|m| m.name.eq_ignore_ascii_case(name))
+}
+
+// Opcode for the mnemonic/addressing mode pair, the documented one if there are several
+pub fn encode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+    let matches = |o: &Opcode| o.mnemonic.eq_ignore_ascii_case(mnemonic) && o.mode == mode;
+    let legal = OPCODES.iter().position(|o| !o.illegal && matches(o));
+    let any = || OPCODES.iter().position(matches);
+    legal.or_else(any).map(|idx| idx as u8)
+}
+
+// Decodes the instruction at the beginning of `bytes`.
+// Returns None when `bytes` is too short to hold the whole instruction.
+pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+    let opcode = *bytes.first()?;
+    let info = &OPCODES[opcode as usize];
+    let len = 1 + addressing_mode_pc_advance(info.mode) as usize;
+    if bytes.len() < len {
+        return None;
+    }
+
+    let operand = match len {
+        1 => 0,
+        2 => bytes[1] as u16,
+        _ => bytes[1] as u16 | (bytes[2] as u16) << 8,
+    };
+
+    Some(Instruction {
+        opcode,
+        mnemonic: info.mnemonic,
+        mode: info.mode,
+        operand,
+        len: len as u8,
+    })
+}
+
+// Decodes the instruction at `pc` of the 64K `memory`, with the operand wrapping around to
+// $0000 as the pc of the Cpu does.
+pub fn fetch(memory: &[u8], pc: u16) -> Instruction {
+    let bytes = [0, 1, 2].map(|i| memory[pc.wrapping_add(i) as usize]);
+    decode(&bytes).unwrap()
+}
+
+// Renders the instruction located at `pc` in the syntax described in docs.md:
+//   LDA #$10, STA $0200,X, ORA ($44),Y, BNE $C00E
+// Addresses found in `symbols` are replaced by their names.
+pub fn format(ins: &Instruction, pc: u16, symbols: Option<&HashMap<u16, String>>) -> String {
+    let name = |addr: u16, default: String| -> String {
+        match symbols.and_then(|s| s.get(&addr)) {
+            Some(sym) => sym.clone(),
+            None => default,
+        }
+    };
+    let zp = |addr: u16| name(addr, format!("${:02X}", addr));
+    let abs = |addr: u16| name(addr, format!("${:04X}", addr));
+
+    let operand = match ins.mode {
+        Implied => return ins.mnemonic.to_string(),
+        Accumulator => "A".to_string(),
+        Immediate => format!("#${:02X}", ins.operand),
+        Absolute => abs(ins.operand),
+        AbsoluteX => format!("{},X", abs(ins.operand)),
+        AbsoluteY => format!("{},Y", abs(ins.operand)),
+        AbsoluteIndirect => format!("({})", abs(ins.operand)),
+        ZeroPage => zp(ins.operand),
+        ZeroPageX => format!("{},X", zp(ins.operand)),
+        ZeroPageY => format!("{},Y", zp(ins.operand)),
+        ZeroPageXIndirect => format!("({},X)", zp(ins.operand)),
+        ZeroPageIndirectY => format!("({}),Y", zp(ins.operand)),
+        Relative => abs(ins.branch_target(pc).unwrap()),
+    };
+
+    format!("{} {}", ins.mnemonic, operand)
+}
+
+// Disassembles cpu memory from `start` up to `end` (inclusive), one line per instruction:
+//   C000  A9 10     LDA #$10
+// with a line for the names of the addresses found in `symbols` (loop:).
+pub fn disassemble(cpu: &Cpu, start: u16, end: u16, symbols: Option<&HashMap<u16, String>>) -> Vec<String> {
+    let memory = cpu.memory();
+    let mut lines = Vec::new();
+
+    let mut addr = start as usize;
+    while addr <= end as usize {
+        if let Some(name) = symbols.and_then(|s| s.get(&(addr as u16))) {
+            lines.push(format!("{}:", name));
+        }
+        let line = match decode(&memory[addr..]) {
+            Some(ins) => {
+                let bytes = &memory[addr..addr + ins.len as usize];
+                format!("{:04X}  {:<8}  {}", addr, hex_bytes(bytes), format(&ins, addr as u16, symbols))
+            }
+            // the instruction does not fit into the memory
+            None => format!("{:04X}  {:<8}  .byte ${:02X}", addr, hex_bytes(&memory[addr..addr + 1]), memory[addr]),
+        };
+        lines.push(line);
+        addr += decode(&memory[addr..]).map_or(1, |ins| ins.len as usize);
+    }
+
+    lines
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+//
+// Recursive traversal
+//
+// Starting from the hardware vectors (if the image covers them) and the given entry points,
+// follows JMP/JSR/branches and marks the reachable instructions as code. Everything else is
+// data. The result is a source file that the assembler turns back into the very same image.
+//
+pub const VECTORS: [(u16, &str); 3] = [(0xfffa, "nmi"), (0xfffc, "reset"), (0xfffe, "irq")];
+const BYTES_PER_LINE: usize = 8;
+
+struct CodeMap<'a> {
+    image: &'a [u8],
+    origin: usize,
+    code: Vec<bool>,  // the byte belongs to an instruction
+    start: Vec<bool>, // an instruction starts at the byte
+}
+
+impl<'a> CodeMap<'a> {
+    fn new(image: &'a [u8], origin: u16) -> CodeMap<'a> {
+        assert!(origin as usize + image.len() <= 0x10000, "image does not fit into the memory");
+        CodeMap {
+            image,
+            origin: origin as usize,
+            code: vec![false; image.len()],
+            start: vec![false; image.len()],
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.origin && addr < self.origin + self.image.len()
+    }
+
+    fn is_code(&self, addr: usize) -> bool {
+        self.contains(addr) && self.code[addr - self.origin]
+    }
+
+    fn is_start(&self, addr: usize) -> bool {
+        self.contains(addr) && self.start[addr - self.origin]
+    }
+
+    fn decode(&self, addr: usize) -> Option<Instruction> {
+        decode(&self.image[addr - self.origin..])
+    }
+
+    fn word(&self, addr: usize) -> Option<u16> {
+        if self.contains(addr) && self.contains(addr + 1) {
+            let i = addr - self.origin;
+            Some(self.image[i] as u16 | (self.image[i + 1] as u16) << 8)
+        } else {
+            None
+        }
+    }
+
+    fn trace(&mut self, entry: u16) {
+        let mut work = vec![entry];
+
+        while let Some(pc) = work.pop() {
+            let mut addr = pc as usize;
+            while self.contains(addr) && !self.is_start(addr) {
+                // running into the illegal opcodes most likely means that we are in the data
+                let ins = match self.decode(addr) {
+                    Some(ins) if !OPCODES[ins.opcode as usize].illegal => ins,
+                    _ => break,
+                };
+                let len = ins.len as usize;
+                if (addr..addr + len).any(|a| self.is_code(a)) {
+                    break; // overlaps with some other instruction
+                }
+
+                self.start[addr - self.origin] = true;
+                for a in addr..addr + len {
+                    self.code[a - self.origin] = true;
+                }
+
+                match (ins.mnemonic, ins.mode) {
+                    (_, Relative) => work.push(ins.branch_target(addr as u16).unwrap()),
+                    ("JSR", _) => work.push(ins.operand),
+                    ("JMP", Absolute) => {
+                        work.push(ins.operand);
+                        break;
+                    }
+                    ("JMP", _) | ("RTS", _) | ("RTI", _) | ("BRK", _) => break,
+                    _ => {}
+                }
+                addr += len;
+            }
+        }
+    }
+
+    // the address referenced by the instruction, if any
+    fn reference(&self, ins: &Instruction, pc: u16) -> Option<u16> {
+        match ins.mode {
+            Implied | Accumulator | Immediate => None,
+            Relative => ins.branch_target(pc),
+            _ => Some(ins.operand),
+        }
+    }
+
+    // instructions which can't be written as plain text because the assembler would
+    // pick another encoding for them (e.g. absolute addressing of a zero page address), or
+    // can't reach their operand (a branch whose target wraps around $FFFF)
+    fn needs_bytes(&self, ins: &Instruction, pc: u16) -> bool {
+        let zero_page_mode = match ins.mode {
+            Relative => {
+                let target = pc as i32 + ins.len as i32 + ins.operand as u8 as i8 as i32;
+                return !(0..=0xffff).contains(&target) || encode(ins.mnemonic, ins.mode) != Some(ins.opcode);
+            }
+            Absolute => ZeroPage,
+            AbsoluteX => ZeroPageX,
+            AbsoluteY => ZeroPageY,
+            _ => return encode(ins.mnemonic, ins.mode) != Some(ins.opcode),
+        };
+        encode(ins.mnemonic, ins.mode) != Some(ins.opcode) ||
+            (ins.operand < 0x100 && encode(ins.mnemonic, zero_page_mode).is_some())
+    }
+}
+
+pub fn disassemble_source(image: &[u8], origin: u16, entry_points: &[u16]) -> String {
+    let mut map = CodeMap::new(image, origin);
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+
+    for (vector, name) in VECTORS {
+        if let Some(target) = map.word(vector as usize) {
+            labels.entry(target).or_insert_with(|| name.to_string());
+            map.trace(target);
+        }
+    }
+    for &entry in entry_points {
+        map.trace(entry);
+    }
+
+    // labels for all the jump targets and referenced addresses
+    for entry in entry_points {
+        labels.entry(*entry).or_insert_with(|| format!("L{:04X}", entry));
+    }
+    for addr in map.origin..map.origin + image.len() {
+        if !map.is_start(addr) {
+            continue;
+        }
+        let ins = map.decode(addr).unwrap();
+        if let Some(target) = map.reference(&ins, addr as u16) {
+            labels.entry(target).or_insert_with(|| format!("L{:04X}", target));
+        }
+    }
+
+    // labels which can't be put in front of a line become equates
+    let equates: BTreeSet<u16> = labels.keys()
+        .copied()
+        .filter(|&a| !map.contains(a as usize) || (map.is_code(a as usize) && !map.is_start(a as usize)))
+        .collect();
+    // a zero page label defined later in the source would make the assembler choose
+    // absolute addressing, so these are kept as numbers
+    let symbols: HashMap<u16, String> = labels.iter()
+        .filter(|(a, _)| **a >= 0x100 || equates.contains(a))
+        .map(|(a, l)| (*a, l.clone()))
+        .collect();
+
+    let mut out = String::new();
+    for addr in &equates {
+        out += &format!("{} = ${:04X}\n", labels[addr], addr);
+    }
+    if !equates.is_empty() {
+        out += "\n";
+    }
+    out += &format!("    .org ${:04X}\n", origin);
+
+    let end = map.origin + image.len();
+    let is_vector = |addr: usize| {
+        VECTORS.iter().any(|(v, _)| *v as usize == addr) &&
+            map.word(addr).is_some() &&
+            !map.is_code(addr) && !map.is_code(addr + 1) &&
+            !labels.contains_key(&(addr as u16 + 1))
+    };
+
+    let mut addr = map.origin;
+    while addr < end {
+        if let Some(label) = labels.get(&(addr as u16)) {
+            out += &format!("{}:\n", label);
+        }
+
+        if map.is_start(addr) {
+            let ins = map.decode(addr).unwrap();
+            let bytes = &image[addr - map.origin..addr - map.origin + ins.len as usize];
+            if map.needs_bytes(&ins, addr as u16) {
+                out += &format!("    .byte {} ; {}\n", byte_list(bytes), format(&ins, addr as u16, None));
+            } else {
+                out += &format!("    {}\n", format(&ins, addr as u16, Some(&symbols)));
+            }
+            addr += ins.len as usize;
+        } else if is_vector(addr) {
+            let target = map.word(addr).unwrap();
+            out += &format!("    .word {}\n", labels.get(&target).cloned().unwrap_or(format!("${:04X}", target)));
+            addr += 2;
+        } else {
+            let mut next = addr + 1;
+            while next < end &&
+                next - addr < BYTES_PER_LINE &&
+                !map.is_code(next) &&
+                !labels.contains_key(&(next as u16)) &&
+                !is_vector(next)
+            {
+                next += 1;
+            }
+            out += &format!("    .byte {}\n", byte_list(&image[addr - map.origin..next - map.origin]));
+            addr = next;
+        }
+    }
+
+    out
+}
+
+fn byte_list(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("${:02X}", b)).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::opcodes::*;
+
+    #[test]
+    fn test_table_matches_step() {
+        // a few opcodes from Cpu::step()
+        assert!(OPCODES[LDA_A9 as usize] == op("LDA", Immediate, 2));
+        assert!(OPCODES[STA_91 as usize] == op("STA", ZeroPageIndirectY, 6));
+        assert!(OPCODES[JMP_6C as usize] == op("JMP", AbsoluteIndirect, 5));
+        assert!(OPCODES[LDX_B6 as usize] == op("LDX", ZeroPageY, 4));
+        assert!(OPCODES[ROR_6A as usize] == op("ROR", Accumulator, 2));
+        assert!(OPCODES[BNE_D0 as usize] == op("BNE", Relative, 2));
+        // the cycles of the undocumented ones are from the comments in Cpu::step()
+        assert!(OPCODES[RRA_7B as usize] == ill("RRA", AbsoluteY, 7));
+        assert!(OPCODES[RRA_63 as usize] == ill("RRA", ZeroPageXIndirect, 8));
+        assert!(OPCODES[NOP_1C as usize] == ill("NOP", AbsoluteX, 4));
+        assert!(OPCODES[NOP_14 as usize] == ill("NOP", ZeroPageX, 4));
+        assert!(OPCODES[JAM_F2 as usize] == ill("JAM", Implied, 0));
+
+        // 151 documented opcodes
+        assert!(OPCODES.iter().filter(|o| !o.illegal).count() == 151);
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert!(OPCODES[LDA_BD as usize].cycles_text() == "4*");
+        assert!(OPCODES[STA_9D as usize].cycles_text() == "5");
+        assert!(OPCODES[LDA_B1 as usize].cycles_text() == "5*");
+        assert!(OPCODES[STA_91 as usize].cycles_text() == "6");
+        assert!(OPCODES[ASL_1E as usize].cycles_text() == "7");
+        assert!(OPCODES[BCC_90 as usize].cycles_text() == "2**");
+        assert!(OPCODES[NOP_1C as usize].cycles_text() == "4*");
+    }
+
+    #[test]
+    fn test_mnemonics() {
+        assert!(OPCODES.iter().all(|o| mnemonic(o.mnemonic).is_some()));
+        assert!(MNEMONICS.iter().all(|m| OPCODES.iter().any(|o| o.mnemonic == m.name)));
+        assert!(mnemonic("adc").unwrap().writes == "NVZC");
+        assert!(mnemonic("BEQ").unwrap().reads == "Z");
+    }
+
+    #[test]
+    fn test_decode() {
+        fn _t(mem: &[u8], mnemonic: &str, mode: AddressingMode, operand: u16, len: u8) {
+            let ins = decode(mem).unwrap();
+            assert!(ins.opcode == mem[0]);
+            assert!(ins.mnemonic == mnemonic);
+            assert!(ins.mode == mode);
+            assert!(ins.operand == operand);
+            assert!(ins.len == len);
+        }
+
+        _t(&[TAX_AA], "TAX", Implied, 0, 1);
+        _t(&[ASL_0A, 0xff], "ASL", Accumulator, 0, 1);
+        _t(&[LDA_A9, 0x10], "LDA", Immediate, 0x10, 2);
+        _t(&[STA_9D, 0x00, 0x02], "STA", AbsoluteX, 0x0200, 3);
+        _t(&[BNE_D0, 0xfe], "BNE", Relative, 0xfe, 2);
+
+        assert!(decode(&[]).is_none());
+        assert!(decode(&[LDA_AD, 0x00]).is_none());
+    }
+
+    #[test]
+    fn test_format() {
+        fn _t(mem: &[u8], pc: u16, exp: &str) {
+            let ins = decode(mem).unwrap();
+            assert!(format(&ins, pc, None) == exp, "{} != {}", format(&ins, pc, None), exp);
+        }
+
+        _t(&[CLC_18], 0, "CLC");
+        _t(&[ROL_2A], 0, "ROL A");
+        _t(&[LDA_A9, 0x10], 0, "LDA #$10");
+        _t(&[LDA_AD, 0x34, 0x12], 0, "LDA $1234");
+        _t(&[STA_9D, 0x00, 0x02], 0, "STA $0200,X");
+        _t(&[LDA_B9, 0x00, 0x02], 0, "LDA $0200,Y");
+        _t(&[JMP_6C, 0xfc, 0xff], 0, "JMP ($FFFC)");
+        _t(&[LDA_A5, 0x44], 0, "LDA $44");
+        _t(&[LDA_B5, 0x44], 0, "LDA $44,X");
+        _t(&[LDX_B6, 0x44], 0, "LDX $44,Y");
+        _t(&[LDA_A1, 0x44], 0, "LDA ($44,X)");
+        _t(&[ORA_11, 0x44], 0, "ORA ($44),Y");
+        _t(&[BNE_D0, 0xfe], 0xc000, "BNE $C000"); // loop: BNE loop
+        _t(&[BCC_90, 0x10], 0xc000, "BCC $C012");
+        _t(&[BPL_10, 0x80], 0x0010, "BPL $FF92"); // wraps around
+    }
+
+    #[test]
+    fn test_format_symbols() {
+        let mut symbols = HashMap::new();
+        symbols.insert(0x0200, "buffer".to_string());
+        symbols.insert(0x0044, "ptr".to_string());
+        symbols.insert(0xc000, "loop".to_string());
+
+        let _t = |mem: &[u8], pc: u16, exp: &str| {
+            let ins = decode(mem).unwrap();
+            assert!(format(&ins, pc, Some(&symbols)) == exp);
+        };
+
+        _t(&[STA_9D, 0x00, 0x02], 0, "STA buffer,X");
+        _t(&[ORA_11, 0x44], 0, "ORA (ptr),Y");
+        _t(&[BNE_D0, 0xfe], 0xc000, "BNE loop");
+        _t(&[LDA_A9, 0x44], 0, "LDA #$44"); // immediates are never substituted
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let mut cpu = Cpu::new();
+        cpu.patch_memory(0xc000, &[LDA_A9, 0x10, STA_9D, 0x00, 0x02, DEX_CA, BNE_D0, 0xfa]);
+        cpu.patch_memory(0xffff, &[LDA_AD]);
+
+        let lines = disassemble(&cpu, 0xc000, 0xc007, None);
+        assert!(lines == vec![
+            "C000  A9 10     LDA #$10",
+            "C002  9D 00 02  STA $0200,X",
+            "C005  CA        DEX",
+            "C006  D0 FA     BNE $C002",
+        ]);
+
+        let lines = disassemble(&cpu, 0xffff, 0xffff, None);
+        assert!(lines == vec!["FFFF  AD        .byte $AD"]);
+
+        let symbols = HashMap::from([(0xc002, "loop".to_string()), (0x0200, "buffer".to_string())]);
+        let lines = disassemble(&cpu, 0xc002, 0xc007, Some(&symbols));
+        assert!(lines == vec!["loop:", "C002  9D 00 02  STA buffer,X", "C005  CA        DEX", "C006  D0 FA     BNE loop"]);
+    }
+
+    #[test]
+    fn test_encode() {
+        assert!(encode("LDA", Immediate) == Some(LDA_A9));
+        assert!(encode("lda", ZeroPageIndirectY) == Some(LDA_B1));
+        assert!(encode("NOP", Implied) == Some(NOP_EA)); // the documented one
+        assert!(encode("NOP", ZeroPage) == Some(NOP_04)); // the first undocumented one
+        assert!(encode("RRA", Absolute) == Some(RRA_6F));
+        assert!(encode("LDA", ZeroPageY).is_none());
+        assert!(encode("JMP", ZeroPage).is_none());
+    }
+
+    #[test]
+    fn test_disassemble_source() {
+        let mut image = vec![0u8; 0x4000]; // $C000 - $FFFF
+        let prog = &[
+            LDX_A2, 0x05,       // C000 reset: LDX #$05
+            LDA_BD, 0x00, 0x03, // C002 LC002: LDA $0300,X
+            STA_95, 0x10,       // C005        STA $10,X
+            DEX_CA,             // C007        DEX
+            BNE_D0, 0xf8,       // C008        BNE LC002
+            JSR_20, 0x12, 0xc0, // C00A        JSR LC012
+            JMP_4C, 0x0a, 0xc0, // C00D        JMP LC00A
+            0x12, 0x34,         // C010 data (JAM)
+            RTS_60,             // C012 LC012: RTS
+            LDA_AD, 0x44, 0x00, // C013 unreachable
+        ];
+        image[..prog.len()].copy_from_slice(prog);
+        image[0x3ffa..].copy_from_slice(&[0x12, 0xc0, 0x00, 0xc0, 0x12, 0xc0]);
+
+        let src = disassemble_source(&image, 0xc000, &[]);
+        let lines: Vec<&str> = src.lines().collect();
+        assert!(lines[..16] == [
+            "L0010 = $0010",
+            "L0300 = $0300",
+            "",
+            "    .org $C000",
+            "reset:",
+            "    LDX #$05",
+            "LC002:",
+            "    LDA L0300,X",
+            "    STA L0010,X",
+            "    DEX",
+            "    BNE LC002",
+            "LC00A:",
+            "    JSR nmi",
+            "    JMP LC00A",
+            "    .byte $12,$34",
+            "nmi:",
+        ], "{:#?}", &lines[..16]);
+        assert!(lines[16..19] == [
+            "    RTS",
+            "    .byte $AD,$44,$00,$00,$00,$00,$00,$00",
+            "    .byte $00,$00,$00,$00,$00,$00,$00,$00",
+        ]);
+        assert!(lines[lines.len() - 3..] == [
+            "    .word nmi",
+            "    .word reset",
+            "    .word nmi",
+        ]);
+    }
+
+    #[test]
+    fn test_disassemble_source_ambiguous() {
+        // LDA $0044 (absolute) and NOP $44 (not the default NOP zero page opcode)
+        let image = &[LDA_AD, 0x44, 0x00, NOP_44, 0x44, RTS_60];
+        let src = disassemble_source(image, 0x8000, &[0x8000]);
+        // NOP_44 is an illegal opcode, so the tracing stops there
+        assert!(src.contains("    .byte $AD,$44,$00 ; LDA $0044\n"), "{}", src);
+        assert!(src.contains("    .byte $44,$44,$60\n"), "{}", src);
+    }
+
+    #[test]
+    fn test_disassemble_source_wrap() {
+        // a branch back from $0000 to $FFF2, and forward from $FFF0 to $0071
+        let mut image = vec![NOP_EA; 0x100];
+        image[..3].copy_from_slice(&[BNE_D0, 0xf0, RTS_60]);
+        let src = disassemble_source(&image, 0x0000, &[0x0000]);
+        assert!(src.contains("    .byte $D0,$F0 ; BNE $FFF2\n"), "{}", src);
+        let program = crate::asm::Assembler::new().assemble(&src).unwrap();
+        assert!(program.image() == Some((0x0000, image)), "{}", src);
+
+        let mut image = vec![NOP_EA; 0x10];
+        image[..3].copy_from_slice(&[BNE_D0, 0x7f, RTS_60]);
+        let src = disassemble_source(&image, 0xfff0, &[0xfff0]);
+        assert!(src.contains("    .byte $D0,$7F ; BNE $0071\n"), "{}", src);
+        let program = crate::asm::Assembler::new().assemble(&src).unwrap();
+        assert!(program.image() == Some((0xfff0, image)), "{}", src);
+    }
+}
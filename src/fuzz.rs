@@ -0,0 +1,272 @@
+// Fuzzing
+//
+// The checks of the fuzz targets of fuzz/ (cargo fuzz run step) and of `mos6502 fuzz`, which
+// runs them on random inputs. An input is A, X, Y, S, P, the pc (little endian) and the bytes
+// which fill the memory from the pc on, repeated: the operands, the pointers and the stack get
+// the same random bytes as the instruction.
+//
+// step: Cpu::step() must not panic (no arithmetic overflow, no index past the memory), except
+// on what the Cpu doesn't implement (BRK, the decimal mode and the undocumented opcodes other
+// than the RRAs of step::UNDOCUMENTED), and after the instruction:
+// - the pc is after the instruction, for the ones which don't jump, or at the target of a taken
+//   branch
+// - S is unchanged, for the ones which don't use the stack
+// - the only bytes changed are the one written by the instruction and the stack
+//
+// differential: the NMOS 6502 and the 2A03 must give the same registers and memory with D
+// clear, and with D set the 2A03 must give what the 6502 gives with D clear (except for D).
+// What the Cpu doesn't implement is skipped, the other panics are failures.
+//
+// The panics are caught (catch_unwind) for `mos6502 fuzz` only: the panic hook of libfuzzer
+// aborts the process first, so a panic of the Cpu is a crash of the target under cargo fuzz.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::cpu::{Cpu, Flags, Variant};
+use crate::disasm::{fetch, format, OPCODES, WRITES};
+use crate::step::{address, fault, taken};
+
+const JUMPS: [&str; 5] = ["JMP", "JSR", "RTS", "RTI", "BRK"];
+const STACK: [&str; 9] = ["PHA", "PHP", "PLA", "PLP", "TXS", "JSR", "RTS", "RTI", "BRK"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub regs: (u8, u8, u8, u8, u8), // a, x, y, s, p
+    pub pc: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Input {
+    pub fn parse(data: &[u8]) -> Option<Input> {
+        match data {
+            [a, x, y, s, p, lo, hi, bytes @ ..] => {
+                Some(Input { regs: (*a, *x, *y, *s, *p), pc: u16::from_le_bytes([*lo, *hi]), bytes: bytes.to_vec() })
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu(&self, variant: Variant, p: u8) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_variant(variant);
+        if !self.bytes.is_empty() {
+            let mut memory: Vec<u8> = self.bytes.iter().cycle().take(0x10000).copied().collect();
+            memory.rotate_right(self.pc as usize);
+            cpu.patch_memory(0, &memory);
+        }
+        let (a, x, y, s, _) = self.regs;
+        cpu.set_registers(a, x, y, s, p);
+        cpu.update_pc(self.pc);
+        cpu
+    }
+
+    // the instruction with the registers, for the messages
+    fn describe(&self, cpu: &Cpu) -> String {
+        let ins = format(&fetch(cpu.memory(), self.pc), self.pc, None);
+        let (a, x, y, s, p) = cpu.registers();
+        format!("${:04X}: {} with A=${:02X} X=${:02X} Y=${:02X} S=${:02X} P=${:02X}", self.pc, ins, a, x, y, s, p)
+    }
+}
+
+// Cpu::step(), Err with the message of the panic
+fn run(cpu: &mut Cpu) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(|| cpu.step())).map_err(|e| {
+        let message = e.downcast_ref::<&str>().map(|s| s.to_string()).or_else(|| e.downcast_ref::<String>().cloned());
+        format!("panicked: {}", message.unwrap_or_default())
+    })
+}
+
+pub fn step(data: &[u8]) -> Result<(), String> {
+    let input = match Input::parse(data) {
+        Some(input) => input,
+        None => return Ok(()),
+    };
+    let mut cpu = input.cpu(Variant::Nmos, input.regs.4);
+    let ins = fetch(cpu.memory(), input.pc);
+    // what the Cpu doesn't implement (it panics on purpose)
+    if fault(&cpu, &ins).is_some() {
+        return Ok(());
+    }
+    let describe = input.describe(&cpu);
+    let written = Some(&ins).filter(|ins| WRITES.contains(&ins.mnemonic)).and_then(|ins| address(&cpu, ins));
+    let branch = ins.branch_target(input.pc).zip(taken(&cpu, &ins));
+    let before = cpu.memory().to_vec();
+    run(&mut cpu).map_err(|e| format!("{}: {}", describe, e))?;
+
+    let mut errors = Vec::new();
+    let next = match branch {
+        Some((target, true)) => target,
+        _ => input.pc.wrapping_add(ins.len as u16),
+    };
+    if !JUMPS.contains(&ins.mnemonic) && cpu.pc() != next {
+        errors.push(format!("the pc is ${:04X} instead of ${:04X}", cpu.pc(), next));
+    }
+    let s = cpu.registers().3;
+    if !STACK.contains(&ins.mnemonic) && s != input.regs.3 {
+        errors.push(format!("S is ${:02X} instead of ${:02X}", s, input.regs.3));
+    }
+    let stack = STACK.contains(&ins.mnemonic);
+    let changed = (before[..] != cpu.memory()[..]).then_some(()).and(before.iter().zip(cpu.memory()).enumerate().find(|&(address, (old, new))| {
+        old != new && Some(address as u16) != written && !(stack && (0x100..0x200).contains(&address))
+    }));
+    if let Some((address, (old, new))) = changed {
+        errors.push(format!("${:04X} changed from ${:02X} to ${:02X}", address, old, new));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", describe, errors.join(", ")))
+    }
+}
+
+// the differences of the registers, the pc and the memory, with `mask` for P and the P pushed at `pushed`
+fn compare(nmos: &Cpu, ricoh: &Cpu, mask: u8, pushed: usize) -> Vec<String> {
+    let mut diffs = Vec::new();
+    let (a, x, y, s, p) = nmos.registers();
+    let (a2, x2, y2, s2, p2) = ricoh.registers();
+    for (name, n, r) in [("A", a, a2), ("X", x, x2), ("Y", y, y2), ("S", s, s2), ("P", p & mask, p2 & mask)] {
+        if n != r {
+            diffs.push(format!("{}: ${:02X} on the 6502, ${:02X} on the 2A03", name, n, r));
+        }
+    }
+    if nmos.pc() != ricoh.pc() {
+        diffs.push(format!("PC: ${:04X} on the 6502, ${:04X} on the 2A03", nmos.pc(), ricoh.pc()));
+    }
+    let memory = nmos.memory().iter().zip(ricoh.memory()).enumerate().position(|(address, (n, r))| {
+        if address == pushed {
+            n & mask != r & mask
+        } else {
+            n != r
+        }
+    });
+    if let Some(address) = memory {
+        let (n, r) = (nmos.memory()[address], ricoh.memory()[address]);
+        diffs.push(format!("${:04X}: ${:02X} on the 6502, ${:02X} on the 2A03", address, n, r));
+    }
+    diffs
+}
+
+pub fn differential(data: &[u8]) -> Result<(), String> {
+    let input = match Input::parse(data) {
+        Some(input) => input,
+        None => return Ok(()),
+    };
+    let binary = input.regs.4 & !Flags::D_Decimal;
+    let mut nmos = input.cpu(Variant::Nmos, binary);
+    if fault(&nmos, &fetch(nmos.memory(), input.pc)).is_some() {
+        return Ok(());
+    }
+    let pushed = 0x100 + input.regs.3 as usize;
+    let describe = input.describe(&nmos);
+    if let Err(e) = run(&mut nmos) {
+        return Err(format!("{}: {}", describe, e));
+    }
+    for (p, mask) in [(binary, 0xff), (binary | Flags::D_Decimal, !Flags::D_Decimal)] {
+        let mut ricoh = input.cpu(Variant::Ricoh2A03, p);
+        let describe = input.describe(&ricoh);
+        if let Err(e) = run(&mut ricoh) {
+            return Err(format!("{}: {}", describe, e));
+        }
+        let diffs = compare(&nmos, &ricoh, mask, pushed);
+        if !diffs.is_empty() {
+            return Err(format!("{}: {}", describe, diffs.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+// the failures by opcode: the count, the first message and its input
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub runs: usize,
+    pub failures: BTreeMap<u8, (usize, String, Vec<u8>)>,
+}
+
+impl Report {
+    pub fn new() -> Report {
+        Report::default()
+    }
+
+    pub fn add(&mut self, data: &[u8], result: Result<(), String>) {
+        self.runs += 1;
+        if let (Err(e), Some(input)) = (result, Input::parse(data)) {
+            let opcode = input.bytes.first().copied().unwrap_or(0);
+            self.failures.entry(opcode).or_insert((0, e, data.to_vec())).0 += 1;
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (opcode, (count, message, data)) in &self.failures {
+            let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(f, "{:02X} {}: {} failure(s), first: {}", opcode, OPCODES[*opcode as usize].mnemonic, count, message)?;
+            writeln!(f, "   input: {}", hex)?;
+        }
+        let failures: usize = self.failures.values().map(|(count, _, _)| count).sum();
+        writeln!(f, "{} runs, {} failures ({} opcodes)", self.runs, failures, self.failures.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A, X, Y, S, P, the pc and the bytes
+    fn input(regs: [u8; 5], pc: u16, bytes: &[u8]) -> Vec<u8> {
+        [&regs[..], &pc.to_le_bytes(), bytes].concat()
+    }
+
+    #[test]
+    fn test_step() {
+        assert!(step(&[1, 2, 3]) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0xa9, 0x10])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0x08], 0x200, &[0x69, 0x10])) == Ok(()));
+        // the stack, and a store: the other bytes are the ones of the memory fill
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0x48])) == Ok(()));
+        assert!(step(&input([0x55, 0, 0, 0xfd, 0], 0x200, &[0x8d, 0x00, 0x03])) == Ok(()));
+
+        // S, the indexed addresses and the pc wrap around
+        assert!(step(&input([0, 0, 0, 0x00, 0], 0x200, &[0x48])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xff, 0], 0x200, &[0x68])) == Ok(()));
+        assert!(step(&input([0, 0x10, 0, 0xfd, 0], 0x200, &[0xbd, 0xff, 0xff])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0xffff, &[0xea])) == Ok(()));
+        assert!(step(&input([0x55, 0, 0, 0xfd, 0], 0xfffe, &[0x8d, 0x00, 0x03])) == Ok(()));
+        // NOP, and NOP #$01 which the Cpu doesn't implement (not checked)
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0xea])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0x80, 0x01])) == Ok(()));
+        // BNE not taken, taken forwards, and backwards across a page
+        assert!(step(&input([0, 0, 0, 0xfd, 0x02], 0x200, &[0xd0, 0x10])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0xd0, 0x10])) == Ok(()));
+        assert!(step(&input([0, 0, 0, 0xfd, 0], 0x200, &[0xd0, 0xf0])) == Ok(()));
+    }
+
+    #[test]
+    fn test_differential() {
+        assert!(differential(&input([0x10, 0, 0, 0xfd, 0x09], 0x200, &[0x69, 0x25])) == Ok(()));
+        assert!(differential(&input([0x10, 0, 0, 0xfd, 0x08], 0x200, &[0xe9, 0x25])) == Ok(()));
+        assert!(differential(&input([0x10, 0, 0, 0xfd, 0x08], 0x200, &[0x08])) == Ok(()));
+        assert!(differential(&input([0x10, 0, 0, 0x00, 0], 0x200, &[0x48])) == Ok(()));
+        assert!(differential(&input([0, 0, 0, 0xfd, 0x01], 0x200, &[0xb0, 0x80])) == Ok(()));
+        // not implemented: skipped
+        assert!(differential(&input([0, 0, 0, 0xfd, 0], 0x200, &[0x00])) == Ok(()));
+        let mut ricoh = Input::parse(&input([0x10, 0, 0, 0xfd, 0x08], 0x200, &[0x69, 0x25])).unwrap().cpu(Variant::Ricoh2A03, 0x08);
+        ricoh.step();
+        assert!(ricoh.registers() == (0x35, 0, 0, 0xfd, 0x08));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::new();
+        let data = input([0, 0, 0, 0xfd, 0], 0x200, &[0xa9, 0]);
+        report.add(&data, step(&data));
+        report.add(&input([0, 0, 0, 0x00, 0], 0x200, &[0x48]), Err("$0200: PHA: panicked".to_string()));
+        assert!(report.to_string() == "\
+48 PHA: 1 failure(s), first: $0200: PHA: panicked
+   input: 0000000000000248
+2 runs, 1 failures (1 opcodes)
+");
+    }
+}
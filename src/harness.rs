@@ -0,0 +1,459 @@
+// Subroutine test harness
+//
+//   let outcome = Harness::new(&image, 0x8000).call(0x8123).with_a(5).with_mem(0x10, &[1, 2]).run()?;
+//   outcome.assert(&Expect::new().a(8).flag(ir::C, false).mem(0x12, &[3]));
+//
+// run() pushes the return address of a sentinel ($FFFF) and runs Cpu::step() from the called
+// address until the matching RTS returns to the sentinel, within a budget of cycles and of
+// instructions. Reaching $FFFF otherwise (a JMP, a JSR, a branch or an RTI) is an error. The
+// outcome has the registers, the flags, the memory the routine wrote (the operands of the
+// stores and read-modify-writes, not the pushes) and the cycles, counted from the cycles of the
+// opcodes with the page crossing and taken branch penalties.
+//
+// What would make the Cpu panic (BRK, decimal mode, the undocumented opcodes it doesn't
+// implement: step::fault()) stops the run with an error instead, as does JAM (a halt). The
+// errors in the subroutines called by the routine come with a backtrace (shadow.rs).
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::coverage::Coverage;
+use crate::cpu::Cpu;
+use crate::disasm::{fetch, format, Instruction, WRITES};
+use crate::profile::Profile;
+use crate::shadow::ShadowStack;
+use crate::step::{address, cost, fault, taken};
+
+// the address RTS returns to at the end of the called routine
+const SENTINEL: u16 = 0xffff;
+
+pub struct Harness<'a> {
+    image: &'a [u8],
+    origin: u16,
+    entry: u16,
+    regs: [u8; 4], // A, X, Y, S
+    p: u8,
+    memory: Vec<(u16, Vec<u8>)>,
+    max_cycles: usize,
+    max_instructions: usize,
+    symbols: Option<&'a HashMap<u16, String>>, // for the backtraces
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub cycles: usize,
+    pub instructions: usize,
+    pub written: BTreeMap<u16, u8>, // the final value of the bytes the routine wrote
+    pub coverage: Coverage,
+    pub profile: Profile,
+    memory: Vec<u8>,
+}
+
+// the expected outcome, what isn't given can have any value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Expect {
+    regs: [Option<u8>; 4],
+    flags: u8, // the flags which are given
+    p: u8,
+    memory: Vec<(u16, Vec<u8>)>,
+    max_cycles: Option<usize>,
+}
+
+impl<'a> Harness<'a> {
+    // `origin`: the address of the first byte of `image`, which is also the default entry
+    pub fn new(image: &'a [u8], origin: u16) -> Harness<'a> {
+        let (a, x, y, s, p) = Cpu::new().registers();
+        Harness {
+            image,
+            origin,
+            entry: origin,
+            regs: [a, x, y, s],
+            p,
+            memory: Vec::new(),
+            max_cycles: 1_000_000,
+            max_instructions: 1_000_000,
+            symbols: None,
+        }
+    }
+
+    pub fn call(mut self, entry: u16) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn with_a(mut self, value: u8) -> Self {
+        self.regs[0] = value;
+        self
+    }
+
+    pub fn with_x(mut self, value: u8) -> Self {
+        self.regs[1] = value;
+        self
+    }
+
+    pub fn with_y(mut self, value: u8) -> Self {
+        self.regs[2] = value;
+        self
+    }
+
+    // the stack pointer before the sentinel is pushed
+    pub fn with_s(mut self, value: u8) -> Self {
+        self.regs[3] = value;
+        self
+    }
+
+    // `flag`: one of the flags of ir (ir::C, ...)
+    pub fn with_flag(mut self, flag: u8, set: bool) -> Self {
+        self.p = if set { self.p | flag } else { self.p & !flag };
+        self
+    }
+
+    // written over the image
+    pub fn with_mem(mut self, address: u16, bytes: &[u8]) -> Self {
+        self.memory.push((address, bytes.to_vec()));
+        self
+    }
+
+    pub fn max_cycles(mut self, cycles: usize) -> Self {
+        self.max_cycles = cycles;
+        self
+    }
+
+    pub fn max_instructions(mut self, instructions: usize) -> Self {
+        self.max_instructions = instructions;
+        self
+    }
+
+    // the names of the addresses in the backtraces of the errors
+    pub fn symbols(mut self, symbols: &'a HashMap<u16, String>) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub fn run(&self) -> Result<Outcome, String> {
+        let mut cpu = Cpu::new();
+        if self.origin as usize + self.image.len() > 0x10000 {
+            return Err(format!("the image of {} bytes at ${:04X} doesn't fit in memory", self.image.len(), self.origin));
+        }
+        cpu.patch_memory(self.origin as usize, self.image);
+        for (address, bytes) in &self.memory {
+            if *address as usize + bytes.len() > 0x10000 {
+                return Err(format!("the {} bytes at ${:04X} don't fit in memory", bytes.len(), address));
+            }
+            cpu.patch_memory(*address as usize, bytes);
+        }
+
+        // RTS returns to the pulled address + 1
+        let [a, x, y, s] = self.regs;
+        if s < 2 {
+            return Err(format!("no room on the stack for the return address with S=${:02X}", s));
+        }
+        let ret = SENTINEL - 1;
+        cpu.patch_memory(0x100 + s as usize - 1, &[ret as u8, (ret >> 8) as u8]);
+        cpu.set_registers(a, x, y, s - 2, self.p);
+        cpu.update_pc(self.entry);
+
+        let mut cycles = 0;
+        let mut instructions = 0;
+        let mut written = Vec::new();
+        let mut coverage = Coverage::new();
+        let mut profile = Profile::new(self.entry);
+        let mut shadow = ShadowStack::new();
+        let mut last: Option<(u16, Instruction, usize)> = None; // the previous instruction, its address and cycle
+        loop {
+            let pc = cpu.pc();
+            let sp = cpu.registers().3;
+            match last {
+                Some((_, ins, _)) if pc == SENTINEL && ins.mnemonic == "RTS" => {
+                    if sp != s {
+                        return Err(format!("returned with S=${:02X} instead of ${:02X} (unbalanced stack)", sp, s));
+                    }
+                    break;
+                }
+                // reported on the instruction which went there
+                Some((pc, ins, at)) if cpu.pc() == SENTINEL => {
+                    let why = format!("went to the sentinel ${:04X} without an RTS", SENTINEL);
+                    return Err(self.error(&shadow, &cpu, pc, &ins, &why, at));
+                }
+                _ => {}
+            }
+            let ins = fetch(cpu.memory(), pc);
+            let spent = cost(&cpu, &ins);
+            let why = if let Some(why) = fault(&cpu, &ins) {
+                why.to_string()
+            } else if instructions == self.max_instructions {
+                format!("more than {} instructions", self.max_instructions)
+            } else if cycles + spent > self.max_cycles {
+                format!("more than {} cycles", self.max_cycles)
+            } else {
+                String::new()
+            };
+            if !why.is_empty() {
+                return Err(self.error(&shadow, &cpu, pc, &ins, &why, cycles));
+            }
+            if WRITES.contains(&ins.mnemonic) {
+                written.extend(address(&cpu, &ins));
+            }
+            coverage.record(pc, ins.len, taken(&cpu, &ins));
+            profile.record(pc, &ins, spent, taken(&cpu, &ins));
+            shadow.observe(&cpu, &ins);
+            last = Some((pc, ins, cycles));
+            cpu.step();
+            instructions += 1;
+            cycles += spent;
+        }
+
+        profile.finish();
+        let (a, x, y, s, p) = cpu.registers();
+        let memory = cpu.memory().to_vec();
+        let written = written.into_iter().map(|addr| (addr, memory[addr as usize])).collect();
+        Ok(Outcome { a, x, y, s, p, cycles, instructions, written, coverage, profile, memory })
+    }
+
+    // the instruction which stops the run, with the backtrace from the pc of the Cpu
+    fn error(&self, shadow: &ShadowStack, cpu: &Cpu, pc: u16, ins: &Instruction, why: &str, cycles: usize) -> String {
+        let mut error = format!("${:04X}: {}: {} (at cycle {})", pc, format(ins, pc, None), why, cycles);
+        if !shadow.is_empty() {
+            let empty = HashMap::new();
+            error += &format!("\nbacktrace:\n{}", shadow.backtrace(cpu.pc(), self.symbols.unwrap_or(&empty)).trim_end());
+        }
+        error
+    }
+}
+
+impl Outcome {
+    pub fn flag(&self, flag: u8) -> bool {
+        self.p & flag != 0
+    }
+
+    pub fn memory(&self, address: u16, len: usize) -> &[u8] {
+        &self.memory[address as usize..(address as usize + len).min(0x10000)]
+    }
+
+    // the differences with the expected outcome, one per line
+    pub fn check(&self, expect: &Expect) -> Result<(), String> {
+        let mut diffs = Vec::new();
+        for ((name, value), expected) in ["A", "X", "Y", "S"].iter().zip([self.a, self.x, self.y, self.s]).zip(expect.regs) {
+            if let Some(expected) = expected.filter(|e| *e != value) {
+                diffs.push(format!("{}: expected ${:02X}, got ${:02X}", name, expected, value));
+            }
+        }
+        for (i, name) in "NV-BDIZC".chars().enumerate() {
+            let flag = 0x80 >> i;
+            if expect.flags & flag != 0 && (expect.p ^ self.p) & flag != 0 {
+                diffs.push(format!("{}: expected {}, got {}", name, (expect.p & flag != 0) as u8, self.flag(flag) as u8));
+            }
+        }
+        for (address, bytes) in &expect.memory {
+            let actual = self.memory(*address, bytes.len());
+            if actual != &bytes[..] {
+                let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+                let marks: Vec<&str> = bytes.iter().zip(actual).map(|(e, a)| if e == a { "  " } else { "^^" }).collect();
+                let expected = format!("${:04X}: expected ", address);
+                diffs.push(format!("{}{}", expected, hex(bytes)));
+                diffs.push(format!("{:>w$}{}", "got ", hex(actual), w = expected.len()));
+                diffs.push(format!("{:w$}{}", "", marks.join(" ").trim_end(), w = expected.len()));
+            }
+        }
+        if let Some(max) = expect.max_cycles.filter(|max| self.cycles > *max) {
+            diffs.push(format!("cycles: expected at most {}, got {}", max, self.cycles));
+        }
+        if diffs.is_empty() {
+            Ok(())
+        } else {
+            Err(diffs.join("\n"))
+        }
+    }
+
+    #[allow(dead_code)] // for the unit tests of the routines
+    #[track_caller]
+    pub fn assert(&self, expect: &Expect) {
+        if let Err(diffs) = self.check(expect) {
+            panic!("unexpected outcome:\n{}\n\nfinal state:\n{}", diffs, self);
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: String = "NV-BDIZC".chars().enumerate().map(|(i, c)| if self.p & 0x80 >> i != 0 { c } else { c.to_ascii_lowercase() }).collect();
+        writeln!(f, "A=${:02X} X=${:02X} Y=${:02X} S=${:02X} P={}", self.a, self.x, self.y, self.s, flags)?;
+        writeln!(f, "{} instructions, {} cycles", self.instructions, self.cycles)?;
+        // the written bytes by runs of consecutive addresses (up to 16 per line)
+        let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+        for (&address, &value) in &self.written {
+            match runs.last_mut() {
+                Some((start, bytes)) if *start as usize + bytes.len() == address as usize && bytes.len() < 16 => bytes.push(value),
+                _ => runs.push((address, vec![value])),
+            }
+        }
+        for (address, bytes) in runs {
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            writeln!(f, "${:04X}: {}", address, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Expect {
+    pub fn new() -> Expect {
+        Expect::default()
+    }
+
+    pub fn a(mut self, value: u8) -> Self {
+        self.regs[0] = Some(value);
+        self
+    }
+
+    pub fn x(mut self, value: u8) -> Self {
+        self.regs[1] = Some(value);
+        self
+    }
+
+    pub fn y(mut self, value: u8) -> Self {
+        self.regs[2] = Some(value);
+        self
+    }
+
+    pub fn s(mut self, value: u8) -> Self {
+        self.regs[3] = Some(value);
+        self
+    }
+
+    pub fn flag(mut self, flag: u8, set: bool) -> Self {
+        self.flags |= flag;
+        self.p = if set { self.p | flag } else { self.p & !flag };
+        self
+    }
+
+    pub fn mem(mut self, address: u16, bytes: &[u8]) -> Self {
+        self.memory.push((address, bytes.to_vec()));
+        self
+    }
+
+    pub fn max_cycles(mut self, cycles: usize) -> Self {
+        self.max_cycles = Some(cycles);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::ir;
+
+    fn image(source: &str) -> (u16, Vec<u8>) {
+        Assembler::new().assemble(source).unwrap().image().unwrap()
+    }
+
+    const ADD: &str = "
+        .org $8000
+add:    CLC
+        ADC $10
+        STA $11
+        JSR double
+        LDX #2
+        LDA table,X
+        RTS
+double: ASL $11
+        ROL $12
+        RTS
+        .org $80FE
+table:  .byte 0, 1, 2
+";
+
+    #[test]
+    fn test_call() {
+        let (origin, image) = image(ADD);
+        let outcome = Harness::new(&image, origin).call(0x8000).with_a(5).with_mem(0x10, &[0x83, 0, 0]).run().unwrap();
+        assert!((outcome.a, outcome.x, outcome.y, outcome.s) == (2, 2, 0, 0xfd));
+        assert!(!outcome.flag(ir::C) && !outcome.flag(ir::Z) && !outcome.flag(ir::N));
+        assert!(outcome.written == BTreeMap::from([(0x11, 0x10), (0x12, 0x01)]));
+        // CLC 2, ADC 3, STA 3, JSR 6, ASL 5, ROL 5, RTS 6, LDX 2, LDA 4 + 1 (page crossing), RTS 6
+        assert!((outcome.instructions, outcome.cycles) == (10, 43));
+        assert!(outcome.memory(0x10, 3) == [0x83, 0x10, 0x01]);
+        assert!(outcome.to_string() == "\
+A=$02 X=$02 Y=$00 S=$FD P=nv-bdIzc
+10 instructions, 43 cycles
+$0011: 10 01
+");
+        let outcome = Harness::new(&image, origin).with_mem(0x10, &[0x40]).with_flag(ir::I, false).with_s(0x80).run().unwrap();
+        assert!(outcome.s == 0x80 && outcome.written[&0x11] == 0x80 && !outcome.flag(ir::I) && !outcome.flag(ir::C));
+    }
+
+    #[test]
+    fn test_expect() {
+        let (origin, image) = image(ADD);
+        let outcome = Harness::new(&image, origin).with_a(5).with_mem(0x10, &[0x83]).run().unwrap();
+        assert!(outcome.check(&Expect::new().a(2).x(2).flag(ir::C, false).mem(0x11, &[0x10, 0x01]).max_cycles(43)).is_ok());
+        let diffs = outcome.check(&Expect::new().a(3).y(0).flag(ir::C, true).flag(ir::Z, false).mem(0x10, &[0x83, 0x20, 0x01, 0x00, 0x05]).max_cycles(40));
+        assert!(diffs.unwrap_err() == "\
+A: expected $03, got $02
+C: expected 1, got 0
+$0010: expected 83 20 01 00 05
+            got 83 10 01 00 00
+                   ^^       ^^
+cycles: expected at most 40, got 43");
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected outcome:\nX: expected $03, got $02\n\nfinal state:\nA=$02")]
+    fn test_assert() {
+        let (origin, image) = image(ADD);
+        Harness::new(&image, origin).run().unwrap().assert(&Expect::new().x(3));
+    }
+
+    #[test]
+    fn test_branches() {
+        let (origin, countdown) = image(".org $8000\n        LDX #5\nloop:   DEX\n        BNE loop\n        RTS\n");
+        let outcome = Harness::new(&countdown, origin).run().unwrap();
+        // LDX 2, DEX 5 * 2, BNE 4 * 3 (taken) + 2, RTS 6
+        assert!((outcome.x, outcome.instructions, outcome.cycles) == (0, 12, 32) && outcome.flag(ir::Z));
+
+        let (origin, skip) = image(".org $8000\n        LDA #1\n        BEQ skip\n        LDX #7\nskip:   RTS\n");
+        assert!(Harness::new(&skip, origin).run().unwrap().x == 7);
+        let outcome = Harness::new(&skip, origin).with_mem(0x8001, &[0]).run().unwrap();
+        assert!((outcome.x, outcome.instructions, outcome.cycles) == (0, 3, 11));
+    }
+
+    #[test]
+    fn test_wrap() {
+        // $FFF0 + $20 is $0010, with a page crossing
+        let (origin, image) = image(".org $8000\nLDA $FFF0,X\nSTA $FFF0,Y\nRTS");
+        let outcome = Harness::new(&image, origin).with_x(0x20).with_y(0x30).with_mem(0x10, &[0x42]).run().unwrap();
+        assert!(outcome.a == 0x42 && outcome.cycles == 5 + 5 + 6);
+        outcome.assert(&Expect::new().mem(0x20, &[0x42]));
+    }
+
+    #[test]
+    fn test_errors() {
+        let run = |source: &str, harness: fn(Harness) -> Harness| {
+            let (origin, image) = image(source);
+            harness(Harness::new(&image, origin)).run().unwrap_err()
+        };
+        assert!(run(".org $8000\nloop: JMP loop", |h| h.max_cycles(100)) == "$8000: JMP $8000: more than 100 cycles (at cycle 99)");
+        assert!(run(".org $8000\nloop: JMP loop", |h| h.max_instructions(10)) == "$8000: JMP $8000: more than 10 instructions (at cycle 30)");
+        assert!(run(".org $8000\nNOP\nBRK", |h| h) == "$8001: BRK: BRK is not implemented by the Cpu (at cycle 2)");
+        assert!(run(".org $8000\nSED\nADC #1", |h| h) == "$8001: ADC #$01: decimal mode is not implemented by the Cpu (at cycle 2)");
+        assert!(run(".org $8000\n.byte $02", |h| h) == "$8000: JAM: JAM halts the Cpu (at cycle 0)");
+        assert!(run(".org $8000\n.byte $80, $01", |h| h) == "$8000: NOP #$01: the undocumented opcode is not implemented by the Cpu (at cycle 0)");
+        assert!(run(".org $8000\n.byte $A7, $10", |h| h) == "$8000: LAX $10: the undocumented opcode is not implemented by the Cpu (at cycle 0)");
+        // the RTS pulls $01FF and $0100 (S wraps around the page $01) and returns to $0000 + 1
+        assert!(run(".org $8000\nPLA\nPLA\nPLA\nRTS", |h| h.with_s(0xfd)) == "$0001: BRK: BRK is not implemented by the Cpu (at cycle 18)");
+        assert!(run(".org $8000\nPLA\nLDA #$FF\nPHA\nLDA #$FE\nPHA\nRTS", |h| h) == "returned with S=$FC instead of $FD (unbalanced stack)");
+        assert!(run(".org $8000\nRTS", |h| h.with_s(1)) == "no room on the stack for the return address with S=$01");
+        assert!(run(".org $8000\nNOP\nJMP $FFFF", |h| h) == "$8001: JMP $FFFF: went to the sentinel $FFFF without an RTS (at cycle 2)");
+        assert!(run(".org $8000\nJSR $FFFF", |h| h) == "$8000: JSR $FFFF: went to the sentinel $FFFF without an RTS (at cycle 0)\nbacktrace:\n#0 $FFFF\n#1 $8000: JSR $FFFF");
+
+        let (origin, image) = image(".org $8000\nmain: JSR fail\nRTS\nfail: NOP\nBRK");
+        let symbols = HashMap::from([(0x8000, "main".to_string()), (0x8004, "fail".to_string())]);
+        let error = Harness::new(&image, origin).symbols(&symbols).run().unwrap_err();
+        assert!(error == "$8005: BRK: BRK is not implemented by the Cpu (at cycle 8)\nbacktrace:\n#0 $8005 fail+1\n#1 $8000 main: JSR fail");
+    }
+}
@@ -0,0 +1,341 @@
+// Memory configuration
+//
+// A subset of the configuration files of ld65:
+//
+//   MEMORY {
+//       ZP:      start = $0000, size = $0100, file = "";
+//       PRG:     start = $8000, size = $7FFA, fill = yes, fillval = $FF;
+//       VECTORS: start = $FFFA, size = $0006, fill = yes;
+//   }
+//   SEGMENTS {
+//       ZEROPAGE: load = ZP;
+//       CODE:     load = PRG;
+//       VECTORS:  load = VECTORS;
+//   }
+//
+// Memory areas: start, size, fill (yes: written with the full size, the rest is filled with
+// fillval), fillval, file (%O: written to the output, "": not written) and type (ro / rw).
+// Segments: load (the memory area), start (a fixed address), align and type (ro / rw / bss / zp).
+// The memory areas are written to the output in the order of the configuration, several of
+// them may have the same addresses (the banks of a cartridge). The segments are placed into
+// their memory area in the order of the configuration, and in the order of the objects.
+//
+// The numbers are $hex, %binary or decimal, the comments start with '#'.
+
+use std::collections::HashMap;
+
+use super::LinkError;
+
+// ZEROPAGE, RAM and the PRG ROM of a 32K NES cartridge (without the iNES header)
+pub const DEFAULT_CONFIG: &str = "
+MEMORY {
+    ZP:      start = $0000, size = $0100, type = rw, file = \"\";
+    RAM:     start = $0200, size = $0600, type = rw, file = \"\";
+    PRG:     start = $8000, size = $7FFA, fill = yes;
+    VECTORS: start = $FFFA, size = $0006, fill = yes;
+}
+SEGMENTS {
+    ZEROPAGE: load = ZP, type = zp;
+    BSS:      load = RAM, type = bss;
+    CODE:     load = PRG;
+    RODATA:   load = PRG;
+    DATA:     load = PRG;
+    VECTORS:  load = VECTORS;
+}
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+    pub fill: bool,
+    pub fill_value: u8,
+    pub file: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentRule {
+    pub name: String,
+    pub memory: usize, // index into Config::memory
+    pub start: Option<u16>,
+    pub align: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub memory: Vec<Memory>,
+    pub segments: Vec<SegmentRule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Word(String),
+    Number(i64),
+    Str(String),
+    Punct(char),
+}
+
+fn error(line: usize, message: &str) -> LinkError {
+    LinkError { file: String::new(), line, message: message.to_string() }
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Tok)>, LinkError> {
+    let mut tokens = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let start = i;
+            i += 1;
+            let tok = match c {
+                '#' => break,
+                c if c.is_whitespace() => continue,
+                '"' => {
+                    while i < chars.len() && chars[i] != '"' {
+                        i += 1;
+                    }
+                    if i == chars.len() {
+                        return Err(error(idx + 1, "unterminated string"));
+                    }
+                    i += 1;
+                    Tok::Str(chars[start + 1..i - 1].iter().collect())
+                }
+                '$' | '%' | '0'..='9' => {
+                    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                    let text: String = chars[start..i].iter().collect();
+                    let number = match text.split_at(1) {
+                        ("$", hex) => i64::from_str_radix(hex, 16).ok(),
+                        ("%", "O") => {
+                            tokens.push((idx + 1, Tok::Word(text)));
+                            continue;
+                        }
+                        ("%", bin) => i64::from_str_radix(bin, 2).ok(),
+                        _ => match text.strip_prefix("0x") {
+                            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                            None => text.parse().ok(),
+                        },
+                    };
+                    match number {
+                        Some(n) => Tok::Number(n),
+                        None => return Err(error(idx + 1, &format!("bad number '{}'", text))),
+                    }
+                }
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                    Tok::Word(chars[start..i].iter().collect())
+                }
+                '{' | '}' | ':' | '=' | ',' | ';' => Tok::Punct(c),
+                _ => return Err(error(idx + 1, &format!("unexpected '{}'", c))),
+            };
+            tokens.push((idx + 1, tok));
+        }
+    }
+    Ok(tokens)
+}
+
+// the attributes of a memory area or a segment, with their lines
+type Attrs = HashMap<String, (usize, Tok)>;
+
+struct Parser {
+    tokens: Vec<(usize, Tok)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens.get(self.pos).or(self.tokens.last()).map_or(0, |(line, _)| *line)
+    }
+
+    fn next(&mut self, what: &str) -> Result<Tok, LinkError> {
+        match self.tokens.get(self.pos) {
+            Some((_, tok)) => {
+                self.pos += 1;
+                Ok(tok.clone())
+            }
+            None => Err(error(self.line(), &format!("expected {}", what))),
+        }
+    }
+
+    fn word(&mut self) -> Result<String, LinkError> {
+        match self.next("a name")? {
+            Tok::Word(w) => Ok(w),
+            _ => Err(error(self.tokens[self.pos - 1].0, "expected a name")),
+        }
+    }
+
+    fn punct(&mut self, c: char) -> Result<(), LinkError> {
+        match self.next(&format!("'{}'", c))? {
+            Tok::Punct(p) if p == c => Ok(()),
+            _ => Err(error(self.tokens[self.pos - 1].0, &format!("expected '{}'", c))),
+        }
+    }
+
+    fn at(&self, c: char) -> bool {
+        matches!(self.tokens.get(self.pos), Some((_, Tok::Punct(p))) if *p == c)
+    }
+
+    // name: attr = value, ... ;   -> the name and the attributes (with their lines)
+    fn entry(&mut self) -> Result<(usize, String, Attrs), LinkError> {
+        let line = self.line();
+        let name = self.word()?;
+        self.punct(':')?;
+        let mut attrs = HashMap::new();
+        while !self.at(';') {
+            let attr_line = self.line();
+            let attr = self.word()?.to_ascii_lowercase();
+            self.punct('=')?;
+            let value = self.next("a value")?;
+            if attrs.insert(attr.clone(), (attr_line, value)).is_some() {
+                return Err(error(attr_line, &format!("duplicate attribute '{}'", attr)));
+            }
+            if !self.at(';') {
+                self.punct(',')?;
+            }
+        }
+        self.pos += 1;
+        Ok((line, name, attrs))
+    }
+}
+
+fn number(attrs: &Attrs, name: &str, max: i64) -> Result<Option<i64>, LinkError> {
+    match attrs.get(name) {
+        Some((_, Tok::Number(n))) if (0..=max).contains(n) => Ok(Some(*n)),
+        Some((line, _)) => Err(error(*line, &format!("bad value of '{}'", name))),
+        None => Ok(None),
+    }
+}
+
+fn yes_no(attrs: &Attrs, name: &str) -> Result<bool, LinkError> {
+    match attrs.get(name) {
+        Some((_, Tok::Word(w))) if w.eq_ignore_ascii_case("yes") => Ok(true),
+        Some((_, Tok::Word(w))) if w.eq_ignore_ascii_case("no") => Ok(false),
+        Some((line, _)) => Err(error(*line, &format!("'{}' must be yes or no", name))),
+        None => Ok(false),
+    }
+}
+
+fn check_attrs(attrs: &Attrs, known: &[&str]) -> Result<(), LinkError> {
+    match attrs.iter().find(|(name, _)| !known.contains(&name.as_str())) {
+        Some((name, (line, _))) => Err(error(*line, &format!("unknown attribute '{}'", name))),
+        None => Ok(()),
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, LinkError> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let mut config = Config { memory: Vec::new(), segments: Vec::new() };
+        let mut loads = Vec::new();
+
+        while parser.pos < parser.tokens.len() {
+            let line = parser.line();
+            let section = parser.word()?.to_ascii_uppercase();
+            if section != "MEMORY" && section != "SEGMENTS" {
+                return Err(error(line, &format!("unknown section '{}'", section)));
+            }
+            parser.punct('{')?;
+            while !parser.at('}') {
+                let (line, name, attrs) = parser.entry()?;
+                if section == "MEMORY" {
+                    check_attrs(&attrs, &["start", "size", "fill", "fillval", "file", "type"])?;
+                    if config.memory.iter().any(|m| m.name == name) {
+                        return Err(error(line, &format!("duplicate memory area '{}'", name)));
+                    }
+                    let start = number(&attrs, "start", 0xffff)?.ok_or_else(|| error(line, "'start' is missing"))?;
+                    let size = number(&attrs, "size", 0x10000 - start)?.ok_or_else(|| error(line, "'size' is missing"))?;
+                    let file = match attrs.get("file") {
+                        Some((_, Tok::Word(w))) if w == "%O" => true,
+                        Some((_, Tok::Str(s))) if s.is_empty() => false,
+                        Some((line, _)) => return Err(error(*line, "'file' must be %O or \"\"")),
+                        None => true,
+                    };
+                    config.memory.push(Memory {
+                        name,
+                        start: start as u16,
+                        size: size as u32,
+                        fill: yes_no(&attrs, "fill")?,
+                        fill_value: number(&attrs, "fillval", 0xff)?.unwrap_or(0) as u8,
+                        file,
+                    });
+                } else {
+                    check_attrs(&attrs, &["load", "start", "align", "type"])?;
+                    if config.segments.iter().any(|s| s.name == name) {
+                        return Err(error(line, &format!("duplicate segment '{}'", name)));
+                    }
+                    let load = match attrs.get("load") {
+                        Some((line, Tok::Word(w))) => (*line, w.clone()),
+                        _ => return Err(error(line, "'load' is missing")),
+                    };
+                    loads.push(load);
+                    config.segments.push(SegmentRule {
+                        name,
+                        memory: 0,
+                        start: number(&attrs, "start", 0xffff)?.map(|s| s as u16),
+                        align: number(&attrs, "align", 0x10000)?.unwrap_or(1).max(1) as u32,
+                    });
+                }
+            }
+            parser.pos += 1;
+        }
+
+        for (segment, (line, load)) in config.segments.iter_mut().zip(loads) {
+            segment.memory = match config.memory.iter().position(|m| m.name == load) {
+                Some(idx) => idx,
+                None => return Err(error(line, &format!("unknown memory area '{}'", load))),
+            };
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(text: &str) -> (usize, String) {
+        let e = Config::parse(text).unwrap_err();
+        (e.line, e.message)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(DEFAULT_CONFIG).unwrap();
+        assert!(config.memory.len() == 4);
+        assert!(config.memory[0] == Memory { name: "ZP".to_string(), start: 0, size: 0x100, fill: false, fill_value: 0, file: false });
+        assert!(config.memory[3] == Memory { name: "VECTORS".to_string(), start: 0xfffa, size: 6, fill: true, fill_value: 0, file: true });
+        assert!(config.segments.iter().map(|s| (s.name.as_str(), s.memory)).collect::<Vec<_>>() == vec![
+            ("ZEROPAGE", 0), ("BSS", 1), ("CODE", 2), ("RODATA", 2), ("DATA", 2), ("VECTORS", 3),
+        ]);
+
+        let config = Config::parse("
+            memory { # two banks
+                PRG0: start = %1000000000000000, size = $4000, fill = yes, fillval = $FF, file = %O;
+                PRG1: start = $8000, size = 16384;
+            }
+            segments { BANK0: load = PRG0, align = $100; BANK1: load = PRG1, start = $9000; }
+        ").unwrap();
+        assert!(config.memory[0] == Memory { name: "PRG0".to_string(), start: 0x8000, size: 0x4000, fill: true, fill_value: 0xff, file: true });
+        assert!(config.memory[1].size == 0x4000 && !config.memory[1].fill);
+        assert!(config.segments[0] == SegmentRule { name: "BANK0".to_string(), memory: 0, start: None, align: 0x100 });
+        assert!(config.segments[1] == SegmentRule { name: "BANK1".to_string(), memory: 1, start: Some(0x9000), align: 1 });
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(error_of("MEMORY { A: start = $10; }") == (1, "'size' is missing".to_string()));
+        assert!(error_of("MEMORY { A: start = $8000, size = $8001; }") == (1, "bad value of 'size'".to_string()));
+        assert!(error_of("MEMORY {\n A: start = 0, size = 1, bank = 2; }") == (2, "unknown attribute 'bank'".to_string()));
+        assert!(error_of("MEMORY { A: start = 0, size = 1, fill = maybe; }") == (1, "'fill' must be yes or no".to_string()));
+        assert!(error_of("MEMORY { A: start = 0 size = 1; }") == (1, "expected ','".to_string()));
+        assert!(error_of("SEGMENTS {\n CODE: load = PRG; }") == (2, "unknown memory area 'PRG'".to_string()));
+        assert!(error_of("FILES { }") == (1, "unknown section 'FILES'".to_string()));
+        assert!(error_of("MEMORY { A: start = $1G; }") == (1, "bad number '$1G'".to_string()));
+        assert!(error_of("MEMORY {\n A: start = 0, size = 1;") == (2, "expected a name".to_string()));
+    }
+}
@@ -0,0 +1,356 @@
+// Linker
+//
+// Places the segments of the relocatable objects (see asm/object.rs) into the memory areas of
+// a configuration (see config.rs), resolves the imports with the exports of the other objects,
+// applies the relocations and writes the memory areas with `file = %O` into one image.
+
+mod config;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::asm::{Object, RelocKind, Target};
+pub use config::{Config, DEFAULT_CONFIG};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkError {
+    pub file: String, // the object or the configuration, empty for the memory layout errors
+    pub line: usize,  // 0 if the error is not about a line
+    pub message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        write!(f, " error: {}", self.message)
+    }
+}
+
+fn error(file: &str, message: &str) -> LinkError {
+    LinkError { file: file.to_string(), line: 0, message: message.to_string() }
+}
+
+// reads an object file
+pub fn load_object<P: AsRef<Path>>(path: P) -> Result<Object, LinkError> {
+    let name = path.as_ref().display().to_string();
+    let text = fs::read_to_string(&path).map_err(|e| error(&name, &e.to_string()))?;
+    Object::parse(&text).map_err(|(line, message)| LinkError { file: name, line, message })
+}
+
+// reads a configuration file
+pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, LinkError> {
+    let name = path.as_ref().display().to_string();
+    let text = fs::read_to_string(&path).map_err(|e| error(&name, &e.to_string()))?;
+    Config::parse(&text).map_err(|e| LinkError { file: name, ..e })
+}
+
+// where a segment of an object went
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub segment: String,
+    pub object: String,
+    pub memory: usize,
+    pub address: u16,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linked {
+    pub image: Vec<u8>,
+    pub symbols: BTreeMap<String, u16>, // the exports
+    pub placements: Vec<Placement>,
+    config: Config,
+    used: Vec<u32>, // the end of the used part of every memory area
+    exporters: BTreeMap<String, String>,
+}
+
+impl Linked {
+    // the memory areas, the segments and the exports
+    pub fn map(&self) -> String {
+        let mut out = String::from("Memory areas:\n");
+        for (memory, used) in self.config.memory.iter().zip(&self.used) {
+            let end = memory.start as u32 + memory.size.max(1) - 1;
+            let used = used - memory.start as u32;
+            out += &format!("  {:<12} ${:04X}-${:04X}  used ${:04X} of ${:04X}\n", memory.name, memory.start, end, used, memory.size);
+        }
+        out += "\nSegments:\n";
+        for p in &self.placements {
+            let end = (p.address as usize + p.size.max(1) - 1) as u16;
+            let memory = &self.config.memory[p.memory].name;
+            out += &format!("  {:<12} {:<12} ${:04X}-${:04X}  ${:04X}  {}\n", p.segment, memory, p.address, end, p.size, p.object);
+        }
+        out += "\nExports:\n";
+        for (name, value) in &self.symbols {
+            out += &format!("  {:<24} ${:04X}  {}\n", name, value, self.exporters[name]);
+        }
+        out
+    }
+}
+
+pub struct Linker {
+    config: Config,
+    objects: Vec<(String, Object)>,
+}
+
+impl Linker {
+    pub fn new(config: Config) -> Linker {
+        Linker { config, objects: Vec::new() }
+    }
+
+    pub fn object(mut self, name: &str, object: Object) -> Linker {
+        self.objects.push((name.to_string(), object));
+        self
+    }
+
+    pub fn link(&self) -> Result<Linked, Vec<LinkError>> {
+        let mut errors = Vec::new();
+        let config = &self.config;
+
+        for (name, object) in &self.objects {
+            for segment in &object.segments {
+                if !config.segments.iter().any(|s| s.name == segment.name) {
+                    errors.push(error(name, &format!("segment '{}' is not in the memory configuration", segment.name)));
+                }
+            }
+        }
+
+        // the segments, in the order of the configuration
+        let mut cursors: Vec<u32> = config.memory.iter().map(|m| m.start as u32).collect();
+        let mut placements = Vec::new();
+        let mut addresses: HashMap<(usize, &str), (u32, usize)> = HashMap::new(); // address and placement
+        for rule in &config.segments {
+            let memory = &config.memory[rule.memory];
+            let cursor = &mut cursors[rule.memory];
+            let mut first = true;
+            for (idx, (name, object)) in self.objects.iter().enumerate() {
+                let segment = match object.segments.iter().find(|s| s.name == rule.name) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                if let (true, Some(start)) = (first, rule.start) {
+                    if (start as u32) < *cursor {
+                        let msg = format!("segment '{}' at ${:04X} overlaps the previous ones in '{}'", rule.name, start, memory.name);
+                        errors.push(error("", &msg));
+                    }
+                    *cursor = (*cursor).max(start as u32);
+                }
+                first = false;
+                *cursor = cursor.div_ceil(rule.align) * rule.align;
+                addresses.insert((idx, segment.name.as_str()), (*cursor, placements.len()));
+                placements.push(Placement {
+                    segment: rule.name.clone(),
+                    object: name.clone(),
+                    memory: rule.memory,
+                    address: *cursor as u16,
+                    size: segment.bytes.len(),
+                });
+                *cursor += segment.bytes.len() as u32;
+            }
+        }
+        for (memory, cursor) in config.memory.iter().zip(&cursors) {
+            let end = memory.start as u32 + memory.size;
+            if *cursor > end {
+                errors.push(error("", &format!("memory area '{}' overflows by {} bytes", memory.name, cursor - end)));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // the exports
+        let mut symbols = BTreeMap::new();
+        let mut exporters = BTreeMap::new();
+        for (idx, (name, object)) in self.objects.iter().enumerate() {
+            for export in &object.exports {
+                let value = match &export.segment {
+                    Some(segment) => match addresses.get(&(idx, segment.as_str())) {
+                        Some((address, _)) => address + export.value as u32,
+                        None => {
+                            errors.push(error(name, &format!("export '{}' is in the unknown segment '{}'", export.name, segment)));
+                            continue;
+                        }
+                    },
+                    None => export.value as u32,
+                };
+                if let Some(other) = exporters.insert(export.name.clone(), name.clone()) {
+                    errors.push(error(name, &format!("'{}' is exported by {} too", export.name, other)));
+                }
+                symbols.insert(export.name.clone(), value as u16);
+            }
+        }
+        for (name, object) in &self.objects {
+            for import in &object.imports {
+                if !symbols.contains_key(&import.name) {
+                    errors.push(error(name, &format!("unresolved import '{}'", import.name)));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // the contents of the memory areas, with the relocations applied
+        let mut areas: Vec<Vec<u8>> = config.memory.iter().map(|m| vec![m.fill_value; m.size as usize]).collect();
+        for (idx, (_, object)) in self.objects.iter().enumerate() {
+            for segment in &object.segments {
+                let (address, p) = addresses[&(idx, segment.name.as_str())];
+                let offset = (address - config.memory[placements[p].memory].start as u32) as usize;
+                areas[placements[p].memory][offset..offset + segment.bytes.len()].copy_from_slice(&segment.bytes);
+            }
+        }
+        for (idx, (name, object)) in self.objects.iter().enumerate() {
+            for r in &object.relocations {
+                let base = match &r.target {
+                    Target::Segment(segment) => addresses.get(&(idx, segment.as_str())).map(|(address, _)| *address as i64),
+                    Target::Import(import) => symbols.get(import).map(|v| *v as i64),
+                };
+                let (address, p) = match (base, addresses.get(&(idx, r.segment.as_str()))) {
+                    (Some(_), Some(placed)) => *placed,
+                    _ => {
+                        errors.push(error(name, &format!("bad relocation at {}+${:04X}", r.segment, r.offset)));
+                        continue;
+                    }
+                };
+                let value = base.unwrap() + r.addend;
+                let bytes = match r.kind {
+                    RelocKind::Word if (0..=0xffff).contains(&value) => vec![value as u8, (value >> 8) as u8],
+                    RelocKind::Byte if (0..=0xff).contains(&value) => vec![value as u8],
+                    RelocKind::Low => vec![value as u8],
+                    RelocKind::High => vec![(value >> 8) as u8],
+                    RelocKind::Word | RelocKind::Byte => {
+                        let what = if r.kind == RelocKind::Word { "word" } else { "byte" };
+                        let msg = format!("value ${:X} at {}+${:04X} does not fit into a {}", value, r.segment, r.offset, what);
+                        errors.push(error(name, &msg));
+                        continue;
+                    }
+                };
+                let memory = placements[p].memory;
+                let offset = (address - config.memory[memory].start as u32) as usize + r.offset as usize;
+                areas[memory][offset..offset + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut image = Vec::new();
+        for ((memory, area), cursor) in config.memory.iter().zip(&areas).zip(&cursors) {
+            if memory.file {
+                let size = if memory.fill { area.len() } else { (cursor - memory.start as u32) as usize };
+                image.extend(&area[..size]);
+            }
+        }
+        Ok(Linked { image, symbols, placements, config: config.clone(), used: cursors, exporters })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::cpu::opcodes::*;
+
+    fn object(source: &str) -> Object {
+        Assembler::new().relocatable().assemble(source).unwrap().object.unwrap()
+    }
+
+    fn errors_of(linker: Linker) -> Vec<String> {
+        linker.link().unwrap_err().into_iter().map(|e| e.to_string()).collect()
+    }
+
+    const CONFIG: &str = "
+        MEMORY {
+            ZP:  start = $0000, size = $0100, file = \"\";
+            PRG: start = $8000, size = $0010, fill = yes, fillval = $FF;
+            VEC: start = $FFFC, size = $0004;
+        }
+        SEGMENTS {
+            ZEROPAGE: load = ZP;
+            CODE:     load = PRG;
+            RODATA:   load = PRG, align = 4;
+            VECTORS:  load = VEC;
+        }
+    ";
+
+    #[test]
+    fn test_link() {
+        let main = object("
+            .import print, msg
+            .importzp ptr
+            .export start
+            start: LDA #<msg
+                   STA ptr
+                   JSR print
+            loop:  JMP loop
+            .segment \"VECTORS\"
+            .word start, start
+        ");
+        let lib = object("
+            .export print, msg, ptr
+            .zeropage
+            .res 2
+            ptr: .res 2
+            .code
+            print: RTS
+            .rodata
+            msg: .byte \"hi\", 0
+        ");
+        let linker = Linker::new(Config::parse(CONFIG).unwrap()).object("main.o", main).object("lib.o", lib);
+        let linked = linker.link().unwrap();
+
+        // main's CODE at $8000 (10 bytes), lib's CODE at $800A, RODATA at $800C (aligned)
+        assert!(linked.image == vec![
+            LDA_A9, 0x0C, STA_85, 0x02, JSR_20, 0x0A, 0x80, JMP_4C, 0x07, 0x80,
+            RTS_60, 0xFF, b'h', b'i', 0, 0xFF,
+            0x00, 0x80, 0x00, 0x80,
+        ]);
+        assert!(linked.symbols == BTreeMap::from([
+            ("msg".to_string(), 0x800C), ("print".to_string(), 0x800A), ("ptr".to_string(), 0x0002), ("start".to_string(), 0x8000),
+        ]));
+        let map = linked.map();
+        assert!(map.contains("  PRG          $8000-$800F  used $000F of $0010\n"));
+        assert!(map.contains("  RODATA       PRG          $800C-$800E  $0003  lib.o\n"));
+        assert!(map.contains("  print                    $800A  lib.o\n"));
+    }
+
+    #[test]
+    fn test_banks() {
+        let config = Config::parse("
+            MEMORY { PRG0: start = $8000, size = 4, fill = yes; PRG1: start = $8000, size = 4; }
+            SEGMENTS { BANK0: load = PRG0; BANK1: load = PRG1, start = $8002; }
+        ").unwrap();
+        let banks = object(".segment \"BANK0\"\nhere: .word here\n.segment \"BANK1\"\nthere: .word there");
+        let linked = Linker::new(config).object("banks.o", banks).link().unwrap();
+        assert!(linked.image == vec![0x00, 0x80, 0, 0, 0, 0, 0x02, 0x80]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let config = || Config::parse(CONFIG).unwrap();
+        let big = object(".res 17");
+        assert!(errors_of(Linker::new(config()).object("a.o", big)) == vec![" error: memory area 'PRG' overflows by 1 bytes"]);
+
+        let bss = object(".bss\n.res 1");
+        assert!(errors_of(Linker::new(config()).object("a.o", bss)) == vec!["a.o: error: segment 'BSS' is not in the memory configuration"]);
+
+        let a = object(".import f\n.export g\ng: JMP f");
+        let b = object(".export g\ng: RTS");
+        assert!(errors_of(Linker::new(config()).object("a.o", a).object("b.o", b)) == vec![
+            "b.o: error: 'g' is exported by a.o too",
+            "a.o: error: unresolved import 'f'",
+        ]);
+
+        let zp = object(".importzp p\nLDA p");
+        let p = object(".export p\np: RTS");
+        assert!(errors_of(Linker::new(config()).object("a.o", zp).object("b.o", p)) == vec![
+            "a.o: error: value $8002 at CODE+$0001 does not fit into a byte",
+        ]);
+    }
+}
@@ -0,0 +1,468 @@
+// Language server
+//
+// `mos6502 lsp` speaks the language server protocol on stdin / stdout:
+//   - diagnostics: the errors of the assembler, every time a document changes
+//   - go to definition and find references for the labels, the equates and the macros
+//   - hover: the description of an instruction, the flags it changes and uses, and its
+//     addressing modes with their opcodes, sizes and cycles; the value of a symbol
+//   - completion of the mnemonics, the directives and the symbols
+// The documents are synchronized in full. The dialect is set with the initialization option
+// {"dialect": "acme"} (ca65 by default).
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use crate::asm::{references, AsmError, Assembler, Dialect, Reference, Span};
+use crate::cpu::{addressing_mode_pc_advance, AddressingMode};
+use crate::disasm::{mnemonic, Mnemonic, MNEMONICS, OPCODES};
+use crate::json::Json;
+
+// CompletionItemKind
+const KEYWORD: i64 = 14;
+const VARIABLE: i64 = 6;
+
+struct Document {
+    text: String,
+    refs: Vec<Reference>,
+    symbols: BTreeMap<String, u16>, // from the last successful assembly
+}
+
+struct Server {
+    dialect: Dialect,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+// the body of the next message (None at the end of the input)
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// serves until the exit notification, the result is the exit code
+pub fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> io::Result<i32> {
+    let mut server = Server { dialect: Dialect::Ca65, documents: HashMap::new(), shutdown: false };
+    while let Some(body) = read_message(&mut input)? {
+        let message = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                write_message(&mut output, &error_response(Json::Null, -32700, &e))?;
+                continue;
+            }
+        };
+        if message.get("method").as_str() == Some("exit") {
+            return Ok(if server.shutdown { 0 } else { 1 });
+        }
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(1)
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    let error = Json::object([("code", Json::from(code)), ("message", Json::from(message))]);
+    Json::object([("jsonrpc", Json::from("2.0")), ("id", id), ("error", error)])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object([("jsonrpc", Json::from("2.0")), ("method", Json::from(method)), ("params", params)])
+}
+
+fn range(span: Span) -> Json {
+    let position = |column: usize| {
+        Json::object([("line", Json::from(span.line.saturating_sub(1))), ("character", Json::from(column.saturating_sub(1)))])
+    };
+    Json::object([("start", position(span.column)), ("end", position(span.column + span.len))])
+}
+
+fn location(uri: &str, span: Span) -> Json {
+    Json::object([("uri", Json::from(uri)), ("range", range(span))])
+}
+
+// file:///path -> /path
+fn uri_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut chars = path.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+// the identifier around a position
+fn word_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let chars: Vec<char> = text.lines().nth(line)?.chars().collect();
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut start = character.min(chars.len());
+    while start > 0 && is_word(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = character.min(chars.len());
+    while end < chars.len() && is_word(chars[end]) {
+        end += 1;
+    }
+    (start < end).then(|| chars[start..end].iter().collect())
+}
+
+fn example(name: &str, mode: AddressingMode) -> String {
+    use AddressingMode::*;
+    let operand = match mode {
+        Implied => return name.to_string(),
+        Accumulator => "A",
+        Immediate => "#$44",
+        ZeroPage => "$44",
+        ZeroPageX => "$44,X",
+        ZeroPageY => "$44,Y",
+        Absolute => "$4400",
+        AbsoluteX => "$4400,X",
+        AbsoluteY => "$4400,Y",
+        AbsoluteIndirect => "($4400)",
+        ZeroPageXIndirect => "($44,X)",
+        ZeroPageIndirectY => "($44),Y",
+        Relative => "label",
+    };
+    format!("{} {}", name, operand)
+}
+
+// markdown: the description, the flags and a table of the addressing modes
+fn instruction_hover(m: &Mnemonic) -> String {
+    let flags = |s: &str| if s.is_empty() { "-".to_string() } else { s.chars().map(String::from).collect::<Vec<_>>().join(" ") };
+    let mut out = format!("**{}** - {}\n\nflags changed: {}, used: {}\n\n", m.name, m.description, flags(m.writes), flags(m.reads));
+    out += "| | opcode | bytes | cycles |\n|---|---|---|---|\n";
+    for (code, o) in OPCODES.iter().enumerate().filter(|(_, o)| o.mnemonic == m.name) {
+        let illegal = if o.illegal { " (undocumented)" } else { "" };
+        let bytes = 1 + addressing_mode_pc_advance(o.mode);
+        out += &format!("| `{}` | ${:02X}{} | {} | {} |\n", example(m.name, o.mode), code, illegal, bytes, o.cycles_text().replace('*', "\\*"));
+    }
+    if OPCODES.iter().any(|o| o.mnemonic == m.name && o.page_penalty()) {
+        out += "\n\\* +1 if a page is crossed, \\*\\* +1 if the branch is taken, +2 if to another page\n";
+    }
+    out
+}
+
+fn diagnostic(e: &AsmError) -> Json {
+    let mut message = e.message.clone();
+    if let Some(note) = &e.note {
+        message = format!("{}\n{}", message, note);
+    }
+    // the errors in the included files are shown at the top
+    let span = if e.file.is_empty() {
+        e.span
+    } else {
+        message = format!("{}:{}: {}", e.file, e.span.line, message);
+        Span::new(1, 1, 0)
+    };
+    Json::object([
+        ("range", range(span)),
+        ("severity", Json::from(1i64)),
+        ("source", Json::from("mos6502")),
+        ("message", Json::from(message)),
+    ])
+}
+
+impl Server {
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let id = message.get("id").clone();
+        let params = message.get("params");
+        let method = message.get("method").as_str().unwrap_or("");
+        let result = match method {
+            "initialize" => {
+                let options = params.get("initializationOptions");
+                if let Some(dialect) = options.get("dialect").as_str().and_then(Dialect::from_name) {
+                    self.dialect = dialect;
+                }
+                let trigger = Json::from(vec![Json::from(self.dialect.directive_prefix().to_string())]);
+                let capabilities = Json::object([
+                    ("textDocumentSync", Json::from(1i64)),
+                    ("definitionProvider", Json::from(true)),
+                    ("referencesProvider", Json::from(true)),
+                    ("hoverProvider", Json::from(true)),
+                    ("completionProvider", Json::object([("triggerCharacters", trigger)])),
+                ]);
+                Json::object([("capabilities", capabilities), ("serverInfo", Json::object([("name", Json::from("mos6502"))]))])
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument");
+                return self.update(document.get("uri").as_str().unwrap_or(""), document.get("text").as_str().unwrap_or(""));
+            }
+            "textDocument/didChange" => {
+                let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+                match params.get("contentChanges").as_array().and_then(|changes| changes.last()) {
+                    Some(change) => return self.update(uri, change.get("text").as_str().unwrap_or("")),
+                    None => return Vec::new(),
+                }
+            }
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+                self.documents.remove(uri);
+                let params = Json::object([("uri", Json::from(uri)), ("diagnostics", Json::Array(Vec::new()))]);
+                return vec![notification("textDocument/publishDiagnostics", params)];
+            }
+            "textDocument/definition" => self.locations(params, true, false),
+            "textDocument/references" => {
+                let declaration = params.get("context").get("includeDeclaration") == &Json::Bool(true);
+                self.locations(params, declaration, true)
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            _ if id.is_null() => return Vec::new(), // the other notifications
+            _ => return vec![error_response(id, -32601, &format!("unknown method '{}'", method))],
+        };
+        if id.is_null() {
+            return Vec::new();
+        }
+        vec![Json::object([("jsonrpc", Json::from("2.0")), ("id", id), ("result", result)])]
+    }
+
+    // assembles the new text, the result is the diagnostics notification
+    fn update(&mut self, uri: &str, text: &str) -> Vec<Json> {
+        let mut assembler = Assembler::new().dialect(self.dialect);
+        if let Some(dir) = uri_path(uri).as_ref().and_then(|p| p.parent()) {
+            assembler = assembler.include_dir(dir);
+        }
+        let previous = self.documents.remove(uri).map(|d| d.symbols).unwrap_or_default();
+        let (symbols, diagnostics) = match assembler.assemble(text) {
+            Ok(program) => (program.symbols, Vec::new()),
+            Err(errors) => (previous, errors.iter().map(diagnostic).collect()),
+        };
+        let refs = references(text, self.dialect);
+        self.documents.insert(uri.to_string(), Document { text: text.to_string(), refs, symbols });
+
+        let params = Json::object([("uri", Json::from(uri)), ("diagnostics", Json::Array(diagnostics))]);
+        vec![notification("textDocument/publishDiagnostics", params)]
+    }
+
+    // the document and the line and character of a request
+    fn position<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, usize, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let position = params.get("position");
+        let line = position.get("line").as_i64()? as usize;
+        let character = position.get("character").as_i64()? as usize;
+        Some((uri, self.documents.get(uri)?, line, character))
+    }
+
+    fn reference_at(document: &Document, line: usize, character: usize) -> Option<&Reference> {
+        document.refs.iter().find(|r| {
+            r.span.line == line + 1 && (r.span.column - 1..=r.span.column - 1 + r.span.len).contains(&character)
+        })
+    }
+
+    fn locations(&self, params: &Json, definitions: bool, uses: bool) -> Json {
+        let (uri, document, line, character) = match self.position(params) {
+            Some(p) => p,
+            None => return Json::Null,
+        };
+        let name = match Server::reference_at(document, line, character) {
+            Some(r) => &r.name,
+            None => return Json::Null,
+        };
+        let locations: Vec<Json> = document.refs.iter()
+            .filter(|r| r.name == *name && (if r.definition { definitions } else { uses }))
+            .map(|r| location(uri, r.span))
+            .collect();
+        if locations.is_empty() { Json::Null } else { Json::Array(locations) }
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let (_, document, line, character) = match self.position(params) {
+            Some(p) => p,
+            None => return Json::Null,
+        };
+        let text = match word_at(&document.text, line, character).and_then(|word| mnemonic(&word)) {
+            Some(m) => instruction_hover(m),
+            None => match Server::reference_at(document, line, character) {
+                Some(r) => match document.symbols.get(&r.name) {
+                    Some(value) => format!("`{}` = ${:04X} ({})", r.name, value, value),
+                    None => return Json::Null,
+                },
+                None => return Json::Null,
+            },
+        };
+        let contents = Json::object([("kind", Json::from("markdown")), ("value", Json::from(text))]);
+        Json::object([("contents", contents)])
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let item = |label: &str, kind: i64, detail: &str| {
+            Json::object([("label", Json::from(label)), ("kind", Json::from(kind)), ("detail", Json::from(detail))])
+        };
+        let mut items: Vec<Json> = MNEMONICS.iter().map(|m| item(m.name, KEYWORD, m.description)).collect();
+        items.extend(self.dialect.directives().iter().map(|d| item(d, KEYWORD, "directive")));
+
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        if let Some(document) = self.documents.get(uri) {
+            let mut names: Vec<&str> = document.refs.iter()
+                .filter(|r| r.definition && !r.name.contains('@'))
+                .map(|r| r.name.as_str())
+                .collect();
+            names.sort();
+            names.dedup();
+            items.extend(names.into_iter().map(|name| item(name, VARIABLE, "symbol")));
+        }
+        Json::Array(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///tmp/test%20dir/main.s";
+
+    fn request(id: i64, method: &str, params: Json) -> Json {
+        Json::object([("jsonrpc", Json::from("2.0")), ("id", Json::from(id)), ("method", Json::from(method)), ("params", params)])
+    }
+
+    fn position(line: i64, character: i64) -> Json {
+        Json::object([
+            ("textDocument", Json::object([("uri", Json::from(URI))])),
+            ("position", Json::object([("line", Json::from(line)), ("character", Json::from(character))])),
+        ])
+    }
+
+    fn open(text: &str) -> Json {
+        let document = Json::object([("uri", Json::from(URI)), ("languageId", Json::from("asm")), ("version", Json::from(1i64)), ("text", Json::from(text))]);
+        notification("textDocument/didOpen", Json::object([("textDocument", document)]))
+    }
+
+    // the exit code and the messages from the server
+    fn session(messages: &[Json]) -> (i32, Vec<Json>) {
+        let mut input = Vec::new();
+        for m in messages {
+            write_message(&mut input, m).unwrap();
+        }
+        let mut output = Vec::new();
+        let code = run(Cursor::new(input), &mut output).unwrap();
+        let mut replies = Vec::new();
+        let mut output = Cursor::new(output);
+        while let Some(body) = read_message(&mut output).unwrap() {
+            replies.push(Json::parse(&body).unwrap());
+        }
+        (code, replies)
+    }
+
+    const SOURCE: &str = "start:  LDX #COUNT\n@loop:  DEX\n        BNE @loop\n        JMP start\nCOUNT = 3\n";
+
+    #[test]
+    fn test_lifecycle() {
+        let init = request(1, "initialize", Json::object([("initializationOptions", Json::object([("dialect", Json::from("acme"))]))]));
+        let (code, replies) = session(&[init, notification("initialized", Json::object([])), request(2, "foo", Json::Null), request(3, "shutdown", Json::Null), notification("exit", Json::Null)]);
+        assert!(code == 0);
+        assert!(replies.len() == 3);
+        let capabilities = replies[0].get("result").get("capabilities");
+        assert!(capabilities.get("hoverProvider") == &Json::Bool(true));
+        assert!(capabilities.get("completionProvider").get("triggerCharacters") == &Json::from(vec![Json::from("!")]));
+        assert!(replies[1].get("error").get("code").as_i64() == Some(-32601));
+        assert!(replies[2].get("id").as_i64() == Some(3) && replies[2].get("result").is_null());
+
+        let (code, _) = session(&[notification("exit", Json::Null)]);
+        assert!(code == 1);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (_, replies) = session(&[open("  LDA #1\n  BNE nowhere\n  FOO\n")]);
+        assert!(replies.len() == 1);
+        assert!(replies[0].get("method").as_str() == Some("textDocument/publishDiagnostics"));
+        let diagnostics = replies[0].get("params").get("diagnostics").as_array().unwrap();
+        assert!(diagnostics.len() == 2);
+        assert!(diagnostics[0].get("message").as_str() == Some("undefined symbol 'nowhere'"));
+        assert!(diagnostics[0].get("range").to_string() == r#"{"start":{"line":1,"character":6},"end":{"line":1,"character":13}}"#);
+        assert!(diagnostics[1].get("message").as_str() == Some("unknown instruction 'FOO'"));
+
+        let (_, replies) = session(&[open(SOURCE)]);
+        assert!(replies[0].get("params").get("diagnostics").as_array() == Some(&[][..]));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let references = Json::object([
+            ("textDocument", Json::object([("uri", Json::from(URI))])),
+            ("position", Json::object([("line", Json::from(2i64)), ("character", Json::from(14i64))])),
+            ("context", Json::object([("includeDeclaration", Json::from(true))])),
+        ]);
+        let (_, replies) = session(&[
+            open(SOURCE),
+            request(1, "textDocument/definition", position(0, 13)), // COUNT
+            request(2, "textDocument/references", references),     // @loop
+            request(3, "textDocument/definition", position(0, 9)),  // LDX
+        ]);
+        let range = |r: &Json| r.get("range").get("start").to_string();
+        let result = replies[1].get("result").as_array().unwrap();
+        assert!(result.len() == 1 && range(&result[0]) == r#"{"line":4,"character":0}"#);
+        assert!(result[0].get("uri").as_str() == Some(URI));
+        let result = replies[2].get("result").as_array().unwrap();
+        assert!(result.iter().map(range).collect::<Vec<_>>() == vec![r#"{"line":1,"character":0}"#, r#"{"line":2,"character":12}"#]);
+        assert!(replies[3].get("result").is_null());
+    }
+
+    #[test]
+    fn test_hover() {
+        let (_, replies) = session(&[
+            open(SOURCE),
+            request(1, "textDocument/hover", position(0, 9)),  // LDX
+            request(2, "textDocument/hover", position(1, 2)),  // @loop
+            request(3, "textDocument/hover", position(4, 10)), // 3
+        ]);
+        let value = |r: &Json| r.get("result").get("contents").get("value").as_str().unwrap().to_string();
+        let ldx = value(&replies[1]);
+        assert!(ldx.starts_with("**LDX** - Load X with memory\n\nflags changed: N Z, used: -\n"));
+        assert!(ldx.contains("| `LDX #$44` | $A2 | 2 | 2 |\n"));
+        assert!(ldx.contains("| `LDX $4400,Y` | $BE | 3 | 4\\* |\n"));
+        assert!(value(&replies[2]) == "`start@loop` = $0002 (2)");
+        assert!(replies[3].get("result").is_null());
+
+        let nop = instruction_hover(mnemonic("NOP").unwrap());
+        assert!(nop.contains("| `NOP` | $EA | 1 | 2 |\n") && nop.contains("| `NOP $44` | $04 (undocumented) | 2 | 3 |\n"));
+    }
+
+    #[test]
+    fn test_completion() {
+        let (_, replies) = session(&[open(SOURCE), request(1, "textDocument/completion", position(3, 8))]);
+        let items = replies[1].get("result").as_array().unwrap();
+        let labels: Vec<&str> = items.iter().map(|i| i.get("label").as_str().unwrap()).collect();
+        assert!(labels.contains(&"ADC") && labels.contains(&".byte") && labels.contains(&"COUNT") && labels.contains(&"start"));
+        assert!(!labels.iter().any(|l| l.contains('@')));
+    }
+
+    #[test]
+    fn test_uri_path() {
+        assert!(uri_path(URI) == Some(PathBuf::from("/tmp/test dir/main.s")));
+        assert!(uri_path("untitled:1").is_none());
+    }
+}
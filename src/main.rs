@@ -1,5 +1,895 @@
+mod asm;
+mod cfg;
+mod coverage;
 mod cpu;
+mod dbg;
+mod disasm;
+mod fuzz;
+mod harness;
+mod ir;
+mod json;
+mod link;
+mod lsp;
+mod mutate;
+mod profile;
+mod rng;
+mod roms;
+mod shadow;
+mod singlestep;
+mod stack;
+mod step;
+mod suite;
+mod superopt;
+mod symbols;
+#[cfg(test)]
+mod tempdir;
+mod toml;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+
+use cpu::Cpu;
+
+fn usage() -> ! {
+    eprintln!("usage:");
+    eprintln!("    mos6502 asm <source> <output> [origin] [-l listing] [-I dir]... [-d ca65|acme|64tass] [-c]");
+    eprintln!("                                                 -- assemble into a raw binary image (-c: a relocatable object)");
+    eprintln!("    mos6502 link <object>... -o <output> [-C config] [-m map]");
+    eprintln!("                                                 -- link objects into an image");
+    eprintln!("    mos6502 lint <source> [origin] [-I dir]... [-d ca65|acme|64tass] [--nes] [--pointers]");
+    eprintln!("                                                 -- warnings about timing, undocumented opcodes, ... (--nes: 2A03,");
+    eprintln!("                                                    --pointers: the page crossings of (zp),Y too)");
+    eprintln!("    mos6502 disasm <image> [origin]              -- disassemble a raw binary image (origin defaults to $0000)");
+    eprintln!("    mos6502 source <image> <origin> [entry...]   -- recursive disassembly into re-assemblable source");
+    eprintln!("    mos6502 cfg <image> <origin> [entry...] [-o dir]");
+    eprintln!("                                                 -- control flow graph, one Graphviz file per function");
+    eprintln!("    mos6502 stack <image> <origin> [entry...]    -- worst case stack usage from the vectors and the entry points");
+    eprintln!("    mos6502 ir <image> <origin> [entry...]       -- the instructions as register/flag/memory operations");
+    eprintln!("    mos6502 superopt [target] [--spec out=expr]... [--out A,X,C,...] [-n length] [--cycles|--bytes]");
+    eprintln!("                     [--illegal] [--exhaustive] [--samples n]");
+    eprintln!("                                                 -- shortest equivalent of \"LDA #0; TAX\" or of the effects");
+    eprintln!("    mos6502 call <image> <origin> <address> [A=n|C=0|$10=1,2,...]... [--expect A=n|C=1|$10=3|cycles=n ...]");
+    eprintln!("                 [--max-cycles n] [--max-instructions n]");
+    eprintln!("                                                 -- run a subroutine until its RTS, check the results");
+    eprintln!("    mos6502 test <file or directory>... [--junit report.xml] [--lcov coverage.info]");
+    eprintln!("                                                 -- run the tests of the *.toml files");
+    eprintln!("    mos6502 mutate <file or directory>...        -- the mutants of the programs which the tests don't kill");
+    eprintln!("    mos6502 profile <test file> [test] [--folded out.folded] [--top n]");
+    eprintln!("                                                 -- cycles by subroutine, loop and address of the tests");
+    eprintln!("    mos6502 dormann <image> [functional|decimal] [--origin a] [--start a] [--success a] [--max n]");
+    eprintln!("                                                 -- run one of Klaus Dormann's test binaries");
+    eprintln!("    mos6502 blargg <rom>... [--max n]            -- run blargg's NES test ROMs, print their text and results");
+    eprintln!("    mos6502 nestest <nestest.nes> <nestest.log>  -- run nestest.nes from $C000 against its golden log");
+    eprintln!("    mos6502 singlestep <file or directory>...    -- run the per-opcode JSON tests of SingleStepTests");
+    eprintln!("    mos6502 fuzz [step|differential] [--runs n] [--seed n] [--length n]");
+    eprintln!("                                                 -- run the checks of the fuzz targets on random inputs");
+    eprintln!("    mos6502 symbols <file>... [--bank n] [-o labels]");
+    eprintln!("                                                 -- convert ca65 .dbg, FCEUX .nl, Mesen .mlb and VICE files to VICE labels");
+    eprintln!("    mos6502 lsp                                  -- language server on stdin/stdout");
+    eprintln!("disasm, call, profile, dormann, blargg and nestest take [--symbols file]... [--bank n] to name the addresses");
+    process::exit(1);
+}
+
+// numbers are accepted in the formats from docs.md: $FA, %00001111, 123 (and 0xFA)
+fn parse_number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix('%') {
+        u16::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+// the names of the addresses in the symbol files (--symbols), for the bank given with --bank
+fn read_symbols(files: &[&String]) -> symbols::Symbols {
+    let mut symbols = symbols::Symbols::new();
+    for file in files {
+        symbols.merge(symbols::Symbols::load(std::path::Path::new(file)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }));
+    }
+    symbols
+}
+
+fn load_symbols(files: &[&String], bank: Option<u16>) -> HashMap<u16, String> {
+    read_symbols(files).names(bank)
+}
+
+fn cmd_asm(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut listing = None;
+    let mut object = false;
+    let mut assembler = asm::Assembler::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" => listing = Some(args.next().unwrap_or_else(|| usage())),
+            "-I" => assembler = assembler.include_dir(args.next().unwrap_or_else(|| usage())),
+            "-d" => {
+                let dialect = args.next().and_then(|d| asm::Dialect::from_name(d)).unwrap_or_else(|| usage());
+                assembler = assembler.dialect(dialect);
+            }
+            "-c" => object = true,
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 || positional.len() > 3 {
+        usage();
+    }
+    if object {
+        assembler = assembler.relocatable();
+    }
+    if let Some(origin) = positional.get(2) {
+        assembler = assembler.origin(parse_number(origin).unwrap_or_else(|| usage()));
+    }
+
+    let program = assembler.assemble_file(positional[0]).unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        process::exit(1);
+    });
+    for text in &program.output {
+        eprintln!("{}", text);
+    }
+    let output = match &program.object {
+        Some(object) => object.to_string().into_bytes(),
+        None => program.image().map(|(_, image)| image).unwrap_or_default(),
+    };
+    fs::write(positional[1], output).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[1], e);
+        process::exit(1);
+    });
+    if let Some(path) = listing {
+        fs::write(path, program.listing()).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+    }
+}
+
+fn cmd_link(args: &[String]) {
+    let mut objects = Vec::new();
+    let mut output = None;
+    let mut config = None;
+    let mut map = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-C" => config = Some(args.next().unwrap_or_else(|| usage())),
+            "-m" => map = Some(args.next().unwrap_or_else(|| usage())),
+            _ => objects.push(arg),
+        }
+    }
+    let output = match output {
+        Some(output) if !objects.is_empty() => output,
+        _ => usage(),
+    };
+
+    let fail = |errors: Vec<link::LinkError>| -> ! {
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        process::exit(1);
+    };
+    let config = match config {
+        Some(path) => link::load_config(path),
+        None => link::Config::parse(link::DEFAULT_CONFIG),
+    };
+    let mut linker = link::Linker::new(config.unwrap_or_else(|e| fail(vec![e])));
+    for path in objects {
+        let object = link::load_object(path).unwrap_or_else(|e| fail(vec![e]));
+        linker = linker.object(path, object);
+    }
+    let linked = linker.link().unwrap_or_else(|errors| fail(errors));
+
+    fs::write(output, &linked.image).unwrap_or_else(|e| {
+        eprintln!("{}: {}", output, e);
+        process::exit(1);
+    });
+    if let Some(path) = map {
+        fs::write(path, linked.map()).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+    }
+}
+
+fn cmd_lint(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut options = asm::LintOptions::default();
+    let mut assembler = asm::Assembler::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => assembler = assembler.include_dir(args.next().unwrap_or_else(|| usage())),
+            "-d" => {
+                let dialect = args.next().and_then(|d| asm::Dialect::from_name(d)).unwrap_or_else(|| usage());
+                assembler = assembler.dialect(dialect);
+            }
+            "--nes" => options.nes = true,
+            "--pointers" => options.pointers = true,
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() || positional.len() > 2 {
+        usage();
+    }
+    if let Some(origin) = positional.get(1) {
+        assembler = assembler.origin(parse_number(origin).unwrap_or_else(|| usage()));
+    }
+
+    let program = assembler.assemble_file(positional[0]).unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        process::exit(1);
+    });
+    let warnings = asm::lint(&program, options);
+    for w in &warnings {
+        println!("{}", w);
+    }
+    if !warnings.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn cmd_disasm(args: &[String]) {
+    let mut positional = Vec::new();
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    let image = match positional.first() {
+        Some(path) => read_file(path),
+        None => usage(),
+    };
+    let origin = match positional.get(1) {
+        Some(s) => parse_number(s).unwrap_or_else(|| usage()),
+        None => 0,
+    };
+    let symbols = load_symbols(&files, bank);
+    if image.is_empty() || origin as usize + image.len() > 0x10000 {
+        eprintln!("image does not fit into the memory");
+        process::exit(1);
+    }
+
+    let mut cpu = Cpu::new();
+    cpu.patch_memory(origin as usize, &image);
+    let end = origin as usize + image.len() - 1;
+    for line in disasm::disassemble(&cpu, origin, end as u16, Some(&symbols)) {
+        println!("{}", line);
+    }
+}
+
+fn cmd_source(args: &[String]) {
+    if args.len() < 2 {
+        usage();
+    }
+    let image = read_file(&args[0]);
+    let origin = parse_number(&args[1]).unwrap_or_else(|| usage());
+    let entries: Vec<u16> = args[2..].iter().map(|s| parse_number(s).unwrap_or_else(|| usage())).collect();
+    if origin as usize + image.len() > 0x10000 {
+        eprintln!("image does not fit into the memory");
+        process::exit(1);
+    }
+
+    print!("{}", disasm::disassemble_source(&image, origin, &entries));
+}
+
+fn cmd_stack(args: &[String]) {
+    if args.len() < 2 {
+        usage();
+    }
+    let image = read_file(&args[0]);
+    let origin = parse_number(&args[1]).unwrap_or_else(|| usage());
+    let entries: Vec<u16> = args[2..].iter().map(|s| parse_number(s).unwrap_or_else(|| usage())).collect();
+    if origin as usize + image.len() > 0x10000 {
+        eprintln!("image does not fit into the memory");
+        process::exit(1);
+    }
+
+    let report = stack::analyze(&image, origin, &entries);
+    print!("{}", report);
+    if !report.problems.is_empty() || report.worst_case().is_none_or(|depth| depth > 0x100) {
+        process::exit(1);
+    }
+}
+
+fn cmd_cfg(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut dir = ".".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => dir = args.next().unwrap_or_else(|| usage()).clone(),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        usage();
+    }
+    let image = read_file(positional[0]);
+    let origin = parse_number(positional[1]).unwrap_or_else(|| usage());
+    let entries: Vec<u16> = positional[2..].iter().map(|s| parse_number(s).unwrap_or_else(|| usage())).collect();
+    if origin as usize + image.len() > 0x10000 {
+        eprintln!("image does not fit into the memory");
+        process::exit(1);
+    }
+
+    let cfg = cfg::Cfg::build(&image, origin, &entries);
+    fs::create_dir_all(&dir).unwrap_or_else(|e| {
+        eprintln!("{}: {}", dir, e);
+        process::exit(1);
+    });
+    for function in &cfg.functions {
+        let path = std::path::Path::new(&dir).join(format!("{}.dot", function.name));
+        fs::write(&path, cfg.dot(function)).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        });
+    }
+    print!("{}", cfg);
+}
+
+fn cmd_ir(args: &[String]) {
+    if args.len() < 2 {
+        usage();
+    }
+    let image = read_file(&args[0]);
+    let origin = parse_number(&args[1]).unwrap_or_else(|| usage());
+    let entries: Vec<u16> = args[2..].iter().map(|s| parse_number(s).unwrap_or_else(|| usage())).collect();
+    if origin as usize + image.len() > 0x10000 {
+        eprintln!("image does not fit into the memory");
+        process::exit(1);
+    }
+
+    print!("{}", ir::lift_cfg(&cfg::Cfg::build(&image, origin, &entries)));
+}
+
+fn cmd_superopt(args: &[String]) {
+    let mut target = None;
+    let mut effects = Vec::new();
+    let mut outputs = None;
+    let mut length = 2;
+    let mut cost = superopt::Cost::Length;
+    let mut illegal = false;
+    let mut exhaustive = false;
+    let mut samples = 500;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--spec" => {
+                let spec = args.next().unwrap_or_else(|| usage());
+                let (output, expr) = spec.split_once('=').unwrap_or_else(|| usage());
+                let output = superopt::Location::parse(output).unwrap_or_else(|| {
+                    eprintln!("{}: not a register, a flag or an address", output.trim());
+                    process::exit(1);
+                });
+                let formula = asm::Formula::parse(expr).unwrap_or_else(|e| {
+                    eprintln!("{}:{}", spec, e);
+                    process::exit(1);
+                });
+                effects.push((output, formula));
+            }
+            "--out" => {
+                let list = args.next().unwrap_or_else(|| usage());
+                outputs = Some(list.split(',').filter(|s| !s.trim().is_empty()).map(|s| superopt::Location::parse(s).unwrap_or_else(|| usage())).collect());
+            }
+            "-n" => length = args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage()),
+            "--cycles" => cost = superopt::Cost::Cycles,
+            "--bytes" => cost = superopt::Cost::Bytes,
+            "--illegal" => illegal = true,
+            "--exhaustive" => exhaustive = true,
+            "--samples" => samples = args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage()),
+            _ if target.is_none() => target = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    // the statements of the target are separated by ';'
+    let goal = match (target, effects.is_empty()) {
+        (Some(text), true) => {
+            let source = text.replace(';', "\n");
+            let program = asm::Assembler::new().assemble(&source).unwrap_or_else(|errors| {
+                for e in errors {
+                    eprintln!("{}", e);
+                }
+                process::exit(1);
+            });
+            let bytes = program.image().map(|(_, bytes)| bytes).unwrap_or_default();
+            let sequence = superopt::Sequence::new(bytes.clone());
+            println!("target: {} ({})", sequence, sequence_cost(&sequence));
+            superopt::Goal::Sequence(bytes)
+        }
+        (None, false) => superopt::Goal::Effects(effects),
+        _ => usage(),
+    };
+
+    let mut optimizer = superopt::Superoptimizer::new(goal).length(length).cost(cost).illegal(illegal).exhaustive(exhaustive).samples(samples);
+    if let Some(outputs) = outputs {
+        optimizer = optimizer.outputs(outputs);
+    }
+    match optimizer.run() {
+        Ok(found) if found.is_empty() => {
+            println!("no equivalent sequence of up to {} instructions", length);
+            process::exit(1);
+        }
+        Ok(found) => {
+            for sequence in &found {
+                println!("{} ({})", sequence, sequence_cost(sequence));
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn sequence_cost(sequence: &superopt::Sequence) -> String {
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    format!(
+        "{} instruction{}, {} cycles, {} byte{}",
+        sequence.length, plural(sequence.length), sequence.cycles, sequence.bytes.len(), plural(sequence.bytes.len())
+    )
+}
+
+fn cmd_call(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut values = Vec::new();
+    let mut expected = Vec::new();
+    let mut expecting = false;
+    let mut max_cycles = None;
+    let mut max_instructions = None;
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--expect" => expecting = true,
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            "--max-cycles" => max_cycles = Some(args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())),
+            "--max-instructions" => max_instructions = Some(args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())),
+            _ if arg.contains('=') && expecting => expected.push(arg),
+            _ if arg.contains('=') => values.push(arg),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 3 {
+        usage();
+    }
+    let image = read_file(positional[0]);
+    let origin = parse_number(positional[1]).unwrap_or_else(|| usage());
+    let entry = parse_number(positional[2]).unwrap_or_else(|| usage());
+
+    // A=5, C=1, $10=1,2,3 (and cycles=40 for the expectations)
+    let parse = |arg: &String| {
+        let (name, value) = arg.split_once('=').unwrap();
+        let bytes: Option<Vec<u8>> = value.split(',').map(|v| parse_number(v.trim()).and_then(|v| u8::try_from(v).ok())).collect();
+        match (superopt::Location::parse(name), bytes) {
+            (Some(location), Some(bytes)) if bytes.len() == 1 || matches!(location, superopt::Location::Memory(_)) => (location, bytes),
+            _ => {
+                eprintln!("{}: expected register=byte, flag=0|1 or address=byte,...", arg);
+                process::exit(1);
+            }
+        }
+    };
+    let symbols = load_symbols(&files, bank);
+    let mut harness = harness::Harness::new(&image, origin).call(entry).symbols(&symbols);
+    for arg in values {
+        harness = match parse(arg) {
+            (superopt::Location::Reg(ir::Reg::A), v) => harness.with_a(v[0]),
+            (superopt::Location::Reg(ir::Reg::X), v) => harness.with_x(v[0]),
+            (superopt::Location::Reg(ir::Reg::Y), v) => harness.with_y(v[0]),
+            (superopt::Location::Reg(ir::Reg::S), v) => harness.with_s(v[0]),
+            (superopt::Location::Flag(flag), v) => harness.with_flag(flag, v[0] != 0),
+            (superopt::Location::Memory(address), v) => harness.with_mem(address, &v),
+        };
+    }
+    if let Some(cycles) = max_cycles {
+        harness = harness.max_cycles(cycles);
+    }
+    if let Some(instructions) = max_instructions {
+        harness = harness.max_instructions(instructions);
+    }
+    let mut expect = harness::Expect::new();
+    for arg in expected {
+        if let Some(cycles) = arg.strip_prefix("cycles=") {
+            expect = expect.max_cycles(cycles.parse().unwrap_or_else(|_| usage()));
+            continue;
+        }
+        expect = match parse(arg) {
+            (superopt::Location::Reg(ir::Reg::A), v) => expect.a(v[0]),
+            (superopt::Location::Reg(ir::Reg::X), v) => expect.x(v[0]),
+            (superopt::Location::Reg(ir::Reg::Y), v) => expect.y(v[0]),
+            (superopt::Location::Reg(ir::Reg::S), v) => expect.s(v[0]),
+            (superopt::Location::Flag(flag), v) => expect.flag(flag, v[0] != 0),
+            (superopt::Location::Memory(address), v) => expect.mem(address, &v),
+        };
+    }
+
+    let outcome = harness.run().unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    });
+    print!("{}", outcome);
+    if let Err(diffs) = outcome.check(&expect) {
+        println!("{}", diffs);
+        process::exit(1);
+    }
+}
+
+fn cmd_test(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut junit = None;
+    let mut lcov = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--junit" => junit = Some(args.next().unwrap_or_else(|| usage())),
+            "--lcov" => lcov = Some(args.next().unwrap_or_else(|| usage())),
+            _ => paths.push(std::path::PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        usage();
+    }
+
+    let files = suite::find(&paths).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let report = suite::Report::run(&files);
+    print!("{}", report);
+    for (path, text) in [(junit, report.junit()), (lcov, report.coverage.to_string())] {
+        if let Some(path) = path {
+            fs::write(path, text).unwrap_or_else(|e| {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            });
+        }
+    }
+    let (_, failed, errors) = report.counts();
+    if failed + errors > 0 {
+        process::exit(1);
+    }
+}
+
+fn cmd_mutate(args: &[String]) {
+    if args.is_empty() {
+        usage();
+    }
+    let paths: Vec<std::path::PathBuf> = args.iter().map(std::path::PathBuf::from).collect();
+    let files = suite::find(&paths).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let report = mutate::Report::run(&files);
+    print!("{}", report);
+    let (_, survived, errors) = report.counts();
+    if survived + errors > 0 {
+        process::exit(1);
+    }
+}
+
+fn cmd_profile(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut folded = None;
+    let mut top = 10;
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--folded" => folded = Some(args.next().unwrap_or_else(|| usage())),
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            "--top" => top = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() || positional.len() > 2 {
+        usage();
+    }
+
+    let suite = suite::Suite::load(std::path::Path::new(positional[0])).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[0], e);
+        process::exit(1);
+    });
+    let results = suite.run();
+    let mut profile = profile::Profile::default();
+    let mut found = false;
+    for (name, verdict) in results.iter().filter(|(name, _)| positional.get(1).is_none_or(|test| *test == name)) {
+        found = true;
+        match verdict {
+            suite::Verdict::Pass(outcome) | suite::Verdict::Fail(outcome, _) => profile.merge(&outcome.profile),
+            suite::Verdict::Error(e) => eprintln!("{}: {}", name, e),
+        }
+    }
+    if !found {
+        eprintln!("{}: no test named {}", positional[0], positional[1]);
+        process::exit(1);
+    }
+    // the labels of the program first
+    let mut symbols = suite.names().clone();
+    for (address, name) in load_symbols(&files, bank) {
+        symbols.entry(address).or_insert(name);
+    }
+    print!("{}", profile.report(&symbols, top));
+    if let Some(path) = folded {
+        fs::write(path, profile.folded(&symbols)).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        });
+    }
+}
+
+fn cmd_dormann(args: &[String]) {
+    let mut positional = Vec::new();
+    let mut dormann = None;
+    let mut overrides = Vec::new();
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            "--origin" | "--start" | "--success" | "--max" => overrides.push((arg.as_str(), args.next().unwrap_or_else(|| usage()))),
+            name if !positional.is_empty() && dormann.is_none() => dormann = Some(roms::Dormann::preset(name).unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 1 {
+        usage();
+    }
+    let mut dormann = dormann.unwrap_or(roms::FUNCTIONAL);
+    for (option, value) in overrides {
+        match option {
+            "--max" => dormann.max_instructions = value.parse().unwrap_or_else(|_| usage()),
+            _ => {
+                let address = parse_number(value).unwrap_or_else(|| usage());
+                match option {
+                    "--origin" => dormann.origin = address,
+                    "--start" => dormann.start = address,
+                    _ => dormann.success = Some(address),
+                }
+            }
+        }
+    }
+
+    match dormann.run(&read_file(positional[0]), &load_symbols(&files, bank)) {
+        Ok(text) => println!("{}", text),
+        Err(text) => {
+            print!("{}", text);
+            process::exit(1);
+        }
+    }
+}
+
+fn cmd_blargg(args: &[String]) {
+    let mut roms = Vec::new();
+    let mut blargg = roms::BLARGG;
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            "--max" => blargg.max_instructions = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            _ => roms.push(arg),
+        }
+    }
+    if roms.is_empty() {
+        usage();
+    }
+
+    let symbols = load_symbols(&files, bank);
+    let mut failed = 0;
+    for rom in &roms {
+        println!("{}:", rom);
+        match blargg.run(&read_file(rom), &symbols) {
+            Ok(text) => println!("{}\n", text),
+            Err(text) => {
+                println!("{}\n", text.trim_end());
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed, {} failed", roms.len() - failed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+fn cmd_nestest(args: &[String]) {
+    let mut positional = Vec::new();
+    let (mut files, mut bank) = (Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => files.push(args.next().unwrap_or_else(|| usage())),
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        usage();
+    }
+    let log = fs::read_to_string(positional[1]).unwrap_or_else(|e| {
+        eprintln!("{}: {}", positional[1], e);
+        process::exit(1);
+    });
+    match roms::nestest(&read_file(positional[0]), &log, &load_symbols(&files, bank)) {
+        Ok(text) => println!("{}", text),
+        Err(text) => {
+            print!("{}", text);
+            process::exit(1);
+        }
+    }
+}
+
+fn cmd_singlestep(args: &[String]) {
+    if args.is_empty() {
+        usage();
+    }
+    let mut files = Vec::new();
+    for arg in args {
+        let path = std::path::Path::new(arg);
+        if !path.is_dir() {
+            files.push(path.to_path_buf());
+            continue;
+        }
+        let mut entries: Vec<_> = fs::read_dir(path)
+            .unwrap_or_else(|e| {
+                eprintln!("{}: {}", arg, e);
+                process::exit(1);
+            })
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        entries.sort();
+        files.extend(entries);
+    }
+
+    let mut summary = singlestep::Summary::new();
+    for path in files {
+        let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(1);
+        });
+        match singlestep::parse(&text) {
+            Ok(cases) => summary.run(&cases),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    print!("{}", summary);
+    if summary.failed() {
+        process::exit(1);
+    }
+}
+
+fn cmd_fuzz(args: &[String]) {
+    let mut check: fn(&[u8]) -> Result<(), String> = fuzz::step;
+    let (mut runs, mut seed, mut length) = (10_000, 1, 8);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "step" => check = fuzz::step,
+            "differential" => check = fuzz::differential,
+            "--runs" | "--seed" | "--length" => {
+                let n: usize = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage());
+                match arg.as_str() {
+                    "--runs" => runs = n,
+                    "--seed" => seed = n,
+                    _ => length = n,
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    // the Cpu panics are the findings, not for stderr
+    std::panic::set_hook(Box::new(|_| {}));
+    // xorshift can't start from 0: the seeds are spread and the one which would give 0 moved
+    let mut rng = rng::Rng(match seed as u32 ^ 0x9e37_79b9 {
+        0 => 1,
+        state => state,
+    });
+    let mut report = fuzz::Report::new();
+    for _ in 0..runs {
+        let len = 7 + 1 + rng.below(length.max(1) as u32) as usize;
+        let data: Vec<u8> = (0..len).map(|_| rng.byte()).collect();
+        let result = check(&data);
+        report.add(&data, result);
+    }
+    let _ = std::panic::take_hook();
+    print!("{}", report);
+    if !report.failures.is_empty() {
+        process::exit(1);
+    }
+}
+
+fn cmd_symbols(args: &[String]) {
+    let mut files = Vec::new();
+    let mut bank = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bank" => bank = Some(args.next().and_then(|n| parse_number(n)).unwrap_or_else(|| usage())),
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let labels = read_symbols(&files).vice(bank);
+    match output {
+        Some(path) => fs::write(path, labels).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }),
+        None => print!("{}", labels),
+    }
+}
+
+fn cmd_lsp() {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    match lsp::run(stdin.lock(), stdout.lock()) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("asm") => cmd_asm(&args[2..]),
+        Some("link") => cmd_link(&args[2..]),
+        Some("lint") => cmd_lint(&args[2..]),
+        Some("disasm") => cmd_disasm(&args[2..]),
+        Some("source") => cmd_source(&args[2..]),
+        Some("stack") => cmd_stack(&args[2..]),
+        Some("cfg") => cmd_cfg(&args[2..]),
+        Some("ir") => cmd_ir(&args[2..]),
+        Some("superopt") => cmd_superopt(&args[2..]),
+        Some("call") => cmd_call(&args[2..]),
+        Some("test") => cmd_test(&args[2..]),
+        Some("mutate") => cmd_mutate(&args[2..]),
+        Some("profile") => cmd_profile(&args[2..]),
+        Some("dormann") => cmd_dormann(&args[2..]),
+        Some("blargg") => cmd_blargg(&args[2..]),
+        Some("nestest") => cmd_nestest(&args[2..]),
+        Some("singlestep") => cmd_singlestep(&args[2..]),
+        Some("fuzz") => cmd_fuzz(&args[2..]),
+        Some("symbols") => cmd_symbols(&args[2..]),
+        Some("lsp") => cmd_lsp(),
+        _ => usage(),
+    }
 }
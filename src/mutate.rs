@@ -0,0 +1,240 @@
+// Mutation testing
+//
+// The mutants of a program change one instruction of a line of its source:
+//
+//   BNE $8010 -> BEQ $8010      the condition of a branch
+//   LDA #$05 -> LDA #$06        an immediate + 1 or - 1
+//   ADC $10 -> SBC $10          ADC into SBC and SBC into ADC
+//   STA $10 deleted             the bytes of the instruction replaced by NOPs
+//
+// The tests of a test file (suite.rs) are run on each mutant: a mutant is killed when a test
+// fails or stops, and survives when they all pass. The surviving mutants are the changes the
+// tests don't see. The tests must pass on the program itself. The SBC of the Cpu borrows as
+// the one of the 6502 (the inverted carry), so the ADC/SBC mutants get the results of the 6502.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::coverage::Line;
+use crate::cpu::AddressingMode::{Immediate, Relative};
+use crate::disasm::{decode, format, OPCODES};
+use crate::suite::{Suite, Verdict};
+
+const NOP: u8 = 0xea;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mutant {
+    pub file: String,
+    pub line: usize,
+    pub address: u16,
+    pub bytes: Vec<u8>, // in place of the bytes of the instruction
+    pub description: String,
+}
+
+// the mutants of the instructions of the lines
+pub fn mutants(lines: &[Line]) -> Vec<Mutant> {
+    let mut mutants = Vec::new();
+    for line in lines {
+        for &offset in &line.instructions {
+            let ins = match decode(&line.bytes[offset..]) {
+                Some(ins) => ins,
+                None => continue,
+            };
+            let address = line.address.wrapping_add(offset as u16);
+            let original = &line.bytes[offset..offset + ins.len as usize];
+            let text = format(&ins, address, None);
+
+            let mut changed = Vec::new();
+            if ins.mode == Relative {
+                // BPL/BMI, BVC/BVS, BCC/BCS, BNE/BEQ
+                changed.push(vec![ins.opcode ^ 0x20, original[1]]);
+            }
+            if ins.mode == Immediate {
+                changed.push(vec![ins.opcode, original[1].wrapping_add(1)]);
+                changed.push(vec![ins.opcode, original[1].wrapping_sub(1)]);
+            }
+            // the opcodes of ADC and SBC differ by $80
+            let swapped = &OPCODES[(ins.opcode ^ 0x80) as usize];
+            if matches!((ins.mnemonic, swapped.mnemonic), ("ADC", "SBC") | ("SBC", "ADC")) && !swapped.illegal && swapped.mode == ins.mode {
+                let mut bytes = original.to_vec();
+                bytes[0] ^= 0x80;
+                changed.push(bytes);
+            }
+            for bytes in changed {
+                let description = format!("{} -> {}", text, format(&decode(&bytes).unwrap(), address, None));
+                mutants.push(Mutant { file: line.file.clone(), line: line.line, address, bytes, description });
+            }
+            if ins.mnemonic != "NOP" {
+                let bytes = vec![NOP; ins.len as usize];
+                mutants.push(Mutant { file: line.file.clone(), line: line.line, address, bytes, description: format!("{} deleted", text) });
+            }
+        }
+    }
+    mutants
+}
+
+// the mutants with the name of the first test which killed them, None for the survivors
+pub type Results = Vec<(Mutant, Option<String>)>;
+
+pub fn run(suite: &Suite) -> Result<Results, String> {
+    let results = suite.run();
+    let failed = results.iter().filter(|(_, verdict)| !matches!(verdict, Verdict::Pass(_))).count();
+    if failed > 0 {
+        return Err(format!("{} of {} tests fail without mutations", failed, results.len()));
+    }
+    let (origin, image) = suite.image();
+    let mut mutated = image.to_vec();
+    let mut results = Vec::new();
+    for mutant in mutants(suite.lines()) {
+        let start = match (mutant.address as usize).checked_sub(origin as usize) {
+            Some(start) if start + mutant.bytes.len() <= image.len() => start,
+            _ => continue,
+        };
+        let end = start + mutant.bytes.len();
+        mutated[start..end].copy_from_slice(&mutant.bytes);
+        let killer = suite.run_image(&mutated).into_iter().find(|(_, verdict)| !matches!(verdict, Verdict::Pass(_))).map(|(name, _)| name);
+        mutated[start..end].copy_from_slice(&image[start..end]);
+        results.push((mutant, killer));
+    }
+    Ok(results)
+}
+
+// the results of the test files, or why they couldn't be loaded or don't pass
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub files: Vec<(String, Result<Results, String>)>,
+}
+
+impl Report {
+    pub fn run(files: &[PathBuf]) -> Report {
+        let files = files.iter().map(|path| (path.display().to_string(), Suite::load(path).and_then(|suite| run(&suite)))).collect();
+        Report { files }
+    }
+
+    // killed, survived, errors (the files)
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for (_, results) in &self.files {
+            match results {
+                Ok(results) => {
+                    let survived = results.iter().filter(|(_, killer)| killer.is_none()).count();
+                    counts.0 += results.len() - survived;
+                    counts.1 += survived;
+                }
+                Err(_) => counts.2 += 1,
+            }
+        }
+        counts
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (file, results) in &self.files {
+            match results {
+                Ok(results) => {
+                    let survivors: Vec<&Mutant> = results.iter().filter(|(_, killer)| killer.is_none()).map(|(mutant, _)| mutant).collect();
+                    writeln!(f, "{}: {} mutants, {} survived", file, results.len(), survivors.len())?;
+                    for mutant in survivors {
+                        writeln!(f, "  {}:{}: ${:04X}: {}", mutant.file, mutant.line, mutant.address, mutant.description)?;
+                    }
+                }
+                Err(e) => {
+                    writeln!(f, "{}: ERROR", file)?;
+                    for line in e.lines() {
+                        writeln!(f, "  {}", line)?;
+                    }
+                }
+            }
+        }
+        let (killed, survived, errors) = self.counts();
+        write!(f, "{} mutants, {} killed, {} survived", killed + survived, killed, survived)?;
+        if killed + survived > 0 {
+            write!(f, " ({:.1}% killed)", 100.0 * killed as f64 / (killed + survived) as f64)?;
+        }
+        match errors {
+            0 => writeln!(f),
+            1 => writeln!(f, ", 1 error"),
+            _ => writeln!(f, ", {} errors", errors),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use std::path::Path;
+
+    #[test]
+    fn test_mutants() {
+        let line = Line { file: "a.s".to_string(), line: 1, address: 0x8000, bytes: vec![0xd0, 0x02, 0x69, 0x01, 0xea], instructions: vec![0, 2, 4] };
+        let mutants = mutants(&[line]);
+        let descriptions: Vec<&str> = mutants.iter().map(|m| m.description.as_str()).collect();
+        assert!(descriptions == [
+            "BNE $8004 -> BEQ $8004",
+            "BNE $8004 deleted",
+            "ADC #$01 -> ADC #$02",
+            "ADC #$01 -> ADC #$00",
+            "ADC #$01 -> SBC #$01",
+            "ADC #$01 deleted",
+        ], "{:?}", descriptions);
+        assert!(mutants[5].address == 0x8002 && mutants[5].bytes == [NOP, NOP]);
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = TempDir::new("mutate");
+        let source = dir.write("add.s", "        .org $8000\nadd:    CLC\n        LDA #5\n        ADC $10\n        STA $11\n        RTS\n");
+        let file = dir.write("add.toml", "source = \"add.s\"\n[[test]]\ncall = \"add\"\nmemory = { \"$10\" = 3 }\nexpect.memory = { \"$11\" = [8] }\n");
+
+        // the carry is clear when the test starts: deleting CLC goes unnoticed
+        let report = Report::run(std::slice::from_ref(&file));
+        let results = report.files[0].1.as_ref().unwrap();
+        assert!(results.len() == 8 && results[1].1 == Some("test 1".to_string()));
+        assert!(report.counts() == (7, 1, 0));
+        let (file, source) = (file.display(), source.display());
+        assert!(report.to_string() == format!("{}: 8 mutants, 1 survived\n  {}:2: $8000: CLC deleted\n8 mutants, 7 killed, 1 survived (87.5% killed)\n", file, source));
+    }
+
+    #[test]
+    fn test_branches() {
+        let dir = TempDir::new("mutate_branches");
+        dir.write("max.s", "        .org $8000\nmax:    CMP $10\n        BCS done\n        LDA $10\ndone:   RTS\n");
+        let text = "source = \"max.s\"\n\
+            [[test]]\ncall = \"max\"\na = 5\nmemory = { \"$10\" = 3 }\nexpect.a = 5\n\
+            [[test]]\ncall = \"max\"\na = 2\nmemory = { \"$10\" = 7 }\nexpect.a = 7\n";
+
+        let results = run(&Suite::load(&dir.write("max.toml", text)).unwrap()).unwrap();
+        let killers: Vec<(&str, Option<&str>)> = results.iter().map(|(mutant, killer)| (mutant.description.as_str(), killer.as_deref())).collect();
+        assert!(killers == [
+            ("CMP $10 deleted", Some("test 1")),
+            ("BCS $8006 -> BCC $8006", Some("test 1")),
+            ("BCS $8006 deleted", Some("test 1")),
+            ("LDA $10 deleted", Some("test 2")),
+            ("RTS deleted", Some("test 1")),
+        ], "{:?}", killers);
+    }
+
+    #[test]
+    fn test_sbc() {
+        // the program passes with the borrow of the 6502 (5 - 3), and the mutants give 5 - 3 - 1
+        // (no SEC) and 5 + 3 + 1 (ADC)
+        let dir = TempDir::new("mutate_sbc");
+        dir.write("sub.s", "        .org $8000\nsub:    SEC\n        LDA $10\n        SBC $11\n        STA $12\n        RTS\n");
+        let text = "source = \"sub.s\"\n[[test]]\ncall = \"sub\"\nmemory = { \"$10\" = 5, \"$11\" = 3 }\nexpect.memory = { \"$12\" = [2] }\n";
+
+        let results = run(&Suite::load(&dir.write("sub.toml", text)).unwrap()).unwrap();
+        let killers: Vec<(&str, Option<&str>)> = results.iter().map(|(mutant, killer)| (mutant.description.as_str(), killer.as_deref())).collect();
+        assert!(killers.contains(&("SEC deleted", Some("test 1"))) && killers.contains(&("SBC $11 -> ADC $11", Some("test 1"))), "{:?}", killers);
+        assert!(killers.iter().all(|(_, killer)| killer.is_some()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let suite = Suite::parse("code = \"LDA #1\\nRTS\"\n[[test]]\nexpect.a = 2\ncall = 0\n", Path::new("")).unwrap();
+        assert!(run(&suite).unwrap_err() == "1 of 1 tests fail without mutations");
+        let report = Report { files: vec![("bad.toml".to_string(), Err("line 1: expected a key".to_string()))] };
+        assert!(report.to_string() == "bad.toml: ERROR\n  line 1: expected a key\n0 mutants, 0 killed, 0 survived, 1 error\n");
+    }
+}
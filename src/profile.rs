@@ -0,0 +1,271 @@
+// Profiler
+//
+// The runs of the harness count the instructions and the cycles of each address, and follow the
+// calls: JSR enters the subroutine at its operand and RTS leaves the last one entered. The
+// cycles of an instruction go to the subroutine running it (its exclusive cycles); the
+// inclusive cycles of a subroutine are the ones from its JSR to its RTS, with the subroutines it
+// calls. A loop is a backward jump (a taken branch or a JMP to a lower address), its cycles are
+// the ones of the addresses between the target and the jump.
+//
+// The folded stacks are one line per chain of calls with its exclusive cycles, the input of
+// flamegraph.pl and inferno:
+//
+//   main;mul 360
+//   main;mul;add 40
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::cpu::AddressingMode::Absolute;
+use crate::disasm::Instruction;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Routine {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub addresses: BTreeMap<u16, (u64, u64)>, // instructions, cycles
+    pub routines: BTreeMap<u16, Routine>,     // by entry
+    pub stacks: BTreeMap<Vec<u16>, u64>,      // the entries of the chains of calls, exclusive cycles
+    pub loops: BTreeMap<(u16, u16), u64>,     // the target and the address of the jump, iterations
+    chain: Vec<u16>,
+    starts: Vec<u64>, // the cycles when the routines of the chain were entered
+    cycles: u64,
+}
+
+impl Profile {
+    // `entry`: the routine which is called first
+    pub fn new(entry: u16) -> Profile {
+        let mut profile = Profile::default();
+        profile.enter(entry);
+        profile
+    }
+
+    fn enter(&mut self, entry: u16) {
+        self.routines.entry(entry).or_default().calls += 1;
+        self.chain.push(entry);
+        self.starts.push(self.cycles);
+    }
+
+    fn leave(&mut self) {
+        if let (Some(entry), Some(start)) = (self.chain.pop(), self.starts.pop()) {
+            // the outermost call of a recursive routine has the cycles of the inner ones
+            if !self.chain.contains(&entry) {
+                self.routines.entry(entry).or_default().inclusive += self.cycles - start;
+            }
+        }
+    }
+
+    // the instruction at `pc` before it runs, `taken`: for the branches
+    pub fn record(&mut self, pc: u16, ins: &Instruction, cycles: usize, taken: Option<bool>) {
+        let cycles = cycles as u64;
+        let counts = self.addresses.entry(pc).or_default();
+        counts.0 += 1;
+        counts.1 += cycles;
+        self.cycles += cycles;
+        if let Some(&entry) = self.chain.last() {
+            self.routines.entry(entry).or_default().exclusive += cycles;
+            match self.stacks.get_mut(&self.chain) {
+                Some(total) => *total += cycles,
+                None => {
+                    self.stacks.insert(self.chain.clone(), cycles);
+                }
+            }
+        }
+
+        let target = match (ins.mnemonic, ins.mode) {
+            ("JMP", Absolute) => Some(ins.operand),
+            _ if taken == Some(true) => ins.branch_target(pc),
+            _ => None,
+        };
+        if let Some(target) = target.filter(|target| *target <= pc) {
+            *self.loops.entry((target, pc)).or_default() += 1;
+        }
+        match ins.mnemonic {
+            "JSR" => self.enter(ins.operand),
+            "RTS" => self.leave(),
+            _ => {}
+        }
+    }
+
+    // at the end of the run, for the routines which didn't return
+    pub fn finish(&mut self) {
+        while !self.chain.is_empty() {
+            self.leave();
+        }
+    }
+
+    pub fn merge(&mut self, other: &Profile) {
+        for (address, (instructions, cycles)) in &other.addresses {
+            let counts = self.addresses.entry(*address).or_default();
+            counts.0 += instructions;
+            counts.1 += cycles;
+        }
+        for (entry, routine) in &other.routines {
+            let total = self.routines.entry(*entry).or_default();
+            total.calls += routine.calls;
+            total.inclusive += routine.inclusive;
+            total.exclusive += routine.exclusive;
+        }
+        for (chain, cycles) in &other.stacks {
+            *self.stacks.entry(chain.clone()).or_default() += cycles;
+        }
+        for (jump, iterations) in &other.loops {
+            *self.loops.entry(*jump).or_default() += iterations;
+        }
+        self.cycles += other.cycles;
+    }
+
+    // the cycles of the addresses from `first` to `last`
+    fn cycles_between(&self, first: u16, last: u16) -> u64 {
+        self.addresses.range(first..=last).map(|(_, (_, cycles))| cycles).sum()
+    }
+
+    // the text report with the `top` hottest loops and addresses
+    pub fn report(&self, symbols: &HashMap<u16, String>, top: usize) -> String {
+        let names = Names::new(symbols);
+        let instructions: u64 = self.addresses.values().map(|(instructions, _)| instructions).sum();
+        let mut text = format!("{} instructions, {} cycles\n", instructions, self.cycles);
+
+        let mut routines: Vec<(&u16, &Routine)> = self.routines.iter().collect();
+        routines.sort_by_key(|(entry, routine)| (std::cmp::Reverse(routine.inclusive), **entry));
+        text += "\nroutine                   calls   inclusive   exclusive\n";
+        for (entry, routine) in routines {
+            let _ = writeln!(text, "{:<20}  {:>9}  {:>10}  {:>10}", names.name(*entry), routine.calls, routine.inclusive, routine.exclusive);
+        }
+
+        let mut loops: Vec<(u16, u16, u64, u64)> =
+            self.loops.iter().map(|(&(first, last), &iterations)| (first, last, iterations, self.cycles_between(first, last))).collect();
+        loops.sort_by_key(|&(first, last, _, cycles)| (std::cmp::Reverse(cycles), first, last));
+        if !loops.is_empty() {
+            let _ = writeln!(text, "\n{:<36}  {:>10}  {:>10}", "loop", "iterations", "cycles");
+            for (first, last, iterations, cycles) in loops.into_iter().take(top) {
+                let _ = writeln!(text, "${:04X}-${:04X} {:<24}  {:>10}  {:>10}", first, last, names.name(first), iterations, cycles);
+            }
+        }
+
+        let mut addresses: Vec<(&u16, &(u64, u64))> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(address, (_, cycles))| (std::cmp::Reverse(*cycles), **address));
+        let _ = writeln!(text, "\n{:<22}  {:>9}  {:>10}", "address", "count", "cycles");
+        for (address, (count, cycles)) in addresses.into_iter().take(top) {
+            let _ = writeln!(text, "${:04X} {:<16}  {:>9}  {:>10}", address, names.name(*address), count, cycles);
+        }
+        text
+    }
+
+    // the folded stacks, with the names of the entries
+    pub fn folded(&self, symbols: &HashMap<u16, String>) -> String {
+        let names = Names::new(symbols);
+        let mut text = String::new();
+        for (chain, cycles) in self.stacks.iter().filter(|(_, cycles)| **cycles > 0) {
+            let chain: Vec<String> = chain.iter().map(|entry| names.name(*entry)).collect();
+            let _ = writeln!(text, "{} {}", chain.join(";"), cycles);
+        }
+        text
+    }
+}
+
+// the names of the addresses: a symbol, a symbol + an offset, or the address
+pub(crate) struct Names {
+    symbols: BTreeMap<u16, String>,
+}
+
+impl Names {
+    pub(crate) fn new(symbols: &HashMap<u16, String>) -> Names {
+        Names { symbols: symbols.iter().map(|(address, name)| (*address, name.clone())).collect() }
+    }
+
+    pub(crate) fn name(&self, address: u16) -> String {
+        match self.symbols.range(..=address).next_back() {
+            Some((&start, name)) if start == address => name.clone(),
+            Some((&start, name)) if address - start < 0x100 => format!("{}+{}", name, address - start),
+            _ => format!("${:04X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::harness::Harness;
+
+    const SOURCE: &str = "\
+        .org $8000
+main:   JSR twice
+        JSR twice
+        RTS
+twice:  JSR add
+add:    INX
+        RTS
+";
+
+    #[test]
+    fn test_calls() {
+        let program = Assembler::new().assemble(SOURCE).unwrap();
+        let (origin, image) = program.image().unwrap();
+        let profile = Harness::new(&image, origin).run().unwrap().profile;
+        let [main, twice, add] = [0x8000, 0x8007, 0x800a];
+        assert!(profile.routines[&main] == Routine { calls: 1, inclusive: 62, exclusive: 18 });
+        assert!(profile.routines[&twice] == Routine { calls: 2, inclusive: 44, exclusive: 28 });
+        // called from twice, and run after it (as part of twice)
+        assert!(profile.routines[&add] == Routine { calls: 2, inclusive: 16, exclusive: 16 });
+        assert!(profile.addresses[&add] == (4, 8));
+
+        let symbols: HashMap<u16, String> = program.symbols.iter().map(|(name, address)| (*address, name.clone())).collect();
+        assert!(profile.folded(&symbols) == "main 18\nmain;twice 28\nmain;twice;add 16\n");
+        let report = profile.report(&symbols, 2);
+        assert!(report.starts_with("\
+13 instructions, 62 cycles
+
+routine                   calls   inclusive   exclusive
+main                          1          62          18
+twice                         2          44          28
+add                           2          16          16
+"), "{}", report);
+    }
+
+    #[test]
+    fn test_branches() {
+        let program = Assembler::new().assemble(".org $8000\nwait:   LDX #3\nloop:   DEX\n        BNE loop\n        RTS\n").unwrap();
+        let (origin, image) = program.image().unwrap();
+        let profile = Harness::new(&image, origin).run().unwrap().profile;
+        // LDX 2, DEX 3 * 2, BNE 2 * 3 (taken) + 2, RTS 6
+        assert!(profile.routines[&0x8000] == Routine { calls: 1, inclusive: 22, exclusive: 22 });
+        assert!(profile.loops == BTreeMap::from([((0x8002, 0x8003), 2)]));
+        let symbols = HashMap::from([(0x8000, "wait".to_string()), (0x8002, "loop".to_string())]);
+        assert!(profile.report(&symbols, 1).contains("\n$8002-$8003 loop                               2          14\n"));
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut profile = Profile::new(0x8000);
+        let jmp = crate::disasm::decode(&[0x4c, 0x00, 0x80]).unwrap();
+        let inx = crate::disasm::decode(&[0xe8]).unwrap();
+        for _ in 0..3 {
+            profile.record(0x8000, &inx, 2, None);
+            profile.record(0x8001, &jmp, 3, None);
+        }
+        let bne = crate::disasm::decode(&[0xd0, 0xfc]).unwrap();
+        profile.record(0x8010, &bne, 3, Some(true));
+        profile.record(0x8010, &bne, 2, Some(false));
+        profile.finish();
+        assert!(profile.loops == BTreeMap::from([((0x8000, 0x8001), 3), ((0x800e, 0x8010), 1)]));
+        assert!(profile.routines[&0x8000] == Routine { calls: 1, inclusive: 20, exclusive: 20 });
+
+        let symbols = HashMap::from([(0x8000, "spin".to_string())]);
+        let report = profile.report(&symbols, 1);
+        assert!(report.contains("\n\
+loop                                  iterations      cycles
+$8000-$8001 spin                               3          15
+"), "{}", report);
+        assert!(report.ends_with("\n\
+address                     count      cycles
+$8001 spin+1                    3           9
+"), "{}", report);
+    }
+}
@@ -0,0 +1,26 @@
+// Pseudo-random numbers
+//
+// A xorshift32 generator for the random programs and inputs of the tests, the
+// superoptimizer and the fuzzer: small, reproducible from a seed, and without a crate.
+// The state must not be 0.
+
+pub(crate) struct Rng(pub(crate) u32);
+
+impl Rng {
+    // xorshift32
+    pub(crate) fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    pub(crate) fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+
+    // below `n` (> 0)
+    pub(crate) fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
@@ -0,0 +1,743 @@
+// Test ROMs
+//
+// Runs the test programs which report their results in memory, with a trace of the last
+// instructions and a backtrace of the calls (shadow.rs) for the failures.
+//
+// Klaus Dormann's 6502_functional_test and 6502_decimal_test
+// (github.com/Klaus2m5/6502_65C02_functional_tests) are loaded at their origin and run from
+// their start address until a trap (a JMP or a taken branch to itself) or a BRK. The
+// functional test passes on the success trap and keeps the number of the current test at
+// $0200. The decimal test passes with 0 in ERROR ($0B) at its end, which must be built as a
+// trap or a BRK: $DB, the STP of the 65C02, is DCP abs,Y on the 6502. The 65C02 tests can't
+// run on the Cpu (an NMOS 6502). The addresses are the ones of the binaries of the repository,
+// the other builds can give theirs.
+//
+// nestest.nes (www.qmtpro.com/~nes/misc/nestest.txt) runs in its automation mode, from $C000
+// with P=$24, S=$FD and 7 cycles, against the log of Nintendulator (nestest.log): the pc, the
+// registers and the cycles before each instruction. The tests keep the number of their first
+// failure in $02 (documented opcodes) and $03 (undocumented ones).
+//
+// blargg's NES test ROMs (instr_test, cpu_timing_test, instr_misc, cpu_interrupts, ...) start
+// from the reset vector and report through $6000: $80 while running, $81 when they need a reset
+// (pressed at least 100 ms later), then the result code (0 if they passed). The status is only
+// valid with the signature $DE $B0 $61 in $6001-$6003, and the text of the ROM is a
+// zero-terminated string at $6004. There is no PPU or APU: $2002 always reads with VBlank set,
+// and the ROMs which wait for an NMI or for the APU don't finish.
+//
+// The NES images run on the 2A03 (ADC and SBC are binary with D set) and are iNES files with
+// the mapper 0 (NROM, 16K or 32K) or 1 (MMC1, the PRG banks only: the serial register written
+// at $8000-$FFFF, the 32K mode and the 16K modes with the first or the last bank fixed, 16K at
+// $C000 from the power on). The writes into the ROM only go to the mapper. The RAM at
+// $6000-$7FFF is always there (its enable bit is ignored), the CHR banks and the mirroring have
+// no effect without a PPU, and the 512K boards (SUROM, the outer bank in the CHR registers) and
+// the other mappers are not supported. A read-modify-write gives the mapper its first write
+// (the unmodified value), MMC1 ignores the second one.
+//
+// The tests of the ROMs themselves are #[ignore]d (the ROMs aren't in the repository), the test
+// binary run with `test_rom --ignored` runs the two binaries, nestest.nes with nestest.log and
+// the *.nes files of blargg/ from the directory in $MOS6502_ROMS (roms/ by default).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::cpu::AddressingMode::*;
+use crate::cpu::{Cpu, Variant};
+use crate::disasm::{decode, fetch, format, Instruction, WRITES};
+use crate::step::{address, cost, fault, taken};
+use crate::ir;
+use crate::shadow::ShadowStack;
+
+// the instructions in the trace
+const TRACE: usize = 20;
+
+struct Step {
+    pc: u16,
+    bytes: Vec<u8>,
+    regs: (u8, u8, u8, u8, u8), // a, x, y, s, p
+    cycles: u64,
+}
+
+// the Cpu with the count of the cycles, the trace and the calls
+pub struct Machine {
+    cpu: Cpu,
+    pub cycles: u64,
+    pub instructions: u64,
+    trace: VecDeque<Step>,
+    shadow: ShadowStack,
+    symbols: HashMap<u16, String>, // the names of the addresses in the trace and the backtrace
+    cartridge: Option<Cartridge>,  // the PRG ROM at $8000-$FFFF
+}
+
+impl Machine {
+    pub fn new(symbols: &HashMap<u16, String>) -> Machine {
+        let symbols = symbols.clone();
+        let (trace, shadow) = (VecDeque::new(), ShadowStack::new());
+        Machine { cpu: Cpu::new(), cycles: 0, instructions: 0, trace, shadow, symbols, cartridge: None }
+    }
+
+    // the banks of the cartridge at $8000-$FFFF, and its mapper for the writes there; the NES
+    // runs a 2A03
+    pub fn insert(&mut self, cartridge: Cartridge) {
+        self.cpu.set_variant(Variant::Ricoh2A03);
+        self.load(0x8000, cartridge.bank(0x8000));
+        self.load(0xc000, cartridge.bank(0xc000));
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn load(&mut self, address: u16, bytes: &[u8]) {
+        self.cpu.patch_memory(address as usize, bytes);
+    }
+
+    pub fn jump(&mut self, pc: u16) {
+        self.cpu.update_pc(pc);
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn set_registers(&mut self, a: u8, x: u8, y: u8, s: u8, p: u8) {
+        self.cpu.set_registers(a, x, y, s, p);
+    }
+
+    // the instruction at the pc
+    pub fn next(&self) -> Instruction {
+        fetch(self.cpu.memory(), self.cpu.pc())
+    }
+
+    // a JMP or a taken branch to itself
+    pub fn trapped(&self) -> bool {
+        let pc = self.cpu.pc();
+        let ins = self.next();
+        match ins.mnemonic {
+            "JMP" if ins.mode == Absolute => ins.operand == pc,
+            _ => ins.branch_target(pc) == Some(pc) && taken(&self.cpu, &ins) == Some(true),
+        }
+    }
+
+    // an error instead of the panics of the Cpu (and for JAM)
+    pub fn step(&mut self) -> Result<(), String> {
+        let pc = self.cpu.pc();
+        let ins = self.next();
+        if let Some(why) = fault(&self.cpu, &ins) {
+            return Err(format!("${:04X}: {}: {}", pc, format(&ins, pc, None), why));
+        }
+        if self.trace.len() == TRACE {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(self.state());
+        self.shadow.observe(&self.cpu, &ins);
+        self.cycles += cost(&self.cpu, &ins) as u64;
+        self.instructions += 1;
+        let rom = match address(&self.cpu, &ins) {
+            Some(address) if address >= 0x8000 && WRITES.contains(&ins.mnemonic) && self.cartridge.is_some() => Some(address),
+            _ => None,
+        };
+        let before = rom.map(|address| self.cpu.memory()[address as usize]);
+        self.cpu.step();
+        if let (Some(address), Some(before)) = (rom, before) {
+            let store = ins.mnemonic.starts_with("ST") || matches!(ins.mnemonic, "SAX" | "SHA" | "SHX" | "SHY" | "TAS");
+            let value = if store { self.cpu.memory()[address as usize] } else { before };
+            let mut cartridge = self.cartridge.take().unwrap();
+            cartridge.write(address, value);
+            self.insert(cartridge);
+        }
+        Ok(())
+    }
+
+    // the next instruction with the registers and the cycles
+    fn state(&self) -> Step {
+        let pc = self.cpu.pc();
+        let bytes = (0..self.next().len as u16).map(|i| self.cpu.memory()[pc.wrapping_add(i) as usize]).collect();
+        Step { pc, bytes, regs: self.cpu.registers(), cycles: self.cycles }
+    }
+
+    // the last instructions, with the registers and the cycles before them, and the backtrace
+    pub fn trace(&self) -> String {
+        let trace: String = self.trace.iter().map(|step| step.text(Some(&self.symbols)) + "\n").collect();
+        if self.shadow.is_empty() {
+            trace
+        } else {
+            trace + "backtrace:\n" + &self.shadow.backtrace(self.cpu.pc(), &self.symbols)
+        }
+    }
+}
+
+impl Step {
+    // the line of the trace, with the names of the addresses in `symbols`
+    fn text(&self, symbols: Option<&HashMap<u16, String>>) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ins = decode(&self.bytes).map_or(String::new(), |ins| format(&ins, self.pc, symbols));
+        let (a, x, y, s, p) = self.regs;
+        format!(
+            "{:04X}  {:<8}  {:<14}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc, bytes.join(" "), ins, a, x, y, p, s, self.cycles
+        )
+    }
+}
+
+// the format of the nestest log
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text(None))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dormann {
+    pub origin: u16,
+    pub start: u16,
+    pub success: Option<u16>,   // the address of the success trap
+    pub test_case: Option<u16>, // the number of the current test
+    pub error: Option<u16>,     // 0 if the test passed
+    pub max_instructions: u64,
+}
+
+pub const FUNCTIONAL: Dormann =
+    Dormann { origin: 0, start: 0x400, success: Some(0x3469), test_case: Some(0x200), error: None, max_instructions: 100_000_000 };
+pub const DECIMAL: Dormann =
+    Dormann { origin: 0x200, start: 0x200, success: None, test_case: None, error: Some(0x0b), max_instructions: 100_000_000 };
+
+impl Dormann {
+    pub fn preset(name: &str) -> Option<Dormann> {
+        match name {
+            "functional" => Some(FUNCTIONAL),
+            "decimal" => Some(DECIMAL),
+            _ => None,
+        }
+    }
+
+    // what happened, Err if the test failed
+    pub fn run(&self, image: &[u8], symbols: &HashMap<u16, String>) -> Result<String, String> {
+        if self.origin as usize + image.len() > 0x10000 {
+            return Err(format!("the image of {} bytes doesn't fit in memory at ${:04X}", image.len(), self.origin));
+        }
+        let mut machine = Machine::new(symbols);
+        machine.load(self.origin, image);
+        machine.jump(self.start);
+        let end = loop {
+            let pc = machine.cpu().pc();
+            match machine.next().opcode {
+                _ if machine.trapped() => break format!("trap at ${:04X}", pc),
+                0x00 => break format!("BRK at ${:04X}", pc),
+                _ => {}
+            }
+            if machine.instructions == self.max_instructions {
+                return Err(self.failure(&machine, &format!("no trap after {} instructions", self.max_instructions)));
+            }
+            if let Err(e) = machine.step() {
+                return Err(self.failure(&machine, &e));
+            }
+        };
+
+        let pc = machine.cpu().pc();
+        let error = self.error.map(|address| machine.cpu().memory()[address as usize]);
+        let what = format!("{} after {} instructions, {} cycles", end, machine.instructions, machine.cycles);
+        if self.success.is_some_and(|success| success != pc) || error.is_some_and(|error| error != 0) {
+            Err(self.failure(&machine, &what))
+        } else {
+            Ok(format!("passed: {}", what))
+        }
+    }
+
+    fn failure(&self, machine: &Machine, what: &str) -> String {
+        let memory = machine.cpu().memory();
+        let mut text = format!("failed: {}", what);
+        if let Some(address) = self.test_case {
+            text += &format!(", test case ${:02X}", memory[address as usize]);
+        }
+        if let Some(address) = self.error {
+            text += &format!(", ERROR=${:02X}", memory[address as usize]);
+        }
+        text + "\n" + &machine.trace()
+    }
+}
+
+// the PRG ROM of an iNES image and the registers of its mapper
+pub struct Cartridge {
+    prg: Vec<u8>,
+    mapper: u8,
+    shift: u8, // MMC1: the bits written so far, from the lowest
+    count: u8,
+    control: u8,
+    bank: u8,
+}
+
+impl Cartridge {
+    pub fn new(image: &[u8]) -> Result<Cartridge, String> {
+        if image.len() < 16 || &image[..4] != b"NES\x1a" {
+            return Err("not an iNES image".to_string());
+        }
+        let mapper = image[6] >> 4 | (image[7] & 0xf0);
+        if mapper > 1 {
+            return Err(format!("mapper {} is not supported (only NROM and MMC1)", mapper));
+        }
+        let start = if image[6] & 0x04 != 0 { 16 + 512 } else { 16 };
+        let len = image[4] as usize * 0x4000;
+        let sizes = if mapper == 0 { 1..=2 } else { 2..=16 };
+        if !sizes.contains(&image[4]) || image.len() < start + len {
+            return Err(format!("bad PRG ROM of {} banks", image[4]));
+        }
+        Ok(Cartridge { prg: image[start..start + len].to_vec(), mapper, shift: 0, count: 0, control: 0x0c, bank: 0 })
+    }
+
+    // the 16K at $8000 or $C000
+    fn bank(&self, address: u16) -> &[u8] {
+        let banks = self.prg.len() / 0x4000;
+        let high = address >= 0xc000;
+        let bank = match (self.mapper, self.control >> 2 & 3) {
+            (0, _) => if high { banks - 1 } else { 0 },
+            (_, 0 | 1) => (self.bank as usize & 0x0e) + high as usize,
+            (_, 2) => if high { self.bank as usize } else { 0 },
+            _ => if high { banks - 1 } else { self.bank as usize },
+        };
+        let start = bank % banks * 0x4000;
+        &self.prg[start..start + 0x4000]
+    }
+
+    // a write into the ROM: the serial register of MMC1, one bit at a time
+    fn write(&mut self, address: u16, value: u8) {
+        if self.mapper != 1 {
+            return;
+        }
+        if value & 0x80 != 0 {
+            (self.shift, self.count) = (0, 0);
+            self.control |= 0x0c;
+            return;
+        }
+        self.shift |= (value & 1) << self.count;
+        self.count += 1;
+        if self.count == 5 {
+            match address {
+                0x8000..=0x9fff => self.control = self.shift,
+                0xe000..=0xffff => self.bank = self.shift & 0x0f,
+                _ => {} // the CHR banks
+            }
+            (self.shift, self.count) = (0, 0);
+        }
+    }
+}
+
+// a line of nestest.log:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+fn log_line(line: &str) -> Option<Step> {
+    let field = |name: &str| line.split_whitespace().find_map(|word| word.strip_prefix(name));
+    let byte = |name: &str| field(name).and_then(|value| u8::from_str_radix(value, 16).ok());
+    let pc = u16::from_str_radix(line.get(..4)?, 16).ok()?;
+    let bytes = line.get(6..14)?.split_whitespace().map(|b| u8::from_str_radix(b, 16).ok()).collect::<Option<_>>()?;
+    let regs = (byte("A:")?, byte("X:")?, byte("Y:")?, byte("SP:")?, byte("P:")?);
+    Some(Step { pc, bytes, regs, cycles: field("CYC:")?.parse().ok()? })
+}
+
+// what happened, Err with the first line which doesn't match the log
+pub fn nestest(image: &[u8], log: &str, symbols: &HashMap<u16, String>) -> Result<String, String> {
+    let cartridge = Cartridge::new(image)?;
+    let mut machine = Machine::new(symbols);
+    machine.insert(cartridge);
+    machine.jump(0xc000);
+    machine.set_registers(0, 0, 0, 0xfd, 0x24);
+    machine.cycles = 7;
+
+    let results = |machine: &Machine| format!("$02=${:02X} $03=${:02X}", machine.cpu().memory()[2], machine.cpu().memory()[3]);
+    let failure = |machine: &Machine, what: String| format!("failed: {}, {}\n{}", what, results(machine), machine.trace());
+    let mut lines = 0;
+    for (i, line) in log.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let expected = log_line(line).ok_or_else(|| format!("line {} of the log: can't read {:?}", i + 1, line))?;
+        let got = machine.state();
+        if (got.pc, got.regs, got.cycles) != (expected.pc, expected.regs, expected.cycles) {
+            return Err(failure(&machine, format!("line {} of the log\nexpected: {}\n     got: {}", i + 1, line, got)));
+        }
+        if let Err(e) = machine.step() {
+            return Err(failure(&machine, format!("line {} of the log: {}", i + 1, e)));
+        }
+        lines += 1;
+    }
+
+    let what = format!("{} lines, {} cycles", lines, machine.cycles);
+    if machine.cpu().memory()[2..4] != [0, 0] {
+        Err(failure(&machine, what))
+    } else {
+        Ok(format!("passed: {}, {}", what, results(&machine)))
+    }
+}
+
+const BLARGG_STATUS: u16 = 0x6000;
+const BLARGG_SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const BLARGG_TEXT: u16 = 0x6004;
+// 100 ms at the 1.79 MHz of the NTSC NES
+const RESET_DELAY: u64 = 178_977;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Blargg {
+    pub max_instructions: u64,
+}
+
+pub const BLARGG: Blargg = Blargg { max_instructions: 500_000_000 };
+
+impl Blargg {
+    // the reset of the NES: the pc from the vector, 3 bytes less of stack and I set
+    fn reset(machine: &mut Machine) {
+        let memory = machine.cpu().memory();
+        let pc = u16::from_le_bytes([memory[0xfffc], memory[0xfffd]]);
+        let (a, x, y, s, p) = machine.cpu().registers();
+        machine.set_registers(a, x, y, s.wrapping_sub(3), p | ir::I);
+        machine.jump(pc);
+        machine.shadow.reset();
+    }
+
+    // the status once the signature is there
+    fn status(machine: &Machine) -> Option<u8> {
+        let memory = machine.cpu().memory();
+        let signature = &memory[BLARGG_STATUS as usize + 1..BLARGG_TEXT as usize];
+        (signature == BLARGG_SIGNATURE).then_some(memory[BLARGG_STATUS as usize])
+    }
+
+    // the text, ending with a new line
+    fn text(machine: &Machine) -> String {
+        let text = &machine.cpu().memory()[BLARGG_TEXT as usize..0x8000];
+        let end = text.iter().position(|&c| c == 0).unwrap_or(text.len());
+        let mut text = String::from_utf8_lossy(&text[..end]).into_owned();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+
+    // the text of the ROM and what happened, Err if the test failed
+    pub fn run(&self, image: &[u8], symbols: &HashMap<u16, String>) -> Result<String, String> {
+        let cartridge = Cartridge::new(image)?;
+        let mut machine = Machine::new(symbols);
+        machine.insert(cartridge);
+        Blargg::reset(&mut machine);
+        machine.set_registers(0, 0, 0, 0xfd, 0x04);
+
+        let failure = |machine: &Machine, what: String| {
+            format!("{}failed: {}\n{}", Blargg::text(machine), what, machine.trace())
+        };
+        let mut resets = 0;
+        let mut requested = None;
+        let mut running = false;
+        let result = loop {
+            match Blargg::status(&machine) {
+                Some(0x80) => {
+                    running = true;
+                    requested = None;
+                }
+                Some(0x81) => match requested {
+                    None => requested = Some(machine.cycles),
+                    Some(cycles) if machine.cycles >= cycles + RESET_DELAY => {
+                        Blargg::reset(&mut machine);
+                        requested = None;
+                        resets += 1;
+                    }
+                    _ => {}
+                },
+                Some(status) if status < 0x80 && running => break status,
+                _ => requested = None,
+            }
+            if machine.instructions == self.max_instructions {
+                return Err(failure(&machine, format!("no result after {} instructions", self.max_instructions)));
+            }
+            machine.load(0x2002, &[0x80]);
+            if let Err(e) = machine.step() {
+                return Err(failure(&machine, e));
+            }
+        };
+
+        let what = format!("{} instructions, {} cycles, {} reset(s)", machine.instructions, machine.cycles, resets);
+        match result {
+            0 => Ok(format!("{}passed after {}", Blargg::text(&machine), what)),
+            code => Err(format!("{}failed: result {} after {}", Blargg::text(&machine), code, what)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn run(source: &str, dormann: Dormann) -> Result<String, String> {
+        let (origin, image) = Assembler::new().assemble(source).unwrap().image().unwrap();
+        Dormann { origin, start: origin, ..dormann }.run(&image, &HashMap::new())
+    }
+
+    const FUNCTIONAL_SRC: &str = "
+        .org $0400
+        LDA #1
+        STA $0200
+        LDX #7
+        STX $0200
+        JMP *+3
+        LDA $0200
+fail:   JMP fail
+        .org $0469
+success: JMP success
+";
+
+    #[test]
+    fn test_functional() {
+        let dormann = Dormann { success: Some(0x469), ..FUNCTIONAL };
+        let fail = run(FUNCTIONAL_SRC, dormann).unwrap_err();
+        assert!(fail == "\
+failed: trap at $0410 after 6 instructions, 19 cycles, test case $07
+0400  A9 01     LDA #$01        A:00 X:00 Y:00 P:04 SP:FD CYC:0
+0402  8D 00 02  STA $0200       A:01 X:00 Y:00 P:04 SP:FD CYC:2
+0405  A2 07     LDX #$07        A:01 X:00 Y:00 P:04 SP:FD CYC:6
+0407  8E 00 02  STX $0200       A:01 X:07 Y:00 P:04 SP:FD CYC:8
+040A  4C 0D 04  JMP $040D       A:01 X:07 Y:00 P:04 SP:FD CYC:12
+040D  AD 00 02  LDA $0200       A:01 X:07 Y:00 P:04 SP:FD CYC:15
+");
+        let pass = run(&FUNCTIONAL_SRC.replace("JMP *+3", "JMP success"), dormann).unwrap();
+        assert!(pass == "passed: trap at $0469 after 5 instructions, 15 cycles");
+
+        // the traps of the binaries are branches to themselves
+        let branches = ".org $0400\nLDX #3\nSTX $0200\nloop: DEX\nBNE loop\nCPX #0\nBNE *\nJMP success\n.org $0469\nsuccess: JMP success";
+        assert!(run(branches, dormann).unwrap() == "passed: trap at $0469 after 11 instructions, 27 cycles");
+        let fail = run(&branches.replace("CPX #0", "CPX #1"), dormann).unwrap_err();
+        assert!(fail.starts_with("failed: trap at $040A after 9 instructions, 22 cycles, test case $03\n"), "{}", fail);
+
+        let long = run(".org $0400\n@loop: INX\nJMP @loop", Dormann { max_instructions: 100, ..dormann }).unwrap_err();
+        assert!(long.starts_with("failed: no trap after 100 instructions, test case $00\n"));
+        assert!(long.lines().count() == 1 + TRACE);
+    }
+
+    #[test]
+    fn test_decimal() {
+        assert!(run(".org $0200\nLDA #0\nSTA $0B\nJMP *", DECIMAL).unwrap() == "passed: trap at $0204 after 2 instructions, 5 cycles");
+        // $DB is not a stop on the 6502
+        let dcp = run(".org $0200\nLDA #0\nSTA $0B\n.byte $DB, $00, $10", DECIMAL).unwrap_err();
+        assert!(dcp.starts_with("failed: $0204: DCP $1000,Y: the undocumented opcode is not implemented by the Cpu, ERROR=$00\n"), "{}", dcp);
+        assert!(run(".org $0200\nLDA #1\nSTA $0B\nBRK", DECIMAL).unwrap_err().starts_with("failed: BRK at $0204 after 2 instructions, 5 cycles, ERROR=$01\n"));
+        let nested = run(".org $0200\nLDA #1\nSTA $0B\nJSR fail\nfail: BRK", DECIMAL).unwrap_err();
+        assert!(nested.starts_with("failed: BRK at $0207 after 3 instructions, 11 cycles, ERROR=$01\n"));
+        assert!(nested.ends_with("  JSR $0207       A:01 X:00 Y:00 P:04 SP:FD CYC:5\nbacktrace:\n#0 $0207\n#1 $0204: JSR $0207\n"), "{}", nested);
+        let (origin, image) = Assembler::new().assemble(".org $0200\nLDA #1\nSTA $0B\nJSR fail\nfail: BRK").unwrap().image().unwrap();
+        let symbols = HashMap::from([(0x0207, "fail".to_string())]);
+        let named = Dormann { origin, start: origin, ..DECIMAL }.run(&image, &symbols).unwrap_err();
+        assert!(named.ends_with("0204  20 07 02  JSR fail        A:01 X:00 Y:00 P:04 SP:FD CYC:5\nbacktrace:\n#0 $0207 fail\n#1 $0204: JSR fail\n"), "{}", named);
+        let fault = run(".org $0200\nSED\nADC #1", DECIMAL).unwrap_err();
+        assert!(fault.starts_with("failed: $0201: ADC #$01: decimal mode is not implemented by the Cpu, ERROR=$00\n0200  F8  "));
+    }
+
+    // an NROM image with the program at $C000
+    fn nes(source: &str) -> Vec<u8> {
+        let (origin, image) = Assembler::new().assemble(source).unwrap().image().unwrap();
+        let mut rom = b"NES\x1a\x01\x01\x00\x00".to_vec();
+        rom.resize(16 + 0x4000 + 0x2000, 0);
+        rom[16 + origin as usize - 0xc000..][..image.len()].copy_from_slice(&image);
+        rom
+    }
+
+    const NESTEST_SRC: &str = ".org $C000\nLDA #0\nSTA $02\nLDX #$81\nBMI loop\nNOP\nloop: JMP loop";
+    const NESTEST_LOG: &str = "\
+C000  A9 00     LDA #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C002  85 02     STA $02 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 27 CYC:9
+C004  A2 81     LDX #$81                        A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C006  30 01     BMI $C009                       A:00 X:81 Y:00 P:A4 SP:FD PPU:  0, 42 CYC:14
+C009  4C 09 C0  JMP $C009                       A:00 X:81 Y:00 P:A4 SP:FD PPU:  0, 51 CYC:17
+";
+
+    #[test]
+    fn test_nestest() {
+        let rom = nes(NESTEST_SRC);
+        assert!(nestest(&rom, NESTEST_LOG, &HashMap::new()).unwrap() == "passed: 5 lines, 20 cycles, $02=$00 $03=$00");
+
+        let fail = nestest(&rom, &NESTEST_LOG.replace("CYC:17", "CYC:18"), &HashMap::new()).unwrap_err();
+        assert!(fail == "\
+failed: line 5 of the log
+expected: C009  4C 09 C0  JMP $C009                       A:00 X:81 Y:00 P:A4 SP:FD PPU:  0, 51 CYC:18
+     got: C009  4C 09 C0  JMP $C009       A:00 X:81 Y:00 P:A4 SP:FD CYC:17, $02=$00 $03=$00
+C000  A9 00     LDA #$00        A:00 X:00 Y:00 P:24 SP:FD CYC:7
+C002  85 02     STA $02         A:00 X:00 Y:00 P:26 SP:FD CYC:9
+C004  A2 81     LDX #$81        A:00 X:00 Y:00 P:26 SP:FD CYC:12
+C006  30 01     BMI $C009       A:00 X:81 Y:00 P:A4 SP:FD CYC:14
+");
+        let error = nestest(&nes(&NESTEST_SRC.replace("#0", "#1")), NESTEST_LOG, &HashMap::new()).unwrap_err();
+        assert!(error.starts_with("failed: line 2 of the log\nexpected: C002  85 02 "));
+        // the 2A03 has no decimal mode
+        let decimal = nes(".org $C000\nSED\nLDA #9\nADC #1\nCMP #$0A\nloop: BEQ loop");
+        let log = "\
+C000  F8        SED                             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C001  A9 09     LDA #$09                        A:00 X:00 Y:00 P:2C SP:FD PPU:  0, 27 CYC:9
+C003  69 01     ADC #$01                        A:09 X:00 Y:00 P:2C SP:FD PPU:  0, 33 CYC:11
+C005  C9 0A     CMP #$0A                        A:0A X:00 Y:00 P:2C SP:FD PPU:  0, 39 CYC:13
+C007  F0 FE     BEQ $C007                       A:0A X:00 Y:00 P:2F SP:FD PPU:  0, 45 CYC:15
+";
+        assert!(nestest(&decimal, log, &HashMap::new()).unwrap() == "passed: 5 lines, 18 cycles, $02=$00 $03=$00");
+        assert!(nestest(&rom, "C000  A9 00  LDA", &HashMap::new()).unwrap_err() == "line 1 of the log: can't read \"C000  A9 00  LDA\"");
+        assert!(nestest(&rom[..100], NESTEST_LOG, &HashMap::new()).unwrap_err() == "bad PRG ROM of 1 banks");
+        assert!(nestest(b"NES\x1a\x01\x01\x40\x00\0\0\0\0\0\0\0\0", NESTEST_LOG, &HashMap::new()).unwrap_err() == "mapper 4 is not supported (only NROM and MMC1)");
+    }
+
+    // a reset from the ROM (counted in $10), then the text "ok\n" and the result
+    const BLARGG_SRC: &str = "
+        .org $C000
+reset:  LDA #$80
+        STA $6000
+        LDA #$DE
+        STA $6001
+        LDA #$B0
+        STA $6002
+        LDA #$61
+        STA $6003
+        LDX $10
+        LDA hi,X
+        PHA
+        LDA lo,X
+        PHA
+        RTS
+first:  INC $10
+        LDA #$81
+        STA $6000
+@wait:  JMP @wait
+second: LDX #0
+@copy:  LDA text,X
+        STA $6004,X
+        INX
+        CPX #4
+        BNE @copy
+        LDA #RESULT
+        STA $6000
+@done:  JMP @done
+hi:     .byte >(first-1), >(second-1)
+lo:     .byte <(first-1), <(second-1)
+text:   .byte 'o', 'k', 10, 0
+        .org $FFFC
+        .word reset
+";
+
+    #[test]
+    fn test_blargg() {
+        let pass = BLARGG.run(&nes(&format!("RESULT = 0\n{}", BLARGG_SRC)), &HashMap::new()).unwrap();
+        assert!(pass.starts_with("ok\npassed after "), "{}", pass);
+        assert!(pass.ends_with(", 1 reset(s)"));
+
+        let fail = BLARGG.run(&nes(&format!("RESULT = 3\n{}", BLARGG_SRC)), &HashMap::new()).unwrap_err();
+        assert!(fail.starts_with("ok\nfailed: result 3 after ") && fail.ends_with(", 1 reset(s)"));
+
+        // binary with D set: 9 + 1 is $0A (result 10), not $10
+        let decimal = BLARGG.run(&nes(&BLARGG_SRC.replace("LDA #RESULT", "SED\nCLC\nLDA #9\nADC #1")), &HashMap::new()).unwrap_err();
+        assert!(decimal.starts_with("ok\nfailed: result 10 after "), "{}", decimal);
+        let long = Blargg { max_instructions: 1000 }.run(&nes(".org $C000\nreset: JMP reset\n.org $FFFC\n.word reset"), &HashMap::new()).unwrap_err();
+        assert!(long.starts_with("failed: no result after 1000 instructions\n"));
+    }
+
+    // an MMC1 image of 4 banks, the program in the last one at $C000 and $10 * n at $2000 in bank n
+    fn mmc1(source: &str) -> Vec<u8> {
+        let (origin, image) = Assembler::new().assemble(source).unwrap().image().unwrap();
+        let mut rom = b"NES\x1a\x04\x01\x10\x00".to_vec();
+        rom.resize(16 + 4 * 0x4000 + 0x2000, 0);
+        rom[16 + 3 * 0x4000 + origin as usize - 0xc000..][..image.len()].copy_from_slice(&image);
+        for bank in 0..4 {
+            rom[16 + bank * 0x4000 + 0x2000] = bank as u8 * 0x10;
+        }
+        rom
+    }
+
+    // the bank 2 at $8000 through the serial register, the result is $20 - what is read at $A000
+    const MMC1_SRC: &str = "
+        .org $C000
+reset:  LDA #$80
+        STA $8000
+        STA $6000
+        LDA #$DE
+        STA $6001
+        LDA #$B0
+        STA $6002
+        LDA #$61
+        STA $6003
+        LDA #2
+        LDX #5
+@bit:   STA $E000
+        LSR A
+        DEX
+        BNE @bit
+        STA $A000       ; only for the mapper
+        LDA #$20
+        SEC
+        SBC $A000
+        STA $6000
+@done:  JMP @done
+        .org $FFFC
+        .word reset
+";
+
+    #[test]
+    fn test_mmc1() {
+        let pass = BLARGG.run(&mmc1(MMC1_SRC), &HashMap::new()).unwrap();
+        assert!(pass.starts_with("passed after "), "{}", pass);
+        let fail = BLARGG.run(&mmc1(&MMC1_SRC.replace("LDA #2", "LDA #1")), &HashMap::new()).unwrap_err();
+        assert!(fail.starts_with("failed: result 16 after "), "{}", fail);
+
+        // 32K at $8000, then the first bank fixed at $8000
+        let mut cartridge = Cartridge::new(&mmc1(MMC1_SRC)).unwrap();
+        assert!(cartridge.bank(0x8000)[0x2000] == 0x00 && cartridge.bank(0xc000)[0x2000] == 0x30);
+        let write = |cartridge: &mut Cartridge, address, value: u8| (0..5).for_each(|i| cartridge.write(address, value >> i));
+        write(&mut cartridge, 0x8000, 0x00);
+        write(&mut cartridge, 0xe000, 0x03);
+        assert!(cartridge.bank(0x8000)[0x2000] == 0x20 && cartridge.bank(0xc000)[0x2000] == 0x30);
+        write(&mut cartridge, 0x8000, 0x08);
+        assert!(cartridge.bank(0x8000)[0x2000] == 0x00 && cartridge.bank(0xc000)[0x2000] == 0x30);
+        cartridge.write(0x8000, 0x80);
+        assert!(cartridge.bank(0x8000)[0x2000] == 0x30 && cartridge.bank(0xc000)[0x2000] == 0x30);
+
+        assert!(Cartridge::new(b"NES\x1a\x01\x01\x10\x00\0\0\0\0\0\0\0\0").err().unwrap() == "bad PRG ROM of 1 banks");
+    }
+
+    fn rom_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::var("MOS6502_ROMS").unwrap_or_else(|_| "roms".to_string());
+        std::path::Path::new(&dir).join(name)
+    }
+
+    fn read_rom(path: &std::path::Path) -> Vec<u8> {
+        std::fs::read(path).unwrap_or_else(|e| panic!("{}: {} (MOS6502_ROMS is the directory of the test ROMs)", path.display(), e))
+    }
+
+    fn rom(name: &str, dormann: Dormann) {
+        let image = read_rom(&rom_path(name));
+        if let Err(e) = dormann.run(&image, &HashMap::new()) {
+            panic!("{}: {}", name, e);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs in $MOS6502_ROMS"]
+    fn test_rom_nestest() {
+        let (rom, log) = (rom_path("nestest.nes"), rom_path("nestest.log"));
+        let image = read_rom(&rom);
+        let log = std::fs::read_to_string(&log).unwrap_or_else(|e| panic!("{}: {}", log.display(), e));
+        if let Err(e) = nestest(&image, &log, &HashMap::new()) {
+            panic!("nestest.nes: {}", e);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs in $MOS6502_ROMS"]
+    fn test_rom_blargg() {
+        let dir = rom_path("blargg");
+        let mut roms: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap_or_else(|e| panic!("{}: {} (MOS6502_ROMS is the directory of the test ROMs)", dir.display(), e))
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "nes"))
+            .collect();
+        roms.sort();
+        let failures: Vec<String> = roms
+            .iter()
+            .filter_map(|path| BLARGG.run(&std::fs::read(path).unwrap(), &HashMap::new()).err().map(|e| format!("{}: {}", path.display(), e)))
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs in $MOS6502_ROMS"]
+    fn test_rom_functional() {
+        rom("6502_functional_test.bin", FUNCTIONAL);
+    }
+
+    #[test]
+    #[ignore = "needs the test ROMs in $MOS6502_ROMS"]
+    fn test_rom_decimal() {
+        rom("6502_decimal_test.bin", DECIMAL);
+    }
+}
@@ -0,0 +1,267 @@
+// Shadow call stack
+//
+// Follows the calls of a program beside the stack of the Cpu: JSR and BRK push a frame with the
+// stack pointer after the push and the address pushed, RTS and RTI pop it. The stack pointer and the return address are checked when they
+// return, the mismatches are the tricks played with the stack:
+//
+//   more bytes on the stack than in the frame       an address pushed by the program, a jump
+//                                                   (PHA, PHA, RTS: a jump table)
+//   fewer bytes                                     the frames above it are dropped (PLA, PLA to
+//                                                   return to the caller's caller, TXS)
+//   another return address                          the return address was changed
+//
+// A return with no frame is the one of the routine which was started. backtrace() prints the
+// frames from the innermost, with the last mismatches:
+//
+//   #0 $8015 mul+5
+//   #1 $8009 square+3: JSR mul
+//   #2 $C012 reset+18: JSR square
+//   stack mismatches (1):
+//     $8030: RTS to $8040 pushed by the program (a jump table?)
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+
+use crate::cpu::Cpu;
+use crate::disasm::Instruction;
+use crate::profile::Names;
+
+// the mismatches kept for the backtrace
+const MISMATCHES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Jsr,
+    Brk,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: Kind,
+    pub site: u16,  // the address of the JSR or of the BRK
+    pub entry: u16, // the called routine or the handler
+    pub ret: u16,   // the address pushed
+    pub s: u8,      // the stack pointer after the push
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShadowStack {
+    pub frames: Vec<Frame>,
+    pub mismatches: VecDeque<String>, // the last ones
+    pub count: usize,                 // all the mismatches
+}
+
+impl ShadowStack {
+    pub fn new() -> ShadowStack {
+        ShadowStack::default()
+    }
+
+    // nothing to show: no frames and no mismatches
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.count == 0
+    }
+
+    fn mismatch(&mut self, pc: u16, what: String) {
+        if self.mismatches.len() == MISMATCHES {
+            self.mismatches.pop_front();
+        }
+        self.mismatches.push_back(format!("${:04X}: {}", pc, what));
+        self.count += 1;
+    }
+
+    // the instruction at the pc of the Cpu, before it runs
+    pub fn observe(&mut self, cpu: &Cpu, ins: &Instruction) {
+        let pc = cpu.pc();
+        let s = cpu.registers().3;
+        let memory = cpu.memory();
+        let pulled = |offset: u8| {
+            let byte = |i: u8| memory[0x100 + s.wrapping_add(offset + i) as usize];
+            u16::from_le_bytes([byte(0), byte(1)])
+        };
+        match ins.mnemonic {
+            "JSR" => self.frames.push(Frame { kind: Kind::Jsr, site: pc, entry: ins.operand, ret: pc.wrapping_add(2), s: s.wrapping_sub(2) }),
+            "BRK" => {
+                let entry = u16::from_le_bytes([memory[0xfffe], memory[0xffff]]);
+                self.frames.push(Frame { kind: Kind::Brk, site: pc, entry, ret: pc.wrapping_add(2), s: s.wrapping_sub(3) });
+            }
+            "RTS" => self.leave(pc, s, pulled(1), "RTS"),
+            "RTI" => self.leave(pc, s, pulled(2), "RTI"),
+            _ => {}
+        }
+    }
+
+    // a reset of the Cpu
+    pub fn reset(&mut self) {
+        self.frames.clear();
+    }
+
+    // `s`: the stack pointer before the pulls, `address`: the one pulled (RTS goes to the next one)
+    fn leave(&mut self, pc: u16, s: u8, address: u16, mnemonic: &str) {
+        let to = |address: u16| if mnemonic == "RTS" { address.wrapping_add(1) } else { address };
+        let mut dropped = 0;
+        while self.frames.last().is_some_and(|frame| frame.s < s) {
+            self.frames.pop();
+            dropped += 1;
+        }
+        if dropped > 0 {
+            self.mismatch(pc, format!("{} drops {} frame(s)", mnemonic, dropped));
+        }
+        match self.frames.last() {
+            Some(frame) if frame.s == s => {
+                if frame.ret != address {
+                    let what = format!("{} returns to ${:04X} instead of ${:04X}", mnemonic, to(address), to(frame.ret));
+                    self.mismatch(pc, what);
+                }
+                self.frames.pop();
+            }
+            Some(_) => self.mismatch(pc, format!("{} to ${:04X} pushed by the program (a jump table?)", mnemonic, to(address))),
+            None => {}
+        }
+    }
+
+    // the frames from the innermost, `pc`: the one of the Cpu
+    pub fn backtrace(&self, pc: u16, symbols: &HashMap<u16, String>) -> String {
+        let names = Names::new(symbols);
+        let at = |address: u16| match names.name(address) {
+            name if name.starts_with('$') => name,
+            name => format!("${:04X} {}", address, name),
+        };
+        let mut text = format!("#0 {}\n", at(pc));
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let call = match frame.kind {
+                Kind::Jsr => format!("JSR {}", names.name(frame.entry)),
+                Kind::Brk => format!("BRK -> {}", names.name(frame.entry)),
+            };
+            let _ = writeln!(text, "#{} {}: {}", i + 1, at(frame.site), call);
+        }
+        if self.count > 0 {
+            let _ = writeln!(text, "stack mismatches ({}):", self.count);
+            for mismatch in &self.mismatches {
+                let _ = writeln!(text, "  {}", mismatch);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::disasm::decode;
+
+    // runs the program from `start` for `steps` instructions
+    fn run(source: &str, start: &str, steps: usize) -> (ShadowStack, u16, HashMap<u16, String>) {
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut cpu = Cpu::new();
+        let (origin, image) = program.image().unwrap();
+        cpu.patch_memory(origin as usize, &image);
+        cpu.update_pc(program.symbols[start]);
+        let mut shadow = ShadowStack::new();
+        for _ in 0..steps {
+            let ins = decode(&cpu.memory()[cpu.pc() as usize..]).unwrap();
+            shadow.observe(&cpu, &ins);
+            cpu.step();
+        }
+        let symbols = program.symbols.iter().map(|(name, address)| (*address, name.clone())).collect();
+        (shadow, cpu.pc(), symbols)
+    }
+
+    const CALLS: &str = "\
+        .org $8000
+main:   JSR square
+        RTS
+square: TAX
+        JSR mul
+        RTS
+mul:    STX $10
+        ASL A
+        RTS
+";
+
+    #[test]
+    fn test_backtrace() {
+        let (shadow, pc, symbols) = run(CALLS, "main", 4);
+        assert!(shadow.frames.len() == 2 && shadow.count == 0);
+        assert!(shadow.backtrace(pc, &symbols) == "#0 $800B mul+2\n#1 $8005 square+1: JSR mul\n#2 $8000 main: JSR square\n");
+        let (shadow, pc, _) = run(CALLS, "main", 7);
+        assert!(shadow.is_empty() && pc == 0x8003);
+    }
+
+    #[test]
+    fn test_branches() {
+        // a call in a loop
+        let source = "\
+        .org $8000
+main:   LDX #2
+loop:   JSR tick
+        DEX
+        BNE loop
+        RTS
+tick:   INY
+        RTS
+";
+        let (shadow, pc, symbols) = run(source, "main", 8);
+        assert!(shadow.backtrace(pc, &symbols) == "#0 $800A tick+1\n#1 $8002 loop: JSR tick\n");
+        let (shadow, pc, _) = run(source, "main", 11);
+        assert!(shadow.is_empty() && pc == 0x8008);
+    }
+
+    #[test]
+    fn test_mismatches() {
+        // a jump through a table, and a routine which returns to its caller's caller
+        let source = "\
+        .org $8000
+main:   JSR jump
+        JSR skip
+        RTS
+jump:   LDA #>(target-1)
+        PHA
+        LDA #<(target-1)
+        PHA
+        RTS
+target: RTS
+skip:   JSR drop
+        NOP
+drop:   PLA
+        PLA
+        RTS
+";
+        let (shadow, pc, symbols) = run(source, "main", 6);
+        assert!(pc == 0x800E && shadow.frames.len() == 1 && shadow.count == 1);
+        assert!(shadow.backtrace(pc, &symbols) == "\
+#0 $800E target
+#1 $8000 main: JSR jump
+stack mismatches (1):
+  $800D: RTS to $800E pushed by the program (a jump table?)
+");
+        let (shadow, pc, _) = run(source, "main", 12);
+        assert!(pc == 0x8006 && shadow.frames.is_empty());
+        assert!(shadow.mismatches.back().unwrap() == "$8015: RTS drops 1 frame(s)");
+
+        // the return address changed on the stack
+        let mut shadow = ShadowStack::new();
+        let mut cpu = Cpu::new();
+        cpu.update_pc(0x8000);
+        shadow.observe(&cpu, &decode(&[0x20, 0x00, 0x90]).unwrap());
+        cpu.set_registers(0, 0, 0, 0xfb, 0);
+        cpu.patch_memory(0x1fc, &[0x10, 0x80]);
+        shadow.observe(&cpu, &decode(&[0x60]).unwrap());
+        assert!(shadow.frames.is_empty() && shadow.mismatches[0] == "$8000: RTS returns to $8011 instead of $8003");
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut cpu = Cpu::new();
+        cpu.patch_memory(0xfffe, &[0x00, 0x90]);
+        cpu.update_pc(0x8010);
+        let mut shadow = ShadowStack::new();
+        shadow.observe(&cpu, &decode(&[0x00]).unwrap());
+        cpu.set_registers(0, 0, 0, 0xfa, 0);
+        cpu.patch_memory(0x1fb, &[0x34, 0x12, 0x80]);
+        shadow.observe(&cpu, &decode(&[0xe8]).unwrap());
+        assert!(shadow.backtrace(0x9000, &HashMap::new()) == "#0 $9000\n#1 $8010: BRK -> $9000\n");
+        shadow.observe(&cpu, &decode(&[0x40]).unwrap());
+        assert!(shadow.is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+// Tom Harte's SingleStepTests (ProcessorTests)
+//
+// The tests of github.com/SingleStepTests/65x02 (the 6502 directory) are one JSON file per
+// opcode (a9.json, ...), each with 10000 runs of one instruction:
+//
+//   {"name": "a9 23 6a",
+//    "initial": {"pc": 59082, "s": 39, "a": 57, "x": 33, "y": 174, "p": 96, "ram": [[59082, 169], ...]},
+//    "final": {...},
+//    "cycles": [[59082, 169, "read"], ...]}
+//
+// A test passes when the registers, the bytes of the final RAM and the number of cycles (the
+// ones of step::cost) are the expected ones. The Cpu has no bus, so the address, the value
+// and the direction of the accesses of each cycle can't be compared. The instructions which
+// would make the Cpu panic are errors.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cpu::Cpu;
+use crate::disasm::{fetch, OPCODES};
+use crate::step::{cost, fault};
+use crate::json::Json;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    pub pc: u16,
+    pub regs: [u8; 5], // s, a, x, y, p
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub initial: State,
+    pub expected: State,
+    pub cycles: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Fail(String),  // the differences
+    Error(String), // the Cpu can't run the instruction
+}
+
+const REGISTERS: [&str; 5] = ["s", "a", "x", "y", "p"];
+
+fn state(json: &Json, what: &str) -> Result<State, String> {
+    let number = |json: &Json, max: i64| json.as_i64().filter(|n| (0..=max).contains(n)).ok_or_else(|| format!("{}: bad value {}", what, json));
+    let pc = number(json.get("pc"), 0xffff)? as u16;
+    let mut regs = [0; 5];
+    for (reg, name) in regs.iter_mut().zip(REGISTERS) {
+        *reg = number(json.get(name), 0xff)? as u8;
+    }
+    let mut ram = Vec::new();
+    for entry in json.get("ram").as_array().ok_or_else(|| format!("{}: the ram is missing", what))? {
+        match entry.as_array() {
+            Some([address, value]) => ram.push((number(address, 0xffff)? as u16, number(value, 0xff)? as u8)),
+            _ => return Err(format!("{}: bad ram entry {}", what, entry)),
+        }
+    }
+    Ok(State { pc, regs, ram })
+}
+
+pub fn parse(text: &str) -> Result<Vec<Case>, String> {
+    let json = Json::parse(text)?;
+    let mut cases = Vec::new();
+    for (i, test) in json.as_array().ok_or("expected an array of tests")?.iter().enumerate() {
+        let name = test.get("name").as_str().map_or_else(|| format!("test {}", i + 1), |name| name.to_string());
+        cases.push(Case {
+            initial: state(test.get("initial"), &name)?,
+            expected: state(test.get("final"), &name)?,
+            cycles: test.get("cycles").as_array().map_or(0, |cycles| cycles.len()),
+            name,
+        });
+    }
+    Ok(cases)
+}
+
+impl Case {
+    // the opcode of the instruction
+    pub fn opcode(&self) -> Option<u8> {
+        self.initial.ram.iter().find(|(address, _)| *address == self.initial.pc).map(|(_, value)| *value)
+    }
+
+    // `cpu` is reused between the tests: the bytes of the test are cleared after it, so the
+    // next one starts from a RAM of zeroes
+    pub fn run(&self, cpu: &mut Cpu) -> Verdict {
+        let verdict = self.check(cpu);
+        for &(address, _) in self.initial.ram.iter().chain(&self.expected.ram) {
+            cpu.patch_memory(address as usize, &[0]);
+        }
+        verdict
+    }
+
+    fn check(&self, cpu: &mut Cpu) -> Verdict {
+        for &(address, value) in &self.initial.ram {
+            cpu.patch_memory(address as usize, &[value]);
+        }
+        let [s, a, x, y, p] = self.initial.regs;
+        cpu.set_registers(a, x, y, s, p);
+        cpu.update_pc(self.initial.pc);
+        let ins = fetch(cpu.memory(), self.initial.pc);
+        if let Some(why) = fault(cpu, &ins) {
+            return Verdict::Error(why.to_string());
+        }
+        let cycles = cost(cpu, &ins);
+        cpu.step();
+
+        let mut diffs = Vec::new();
+        if cpu.pc() != self.expected.pc {
+            diffs.push(format!("PC: expected ${:04X}, got ${:04X}", self.expected.pc, cpu.pc()));
+        }
+        let (a, x, y, s, p) = cpu.registers();
+        for ((name, expected), value) in REGISTERS.iter().zip(self.expected.regs).zip([s, a, x, y, p]) {
+            if expected != value {
+                diffs.push(format!("{}: expected ${:02X}, got ${:02X}", name.to_ascii_uppercase(), expected, value));
+            }
+        }
+        for &(address, expected) in &self.expected.ram {
+            let value = cpu.memory()[address as usize];
+            if expected != value {
+                diffs.push(format!("${:04X}: expected ${:02X}, got ${:02X}", address, expected, value));
+            }
+        }
+        if cycles != self.cycles {
+            diffs.push(format!("cycles: expected {}, got {}", self.cycles, cycles));
+        }
+        if diffs.is_empty() {
+            Verdict::Pass
+        } else {
+            Verdict::Fail(diffs.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Row {
+    pub passed: usize,
+    pub failed: usize,
+    pub errors: usize,
+    pub first: Option<String>, // the first failure or error
+}
+
+// the results by opcode
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub rows: BTreeMap<u8, Row>,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary::default()
+    }
+
+    pub fn run(&mut self, cases: &[Case]) {
+        let mut cpu = Cpu::new();
+        for case in cases {
+            let opcode = match case.opcode() {
+                Some(opcode) => opcode,
+                None => continue,
+            };
+            let row = self.rows.entry(opcode).or_default();
+            let verdict = case.run(&mut cpu);
+            let message = match &verdict {
+                Verdict::Pass => None,
+                Verdict::Fail(diffs) => Some(diffs),
+                Verdict::Error(why) => Some(why),
+            };
+            match verdict {
+                Verdict::Pass => row.passed += 1,
+                Verdict::Fail(_) => row.failed += 1,
+                Verdict::Error(_) => row.errors += 1,
+            }
+            if row.first.is_none() {
+                row.first = message.map(|message| format!("{}: {}", case.name, message));
+            }
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.rows.values().any(|row| row.failed + row.errors > 0)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "op  instruction        passed  failed  errors  first failure")?;
+        let mut total = Row::default();
+        for (&opcode, row) in &self.rows {
+            let info = &OPCODES[opcode as usize];
+            let name = format!("{} {:?}", info.mnemonic, info.mode);
+            write!(f, "{:02X}  {:<17}  {:>6}  {:>6}  {:>6}", opcode, name, row.passed, row.failed, row.errors)?;
+            match &row.first {
+                Some(first) => writeln!(f, "  {}", first)?,
+                None => writeln!(f)?,
+            }
+            total.passed += row.passed;
+            total.failed += row.failed;
+            total.errors += row.errors;
+        }
+        let opcodes = self.rows.values().filter(|row| row.failed + row.errors == 0).count();
+        writeln!(f, "    {:<17}  {:>6}  {:>6}  {:>6}", "total", total.passed, total.failed, total.errors)?;
+        writeln!(f, "{} of {} opcodes pass all their tests", opcodes, self.rows.len())?;
+        writeln!(f, "(the cycles are the ones of the opcode table with the page crossings, the Cpu doesn't count them)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LDA #$23, a BNE not taken, a BRK and an ADC with V
+    const TESTS: &str = r#"[
+        {"name": "a9 23 6a",
+         "initial": {"pc": 59082, "s": 39, "a": 57, "x": 33, "y": 174, "p": 96, "ram": [[59082, 169], [59083, 35], [59084, 106]]},
+         "final": {"pc": 59084, "s": 39, "a": 35, "x": 33, "y": 174, "p": 96, "ram": [[59082, 169], [59083, 35], [59084, 106]]},
+         "cycles": [[59082, 169, "read"], [59083, 35, "read"]]},
+        {"name": "d0 05 00",
+         "initial": {"pc": 512, "s": 39, "a": 57, "x": 33, "y": 174, "p": 34, "ram": [[512, 208], [513, 5], [514, 0]]},
+         "final": {"pc": 514, "s": 39, "a": 57, "x": 33, "y": 174, "p": 34, "ram": [[512, 208], [513, 5], [514, 0]]},
+         "cycles": [[512, 208, "read"], [513, 5, "read"]]},
+        {"name": "00 01 02",
+         "initial": {"pc": 512, "s": 39, "a": 0, "x": 0, "y": 0, "p": 32, "ram": [[512, 0]]},
+         "final": {"pc": 0, "s": 36, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 0]]},
+         "cycles": [[512, 0, "read"]]},
+        {"name": "69 01 00",
+         "initial": {"pc": 768, "s": 255, "a": 127, "x": 0, "y": 0, "p": 32, "ram": [[768, 105], [769, 1]]},
+         "final": {"pc": 770, "s": 255, "a": 128, "x": 0, "y": 0, "p": 224, "ram": [[768, 105], [769, 1]]},
+         "cycles": [[768, 105, "read"], [769, 1, "read"]]}
+    ]"#;
+
+    #[test]
+    fn test_run() {
+        let cases = parse(TESTS).unwrap();
+        assert!(cases.len() == 4 && cases[0].opcode() == Some(0xa9) && cases[0].cycles == 2);
+        let mut cpu = Cpu::new();
+        assert!(cases[0].run(&mut cpu) == Verdict::Pass);
+        assert!(cpu.memory()[59082..59085] == [0, 0, 0]);
+        assert!(cases[1].run(&mut cpu) == Verdict::Pass);
+        assert!(cases[2].run(&mut cpu) == Verdict::Error("BRK is not implemented by the Cpu".to_string()));
+        assert!(cases[3].run(&mut cpu) == Verdict::Pass);
+        let mut wrong = cases[3].clone();
+        wrong.expected.regs[4] = 0xa0;
+        assert!(wrong.run(&mut cpu) == Verdict::Fail("P: expected $A0, got $E0".to_string()));
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut summary = Summary::new();
+        summary.run(&parse(TESTS).unwrap());
+        assert!(summary.failed());
+        assert!(summary.to_string() == "\
+op  instruction        passed  failed  errors  first failure
+00  BRK Implied             0       0       1  00 01 02: BRK is not implemented by the Cpu
+69  ADC Immediate           1       0       0
+A9  LDA Immediate           1       0       0
+D0  BNE Relative            1       0       0
+    total                   3       0       1
+3 of 4 opcodes pass all their tests
+(the cycles are the ones of the opcode table with the page crossings, the Cpu doesn't count them)
+");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("{}").unwrap_err() == "expected an array of tests");
+        assert!(parse(r#"[{"name": "t", "initial": {"pc": 70000}}]"#).unwrap_err() == "t: bad value 70000");
+        assert!(parse(r#"[{"name": "t", "initial": {"pc": 0, "s": 0, "a": 0, "x": 0, "y": 0, "p": 0}}]"#).unwrap_err() == "t: the ram is missing");
+    }
+}
@@ -0,0 +1,349 @@
+// Maximum stack depth
+//
+// Follows the code from the hardware vectors and the given entry points as the recursive
+// disassembler does, and computes for every routine (JSR target) the number of bytes it
+// can have on the stack: PHA/PHP +1, PLA/PLP -1, JSR +2 plus the usage of the called
+// routine. The interrupt handlers get 3 more bytes for the return address and P.
+// TXS starts a new stack (the reset code), JMP (indirect) and the routines outside of
+// the image are not followed.
+//
+// Reported as problems: recursion (unbounded usage), paths which reach an instruction
+// with different depths, RTS/RTI with bytes still pushed (or pulled too many) and
+// pulls with nothing pushed.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::cpu::AddressingMode::*;
+use crate::disasm::{decode, Instruction, OPCODES, VECTORS};
+
+// deeper than that the stack wraps around in page $01
+const LIMIT: i32 = 0x100;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Usage {
+    depth: usize,
+    chain: Vec<u16>, // the routines called on the deepest path
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub address: u16,
+    pub interrupt: bool,
+    pub depth: Option<usize>, // None if unbounded, with the 3 bytes of an interrupt
+    pub chain: Vec<u16>,      // the deepest call chain (without the entry point)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub entries: Vec<Entry>,
+    pub problems: BTreeSet<(u16, String)>,
+}
+
+impl Report {
+    // the deepest entry point without an interrupt plus all the interrupt handlers (nesting)
+    pub fn worst_case(&self) -> Option<usize> {
+        let mut main = 0;
+        let mut interrupts = 0;
+        for entry in &self.entries {
+            let depth = entry.depth?;
+            if entry.interrupt {
+                interrupts += depth;
+            } else {
+                main = main.max(depth);
+            }
+        }
+        Some(main + interrupts)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{} ${:04X}: ", entry.name, entry.address)?;
+            match entry.depth {
+                Some(depth) => write!(f, "{} bytes", depth)?,
+                None => write!(f, "unbounded")?,
+            }
+            if entry.interrupt {
+                write!(f, " (3 for the interrupt)")?;
+            }
+            let chain: Vec<String> = entry.chain.iter().map(|a| format!("${:04X}", a)).collect();
+            if !chain.is_empty() {
+                write!(f, ", {} -> {}", entry.name, chain.join(" -> "))?;
+            }
+            writeln!(f)?;
+        }
+        match self.worst_case() {
+            Some(depth) if depth > LIMIT as usize => writeln!(f, "worst case: {} bytes, overflows page $01", depth)?,
+            Some(depth) => writeln!(f, "worst case: {} bytes", depth)?,
+            None => writeln!(f, "worst case: unbounded")?,
+        }
+        for (address, message) in &self.problems {
+            writeln!(f, "${:04X}: warning: {}", address, message)?;
+        }
+        Ok(())
+    }
+}
+
+struct Analyzer<'a> {
+    image: &'a [u8],
+    origin: usize,
+    routines: HashMap<u16, Option<Usage>>,
+    active: Vec<u16>, // the routines being analyzed, for the recursion
+    problems: BTreeSet<(u16, String)>,
+}
+
+impl<'a> Analyzer<'a> {
+    fn contains(&self, addr: u16) -> bool {
+        (addr as usize) >= self.origin && (addr as usize) < self.origin + self.image.len()
+    }
+
+    fn decode(&self, addr: u16) -> Option<Instruction> {
+        decode(&self.image[addr as usize - self.origin..])
+    }
+
+    fn word(&self, addr: u16) -> Option<u16> {
+        if self.contains(addr) && self.contains(addr.wrapping_add(1)) {
+            let i = addr as usize - self.origin;
+            Some(self.image[i] as u16 | (self.image[i + 1] as u16) << 8)
+        } else {
+            None
+        }
+    }
+
+    fn problem(&mut self, addr: u16, message: String) {
+        self.problems.insert((addr, message));
+    }
+
+    // the usage of the routine at `entry` (None if unbounded)
+    fn routine(&mut self, entry: u16) -> Option<Usage> {
+        if let Some(usage) = self.routines.get(&entry) {
+            return usage.clone();
+        }
+        if let Some(idx) = self.active.iter().position(|a| *a == entry) {
+            let cycle: Vec<String> = self.active[idx..].iter().chain([&entry]).map(|a| format!("${:04X}", a)).collect();
+            self.problem(entry, format!("recursion {}: the stack usage is unbounded", cycle.join(" -> ")));
+            return None;
+        }
+        self.active.push(entry);
+        let usage = self.walk(entry);
+        self.active.pop();
+        self.routines.insert(entry, usage.clone());
+        usage
+    }
+
+    fn walk(&mut self, entry: u16) -> Option<Usage> {
+        let mut usage = Usage { depth: 0, chain: Vec::new() };
+        let mut seen: HashMap<u16, i32> = HashMap::new();
+        let mut work = vec![(entry, 0i32)];
+
+        while let Some((mut pc, mut depth)) = work.pop() {
+            loop {
+                if !self.contains(pc) {
+                    self.problem(pc, "the code runs out of the image".to_string());
+                    break;
+                }
+                match seen.get(&pc) {
+                    Some(&d) if d == depth => break,
+                    Some(&d) => {
+                        self.problem(pc, format!("reached with {} and {} bytes on the stack (unbalanced pushes and pulls)", d, depth));
+                        if depth < d {
+                            break;
+                        }
+                    }
+                    None => {}
+                }
+                if depth > LIMIT {
+                    self.problem(pc, "the stack grows without bound".to_string());
+                    return None;
+                }
+                seen.insert(pc, depth);
+
+                let ins = match self.decode(pc) {
+                    Some(ins) if !OPCODES[ins.opcode as usize].illegal => ins,
+                    _ => {
+                        self.problem(pc, "the code runs into data (an undocumented opcode)".to_string());
+                        break;
+                    }
+                };
+                let next = pc.wrapping_add(ins.len as u16);
+                match (ins.mnemonic, ins.mode) {
+                    ("PHA", _) | ("PHP", _) => depth += 1,
+                    ("PLA", _) | ("PLP", _) => {
+                        if depth == 0 {
+                            self.problem(pc, format!("{} with nothing pushed", ins.mnemonic));
+                        }
+                        depth -= 1;
+                    }
+                    ("TXS", _) => depth = 0,
+                    ("JSR", _) => {
+                        let (callee, chain) = if self.contains(ins.operand) {
+                            let callee = self.routine(ins.operand)?;
+                            (callee.depth, callee.chain)
+                        } else {
+                            self.problem(pc, format!("JSR ${:04X} is out of the image, only its return address is counted", ins.operand));
+                            (0, Vec::new())
+                        };
+                        let total = depth.max(0) as usize + 2 + callee;
+                        if total > usage.depth {
+                            usage.depth = total;
+                            usage.chain = [ins.operand].into_iter().chain(chain).collect();
+                        }
+                    }
+                    ("RTS", _) | ("RTI", _) => {
+                        if depth != 0 {
+                            let what = if depth > 0 { "still pushed" } else { "pulled too many" };
+                            let plural = if depth.abs() > 1 { "s" } else { "" };
+                            self.problem(pc, format!("{} with {} byte{} {}", ins.mnemonic, depth.abs(), plural, what));
+                        }
+                        break;
+                    }
+                    ("JMP", Absolute) => {
+                        work.push((ins.operand, depth));
+                        break;
+                    }
+                    ("JMP", _) => {
+                        self.problem(pc, "the targets of JMP (indirect) are not followed".to_string());
+                        break;
+                    }
+                    ("BRK", _) => break,
+                    (_, Relative) => work.push((ins.branch_target(pc).unwrap(), depth)),
+                    _ => {}
+                }
+                usage.depth = usage.depth.max(depth.max(0) as usize);
+                pc = next;
+            }
+        }
+        Some(usage)
+    }
+}
+
+// `origin`: the address of the first byte of `image`
+pub fn analyze(image: &[u8], origin: u16, entry_points: &[u16]) -> Report {
+    let mut analyzer = Analyzer {
+        image,
+        origin: origin as usize,
+        routines: HashMap::new(),
+        active: Vec::new(),
+        problems: BTreeSet::new(),
+    };
+
+    let mut starts = Vec::new();
+    for (vector, name) in VECTORS {
+        if let Some(target) = analyzer.word(vector) {
+            starts.push((name.to_string(), target, vector != 0xfffc));
+        }
+    }
+    for &entry in entry_points {
+        starts.push((format!("L{:04X}", entry), entry, false));
+    }
+
+    let mut entries = Vec::new();
+    for (name, address, interrupt) in starts {
+        let usage = analyzer.routine(address);
+        let extra = if interrupt { 3 } else { 0 };
+        entries.push(Entry {
+            name,
+            address,
+            interrupt,
+            depth: usage.as_ref().map(|u| u.depth + extra),
+            chain: usage.map(|u| u.chain).unwrap_or_default(),
+        });
+    }
+    Report { entries, problems: analyzer.problems }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn report(source: &str, entries: &[u16]) -> Report {
+        let (origin, image) = Assembler::new().assemble(source).unwrap().image().unwrap();
+        analyze(&image, origin, entries)
+    }
+
+    const VECTORS: &str = "
+        .org $FFFA
+        .word nmi, reset, irq
+";
+
+    #[test]
+    fn test_depth() {
+        let src = "
+        .org $8000
+reset:  LDX #$FF
+        TXS
+        PHA
+        JSR one
+        PLA
+loop:   JMP loop
+one:    PHA
+        PHP
+        JSR two
+        PLP
+        PLA
+        RTS
+two:    LDA #1
+        BEQ @skip
+        PHA
+        PLA
+@skip:  RTS
+nmi:    PHA
+        JSR two
+        PLA
+        RTI
+irq:    RTI
+".to_string() + VECTORS;
+        let report = report(&src, &[]);
+        assert!(report.entries.iter().map(|e| (e.name.as_str(), e.depth, e.chain.clone())).collect::<Vec<_>>() == vec![
+            ("nmi", Some(7), vec![0x8013]),
+            ("reset", Some(8), vec![0x800b, 0x8013]),
+            ("irq", Some(3), vec![]),
+        ]);
+        assert!(report.worst_case() == Some(18));
+        assert!(report.problems.is_empty());
+        assert!(report.to_string() == "\
+nmi $801A: 7 bytes (3 for the interrupt), nmi -> $8013
+reset $8000: 8 bytes, reset -> $800B -> $8013
+irq $8020: 3 bytes (3 for the interrupt)
+worst case: 18 bytes
+");
+    }
+
+    #[test]
+    fn test_problems() {
+        let src = "
+        .org $8000
+reset:  JSR $1234
+        BCC @skip
+        PHA
+@skip:  PLA
+        PLA
+        RTS
+rec:    PHA
+        JSR rec2
+        PLA
+        RTS
+rec2:   JSR rec
+        JMP ($1234)
+nmi:
+irq:    PHA
+        RTI
+".to_string() + VECTORS;
+        let report = report(&src, &[0x8009]);
+        let problems: Vec<String> = report.problems.iter().map(|(a, m)| format!("${:04X}: {}", a, m)).collect();
+        assert!(problems == vec![
+            "$8000: JSR $1234 is out of the image, only its return address is counted",
+            "$8006: reached with 1 and 0 bytes on the stack (unbalanced pushes and pulls)",
+            "$8007: PLA with nothing pushed",
+            "$8008: RTS with 1 byte pulled too many",
+            "$8009: recursion $8009 -> $800F -> $8009: the stack usage is unbounded",
+            "$8016: RTI with 1 byte still pushed",
+        ]);
+        assert!(report.entries.iter().map(|e| e.depth).collect::<Vec<_>>() == vec![Some(4), Some(2), Some(4), None]);
+        assert!(report.worst_case().is_none());
+    }
+}
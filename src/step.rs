@@ -0,0 +1,99 @@
+// What Cpu::step() does with the instruction at the pc
+//
+// fault() tells why step() would panic (BRK, decimal mode on the NMOS 6502, the undocumented
+// opcodes other than UNDOCUMENTED, JAM), for the runners to stop with an error instead.
+// address() is the operand in memory, taken() whether a branch is taken, and cost() the cycles
+// with the page crossing and taken branch penalties.
+
+use crate::cpu::AddressingMode::*;
+use crate::cpu::{Cpu, Flags, Variant};
+use crate::disasm::{Instruction, OPCODES};
+
+// the undocumented opcodes cpu.rs implements (RRA, without the indirect and the absolute,Y
+// modes); fault() stops the run on the others
+pub(crate) const UNDOCUMENTED: [u8; 4] = [0x67, 0x6f, 0x77, 0x7f];
+
+// why Cpu::step() would panic on the instruction at its pc
+pub(crate) fn fault(cpu: &Cpu, ins: &Instruction) -> Option<&'static str> {
+    let (_, _, _, _, p) = cpu.registers();
+    let info = &OPCODES[ins.opcode as usize];
+    match ins.mnemonic {
+        "JAM" => Some("JAM halts the Cpu"),
+        _ if info.illegal && !UNDOCUMENTED.contains(&ins.opcode) => Some("the undocumented opcode is not implemented by the Cpu"),
+        "BRK" => Some("BRK is not implemented by the Cpu"),
+        "ADC" | "SBC" | "RRA" if p & Flags::D_Decimal != 0 && cpu.variant() == Variant::Nmos => Some("decimal mode is not implemented by the Cpu"),
+        _ => None,
+    }
+}
+
+fn pointer(cpu: &Cpu, zp: u8) -> u16 {
+    let memory = cpu.memory();
+    memory[zp as usize] as u16 | (memory[zp.wrapping_add(1) as usize] as u16) << 8
+}
+
+// the address of the operand in memory
+pub(crate) fn address(cpu: &Cpu, ins: &Instruction) -> Option<u16> {
+    let (_, x, y, _, _) = cpu.registers();
+    match ins.mode {
+        ZeroPage | Absolute => Some(ins.operand),
+        ZeroPageX => Some((ins.operand as u8).wrapping_add(x) as u16),
+        ZeroPageY => Some((ins.operand as u8).wrapping_add(y) as u16),
+        AbsoluteX => Some(ins.operand.wrapping_add(x as u16)),
+        AbsoluteY => Some(ins.operand.wrapping_add(y as u16)),
+        ZeroPageXIndirect => Some(pointer(cpu, (ins.operand as u8).wrapping_add(x))),
+        ZeroPageIndirectY => Some(pointer(cpu, ins.operand as u8).wrapping_add(y as u16)),
+        _ => None,
+    }
+}
+
+// whether the branch at the pc of the Cpu is taken, None for the other instructions
+pub(crate) fn taken(cpu: &Cpu, ins: &Instruction) -> Option<bool> {
+    ins.branch_target(cpu.pc())?;
+    let p = cpu.registers().4;
+    Some(match ins.mnemonic {
+        "BPL" => p & Flags::N_Negative == 0,
+        "BMI" => p & Flags::N_Negative != 0,
+        "BVC" => p & Flags::V_Overflow == 0,
+        "BVS" => p & Flags::V_Overflow != 0,
+        "BCC" => p & Flags::C_Carry == 0,
+        "BCS" => p & Flags::C_Carry != 0,
+        "BNE" => p & Flags::Z_Zero == 0,
+        _ => p & Flags::Z_Zero != 0,
+    })
+}
+
+// the cycles of the instruction at the pc of the Cpu
+pub(crate) fn cost(cpu: &Cpu, ins: &Instruction) -> usize {
+    let info = &OPCODES[ins.opcode as usize];
+    let pc = cpu.pc();
+    let mut cycles = info.cycles as usize;
+    if let Some(target) = ins.branch_target(pc) {
+        if taken(cpu, ins) == Some(true) {
+            cycles += 1 + (target >> 8 != pc.wrapping_add(ins.len as u16) >> 8) as usize;
+        }
+    } else if info.page_penalty() {
+        let base = match ins.mode {
+            ZeroPageIndirectY => pointer(cpu, ins.operand as u8),
+            _ => ins.operand,
+        };
+        cycles += (address(cpu, ins).unwrap() >> 8 != base >> 8) as usize;
+    }
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::decode;
+
+    #[test]
+    fn test_undocumented() {
+        let cpu = Cpu::new();
+        for (opcode, info) in OPCODES.iter().enumerate().filter(|(opcode, info)| info.illegal && !UNDOCUMENTED.contains(&(*opcode as u8))) {
+            let why = if info.mnemonic == "JAM" { "JAM halts the Cpu" } else { "the undocumented opcode is not implemented by the Cpu" };
+            assert!(fault(&cpu, &decode(&[opcode as u8, 0x10, 0x20]).unwrap()) == Some(why), "{:02X}", opcode);
+        }
+        assert!(fault(&cpu, &decode(&[0xea]).unwrap()).is_none());
+        assert!(fault(&cpu, &decode(&[0x67, 0x10]).unwrap()).is_none());
+    }
+}
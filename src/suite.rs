@@ -0,0 +1,660 @@
+// Test files for the routines of a program
+//
+// A test file (TOML, see toml.rs) gives the program and the tests of its routines, run with the
+// harness:
+//
+//   source = "math.s"           # assembled, relative to the test file (dialect = "acme", ...)
+//                               # or code = """ ... """, or image = "math.bin" with origin = 0x8000
+//                               # (and dbg = "math.dbg", the ca65 debug file, for the coverage
+//                               # and the labels)
+//   [[test]]
+//   name = "add"
+//   call = "add"                # the entry: a number, or an expression with the labels ("$8000")
+//   a = 5                       # the registers a x y s and the flags n v d i z c (true/false)
+//   c = false
+//   memory = { "$10" = [3, 4], "table+1" = 2 }
+//   max_cycles = 1000           # the budget, max_instructions too
+//
+//   [test.expect]               # what isn't given can have any value
+//   a = 9
+//   z = false
+//   memory = { "result" = [9] }
+//   cycles = 20                 # at most
+//
+// The bytes and the addresses can be numbers or expressions. A Report has the results of the
+// files and prints them as text or as a JUnit XML report, with the coverage of the source lines
+// (see coverage.rs).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::asm::{Assembler, Dialect, Formula};
+use crate::coverage::{Coverage, Lcov, Line};
+use crate::dbg::DebugInfo;
+use crate::harness::{Expect, Harness, Outcome};
+use crate::ir;
+use crate::json::Json;
+use crate::toml;
+
+const REGISTERS: [&str; 4] = ["a", "x", "y", "s"];
+const FLAGS: [(&str, u8); 6] = [("n", ir::N), ("v", ir::V), ("d", ir::D), ("i", ir::I), ("z", ir::Z), ("c", ir::C)];
+
+pub struct Suite {
+    origin: u16,
+    image: Vec<u8>,
+    lines: Vec<Line>, // for the coverage
+    names: HashMap<u16, String>, // the symbols by address, for the profiles and the backtraces
+    cases: Vec<Case>,
+}
+
+struct Case {
+    name: String,
+    entry: u16,
+    regs: [Option<u8>; 4],
+    flags: Vec<(u8, bool)>,
+    memory: Vec<(u16, Vec<u8>)>,
+    max_cycles: Option<usize>,
+    max_instructions: Option<usize>,
+    expect: Expect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Pass(Outcome),
+    Fail(Outcome, String), // with the differences
+    Error(String),         // the run stopped
+}
+
+// the values of a test file, with the symbols of the program
+struct Values {
+    symbols: HashMap<String, i64>,
+}
+
+impl Values {
+    // a number or an expression
+    fn number(&self, value: &Json, what: &str) -> Result<i64, String> {
+        match value {
+            Json::Number(_) => value.as_i64().ok_or_else(|| format!("{}: not an integer", what)),
+            Json::String(text) => {
+                let formula = Formula::parse(text).map_err(|e| format!("{}: {}", what, e.message))?;
+                formula.eval(&self.symbols).ok_or_else(|| format!("{}: undefined symbol in {}", what, text))
+            }
+            _ => Err(format!("{}: expected a number or an expression", what)),
+        }
+    }
+
+    fn byte(&self, value: &Json, what: &str) -> Result<u8, String> {
+        match self.number(value, what)? {
+            n @ -128..=255 => Ok(n as u8),
+            n => Err(format!("{}: {} is not a byte", what, n)),
+        }
+    }
+
+    fn address(&self, value: &Json, what: &str) -> Result<u16, String> {
+        match self.number(value, what)? {
+            n @ 0..=0xffff => Ok(n as u16),
+            n => Err(format!("{}: {} is not an address", what, n)),
+        }
+    }
+
+    fn count(&self, value: &Json, what: &str) -> Result<Option<usize>, String> {
+        match value {
+            Json::Null => Ok(None),
+            _ => usize::try_from(self.number(value, what)?).map(Some).map_err(|_| format!("{}: expected a positive number", what)),
+        }
+    }
+
+    fn flag(&self, value: &Json, what: &str) -> Result<bool, String> {
+        match value {
+            Json::Bool(set) => Ok(*set),
+            _ => match self.number(value, what)? {
+                n @ (0 | 1) => Ok(n == 1),
+                _ => Err(format!("{}: expected true or false", what)),
+            },
+        }
+    }
+
+    // { "address" = [bytes] or byte, ... }
+    fn memory(&self, value: &Json, what: &str) -> Result<Vec<(u16, Vec<u8>)>, String> {
+        let entries = match value {
+            Json::Null => return Ok(Vec::new()),
+            Json::Object(entries) => entries,
+            _ => return Err(format!("{}: expected a table of addresses", what)),
+        };
+        let mut memory = Vec::new();
+        for (key, bytes) in entries {
+            let what = format!("{} {}", what, key);
+            let address = self.address(&Json::String(key.clone()), &what)?;
+            let bytes = match bytes {
+                Json::Array(items) => items.iter().map(|b| self.byte(b, &what)).collect::<Result<_, _>>()?,
+                _ => vec![self.byte(bytes, &what)?],
+            };
+            memory.push((address, bytes));
+        }
+        Ok(memory)
+    }
+}
+
+fn check_keys(table: &Json, allowed: &[&str], what: &str) -> Result<(), String> {
+    match table {
+        Json::Object(entries) => match entries.iter().find(|(k, _)| !allowed.contains(&k.as_str())) {
+            Some((key, _)) => Err(format!("{}unknown key '{}'", what, key)),
+            None => Ok(()),
+        },
+        _ => Err(format!("{}expected a table", what)),
+    }
+}
+
+impl Suite {
+    pub fn load(path: &Path) -> Result<Suite, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Suite::parse(&text, path.parent().unwrap_or(Path::new("")))
+    }
+
+    // `dir`: the directory of the source or image files
+    pub fn parse(text: &str, dir: &Path) -> Result<Suite, String> {
+        let doc = toml::parse(text)?;
+        check_keys(&doc, &["source", "code", "dialect", "image", "origin", "dbg", "test"], "")?;
+
+        let mut values = Values { symbols: HashMap::new() };
+        let origin = match doc.get("origin") {
+            Json::Null => None,
+            origin => Some(values.address(origin, "origin")?),
+        };
+        let mut assembler = Assembler::new();
+        if let Some(name) = doc.get("dialect").as_str() {
+            assembler = assembler.dialect(Dialect::from_name(name).ok_or_else(|| format!("dialect: unknown dialect {}", name))?);
+        }
+        if let Some(origin) = origin {
+            assembler = assembler.origin(origin);
+        }
+        let assembled = match (doc.get("source").as_str(), doc.get("code").as_str(), doc.get("image").as_str()) {
+            (Some(source), None, None) => assembler.assemble_file(dir.join(source)),
+            (None, Some(code), None) => assembler.assemble(code).map_err(|errors| {
+                errors.into_iter().map(|mut e| {
+                    e.file = "code".to_string();
+                    e
+                }).collect()
+            }),
+            (None, None, Some(image)) => {
+                let path = dir.join(image);
+                let image = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                let origin = origin.ok_or("origin: the origin of the image is missing")?;
+                if origin as usize + image.len() > 0x10000 {
+                    return Err(format!("{}: the image doesn't fit in memory at ${:04X}", path.display(), origin));
+                }
+                let lines = match doc.get("dbg").as_str() {
+                    Some(dbg) => {
+                        let path = dir.join(dbg);
+                        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+                        let info = DebugInfo::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+                        values.symbols = info.symbols.iter().map(|(name, value)| (name.clone(), *value as i64)).collect();
+                        Line::from_dbg(&info, &image, origin)
+                    }
+                    None => Vec::new(),
+                };
+                return Suite::cases(&doc, &values, origin, image, lines);
+            }
+            _ => return Err("expected one of source, code or image".to_string()),
+        };
+        if !doc.get("dbg").is_null() {
+            return Err("dbg: only for an image, the line info of the source is the one of the assembler".to_string());
+        }
+        let program = assembled.map_err(|errors| errors.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))?;
+        values.symbols = program.symbols.iter().map(|(name, value)| (name.clone(), *value as i64)).collect();
+        let (origin, image) = program.image().ok_or("the program is empty")?;
+        Suite::cases(&doc, &values, origin, image, Line::from_program(&program))
+    }
+
+    fn cases(doc: &Json, values: &Values, origin: u16, image: Vec<u8>, lines: Vec<Line>) -> Result<Suite, String> {
+        let tests = match doc.get("test") {
+            Json::Array(tests) => tests,
+            _ => return Err("there are no tests ([[test]])".to_string()),
+        };
+        let mut cases = Vec::new();
+        for (i, test) in tests.iter().enumerate() {
+            let name = test.get("name").as_str().map_or_else(|| format!("test {}", i + 1), |name| name.to_string());
+            let what = format!("test '{}': ", name);
+            let keys: Vec<&str> = ["name", "call", "memory", "max_cycles", "max_instructions", "expect"].into_iter()
+                .chain(REGISTERS).chain(FLAGS.iter().map(|(name, _)| *name)).collect();
+            check_keys(test, &keys, &what)?;
+
+            let entry = match test.get("call") {
+                Json::Null => return Err(format!("{}call: the address of the routine is missing", what)),
+                call => values.address(call, &format!("{}call", what))?,
+            };
+            let mut regs = [None; 4];
+            for (reg, name) in regs.iter_mut().zip(REGISTERS) {
+                if !test.get(name).is_null() {
+                    *reg = Some(values.byte(test.get(name), &format!("{}{}", what, name))?);
+                }
+            }
+            let mut flags = Vec::new();
+            for (name, flag) in FLAGS {
+                if !test.get(name).is_null() {
+                    flags.push((flag, values.flag(test.get(name), &format!("{}{}", what, name))?));
+                }
+            }
+
+            let mut expect = Expect::new();
+            let expected = test.get("expect");
+            if !expected.is_null() {
+                let keys: Vec<&str> = ["memory", "cycles"].into_iter().chain(REGISTERS).chain(FLAGS.iter().map(|(name, _)| *name)).collect();
+                check_keys(expected, &keys, &format!("{}expect: ", what))?;
+                let what = format!("{}expect ", what);
+                for name in REGISTERS {
+                    if !expected.get(name).is_null() {
+                        let value = values.byte(expected.get(name), &format!("{}{}", what, name))?;
+                        expect = match name {
+                            "a" => expect.a(value),
+                            "x" => expect.x(value),
+                            "y" => expect.y(value),
+                            _ => expect.s(value),
+                        };
+                    }
+                }
+                for (name, flag) in FLAGS {
+                    if !expected.get(name).is_null() {
+                        expect = expect.flag(flag, values.flag(expected.get(name), &format!("{}{}", what, name))?);
+                    }
+                }
+                for (address, bytes) in values.memory(expected.get("memory"), &format!("{}memory", what))? {
+                    expect = expect.mem(address, &bytes);
+                }
+                if let Some(cycles) = values.count(expected.get("cycles"), &format!("{}cycles", what))? {
+                    expect = expect.max_cycles(cycles);
+                }
+            }
+
+            cases.push(Case {
+                entry,
+                regs,
+                flags,
+                memory: values.memory(test.get("memory"), &format!("{}memory", what))?,
+                max_cycles: values.count(test.get("max_cycles"), &format!("{}max_cycles", what))?,
+                max_instructions: values.count(test.get("max_instructions"), &format!("{}max_instructions", what))?,
+                expect,
+                name,
+            });
+        }
+        // the first name in alphabetical order for the addresses with several
+        let mut names: HashMap<u16, String> = HashMap::new();
+        for (name, value) in &values.symbols {
+            if let Ok(address) = u16::try_from(*value) {
+                let known = names.entry(address).or_insert_with(|| name.clone());
+                if name < known {
+                    *known = name.clone();
+                }
+            }
+        }
+        Ok(Suite { origin, image, lines, names, cases })
+    }
+
+    pub fn run(&self) -> Results {
+        self.run_image(&self.image)
+    }
+
+    // the tests on another image at the same origin (a mutant, see mutate.rs)
+    pub fn run_image(&self, image: &[u8]) -> Results {
+        let mut results = Vec::new();
+        for case in &self.cases {
+            let mut harness = Harness::new(image, self.origin).call(case.entry).symbols(&self.names);
+            for (value, reg) in case.regs.iter().zip(0..) {
+                if let Some(value) = *value {
+                    harness = match reg {
+                        0 => harness.with_a(value),
+                        1 => harness.with_x(value),
+                        2 => harness.with_y(value),
+                        _ => harness.with_s(value),
+                    };
+                }
+            }
+            for &(flag, set) in &case.flags {
+                harness = harness.with_flag(flag, set);
+            }
+            for (address, bytes) in &case.memory {
+                harness = harness.with_mem(*address, bytes);
+            }
+            if let Some(cycles) = case.max_cycles {
+                harness = harness.max_cycles(cycles);
+            }
+            if let Some(instructions) = case.max_instructions {
+                harness = harness.max_instructions(instructions);
+            }
+            let verdict = match harness.run() {
+                Ok(outcome) => match outcome.check(&case.expect) {
+                    Ok(()) => Verdict::Pass(outcome),
+                    Err(diffs) => Verdict::Fail(outcome, diffs),
+                },
+                Err(e) => Verdict::Error(e),
+            };
+            results.push((case.name.clone(), verdict));
+        }
+        results
+    }
+
+    pub fn image(&self) -> (u16, &[u8]) {
+        (self.origin, &self.image)
+    }
+
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    pub fn names(&self) -> &HashMap<u16, String> {
+        &self.names
+    }
+
+    // the coverage of the tests which ran to the end
+    pub fn lcov(&self, results: &Results, lcov: &mut Lcov) {
+        let mut coverage = Coverage::new();
+        for (_, verdict) in results {
+            if let Verdict::Pass(outcome) | Verdict::Fail(outcome, _) = verdict {
+                coverage.merge(&outcome.coverage);
+            }
+        }
+        lcov.add(&self.lines, &coverage);
+    }
+}
+
+// the test files: the given files and the *.toml files of the given directories (recursively)
+pub fn find(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir() || path.extension().is_some_and(|ext| ext == "toml"))
+            .collect();
+        entries.sort();
+        files.extend(find(&entries)?);
+    }
+    Ok(files)
+}
+
+// the names of the tests with their verdicts
+pub type Results = Vec<(String, Verdict)>;
+
+// the results of the test files, or why they couldn't be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub files: Vec<(String, Result<Results, String>)>,
+    pub coverage: Lcov,
+}
+
+impl Report {
+    pub fn run(files: &[PathBuf]) -> Report {
+        let mut coverage = Lcov::new();
+        let files = files
+            .iter()
+            .map(|path| {
+                let results = Suite::load(path).map(|suite| {
+                    let results = suite.run();
+                    suite.lcov(&results, &mut coverage);
+                    results
+                });
+                (path.display().to_string(), results)
+            })
+            .collect();
+        Report { files, coverage }
+    }
+
+    // passed, failed, errors (the tests which stopped and the files which couldn't be loaded)
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for (_, results) in &self.files {
+            match results {
+                Ok(results) => {
+                    for (_, verdict) in results {
+                        match verdict {
+                            Verdict::Pass(_) => counts.0 += 1,
+                            Verdict::Fail(..) => counts.1 += 1,
+                            Verdict::Error(_) => counts.2 += 1,
+                        }
+                    }
+                }
+                Err(_) => counts.2 += 1,
+            }
+        }
+        counts
+    }
+
+    pub fn junit(&self) -> String {
+        let (passed, failed, errors) = self.counts();
+        let mut xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+        xml += &format!("<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", passed + failed + errors, failed, errors);
+        for (file, results) in &self.files {
+            let file = escape(file);
+            let results = match results {
+                Ok(results) => results,
+                Err(e) => {
+                    xml += &format!("  <testsuite name=\"{}\" tests=\"1\" failures=\"0\" errors=\"1\">\n", file);
+                    xml += &format!("    <testcase classname=\"{}\" name=\"(load)\">\n", file);
+                    xml += &format!("      <error message=\"{}\">{}</error>\n", escape(e.lines().next().unwrap_or("")), escape(e));
+                    xml += "    </testcase>\n  </testsuite>\n";
+                    continue;
+                }
+            };
+            let failures = results.iter().filter(|(_, v)| matches!(v, Verdict::Fail(..))).count();
+            let errors = results.iter().filter(|(_, v)| matches!(v, Verdict::Error(_))).count();
+            xml += &format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n", file, results.len(), failures, errors);
+            for (name, verdict) in results {
+                let testcase = format!("    <testcase classname=\"{}\" name=\"{}\"", file, escape(name));
+                match verdict {
+                    Verdict::Pass(_) => xml += &format!("{}/>\n", testcase),
+                    Verdict::Fail(outcome, diffs) => {
+                        xml += &format!("{}>\n", testcase);
+                        let text = format!("{}\n\nfinal state:\n{}", diffs, outcome);
+                        xml += &format!("      <failure message=\"{}\">{}</failure>\n", escape(diffs.lines().next().unwrap_or("")), escape(&text));
+                        xml += "    </testcase>\n";
+                    }
+                    Verdict::Error(e) => {
+                        xml += &format!("{}>\n", testcase);
+                        xml += &format!("      <error message=\"{}\">{}</error>\n", escape(e.lines().next().unwrap_or("")), escape(e));
+                        xml += "    </testcase>\n";
+                    }
+                }
+            }
+            xml += "  </testsuite>\n";
+        }
+        xml += "</testsuites>\n";
+        xml
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut failures = Vec::new();
+        for (file, results) in &self.files {
+            match results {
+                Ok(results) => {
+                    for (name, verdict) in results {
+                        match verdict {
+                            Verdict::Pass(outcome) => writeln!(f, "{}: {} ... ok ({} cycles)", file, name, outcome.cycles)?,
+                            Verdict::Fail(outcome, diffs) => {
+                                writeln!(f, "{}: {} ... FAILED", file, name)?;
+                                failures.push(format!("{}: {}\n{}\n\nfinal state:\n{}", file, name, diffs, outcome));
+                            }
+                            Verdict::Error(e) => {
+                                writeln!(f, "{}: {} ... ERROR", file, name)?;
+                                failures.push(format!("{}: {}\n{}\n", file, name, e));
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    writeln!(f, "{}: ERROR", file)?;
+                    failures.push(format!("{}\n{}\n", file, e));
+                }
+            }
+        }
+        if !failures.is_empty() {
+            writeln!(f, "\nfailures:\n")?;
+            for failure in &failures {
+                writeln!(f, "{}", failure)?;
+            }
+        }
+        let (passed, failed, errors) = self.counts();
+        let plural = if errors == 1 { "" } else { "s" };
+        writeln!(f, "{} passed, {} failed, {} error{}", passed, failed, errors, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    const SUITE: &str = r#"
+code = """
+result = $11
+        .org $8000
+add:    CLC
+        ADC $10
+        STA result
+        RTS
+loop:   JMP loop
+"""
+
+[[test]]
+name = "add"
+call = "add"
+a = 5
+memory = { "$10" = 3 }
+[test.expect]
+a = 8
+c = false
+memory = { result = [8] }
+cycles = 20
+
+[[test]]
+name = "carry"
+call = 0x8000
+a = 0xFF
+memory = { "result-1" = [2, 0] }
+[test.expect]
+a = 1
+z = true
+memory = { "$10" = [2, 1] }
+
+[[test]]
+call = "loop"
+max_cycles = 30
+"#;
+
+    fn report(text: &str) -> Report {
+        let files = vec![("math.toml".to_string(), Suite::parse(text, Path::new("")).map(|suite| suite.run()))];
+        Report { files, coverage: Lcov::new() }
+    }
+
+    #[test]
+    fn test_run() {
+        let report = report(SUITE);
+        let results = report.files[0].1.as_ref().unwrap();
+        assert!(results.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>() == ["add", "carry", "test 3"]);
+        assert!(matches!(&results[0].1, Verdict::Pass(outcome) if outcome.a == 8 && outcome.cycles == 14));
+        assert!(report.counts() == (1, 1, 1));
+        assert!(report.to_string() == "\
+math.toml: add ... ok (14 cycles)
+math.toml: carry ... FAILED
+math.toml: test 3 ... ERROR
+
+failures:
+
+math.toml: carry
+Z: expected 1, got 0
+
+final state:
+A=$01 X=$00 Y=$00 S=$FD P=nv-bdIzC
+4 instructions, 14 cycles
+$0011: 01
+
+math.toml: test 3
+$8006: JMP $8006: more than 30 cycles (at cycle 30)
+
+1 passed, 1 failed, 1 error
+");
+    }
+
+    #[test]
+    fn test_branches() {
+        let text = r#"
+code = """
+        .org $8000
+bits:   LDX #0          ; the bits set in A, in X
+next:   ASL A
+        BCC zero
+        INX
+zero:   CMP #0
+        BNE next
+        RTS
+"""
+[[test]]
+call = "bits"
+a = 0xA5
+expect.x = 4
+[[test]]
+call = "bits"
+expect.x = 0
+"#;
+        let results = Suite::parse(text, Path::new("")).unwrap().run();
+        assert!(matches!(&results[0].1, Verdict::Pass(outcome) if outcome.instructions == 38));
+        // LDX 2, ASL 2, BCC 3 (taken), CMP 2, BNE 2 (not taken), RTS 6
+        assert!(matches!(&results[1].1, Verdict::Pass(outcome) if outcome.cycles == 17));
+    }
+
+    #[test]
+    fn test_junit() {
+        let mut report = report(SUITE);
+        report.files.push(("bad.toml".to_string(), Err("line 1: expected a key".to_string())));
+        let xml = report.junit();
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"4\" failures=\"1\" errors=\"2\">\n"));
+        assert!(xml.contains("  <testsuite name=\"math.toml\" tests=\"3\" failures=\"1\" errors=\"1\">\n    <testcase classname=\"math.toml\" name=\"add\"/>\n"));
+        assert!(xml.contains("      <failure message=\"Z: expected 1, got 0\">Z: expected 1, got 0\n"));
+        assert!(xml.contains("      <error message=\"$8006: JMP $8006: more than 30 cycles (at cycle 30)\">"));
+        assert!(xml.contains("    <testcase classname=\"bad.toml\" name=\"(load)\">\n      <error message=\"line 1: expected a key\">"));
+        assert!(xml.ends_with("  </testsuite>\n</testsuites>\n"));
+        assert!(escape("<a & \"b\">") == "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| Suite::parse(text, Path::new("")).err().unwrap();
+        let code = "code = \"label: RTS\"\n";
+        assert!(error("[[test]]\ncall = 0") == "expected one of source, code or image");
+        assert!(error(code) == "there are no tests ([[test]])");
+        assert!(error("code = \"LDA #\"\n[[test]]\ncall = 0") == "code:1:6: error: expected expression");
+        assert!(error(&format!("{}[[test]]\nname = \"t\"\ncall = \"nowhere\"", code)) == "test 't': call: undefined symbol in nowhere");
+        assert!(error(&format!("{}[[test]]\ncall = \"label\"\na = 256", code)) == "test 'test 1': a: 256 is not a byte");
+        assert!(error(&format!("{}[[test]]\ncall = \"label\"\nq = 1", code)) == "test 'test 1': unknown key 'q'");
+        assert!(error(&format!("{}[[test]]\ncall = \"label\"\n[test.expect]\nc = 2", code)) == "test 'test 1': expect c: expected true or false");
+        assert!(error(&format!("{}[[test]]\nmemory = {{ \"$10\" = \"x\" }}", code)) == "test 'test 1': call: the address of the routine is missing");
+        assert!(error(&format!("{}[[test]]\ncall = 0\nmemory = {{ \"$10\" = \"x\" }}", code)) == "test 'test 1': memory $10: undefined symbol in x");
+        assert!(error("image = \"nothing.bin\"\n[[test]]\ncall = 0").starts_with("nothing.bin: "));
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new("suite");
+        dir.write("sub/inc.s", "  .org $C000\ninc: INX\n  RTS\n");
+        let inc = dir.write("sub/inc.toml", "source = \"inc.s\"\n[[test]]\ncall = \"inc\"\nx = 1\nexpect.x = 2\n");
+        dir.write("sub/inc.bin", [0xca, 0x60]);
+        let dec = dir.write("dec.toml", "image = \"sub/inc.bin\"\norigin = \"$C000\"\n[[test]]\ncall = 0xC000\nexpect.x = 0xFF\n");
+        dir.write("notes.txt", "");
+
+        let files = find(&[dir.path().to_path_buf()]).unwrap();
+        assert!(files == vec![dec, inc]);
+        let report = Report::run(&files);
+        assert!(report.counts() == (2, 0, 0));
+    }
+}
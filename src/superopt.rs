@@ -0,0 +1,624 @@
+// Superoptimizer
+//
+// Finds the shortest (or the fastest, or the smallest) sequences of instructions which do the
+// same as a target sequence, or which have the given effects:
+//
+//   LDA $10; CLC; ADC #1; STA $10 with only the memory as output  ->  INC $10
+//   A = X + 1                                                      ->  INX; TXA
+//
+// The candidates are all the sequences of up to N instructions without control flow (no
+// branches, jumps, BRK) and without indirect addressing, with the immediate values and the
+// addresses of the goal plus 0, 1 and $FF. As every candidate is run by Cpu::step(), the only
+// undocumented opcodes tried are the RRAs it implements (step::UNDOCUMENTED).
+//
+// A candidate is equivalent when Cpu::step() gives the same outputs as the target from all
+// the inputs: random ones, or all the values of the registers and flags the goal reads
+// (exhaustive) with the other registers and the memory random (repeated up to the number of
+// samples). The memory varies in the zero page, the stack and the pages of the addresses of
+// the goal, and S is in $10-$EF. The inputs avoid what makes the Cpu panic: D is 0, and a
+// sequence which would reach decimal mode is not equivalent.
+//
+// The outputs of a target are all the registers, flags and memory (the registers and flags can
+// be restricted). With a specification, the registers and flags it doesn't mention are free and
+// the memory it doesn't mention must not change.
+
+use std::fmt;
+
+use crate::asm::Formula;
+use crate::cpu::AddressingMode::*;
+use crate::cpu::Cpu;
+use crate::disasm::{decode, format, Instruction, OPCODES, WRITES};
+use crate::step::{fault, UNDOCUMENTED};
+use crate::ir::{self, Reg};
+use crate::rng::Rng;
+
+const CONSTANTS: [u8; 3] = [0x00, 0x01, 0xff];
+const IMAGES: usize = 16;               // random contents of the memory
+const EXHAUSTIVE_LIMIT: usize = 1 << 18; // inputs
+const FLAGS: [u8; 6] = [ir::N, ir::V, ir::D, ir::I, ir::Z, ir::C];
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Location {
+    Reg(Reg),
+    Flag(u8),
+    Memory(u16),
+}
+
+impl Location {
+    // A, X, Y, S, one of the flags NVDIZC, or an address
+    pub fn parse(text: &str) -> Option<Location> {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+        if let Some(r) = [Reg::A, Reg::X, Reg::Y, Reg::S].into_iter().find(|r| format!("{:?}", r) == upper) {
+            return Some(Location::Reg(r));
+        }
+        if let Some(i) = "NVDIZC".find(&upper).filter(|_| upper.len() == 1) {
+            return Some(Location::Flag(FLAGS[i]));
+        }
+        match Formula::parse(text).ok()?.eval(&Default::default())? {
+            address @ 0..=0xffff => Some(Location::Memory(address as u16)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Goal {
+    Sequence(Vec<u8>),                 // the bytes of the target
+    Effects(Vec<(Location, Formula)>), // the values of the outputs, from A X Y S N V D I Z C
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Cost {
+    Length,
+    Cycles,
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub bytes: Vec<u8>,
+    pub length: usize,
+    pub cycles: usize,
+}
+
+impl Sequence {
+    pub fn new(bytes: Vec<u8>) -> Sequence {
+        let instructions = instructions(&bytes);
+        let cycles = instructions.iter().map(|ins| OPCODES[ins.opcode as usize].cycles as usize).sum();
+        Sequence { length: instructions.len(), cycles, bytes }
+    }
+}
+
+impl fmt::Display for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pc = 0u16;
+        let text: Vec<String> = instructions(&self.bytes).iter().map(|ins| {
+            let text = format(ins, pc, None);
+            pc += ins.len as u16;
+            text
+        }).collect();
+        if text.is_empty() {
+            write!(f, "(nothing)")
+        } else {
+            write!(f, "{}", text.join("; "))
+        }
+    }
+}
+
+fn instructions(bytes: &[u8]) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while let Some(ins) = decode(&bytes[offset..]) {
+        offset += ins.len as usize;
+        result.push(ins);
+    }
+    result
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Input {
+    regs: [u8; 4], // A X Y S
+    p: u8,
+    image: usize,
+}
+
+// what a sequence must give from an input
+#[derive(Debug, Clone)]
+struct Expected {
+    regs: [Option<u8>; 4],
+    flags: u8, // the flags which matter
+    p: u8,
+    memory: Vec<(u16, u8)>, // the bytes which change
+}
+
+pub struct Superoptimizer {
+    goal: Goal,
+    outputs: Option<Vec<Location>>,
+    length: usize,
+    cost: Cost,
+    illegal: bool,
+    exhaustive: bool,
+    samples: usize,
+}
+
+// the Cpu with the memory of the inputs
+struct Machine {
+    cpu: Cpu,
+    code: u16,
+    ranges: Vec<(u16, u16)>, // the memory which varies (first and last address)
+    images: Vec<Vec<u8>>,
+}
+
+impl Machine {
+    fn load(&mut self, input: &Input) {
+        for &(first, last) in &self.ranges {
+            self.cpu.patch_memory(first as usize, &self.images[input.image][first as usize..=last as usize]);
+        }
+        let [a, x, y, s] = input.regs;
+        self.cpu.set_registers(a, x, y, s, input.p);
+    }
+
+    // false if the Cpu would panic
+    fn run(&mut self, instructions: &[&[u8]], input: &Input) -> bool {
+        self.load(input);
+        let mut pc = self.code;
+        for bytes in instructions {
+            self.cpu.patch_memory(pc as usize, bytes);
+            self.cpu.update_pc(pc);
+            if fault(&self.cpu, &decode(bytes).unwrap()).is_some() {
+                return false;
+            }
+            self.cpu.step();
+            pc += bytes.len() as u16;
+        }
+        true
+    }
+
+    fn matches(&mut self, instructions: &[&[u8]], writes: &[(u16, u16)], input: &Input, expected: &Expected) -> bool {
+        if !self.run(instructions, input) {
+            return false;
+        }
+        let (a, x, y, s, p) = self.cpu.registers();
+        if [a, x, y, s].iter().zip(expected.regs).any(|(r, e)| e.is_some_and(|e| e != *r)) || (p ^ expected.p) & expected.flags != 0 {
+            return false;
+        }
+        let memory = self.cpu.memory();
+        let image = &self.images[input.image];
+        let changed = |addr: u16| expected.memory.iter().find(|(a, _)| *a == addr).map_or(image[addr as usize], |(_, v)| *v);
+        writes.iter().all(|&(first, last)| (first..=last).all(|addr| memory[addr as usize] == changed(addr)))
+            && expected.memory.iter().all(|&(addr, value)| memory[addr as usize] == value)
+    }
+}
+
+// the memory an instruction can write (first and last addresses), an indexed address wraps
+// around to the zero page
+fn writes(ins: &Instruction) -> Vec<(u16, u16)> {
+    if matches!(ins.mnemonic, "PHA" | "PHP") {
+        return vec![(0x100, 0x1ff)];
+    }
+    if !WRITES.contains(&ins.mnemonic) {
+        return Vec::new();
+    }
+    match ins.mode {
+        ZeroPage | Absolute => vec![(ins.operand, ins.operand)],
+        ZeroPageX | ZeroPageY => vec![(0, 0xff)],
+        AbsoluteX | AbsoluteY => match ins.operand.checked_add(0xff) {
+            Some(last) => vec![(ins.operand, last)],
+            None => vec![(ins.operand, 0xffff), (0, ins.operand.wrapping_add(0xff))],
+        },
+        _ => Vec::new(),
+    }
+}
+
+// the instructions which can be in a sequence
+fn supported(opcode: u8, illegal: bool) -> bool {
+    let info = &OPCODES[opcode as usize];
+    if info.illegal && !(illegal && UNDOCUMENTED.contains(&opcode)) {
+        return false;
+    }
+    !matches!(info.mnemonic, "BRK" | "JMP" | "JSR" | "RTS" | "RTI")
+        && !matches!(info.mode, Relative | AbsoluteIndirect | ZeroPageXIndirect | ZeroPageIndirectY)
+}
+
+impl Superoptimizer {
+    pub fn new(goal: Goal) -> Superoptimizer {
+        Superoptimizer { goal, outputs: None, length: 2, cost: Cost::Length, illegal: false, exhaustive: false, samples: 500 }
+    }
+
+    // the registers and flags which matter for a target sequence (all of them by default)
+    pub fn outputs(mut self, outputs: Vec<Location>) -> Superoptimizer {
+        self.outputs = Some(outputs);
+        self
+    }
+
+    // the maximum number of instructions
+    pub fn length(mut self, length: usize) -> Superoptimizer {
+        self.length = length;
+        self
+    }
+
+    pub fn cost(mut self, cost: Cost) -> Superoptimizer {
+        self.cost = cost;
+        self
+    }
+
+    pub fn illegal(mut self, illegal: bool) -> Superoptimizer {
+        self.illegal = illegal;
+        self
+    }
+
+    pub fn exhaustive(mut self, exhaustive: bool) -> Superoptimizer {
+        self.exhaustive = exhaustive;
+        self
+    }
+
+    // the number of random inputs
+    pub fn samples(mut self, samples: usize) -> Superoptimizer {
+        self.samples = samples;
+        self
+    }
+
+    fn key(&self, sequence: &Sequence) -> (usize, usize, usize, usize) {
+        let primary = match self.cost {
+            Cost::Length => sequence.length,
+            Cost::Cycles => sequence.cycles,
+            Cost::Bytes => sequence.bytes.len(),
+        };
+        (primary, sequence.length, sequence.cycles, sequence.bytes.len())
+    }
+
+    // the equivalent sequences of the lowest cost
+    pub fn run(&self) -> Result<Vec<Sequence>, String> {
+        // the constants and the addresses of the goal
+        let mut constants = CONSTANTS.to_vec();
+        let mut zero_page = Vec::new();
+        let mut absolute = Vec::new();
+        let target = match &self.goal {
+            Goal::Sequence(bytes) => {
+                let target = instructions(bytes);
+                if target.iter().map(|ins| ins.len as usize).sum::<usize>() != bytes.len() {
+                    return Err("the target ends with an incomplete instruction".to_string());
+                }
+                for ins in &target {
+                    if !supported(ins.opcode, true) {
+                        return Err(format!("{} is not supported (control flow, indirect addressing, or undocumented and not run by the Cpu)", format(ins, 0, None)));
+                    }
+                    match ins.mode {
+                        Immediate => constants.push(ins.operand as u8),
+                        ZeroPage | ZeroPageX | ZeroPageY => zero_page.push(ins.operand as u8),
+                        Absolute | AbsoluteX | AbsoluteY => absolute.push(ins.operand),
+                        _ => {}
+                    }
+                }
+                target
+            }
+            Goal::Effects(effects) => {
+                for (location, formula) in effects {
+                    match location {
+                        Location::Memory(address @ 0..=0xff) => zero_page.push(*address as u8),
+                        Location::Memory(address) => absolute.push(*address),
+                        _ => {}
+                    }
+                    constants.extend(formula.leaves().1.iter().map(|n| *n as u8));
+                }
+                Vec::new()
+            }
+        };
+        for pool in [&mut constants, &mut zero_page] {
+            pool.sort();
+            pool.dedup();
+        }
+        absolute.sort();
+        absolute.dedup();
+
+        let mut machine = self.machine(&absolute)?;
+        let inputs = self.inputs(&target)?;
+        let expected: Vec<Expected> = inputs.iter().map(|input| self.expected(&mut machine, &target, input)).collect::<Result<_, _>>()?;
+
+        // the instructions the candidates are made of
+        let mut alphabet: Vec<Vec<u8>> = Vec::new();
+        for opcode in 0..=255u8 {
+            if !supported(opcode, self.illegal) {
+                continue;
+            }
+            match OPCODES[opcode as usize].mode {
+                Immediate => alphabet.extend(constants.iter().map(|c| vec![opcode, *c])),
+                ZeroPage | ZeroPageX | ZeroPageY => alphabet.extend(zero_page.iter().map(|a| vec![opcode, *a])),
+                Absolute | AbsoluteX | AbsoluteY => alphabet.extend(absolute.iter().map(|a| vec![opcode, *a as u8, (a >> 8) as u8])),
+                _ => alphabet.push(vec![opcode]),
+            }
+        }
+        let alphabet_writes: Vec<Vec<(u16, u16)>> = alphabet.iter().map(|bytes| writes(&decode(bytes).unwrap())).collect();
+
+        let mut found: Vec<Sequence> = Vec::new();
+        for length in 0..=self.length {
+            // no longer sequence can be cheaper
+            let bound = match self.cost {
+                Cost::Length => length,
+                Cost::Cycles => 2 * length,
+                Cost::Bytes => length,
+            };
+            if found.iter().any(|s| self.key(s).0 <= bound) {
+                break;
+            }
+            let mut digits = vec![0; length];
+            loop {
+                let candidate: Vec<&[u8]> = digits.iter().map(|&d| alphabet[d].as_slice()).collect();
+                let writes: Vec<(u16, u16)> = digits.iter().flat_map(|&d| alphabet_writes[d].iter().copied()).collect();
+                if inputs.iter().zip(&expected).all(|(input, expected)| machine.matches(&candidate, &writes, input, expected)) {
+                    found.push(Sequence::new(candidate.concat()));
+                }
+
+                // the next candidate
+                let mut i = 0;
+                while i < length {
+                    digits[i] += 1;
+                    if digits[i] < alphabet.len() {
+                        break;
+                    }
+                    digits[i] = 0;
+                    i += 1;
+                }
+                if i == length {
+                    break;
+                }
+            }
+        }
+
+        found.sort_by_key(|s| self.key(s));
+        if let Some(best) = found.first().map(|s| self.key(s).0) {
+            found.retain(|s| self.key(s).0 == best);
+        }
+        Ok(found)
+    }
+
+    fn machine(&self, absolute: &[u16]) -> Result<Machine, String> {
+        // the memory which varies: the zero page, the stack and the pages of the addresses
+        let mut ranges = vec![(0x0000, 0x01ff)];
+        for &address in absolute.iter().filter(|a| **a > 0x1ff) {
+            ranges.push((address, address.saturating_add(0xff)));
+        }
+        ranges.sort();
+        let mut merged: Vec<(u16, u16)> = Vec::new();
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(previous) if first <= previous.1.saturating_add(1) => previous.1 = previous.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+
+        // the code on a page out of it
+        let code = (0x02..=0xff).rev().map(|page: u16| page << 8)
+            .find(|&page| merged.iter().all(|&(first, last)| last < page || first > page + 0xff))
+            .ok_or("no room for the code")?;
+
+        let mut rng = Rng(0x2545f491);
+        let images = (0..IMAGES).map(|_| {
+            let mut image = vec![0; 0x10000];
+            for &(first, last) in &merged {
+                for byte in &mut image[first as usize..=last as usize] {
+                    *byte = rng.byte();
+                }
+            }
+            image
+        }).collect();
+        Ok(Machine { cpu: Cpu::new(), code, ranges: merged, images })
+    }
+
+    fn inputs(&self, target: &[Instruction]) -> Result<Vec<Input>, String> {
+        let mut rng = Rng(0x9e3779b9);
+        let mut random = |image: usize| Input {
+            regs: [rng.byte(), rng.byte(), rng.byte(), 0x10 + rng.below(0xe0) as u8],
+            p: rng.byte() & !ir::D,
+            image: image % IMAGES,
+        };
+        if !self.exhaustive {
+            return Ok((0..self.samples).map(&mut random).collect());
+        }
+
+        // all the values of the registers and the flags read by the goal
+        let (regs, flags) = match &self.goal {
+            Goal::Sequence(_) => {
+                let stmts: Vec<ir::Stmt> = target.iter().flat_map(|ins| ir::lift(ins, 0)).collect();
+                ir::inputs(&stmts)
+            }
+            Goal::Effects(effects) => {
+                let mut regs = Vec::new();
+                let mut flags = 0;
+                for (_, formula) in effects {
+                    for symbol in formula.leaves().0 {
+                        match Location::parse(&symbol) {
+                            Some(Location::Reg(r)) if !regs.contains(&r) => regs.push(r),
+                            Some(Location::Flag(f)) => flags |= f,
+                            _ => {}
+                        }
+                    }
+                }
+                (regs, flags)
+            }
+        };
+        let flags: Vec<u8> = FLAGS.into_iter().filter(|f| flags & f != 0 && *f != ir::D).collect();
+        let values = |r: &Reg| if *r == Reg::S { 0xe0 } else { 0x100 };
+        let count = regs.iter().map(values).product::<usize>() << flags.len();
+        if count > EXHAUSTIVE_LIMIT {
+            return Err(format!("{} inputs are too many to be exhaustive (the limit is {})", count, EXHAUSTIVE_LIMIT));
+        }
+        // repeated with other random values for the rest, up to the samples
+        let repeat = self.samples.div_ceil(count).max(1);
+        Ok((0..count * repeat).map(|i| {
+            let mut input = random(i);
+            let mut rest = i % count;
+            for r in &regs {
+                let value = (rest % values(r)) as u8;
+                input.regs[*r as usize] = if *r == Reg::S { 0x10 + value } else { value };
+                rest /= values(r);
+            }
+            for f in &flags {
+                input.p = if rest & 1 != 0 { input.p | f } else { input.p & !f };
+                rest >>= 1;
+            }
+            input
+        }).collect())
+    }
+
+    fn expected(&self, machine: &mut Machine, target: &[Instruction], input: &Input) -> Result<Expected, String> {
+        match &self.goal {
+            Goal::Sequence(bytes) => {
+                let mut offset = 0;
+                let instructions: Vec<&[u8]> = target.iter().map(|ins| {
+                    offset += ins.len as usize;
+                    &bytes[offset - ins.len as usize..offset]
+                }).collect();
+                if !machine.run(&instructions, input) {
+                    return Err("the target makes the Cpu panic (decimal mode)".to_string());
+                }
+                let (a, x, y, s, p) = machine.cpu.registers();
+                let mut expected = Expected { regs: [Some(a), Some(x), Some(y), Some(s)], flags: ir::ALL, p, memory: Vec::new() };
+                if let Some(outputs) = &self.outputs {
+                    expected.flags = 0;
+                    for (i, r) in [Reg::A, Reg::X, Reg::Y, Reg::S].iter().enumerate() {
+                        if !outputs.contains(&Location::Reg(*r)) {
+                            expected.regs[i] = None;
+                        }
+                    }
+                    for output in outputs {
+                        if let Location::Flag(f) = output {
+                            expected.flags |= f;
+                        }
+                    }
+                }
+                let image = &machine.images[input.image];
+                for (first, last) in target.iter().flat_map(writes) {
+                    for addr in first..=last {
+                        let value = machine.cpu.memory()[addr as usize];
+                        if value != image[addr as usize] && !expected.memory.iter().any(|(a, _)| *a == addr) {
+                            expected.memory.push((addr, value));
+                        }
+                    }
+                }
+                Ok(expected)
+            }
+            Goal::Effects(effects) => {
+                let mut symbols = std::collections::HashMap::new();
+                for (name, value) in ["A", "X", "Y", "S"].iter().zip(input.regs) {
+                    symbols.insert(name.to_string(), value as i64);
+                }
+                for (name, f) in "NVDIZC".chars().zip(FLAGS) {
+                    symbols.insert(name.to_string(), (input.p & f != 0) as i64);
+                }
+                let mut expected = Expected { regs: [None; 4], flags: 0, p: 0, memory: Vec::new() };
+                for (location, formula) in effects {
+                    let value = formula.eval(&symbols).ok_or("the specification uses an unknown symbol (the inputs are A X Y S N V D I Z C)")?;
+                    match location {
+                        Location::Reg(r) => expected.regs[*r as usize] = Some(value as u8),
+                        Location::Flag(f) => {
+                            expected.flags |= f;
+                            expected.p |= if value != 0 { *f } else { 0 };
+                        }
+                        Location::Memory(addr) => expected.memory.push((*addr, value as u8)),
+                    }
+                }
+                Ok(expected)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn assemble(text: &str) -> Vec<u8> {
+        Assembler::new().assemble(&text.replace(';', "\n")).unwrap().image().unwrap().1
+    }
+
+    fn texts(found: &[Sequence]) -> Vec<String> {
+        found.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sequence() {
+        let found = Superoptimizer::new(Goal::Sequence(assemble("LDA $10; CLC; ADC #1; STA $10")))
+            .outputs(vec![])
+            .length(1)
+            .run()
+            .unwrap();
+        assert!(texts(&found) == vec!["INC $10"]);
+        assert!((found[0].length, found[0].cycles, found[0].bytes.len()) == (1, 5, 2));
+
+        // all the outputs
+        let found = Superoptimizer::new(Goal::Sequence(assemble("LDX #0; TXA"))).run().unwrap();
+        assert!(texts(&found).contains(&"LDA #$00; TAX".to_string()) && texts(&found).contains(&"LDX #$00; TXA".to_string()));
+        assert!(!texts(&found).contains(&"LDX #$00; TAX".to_string()));
+        assert!(found.len() == 7 && found.iter().all(|s| s.length == 2));
+
+        // the store wraps around to the zero page
+        let found = Superoptimizer::new(Goal::Sequence(assemble("STA $FFF0,X"))).length(1).run().unwrap();
+        assert!(texts(&found) == vec!["STA $FFF0,X"]);
+    }
+
+    #[test]
+    fn test_costs() {
+        // A unchanged, C = 0, N and Z from A
+        let target = assemble("ASL A; ROR A");
+        let found = Superoptimizer::new(Goal::Sequence(target.clone())).exhaustive(true).run().unwrap();
+        assert!(texts(&found).contains(&"ASL A; ROR A".to_string()));
+        assert!(texts(&found).contains(&"CLC; ORA #$00".to_string()));
+        let found = Superoptimizer::new(Goal::Sequence(target)).cost(Cost::Bytes).exhaustive(true).run().unwrap();
+        assert!(found.iter().all(|s| s.bytes.len() == 2));
+        assert!(!texts(&found).contains(&"CLC; ORA #$00".to_string()));
+    }
+
+    #[test]
+    fn test_effects() {
+        let spec = vec![(Location::parse("A").unwrap(), Formula::parse("X + 1").unwrap())];
+        let found = Superoptimizer::new(Goal::Effects(spec)).run().unwrap();
+        assert!(texts(&found) == vec!["INX; TXA"]);
+
+        let spec = vec![
+            (Location::parse("$0200").unwrap(), Formula::parse("A").unwrap()),
+            (Location::parse("C").unwrap(), Formula::parse("1").unwrap()),
+        ];
+        let found = Superoptimizer::new(Goal::Effects(spec)).exhaustive(true).run().unwrap();
+        // CMP #0 sets C too
+        assert!(texts(&found)[..2] == ["STA $0200; SEC", "SEC; STA $0200"]);
+        assert!(found.len() == 9 && texts(&found).contains(&"CMP #$00; STA $0200".to_string()));
+    }
+
+    #[test]
+    fn test_overflow() {
+        // V is compared: SBC is an ADC of the complement
+        let found = Superoptimizer::new(Goal::Sequence(assemble("CLC; ADC #1"))).run().unwrap();
+        assert!(texts(&found).contains(&"CLC; ADC #$01".to_string()) && texts(&found).contains(&"SEC; ADC #$00".to_string()));
+        let found = Superoptimizer::new(Goal::Sequence(assemble("SEC; SBC #1"))).run().unwrap();
+        assert!(texts(&found).contains(&"CLC; ADC #$FF".to_string()));
+        let found = Superoptimizer::new(Goal::Sequence(assemble("BIT $10"))).outputs(vec![Location::Flag(ir::V)]).run().unwrap();
+        assert!(texts(&found) == vec!["BIT $10"]);
+        let a = (Location::parse("A").unwrap(), Formula::parse("A + 1").unwrap());
+        let found = Superoptimizer::new(Goal::Effects(vec![a.clone()])).run().unwrap();
+        assert!(texts(&found).contains(&"SEC; ADC #$00".to_string()));
+        let v = (Location::parse("V").unwrap(), Formula::parse("0").unwrap());
+        let found = Superoptimizer::new(Goal::Effects(vec![a, v])).run().unwrap();
+        assert!(found.iter().all(|s| !s.to_string().contains("ADC")));
+    }
+
+    #[test]
+    fn test_illegal() {
+        let target = assemble("ROR $10; ADC $10");
+        let found = Superoptimizer::new(Goal::Sequence(target.clone())).run().unwrap();
+        assert!(texts(&found) == vec!["ROR $10; ADC $10"]);
+        let found = Superoptimizer::new(Goal::Sequence(target)).illegal(true).run().unwrap();
+        assert!(texts(&found) == vec!["RRA $10"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Superoptimizer::new(Goal::Sequence(assemble("BNE *"))).run().is_err());
+        assert!(Superoptimizer::new(Goal::Sequence(assemble("LDA ($10),Y"))).run().is_err());
+        assert!(Superoptimizer::new(Goal::Sequence(assemble("LAX $10"))).run().is_err());
+        assert!(Superoptimizer::new(Goal::Sequence(assemble("STA $10,X; STY $20"))).exhaustive(true).run().is_err());
+        assert!(Location::parse("q").is_none() && Location::parse("$10") == Some(Location::Memory(0x10)));
+    }
+}
@@ -0,0 +1,230 @@
+// Symbol files
+//
+// The labels of the toolchains and of the emulators, by the extension of the file:
+//
+//   .dbg   the labels of a ca65 debug file (see dbg.rs)
+//   .nl    FCEUX: $C000#reset#comment, $0300/10#buffer# (16 bytes); the bank is the number
+//          before .nl in the name of the file (game.nes.1.nl, in hex), game.nes.ram.nl has none
+//   .mlb   Mesen: P:0010:reset:comment, R:0300-030F:buffer; P is an offset in the PRG ROM (the 32K
+//          from $8000, the bank is its 16K), R the internal RAM, W and S the work and save RAM at $6000, G
+//          the registers (the names of Mesen 2 too: NesPrgRom, NesInternalRam, ...)
+//   others VICE: al C:0810 .start (the only format written)
+//
+// The symbols of the other banks have the same addresses: names() and vice() take the ones
+// without a bank and the ones of the given bank, or all of them. names() keeps one name by
+// address for the disassembler, vice() writes them all (VICE takes several labels by address).
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::dbg::DebugInfo;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u16,
+    pub bank: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    pub symbols: Vec<Symbol>,
+}
+
+fn hex(text: &str, what: &str) -> Result<u32, String> {
+    u32::from_str_radix(text.trim(), 16).map_err(|_| format!("{}: bad address {}", what, text.trim()))
+}
+
+fn address(value: u32, what: &str) -> Result<u16, String> {
+    u16::try_from(value).map_err(|_| format!("{}: ${:X} is past $FFFF", what, value))
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    fn add(&mut self, name: &str, address: u16, bank: Option<u16>) {
+        self.symbols.push(Symbol { name: name.to_string(), address, bank });
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let symbols = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => DebugInfo::parse(&text).map(|info| Symbols::from_dbg(&info)),
+            Some("nl") => {
+                // game.nes.1.nl: bank 1, game.nes.ram.nl: no bank
+                let bank = name.trim_end_matches(".nl").rsplit_once('.').and_then(|(_, bank)| u16::from_str_radix(bank, 16).ok());
+                Symbols::parse_nl(&text, bank)
+            }
+            Some("mlb") => Symbols::parse_mlb(&text),
+            _ => Symbols::parse_vice(&text),
+        };
+        symbols.map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_dbg(info: &DebugInfo) -> Symbols {
+        let mut symbols = Symbols::new();
+        for (name, address) in &info.symbols {
+            symbols.add(name, *address, None);
+        }
+        symbols
+    }
+
+    // al C:0810 .start
+    pub fn parse_vice(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (n, line) in text.lines().enumerate() {
+            let what = format!("line {}", n + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (address, name) = match fields[..] {
+                [] => continue,
+                ["al", address, name] => (address, name),
+                _ => return Err(format!("{}: expected al address .label", what)),
+            };
+            // the memory of the computer, not the ones of the drives (8:, 9:, ...)
+            let address = match address.split_once(':') {
+                Some(("C", address)) | Some(("c", address)) => address,
+                Some(_) => continue,
+                None => address,
+            };
+            symbols.add(name.trim_start_matches('.'), self::address(hex(address, &what)?, &what)?, None);
+        }
+        Ok(symbols)
+    }
+
+    // $C000#reset#comment, the comments alone have no name
+    pub fn parse_nl(text: &str, bank: Option<u16>) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let what = format!("line {}", n + 1);
+            let mut fields = line.splitn(3, '#');
+            let address = fields.next().unwrap_or("").trim();
+            let name = fields.next().ok_or_else(|| format!("{}: expected $address#name#comment", what))?.trim();
+            let address = address.strip_prefix('$').ok_or_else(|| format!("{}: expected $address", what))?;
+            // $0300/10: an array, named at its first byte
+            let address = address.split_once('/').map_or(address, |(address, _)| address);
+            if !name.is_empty() {
+                symbols.add(name, self::address(hex(address, &what)?, &what)?, bank);
+            }
+        }
+        Ok(symbols)
+    }
+
+    // P:0010:reset:comment, R:0300-030F:buffer
+    pub fn parse_mlb(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+        for (n, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let what = format!("line {}", n + 1);
+            let mut fields = line.splitn(4, ':');
+            let (kind, range, name) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(range), Some(name)) => (kind.trim(), range, name.trim()),
+                _ => return Err(format!("{}: expected type:address:name", what)),
+            };
+            let offset = hex(range.split_once('-').map_or(range, |(first, _)| first), &what)?;
+            let (address, bank) = match kind {
+                "P" | "NesPrgRom" => (0x8000 + offset % 0x8000, Some((offset / 0x4000) as u16)),
+                "R" | "NesInternalRam" => (offset % 0x800, None),
+                "W" | "S" | "NesWorkRam" | "NesSaveRam" => (0x6000 + offset, None),
+                "G" | "NesMemory" | "Register" => (offset, None),
+                // the memory of the PPU, ...
+                _ => continue,
+            };
+            if !name.is_empty() {
+                symbols.add(name, self::address(address, &what)?, bank);
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn merge(&mut self, other: Symbols) {
+        self.symbols.extend(other.symbols);
+    }
+
+    // the symbols of the given bank (None: all the banks)
+    fn bank(&self, bank: Option<u16>) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(move |symbol| bank.is_none() || symbol.bank.is_none() || symbol.bank == bank)
+    }
+
+    // the names by address for the given bank, the first in alphabetical order for the addresses
+    // with several
+    pub fn names(&self, bank: Option<u16>) -> HashMap<u16, String> {
+        let mut names: HashMap<u16, String> = HashMap::new();
+        for symbol in self.bank(bank) {
+            let known = names.entry(symbol.address).or_insert_with(|| symbol.name.clone());
+            if symbol.name < *known {
+                *known = symbol.name.clone();
+            }
+        }
+        names
+    }
+
+    // the VICE labels of the given bank, by address
+    pub fn vice(&self, bank: Option<u16>) -> String {
+        let mut labels: Vec<(u16, &str)> = self.bank(bank).map(|symbol| (symbol.address, symbol.name.as_str())).collect();
+        labels.sort();
+        labels.dedup();
+        labels.iter().map(|(address, name)| format!("al C:{:04X} .{}\n", address, name)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    fn list(symbols: &Symbols) -> Vec<(&str, u16, Option<u16>)> {
+        symbols.symbols.iter().map(|s| (s.name.as_str(), s.address, s.bank)).collect()
+    }
+
+    #[test]
+    fn test_formats() {
+        let vice = Symbols::parse_vice("al C:0810 .start\nal 0820 loop\n\nal 8:0300 .drive\n").unwrap();
+        assert!(list(&vice) == [("start", 0x810, None), ("loop", 0x820, None)]);
+
+        let nl = Symbols::parse_nl("$C000#reset#the entry\n$0300/10#buffer#\n$C010##a comment\n", Some(1)).unwrap();
+        assert!(list(&nl) == [("reset", 0xc000, Some(1)), ("buffer", 0x300, Some(1))]);
+
+        let mlb = Symbols::parse_mlb("P:4010:nmi:the handler: NMI\nR:0300-030F:buffer\nS:0010:save\nG:2000:PPUCTRL\nNesPrgRom:0000:reset\nV:0000:vram\n").unwrap();
+        assert!(list(&mlb) == [("nmi", 0xc010, Some(1)), ("buffer", 0x300, None), ("save", 0x6010, None), ("PPUCTRL", 0x2000, None), ("reset", 0x8000, Some(0))]);
+
+        let info = DebugInfo::parse("sym\tid=0,name=\"main\",val=0x8000,type=lab\n").unwrap();
+        assert!(list(&Symbols::from_dbg(&info)) == [("main", 0x8000, None)]);
+    }
+
+    #[test]
+    fn test_names() {
+        let mut symbols = Symbols::parse_nl("$8000#reset#\n$8000#start#\n", Some(0)).unwrap();
+        symbols.merge(Symbols::parse_nl("$8000#bank1#\n", Some(1)).unwrap());
+        symbols.merge(Symbols::parse_vice("al C:0300 .buffer\n").unwrap());
+        let bank0 = symbols.names(Some(0));
+        assert!(bank0 == HashMap::from([(0x8000, "reset".to_string()), (0x300, "buffer".to_string())]));
+        assert!(symbols.names(Some(1))[&0x8000] == "bank1" && symbols.names(None)[&0x8000] == "bank1");
+        assert!(symbols.vice(Some(0)) == "al C:0300 .buffer\nal C:8000 .reset\nal C:8000 .start\n");
+        assert!(Symbols::parse_vice(&symbols.vice(Some(0))).unwrap().names(None) == bank0);
+        // the same label in two banks is written once
+        symbols.merge(Symbols::parse_nl("$0300#buffer#\n", Some(1)).unwrap());
+        assert!(symbols.vice(None) == "al C:0300 .buffer\nal C:8000 .bank1\nal C:8000 .reset\nal C:8000 .start\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Symbols::parse_vice("al C:0810").unwrap_err() == "line 1: expected al address .label");
+        assert!(Symbols::parse_vice("al C:12345 .x").unwrap_err() == "line 1: $12345 is past $FFFF");
+        assert!(Symbols::parse_nl("C000#reset#", None).unwrap_err() == "line 1: expected $address");
+        assert!(Symbols::parse_nl("$C0G0#reset#", None).unwrap_err() == "line 1: bad address C0G0");
+        assert!(Symbols::parse_mlb("P:0010").unwrap_err() == "line 1: expected type:address:name");
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = TempDir::new("symbols");
+        assert!(list(&Symbols::load(&dir.write("game.nes.1F.nl", "$C000#reset#\n")).unwrap()) == [("reset", 0xc000, Some(0x1f))]);
+        assert!(list(&Symbols::load(&dir.write("game.nes.ram.nl", "$0300#buffer#\n")).unwrap()) == [("buffer", 0x300, None)]);
+        assert!(list(&Symbols::load(&dir.write("game.lbl", "al C:8000 .main\n")).unwrap()) == [("main", 0x8000, None)]);
+        let none = dir.path().join("none.mlb");
+        assert!(Symbols::load(&none).unwrap_err().starts_with(&format!("{}: ", none.display())));
+    }
+}
@@ -0,0 +1,37 @@
+// Temporary directories of the tests
+//
+// A directory of the temp dir named after the test and the process, with the files the test
+// writes in it. It is removed when dropped, also when the test fails and unwinds.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    // an empty directory, what a previous run left there is removed
+    pub(crate) fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("mos6502_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+
+    // writes the file (and its directories), returns its path
+    pub(crate) fn write(&self, path: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,407 @@
+// TOML, enough for the test files
+//
+// The documents are parsed into Json values (the tables are objects, the integers numbers).
+// Supported: comments, [tables], [[arrays of tables]], dotted keys, quoted keys, basic and
+// literal strings (also multi-line), decimal, hexadecimal (0x), octal (0o) and binary (0b)
+// integers, booleans, arrays and inline tables. Not supported: floats and dates.
+
+use crate::json::Json;
+
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut parser = Parser { chars: text.chars().collect(), pos: 0, line: 1 };
+    let mut root = Json::Object(Vec::new());
+    let mut current: Vec<String> = Vec::new();
+    loop {
+        parser.blank();
+        let c = match parser.peek() {
+            Some(c) => c,
+            None => return Ok(root),
+        };
+        if c == '[' {
+            parser.pos += 1;
+            let array = parser.eat('[');
+            let path = parser.key()?;
+            parser.expect(']')?;
+            if array {
+                parser.expect(']')?;
+            }
+            let (last, parent) = path.split_last().unwrap();
+            let entries = parser.table(&mut root, parent)?;
+            match entries.iter_mut().find(|(k, _)| k == last) {
+                Some((_, Json::Array(tables))) if array => tables.push(Json::Object(Vec::new())),
+                Some((_, Json::Object(_))) if !array => {}
+                Some(_) => return Err(parser.error(&format!("'{}' is defined twice", path.join(".")))),
+                None if array => entries.push((last.clone(), Json::Array(vec![Json::Object(Vec::new())]))),
+                None => entries.push((last.clone(), Json::Object(Vec::new()))),
+            }
+            current = path;
+        } else {
+            let path = parser.key()?;
+            parser.expect('=')?;
+            let value = parser.value()?;
+            let full: Vec<String> = current.iter().chain(&path).cloned().collect();
+            parser.insert(&mut root, &full, value)?;
+        }
+        parser.end_of_line()?;
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {}", self.line, msg)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    // spaces and tabs
+    fn space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    // spaces, new lines and comments
+    fn blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.next();
+                }
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.space();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(self.error(&format!("expected '{}'", c))) }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.space();
+        if self.peek() == Some('#') {
+            while self.peek().is_some_and(|c| c != '\n') {
+                self.pos += 1;
+            }
+        }
+        self.eat('\r');
+        match self.next() {
+            None | Some('\n') => Ok(()),
+            Some(_) => Err(self.error("unexpected characters after the value")),
+        }
+    }
+
+    // a dotted key: name, "quoted name", a.b.c
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        loop {
+            self.space();
+            let part = match self.peek() {
+                Some('"') => self.string()?,
+                Some('\'') => self.literal()?,
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            path.push(part);
+            if !self.eat('.') {
+                return Ok(path);
+            }
+        }
+    }
+
+    // the entries of the table at `path` (created if needed, the last table of the arrays)
+    fn table<'a>(&self, root: &'a mut Json, path: &[String]) -> Result<&'a mut Vec<(String, Json)>, String> {
+        let mut table = root;
+        for key in path {
+            let entries = match table {
+                Json::Object(entries) => entries,
+                _ => unreachable!(),
+            };
+            let idx = match entries.iter().position(|(k, _)| k == key) {
+                Some(idx) => idx,
+                None => {
+                    entries.push((key.clone(), Json::Object(Vec::new())));
+                    entries.len() - 1
+                }
+            };
+            let value = &mut entries[idx].1;
+            let is_table = match &*value {
+                Json::Array(tables) => matches!(tables.last(), Some(Json::Object(_))),
+                value => matches!(value, Json::Object(_)),
+            };
+            if !is_table {
+                return Err(self.error(&format!("'{}' is not a table", key)));
+            }
+            table = match value {
+                Json::Array(tables) => tables.last_mut().unwrap(),
+                value => value,
+            };
+        }
+        match table {
+            Json::Object(entries) => Ok(entries),
+            _ => unreachable!(),
+        }
+    }
+
+    fn insert(&self, root: &mut Json, path: &[String], value: Json) -> Result<(), String> {
+        let (last, parent) = path.split_last().unwrap();
+        let entries = self.table(root, parent)?;
+        if entries.iter().any(|(k, _)| k == last) {
+            return Err(self.error(&format!("'{}' is defined twice", path.join("."))));
+        }
+        entries.push((last.clone(), value));
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.space();
+        match self.peek() {
+            Some('"') => self.string().map(Json::String),
+            Some('\'') => self.literal().map(Json::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.blank();
+                    if self.eat(']') {
+                        return Ok(Json::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.blank();
+                    if self.eat(']') {
+                        return Ok(Json::Array(items));
+                    }
+                    self.expect(',')?;
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut table = Json::Object(Vec::new());
+                if self.eat('}') {
+                    return Ok(table);
+                }
+                loop {
+                    let path = self.key()?;
+                    self.expect('=')?;
+                    let value = self.value()?;
+                    self.insert(&mut table, &path, value)?;
+                    if self.eat('}') {
+                        return Ok(table);
+                    }
+                    self.expect(',')?;
+                }
+            }
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || "+-_.:".contains(c)) {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    _ => integer(&word).map(|n| Json::Number(n as f64)).ok_or_else(|| self.error(&format!("'{}' is not a supported value", word))),
+                }
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    // "basic" or """multi-line basic"""
+    fn string(&mut self) -> Result<String, String> {
+        let multi = self.chars[self.pos..].starts_with(&['"', '"', '"']);
+        self.pos += if multi { 3 } else { 1 };
+        if multi {
+            self.skip_first_newline();
+        }
+        let mut s = String::new();
+        loop {
+            let c = match self.next() {
+                Some('\n') if !multi => return Err(self.error("unterminated string")),
+                Some(c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            match c {
+                '"' if !multi => return Ok(s),
+                '"' if self.chars[self.pos..].starts_with(&['"', '"']) => {
+                    self.pos += 2;
+                    return Ok(s);
+                }
+                '\\' => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some(c @ ('"' | '\\')) => s.push(c),
+                    Some(u @ ('u' | 'U')) => {
+                        let len = if u == 'u' { 4 } else { 8 };
+                        let hex: String = self.chars.iter().skip(self.pos).take(len).collect();
+                        let code = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or_else(|| self.error("bad escape"))?;
+                        self.pos += len;
+                        s.push(code);
+                    }
+                    // a line ending backslash trims the white space up to the next text
+                    Some(c) if multi && c.is_whitespace() => {
+                        while self.peek().is_some_and(|c| c.is_whitespace()) {
+                            self.next();
+                        }
+                    }
+                    _ => return Err(self.error("bad escape")),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    // 'literal' or '''multi-line literal'''
+    fn literal(&mut self) -> Result<String, String> {
+        let multi = self.chars[self.pos..].starts_with(&['\'', '\'', '\'']);
+        self.pos += if multi { 3 } else { 1 };
+        if multi {
+            self.skip_first_newline();
+        }
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('\'') if !multi => return Ok(s),
+                Some('\'') if self.chars[self.pos..].starts_with(&['\'', '\'']) => {
+                    self.pos += 2;
+                    return Ok(s);
+                }
+                Some('\n') if !multi => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn skip_first_newline(&mut self) {
+        if self.chars[self.pos..].starts_with(&['\r', '\n']) {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.next();
+        }
+    }
+}
+
+// 42, -1, 1_000, 0xFF, 0o17, 0b1010
+fn integer(word: &str) -> Option<i64> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word.strip_prefix('+').unwrap_or(word)),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+    // the underscores are only between digits
+    if digits.is_empty() || digits.starts_with(['_', '+', '-']) || digits.ends_with('_') || digits.contains("__") || (radix != 10 && negative) {
+        return None;
+    }
+    let value = i64::from_str_radix(&digits.replace('_', ""), radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# comment
+source = "math.s"   # comment
+origin = 0x8000
+"quoted key" = 'C:\path'
+flags.c = true
+
+[[test]]
+name = "add"
+a = -1_000
+memory = { "$10" = [1, 2, 0b11], x.y = 0o17 }
+
+[test.expect]
+list = [
+    1,  # one
+    2,
+]
+
+[[test]]
+name = """
+first \
+   second"""
+code = '''
+  LDA #1
+'''
+"#;
+        let doc = parse(text).unwrap();
+        assert!(doc.get("source").as_str() == Some("math.s"));
+        assert!(doc.get("origin").as_i64() == Some(0x8000));
+        assert!(doc.get("quoted key").as_str() == Some("C:\\path"));
+        assert!(doc.get("flags").get("c") == &Json::Bool(true));
+        let tests = doc.get("test").as_array().unwrap();
+        assert!(tests.len() == 2);
+        assert!(tests[0].get("a").as_i64() == Some(-1000));
+        assert!(tests[0].get("memory").get("$10") == &Json::Array(vec![Json::from(1i64), Json::from(2i64), Json::from(3i64)]));
+        assert!(tests[0].get("memory").get("x").get("y").as_i64() == Some(15));
+        assert!(tests[0].get("expect").get("list").as_array().unwrap().len() == 2);
+        assert!(tests[1].get("name").as_str() == Some("first second"));
+        assert!(tests[1].get("code").as_str() == Some("  LDA #1\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("a = 1\na = 2").unwrap_err() == "line 2: 'a' is defined twice");
+        assert!(parse("a = 1.5").unwrap_err() == "line 1: '1.5' is not a supported value");
+        assert!(parse("a = 1 2").unwrap_err() == "line 1: unexpected characters after the value");
+        assert!(parse("\n\na = \"abc").unwrap_err() == "line 3: unterminated string");
+        assert!(parse("a = 1\n[a]").unwrap_err() == "line 2: 'a' is defined twice");
+        assert!(parse("a = 1\n[a.b]").unwrap_err() == "line 2: 'a' is not a table");
+        assert!(parse("= 1").unwrap_err() == "line 1: expected a key");
+        assert!(parse("a = [1, 2").unwrap_err() == "line 1: expected ','");
+        assert!(integer("0x_1").is_none() && integer("-0x1").is_none() && integer("1__0").is_none());
+    }
+}